@@ -0,0 +1,560 @@
+use std::{slice, sync::Arc};
+
+use anyhow::{ensure, Result};
+use ash::{prelude::VkResult, vk};
+use bytemuck::Pod;
+
+use crate::backend::{BarrierBuilder, Device, DeviceGeneration, PipelineLayout, QueryPool, ShaderBindingTable};
+
+//Pools cached by the device point back to it, an Arc would keep the device alive forever
+enum CommandPoolDevice {
+    Shared(Arc<Device>),
+    Owner(*const Device)
+}
+
+pub struct CommandPool {
+    command_pool: vk::CommandPool,
+    queue_family_index: u32,
+
+    device_generation: DeviceGeneration,
+    device: CommandPoolDevice
+}
+
+unsafe impl Send for CommandPool {}
+unsafe impl Sync for CommandPool {}
+
+impl CommandPool {
+    pub fn new(device: Arc<Device>, queue_family_index: u32, flags: vk::CommandPoolCreateFlags) -> VkResult<Arc<Self>> {
+        let command_pool_create_info = vk::CommandPoolCreateInfo::default().queue_family_index(queue_family_index).flags(flags);
+
+        let command_pool = unsafe { device.loader().create_command_pool(&command_pool_create_info, None)? };
+
+        Ok(Arc::new(Self {
+            command_pool,
+            queue_family_index,
+
+            device_generation: device.generation(),
+            device: CommandPoolDevice::Shared(device)
+        }))
+    }
+
+    //The device destroys the pool itself and must outlive every reference to it
+    pub(crate) unsafe fn new_owned_by(device: *const Device, command_pool: vk::CommandPool, queue_family_index: u32, device_generation: DeviceGeneration) -> Self {
+        Self {
+            command_pool,
+            queue_family_index,
+
+            device_generation,
+            device: CommandPoolDevice::Owner(device)
+        }
+    }
+
+    pub fn allocate(&self, count: u32, level: vk::CommandBufferLevel) -> VkResult<Vec<vk::CommandBuffer>> {
+        let command_buffer_allocate_info = vk::CommandBufferAllocateInfo::default()
+            .command_pool(self.command_pool)
+            .level(level)
+            .command_buffer_count(count);
+
+        unsafe { self.device().loader().allocate_command_buffers(&command_buffer_allocate_info) }
+    }
+
+    //The command buffers must not be pending anymore
+    #[inline]
+    pub fn free(&self, command_buffers: &[vk::CommandBuffer]) {
+        unsafe { self.device().loader().free_command_buffers(self.command_pool, command_buffers) }
+    }
+
+    #[inline]
+    pub fn reset(&self) -> VkResult<()> {
+        unsafe { self.device().loader().reset_command_pool(self.command_pool, vk::CommandPoolResetFlags::empty()) }
+    }
+
+    #[inline]
+    pub fn command_pool(&self) -> &vk::CommandPool {
+        &self.command_pool
+    }
+
+    #[inline]
+    pub fn queue_family_index(&self) -> u32 {
+        self.queue_family_index
+    }
+
+    #[inline]
+    pub fn device(&self) -> &Device {
+        match &self.device {
+            CommandPoolDevice::Shared(device) => device,
+            CommandPoolDevice::Owner(device) => unsafe { &**device }
+        }
+    }
+}
+
+impl Drop for CommandPool {
+    #[inline]
+    fn drop(&mut self) {
+        if let CommandPoolDevice::Shared(device) = &self.device {
+            device.assert_generation(self.device_generation);
+
+            unsafe {
+                device.loader().destroy_command_pool(self.command_pool, None);
+            }
+        }
+    }
+}
+
+pub struct CommandBuffer {
+    command_buffer: vk::CommandBuffer,
+
+    command_pool: Arc<CommandPool>
+}
+
+impl CommandBuffer {
+    pub fn new(command_pool: Arc<CommandPool>, level: vk::CommandBufferLevel) -> VkResult<Self> {
+        let command_buffer = command_pool.allocate(1, level)?[0];
+
+        Ok(Self { command_buffer, command_pool })
+    }
+
+    #[inline]
+    pub fn begin(&self) -> VkResult<()> {
+        self.begin_with_flags(vk::CommandBufferUsageFlags::empty())
+    }
+
+    #[inline]
+    pub fn begin_one_time_submit(&self) -> VkResult<()> {
+        self.begin_with_flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT)
+    }
+
+    #[inline]
+    pub fn begin_with_flags(&self, flags: vk::CommandBufferUsageFlags) -> VkResult<()> {
+        let command_buffer_begin_info = vk::CommandBufferBeginInfo::default().flags(flags);
+
+        unsafe { self.command_pool.device().loader().begin_command_buffer(self.command_buffer, &command_buffer_begin_info) }
+    }
+
+    #[inline]
+    pub fn end(&self) -> VkResult<()> {
+        unsafe { self.command_pool.device().loader().end_command_buffer(self.command_buffer) }
+    }
+
+    pub fn draw_mesh_tasks(&self, task_count: u32, first_task: u32) -> VkResult<()> {
+        let device = self.command_pool.device();
+        if !device.extensions().nv_mesh_shader() {
+            return Err(vk::Result::ERROR_EXTENSION_NOT_PRESENT)
+        }
+
+        unsafe { device.mesh_shader_loader().cmd_draw_mesh_tasks(self.command_buffer, task_count, first_task) };
+
+        Ok(())
+    }
+
+    pub fn trace_rays(&self, shader_binding_table: &ShaderBindingTable, width: u32, height: u32, depth: u32) -> VkResult<()> {
+        let device = self.command_pool.device();
+        if !device.supports_ray_tracing() {
+            return Err(vk::Result::ERROR_FEATURE_NOT_PRESENT)
+        }
+
+        unsafe {
+            device.ray_tracing_pipeline_loader().cmd_trace_rays(
+                self.command_buffer,
+                shader_binding_table.raygen_region(),
+                shader_binding_table.miss_region(),
+                shader_binding_table.hit_region(),
+                shader_binding_table.callable_region(),
+                width,
+                height,
+                depth
+            )
+        };
+
+        Ok(())
+    }
+
+    #[inline]
+    pub fn bind_pipeline(&self, bind_point: vk::PipelineBindPoint, pipeline: vk::Pipeline) {
+        unsafe { self.command_pool.device().loader().cmd_bind_pipeline(self.command_buffer, bind_point, pipeline) }
+    }
+
+    #[inline]
+    pub fn bind_descriptor_sets(
+        &self,
+        bind_point: vk::PipelineBindPoint,
+        layout: vk::PipelineLayout,
+        first_set: u32,
+        descriptor_sets: &[vk::DescriptorSet],
+        dynamic_offsets: &[u32]
+    ) {
+        unsafe {
+            self.command_pool
+                .device()
+                .loader()
+                .cmd_bind_descriptor_sets(self.command_buffer, bind_point, layout, first_set, descriptor_sets, dynamic_offsets)
+        }
+    }
+
+    #[inline]
+    pub fn bind_vertex_buffers(&self, first_binding: u32, buffers: &[vk::Buffer], offsets: &[vk::DeviceSize]) {
+        unsafe {
+            self.command_pool
+                .device()
+                .loader()
+                .cmd_bind_vertex_buffers(self.command_buffer, first_binding, buffers, offsets)
+        }
+    }
+
+    #[inline]
+    pub fn bind_index_buffer(&self, buffer: vk::Buffer, offset: vk::DeviceSize, index_type: vk::IndexType) {
+        unsafe { self.command_pool.device().loader().cmd_bind_index_buffer(self.command_buffer, buffer, offset, index_type) }
+    }
+
+    //Graphics pipelines use dynamic viewport and scissor state
+    #[inline]
+    pub fn set_viewport(&self, viewport: vk::Viewport) {
+        unsafe { self.command_pool.device().loader().cmd_set_viewport(self.command_buffer, 0, slice::from_ref(&viewport)) }
+    }
+
+    #[inline]
+    pub fn set_scissor(&self, scissor: vk::Rect2D) {
+        unsafe { self.command_pool.device().loader().cmd_set_scissor(self.command_buffer, 0, slice::from_ref(&scissor)) }
+    }
+
+    #[inline]
+    pub fn draw(&self, vertex_count: u32, instance_count: u32, first_vertex: u32, first_instance: u32) {
+        unsafe {
+            self.command_pool
+                .device()
+                .loader()
+                .cmd_draw(self.command_buffer, vertex_count, instance_count, first_vertex, first_instance)
+        }
+    }
+
+    #[inline]
+    pub fn draw_indexed(&self, index_count: u32, instance_count: u32, first_index: u32, vertex_offset: i32, first_instance: u32) {
+        unsafe {
+            self.command_pool
+                .device()
+                .loader()
+                .cmd_draw_indexed(self.command_buffer, index_count, instance_count, first_index, vertex_offset, first_instance)
+        }
+    }
+
+    pub fn dispatch(&self, group_count_x: u32, group_count_y: u32, group_count_z: u32) -> Result<()> {
+        let device = self.command_pool.device();
+
+        let max_group_count = device.properties().properties.limits.max_compute_work_group_count;
+        let group_count = [group_count_x, group_count_y, group_count_z];
+        ensure!(
+            group_count.iter().zip(max_group_count.iter()).all(|(count, max)| count <= max),
+            "Dispatch of {:?} work groups exceeds the device limit of {:?}",
+            group_count,
+            max_group_count
+        );
+
+        unsafe { device.loader().cmd_dispatch(self.command_buffer, group_count_x, group_count_y, group_count_z) };
+
+        Ok(())
+    }
+
+    #[inline]
+    pub fn begin_label(&self, name: &str, color: [f32; 4]) {
+        self.command_pool.device().cmd_begin_debug_label(self.command_buffer, name, color);
+    }
+
+    #[inline]
+    pub fn end_label(&self) {
+        self.command_pool.device().cmd_end_debug_label(self.command_buffer);
+    }
+
+    #[inline]
+    pub fn insert_label(&self, name: &str, color: [f32; 4]) {
+        self.command_pool.device().cmd_insert_debug_label(self.command_buffer, name, color);
+    }
+
+    pub fn begin_rendering(&self, render_area: vk::Rect2D, color_attachments: &[vk::RenderingAttachmentInfo], depth_attachment: Option<&vk::RenderingAttachmentInfo>) {
+        let mut rendering_info = vk::RenderingInfo::default()
+            .render_area(render_area)
+            .layer_count(1)
+            .color_attachments(color_attachments);
+
+        if let Some(depth_attachment) = depth_attachment {
+            rendering_info = rendering_info.depth_attachment(depth_attachment);
+        }
+
+        self.command_pool.device().cmd_begin_rendering(self.command_buffer, &rendering_info);
+    }
+
+    #[inline]
+    pub fn end_rendering(&self) {
+        self.command_pool.device().cmd_end_rendering(self.command_buffer);
+    }
+
+    pub fn push_constants<T: Pod>(&self, layout: &PipelineLayout, stages: vk::ShaderStageFlags, offset: u32, data: &T) -> Result<()> {
+        let bytes = bytemuck::bytes_of(data);
+        layout.validate_push_constants(stages, offset, bytes.len() as u32)?;
+
+        unsafe {
+            self.command_pool
+                .device()
+                .loader()
+                .cmd_push_constants(self.command_buffer, *layout.pipeline_layout(), stages, offset, bytes);
+        }
+
+        Ok(())
+    }
+
+    #[inline]
+    pub fn pipeline_barrier2(&self, dependency: &BarrierBuilder) {
+        self.command_pool.device().cmd_pipeline_barrier2(self.command_buffer, dependency);
+    }
+
+    #[inline]
+    pub fn write_timestamp(&self, query_pool: &QueryPool, stage: vk::PipelineStageFlags, index: u32) {
+        query_pool.cmd_write_timestamp(self.command_buffer, stage, index);
+    }
+
+    #[inline]
+    pub fn command_buffer(&self) -> &vk::CommandBuffer {
+        &self.command_buffer
+    }
+
+    #[inline]
+    pub fn command_pool(&self) -> &Arc<CommandPool> {
+        &self.command_pool
+    }
+}
+
+impl Drop for CommandBuffer {
+    #[inline]
+    fn drop(&mut self) {
+        self.command_pool.free(slice::from_ref(&self.command_buffer));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backend::{
+            resource::{Buffer, BufferDesc, Image, ImageDesc},
+            swapchain::capture_image,
+            ComputePipelineDesc, DescriptorPool, DescriptorSetLayout, DescriptorWriter, Fence, GraphicsPipelineDesc, Pipeline, VertexLayout, ENTRY_POINT
+        },
+        renderer::test_device,
+        resource::{Shader, ShaderStage}
+    };
+
+    const DOUBLE_GLSL: &str = "#version 450
+layout(local_size_x = 64) in;
+layout(set = 0, binding = 0) buffer Values { uint values[]; };
+void main() { values[gl_GlobalInvocationID.x] *= 2; }
+";
+
+    #[test]
+    fn compute_dispatch_doubles_storage_buffer() {
+        let device = match test_device() {
+            Some(device) => device,
+            None => return
+        };
+
+        let values: Vec<u32> = (0..64).collect();
+        let buffer = Buffer::new(device.clone(), &BufferDesc::new_gpu_to_cpu(256, vk::BufferUsageFlags::STORAGE_BUFFER)).unwrap();
+        buffer.write_slice(0, &values).unwrap();
+
+        let binding = vk::DescriptorSetLayoutBinding::default()
+            .binding(0)
+            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::COMPUTE);
+        let set_layout = DescriptorSetLayout::new(device.clone(), slice::from_ref(&binding)).unwrap();
+
+        let pool_size = vk::DescriptorPoolSize::default().ty(vk::DescriptorType::STORAGE_BUFFER).descriptor_count(1);
+        let descriptor_pool = DescriptorPool::new(device.clone(), 1, slice::from_ref(&pool_size), vk::DescriptorPoolCreateFlags::empty()).unwrap();
+        let descriptor_sets = descriptor_pool.allocate(&[&set_layout]).unwrap();
+        DescriptorWriter::new()
+            .write_buffer(descriptor_sets[0], 0, vk::DescriptorType::STORAGE_BUFFER, *buffer.buffer(), 0, vk::WHOLE_SIZE)
+            .flush(&device);
+
+        let spirv = Shader::from_glsl(DOUBLE_GLSL).compile(ShaderStage::Compute, "main").unwrap();
+        let layout = PipelineLayout::new(device.clone(), &[&set_layout], &[]).unwrap();
+        let desc = ComputePipelineDesc {
+            spirv: &spirv,
+            entry_point: ENTRY_POINT,
+            layout: *layout.pipeline_layout()
+        };
+        let pipeline = Pipeline::new_compute(device.clone(), &desc, None).unwrap();
+
+        let command_pool = CommandPool::new(device.clone(), device.direct_queue().family_index(), vk::CommandPoolCreateFlags::empty()).unwrap();
+        let command_buffer = CommandBuffer::new(command_pool, vk::CommandBufferLevel::PRIMARY).unwrap();
+
+        command_buffer.begin_one_time_submit().unwrap();
+        command_buffer.bind_pipeline(vk::PipelineBindPoint::COMPUTE, *pipeline.pipeline());
+        command_buffer.bind_descriptor_sets(vk::PipelineBindPoint::COMPUTE, *layout.pipeline_layout(), 0, &descriptor_sets, &[]);
+        command_buffer.dispatch(1, 1, 1).unwrap();
+
+        let memory_barrier = vk::MemoryBarrier::default().src_access_mask(vk::AccessFlags::SHADER_WRITE).dst_access_mask(vk::AccessFlags::HOST_READ);
+        unsafe {
+            device.loader().cmd_pipeline_barrier(
+                *command_buffer.command_buffer(),
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::HOST,
+                vk::DependencyFlags::empty(),
+                slice::from_ref(&memory_barrier),
+                &[],
+                &[]
+            );
+        }
+        command_buffer.end().unwrap();
+
+        let fence = Fence::new(device.clone(), false).unwrap();
+        device.direct_queue().submit(&device, slice::from_ref(command_buffer.command_buffer()), &[], &[], Some(&fence)).unwrap();
+        fence.wait(u64::MAX).unwrap();
+
+        let doubled: Vec<_> = buffer
+            .read_bytes()
+            .unwrap()
+            .chunks_exact(4)
+            .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+        assert_eq!(doubled, values.iter().map(|value| value * 2).collect::<Vec<_>>());
+    }
+
+    const TRIANGLE_VERTEX_GLSL: &str = "#version 450
+const vec2 positions[3] = vec2[](vec2(-0.5, -0.5), vec2(0.5, -0.5), vec2(0.0, 0.5));
+void main() { gl_Position = vec4(positions[gl_VertexIndex], 0.0, 1.0); }
+";
+
+    const TRIANGLE_FRAGMENT_GLSL: &str = "#version 450
+layout(location = 0) out vec4 color;
+void main() { color = vec4(1.0, 0.0, 0.0, 1.0); }
+";
+
+    #[test]
+    fn draw_rasterizes_a_triangle() {
+        let device = match test_device() {
+            Some(device) if device.supports_dynamic_rendering() => device,
+            _ => return
+        };
+
+        let extent = vk::Extent2D { width: 8, height: 8 };
+        let format = vk::Format::R8G8B8A8_UNORM;
+        let image = Image::new(
+            device.clone(),
+            &ImageDesc::new_2d(extent, format, vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC)
+        )
+        .unwrap();
+
+        let vertex_spirv = Shader::from_glsl(TRIANGLE_VERTEX_GLSL).compile(ShaderStage::Vertex, "main").unwrap();
+        let fragment_spirv = Shader::from_glsl(TRIANGLE_FRAGMENT_GLSL).compile(ShaderStage::Fragment, "main").unwrap();
+        let layout = PipelineLayout::new(device.clone(), &[], &[]).unwrap();
+        let vertex_layout = VertexLayout::new();
+        let desc = GraphicsPipelineDesc {
+            vertex_spirv: &vertex_spirv,
+            vertex_entry_point: None,
+            fragment_spirv: &fragment_spirv,
+            fragment_entry_point: None,
+            vertex_layout: &vertex_layout,
+            topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+            cull_mode: vk::CullModeFlags::NONE,
+            depth_test: false,
+            render_pass: vk::RenderPass::null(),
+            color_formats: slice::from_ref(&format),
+            depth_format: vk::Format::UNDEFINED,
+            layout: *layout.pipeline_layout()
+        };
+        let pipeline = Pipeline::new_graphics(device.clone(), &desc, None).unwrap();
+
+        let command_pool = CommandPool::new(device.clone(), device.direct_queue().family_index(), vk::CommandPoolCreateFlags::empty()).unwrap();
+        let command_buffer = CommandBuffer::new(command_pool, vk::CommandBufferLevel::PRIMARY).unwrap();
+
+        let subresource_range = vk::ImageSubresourceRange::default().aspect_mask(vk::ImageAspectFlags::COLOR).level_count(1).layer_count(1);
+        let barrier = |old_layout, new_layout, src_access_mask, dst_access_mask| {
+            vk::ImageMemoryBarrier::default()
+                .src_access_mask(src_access_mask)
+                .dst_access_mask(dst_access_mask)
+                .old_layout(old_layout)
+                .new_layout(new_layout)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(*image.image())
+                .subresource_range(subresource_range)
+        };
+
+        command_buffer.begin_one_time_submit().unwrap();
+        unsafe {
+            let to_attachment = barrier(vk::ImageLayout::UNDEFINED, vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL, vk::AccessFlags::empty(), vk::AccessFlags::COLOR_ATTACHMENT_WRITE);
+            device.loader().cmd_pipeline_barrier(
+                *command_buffer.command_buffer(),
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                slice::from_ref(&to_attachment)
+            );
+        }
+
+        let render_area = vk::Rect2D::default().extent(extent);
+        let color_attachment = vk::RenderingAttachmentInfo::default()
+            .image_view(*image.view())
+            .image_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+            .load_op(vk::AttachmentLoadOp::CLEAR)
+            .store_op(vk::AttachmentStoreOp::STORE)
+            .clear_value(vk::ClearValue {
+                color: vk::ClearColorValue { float32: [0.0, 0.0, 0.0, 1.0] }
+            });
+        command_buffer.begin_rendering(render_area, slice::from_ref(&color_attachment), None);
+        command_buffer.bind_pipeline(vk::PipelineBindPoint::GRAPHICS, *pipeline.pipeline());
+        command_buffer.set_viewport(vk::Viewport::default().width(extent.width as f32).height(extent.height as f32).max_depth(1.0));
+        command_buffer.set_scissor(render_area);
+        command_buffer.draw(3, 1, 0, 0);
+        command_buffer.end_rendering();
+
+        unsafe {
+            let to_general = barrier(
+                vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                vk::ImageLayout::GENERAL,
+                vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+                vk::AccessFlags::TRANSFER_READ
+            );
+            device.loader().cmd_pipeline_barrier(
+                *command_buffer.command_buffer(),
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                slice::from_ref(&to_general)
+            );
+        }
+        command_buffer.end().unwrap();
+
+        let fence = Fence::new(device.clone(), false).unwrap();
+        device.direct_queue().submit(&device, slice::from_ref(command_buffer.command_buffer()), &[], &[], Some(&fence)).unwrap();
+        fence.wait(u64::MAX).unwrap();
+
+        let image_data = capture_image(&device, *image.image(), vk::ImageLayout::GENERAL, extent, format, None).unwrap();
+        let pixel = |x: u32, y: u32| {
+            let offset = ((y * extent.width + x) * 4) as usize;
+            &image_data.bytes[offset..offset + 4]
+        };
+
+        //The triangle covers the center, the corners keep the clear color
+        assert_eq!(pixel(4, 4), [255u8, 0, 0, 255]);
+        assert_eq!(pixel(0, 0), [0u8, 0, 0, 255]);
+    }
+
+    #[test]
+    fn dispatch_above_the_device_limit_is_rejected() {
+        let device = match test_device() {
+            Some(device) => device,
+            None => return
+        };
+
+        let command_pool = CommandPool::new(device.clone(), device.direct_queue().family_index(), vk::CommandPoolCreateFlags::empty()).unwrap();
+        let command_buffer = CommandBuffer::new(command_pool, vk::CommandBufferLevel::PRIMARY).unwrap();
+
+        let max_group_count = device.properties().properties.limits.max_compute_work_group_count;
+        command_buffer.begin_one_time_submit().unwrap();
+        assert!(command_buffer.dispatch(max_group_count[0].saturating_add(1), 1, 1).is_err() || max_group_count[0] == u32::MAX);
+        command_buffer.end().unwrap();
+    }
+}
@@ -0,0 +1,377 @@
+use std::{slice, sync::Arc};
+
+use anyhow::{ensure, Result};
+use ash::{prelude::VkResult, vk};
+
+use crate::backend::{
+    resource::{Image, Sampler},
+    Device, DeviceGeneration
+};
+
+pub struct DescriptorSetLayout {
+    descriptor_set_layout: vk::DescriptorSetLayout,
+
+    device_generation: DeviceGeneration,
+    device: Arc<Device>
+}
+
+impl DescriptorSetLayout {
+    pub fn new(device: Arc<Device>, bindings: &[vk::DescriptorSetLayoutBinding]) -> VkResult<Self> {
+        let descriptor_set_layout_create_info = vk::DescriptorSetLayoutCreateInfo::default().bindings(bindings);
+
+        let descriptor_set_layout = unsafe { device.loader().create_descriptor_set_layout(&descriptor_set_layout_create_info, None)? };
+
+        Ok(Self {
+            descriptor_set_layout,
+
+            device_generation: device.generation(),
+            device
+        })
+    }
+
+    pub fn new_with_flags(
+        device: Arc<Device>,
+        bindings: &[vk::DescriptorSetLayoutBinding],
+        binding_flags: &[vk::DescriptorBindingFlags],
+        flags: vk::DescriptorSetLayoutCreateFlags
+    ) -> VkResult<Self> {
+        let mut descriptor_set_layout_binding_flags_create_info = vk::DescriptorSetLayoutBindingFlagsCreateInfo::default().binding_flags(binding_flags);
+        let descriptor_set_layout_create_info = vk::DescriptorSetLayoutCreateInfo::default()
+            .bindings(bindings)
+            .flags(flags)
+            .push_next(&mut descriptor_set_layout_binding_flags_create_info);
+
+        let descriptor_set_layout = unsafe { device.loader().create_descriptor_set_layout(&descriptor_set_layout_create_info, None)? };
+
+        Ok(Self {
+            descriptor_set_layout,
+
+            device_generation: device.generation(),
+            device
+        })
+    }
+
+    #[inline]
+    pub fn descriptor_set_layout(&self) -> &vk::DescriptorSetLayout {
+        &self.descriptor_set_layout
+    }
+}
+
+impl Drop for DescriptorSetLayout {
+    #[inline]
+    fn drop(&mut self) {
+        self.device.assert_generation(self.device_generation);
+
+        unsafe {
+            self.device.loader().destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+        }
+    }
+}
+
+pub struct DescriptorPool {
+    descriptor_pool: vk::DescriptorPool,
+
+    device_generation: DeviceGeneration,
+    device: Arc<Device>
+}
+
+impl DescriptorPool {
+    pub fn new(device: Arc<Device>, max_sets: u32, pool_sizes: &[vk::DescriptorPoolSize], flags: vk::DescriptorPoolCreateFlags) -> VkResult<Self> {
+        let descriptor_pool_create_info = vk::DescriptorPoolCreateInfo::default().max_sets(max_sets).pool_sizes(pool_sizes).flags(flags);
+
+        let descriptor_pool = unsafe { device.loader().create_descriptor_pool(&descriptor_pool_create_info, None)? };
+
+        Ok(Self {
+            descriptor_pool,
+
+            device_generation: device.generation(),
+            device
+        })
+    }
+
+    pub fn allocate(&self, layouts: &[&DescriptorSetLayout]) -> VkResult<Vec<vk::DescriptorSet>> {
+        let set_layouts: Vec<_> = layouts.iter().map(|layout| layout.descriptor_set_layout).collect();
+
+        let descriptor_set_allocate_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(self.descriptor_pool)
+            .set_layouts(&set_layouts);
+
+        unsafe { self.device.loader().allocate_descriptor_sets(&descriptor_set_allocate_info) }
+    }
+
+    #[inline]
+    pub fn reset(&self) -> VkResult<()> {
+        unsafe { self.device.loader().reset_descriptor_pool(self.descriptor_pool, vk::DescriptorPoolResetFlags::empty()) }
+    }
+
+    #[inline]
+    pub fn descriptor_pool(&self) -> &vk::DescriptorPool {
+        &self.descriptor_pool
+    }
+}
+
+impl Drop for DescriptorPool {
+    #[inline]
+    fn drop(&mut self) {
+        self.device.assert_generation(self.device_generation);
+
+        unsafe {
+            self.device.loader().destroy_descriptor_pool(self.descriptor_pool, None);
+        }
+    }
+}
+
+enum DescriptorInfo {
+    Buffer(vk::DescriptorBufferInfo),
+    Image(vk::DescriptorImageInfo)
+}
+
+struct PendingWrite {
+    set: vk::DescriptorSet,
+    binding: u32,
+    array_element: u32,
+    descriptor_type: vk::DescriptorType,
+    info: DescriptorInfo
+}
+
+#[derive(Default)]
+pub struct DescriptorWriter {
+    writes: Vec<PendingWrite>
+}
+
+impl DescriptorWriter {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn write_buffer(
+        &mut self,
+        set: vk::DescriptorSet,
+        binding: u32,
+        descriptor_type: vk::DescriptorType,
+        buffer: vk::Buffer,
+        offset: vk::DeviceSize,
+        range: vk::DeviceSize
+    ) -> &mut Self {
+        self.writes.push(PendingWrite {
+            set,
+            binding,
+            array_element: 0,
+            descriptor_type,
+            info: DescriptorInfo::Buffer(vk::DescriptorBufferInfo::default().buffer(buffer).offset(offset).range(range))
+        });
+        self
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn write_image(
+        &mut self,
+        set: vk::DescriptorSet,
+        binding: u32,
+        array_element: u32,
+        descriptor_type: vk::DescriptorType,
+        image_view: vk::ImageView,
+        image_layout: vk::ImageLayout,
+        sampler: vk::Sampler
+    ) -> &mut Self {
+        self.writes.push(PendingWrite {
+            set,
+            binding,
+            array_element,
+            descriptor_type,
+            info: DescriptorInfo::Image(vk::DescriptorImageInfo::default().image_view(image_view).image_layout(image_layout).sampler(sampler))
+        });
+        self
+    }
+
+    pub fn flush(&mut self, device: &Device) {
+        let descriptor_writes: Vec<_> = self
+            .writes
+            .iter()
+            .map(|write| {
+                let descriptor_write = vk::WriteDescriptorSet::default()
+                    .dst_set(write.set)
+                    .dst_binding(write.binding)
+                    .dst_array_element(write.array_element)
+                    .descriptor_type(write.descriptor_type);
+
+                match &write.info {
+                    DescriptorInfo::Buffer(info) => descriptor_write.buffer_info(slice::from_ref(info)),
+                    DescriptorInfo::Image(info) => descriptor_write.image_info(slice::from_ref(info))
+                }
+            })
+            .collect();
+
+        unsafe {
+            device.loader().update_descriptor_sets(&descriptor_writes, &[]);
+        }
+
+        self.writes.clear();
+    }
+}
+
+//Hands out the lowest never used index unless a freed one can be reused
+#[derive(Debug)]
+struct SlotAllocator {
+    capacity: u32,
+    allocated: Vec<bool>,
+    free_indices: Vec<u32>
+}
+
+impl SlotAllocator {
+    #[inline]
+    fn new(capacity: u32) -> Self {
+        Self {
+            capacity,
+            allocated: Vec::new(),
+            free_indices: Vec::new()
+        }
+    }
+
+    fn allocate(&mut self) -> Option<u32> {
+        let index = match self.free_indices.pop() {
+            Some(index) => index,
+            None if (self.allocated.len() as u32) < self.capacity => {
+                self.allocated.push(false);
+                self.allocated.len() as u32 - 1
+            }
+            None => return None
+        };
+
+        self.allocated[index as usize] = true;
+
+        Some(index)
+    }
+
+    fn free(&mut self, index: u32) -> Result<()> {
+        ensure!(
+            self.allocated.get(index as usize).copied().unwrap_or(false),
+            "Bindless slot {} is not allocated",
+            index
+        );
+
+        self.allocated[index as usize] = false;
+        self.free_indices.push(index);
+
+        Ok(())
+    }
+}
+
+pub struct BindlessTextureTable {
+    descriptor_set_layout: DescriptorSetLayout,
+    descriptor_pool: DescriptorPool,
+    descriptor_set: vk::DescriptorSet,
+
+    slots: SlotAllocator
+}
+
+impl BindlessTextureTable {
+    pub const BINDING: u32 = 0;
+
+    pub fn new(device: Arc<Device>, capacity: u32) -> VkResult<Self> {
+        if !device.supports_bindless() {
+            return Err(vk::Result::ERROR_FEATURE_NOT_PRESENT)
+        }
+
+        let binding = vk::DescriptorSetLayoutBinding::default()
+            .binding(Self::BINDING)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .descriptor_count(capacity)
+            .stage_flags(vk::ShaderStageFlags::ALL);
+        let binding_flags = vk::DescriptorBindingFlags::PARTIALLY_BOUND | vk::DescriptorBindingFlags::UPDATE_AFTER_BIND;
+
+        let descriptor_set_layout = DescriptorSetLayout::new_with_flags(
+            device.clone(),
+            slice::from_ref(&binding),
+            slice::from_ref(&binding_flags),
+            vk::DescriptorSetLayoutCreateFlags::UPDATE_AFTER_BIND_POOL
+        )?;
+
+        let pool_size = vk::DescriptorPoolSize::default().ty(vk::DescriptorType::COMBINED_IMAGE_SAMPLER).descriptor_count(capacity);
+        let descriptor_pool = DescriptorPool::new(device, 1, slice::from_ref(&pool_size), vk::DescriptorPoolCreateFlags::UPDATE_AFTER_BIND)?;
+
+        let descriptor_set = descriptor_pool.allocate(&[&descriptor_set_layout])?[0];
+
+        Ok(Self {
+            descriptor_set_layout,
+            descriptor_pool,
+            descriptor_set,
+
+            slots: SlotAllocator::new(capacity)
+        })
+    }
+
+    //Returns the array index the shader uses to sample the texture, the image must be in SHADER_READ_ONLY_OPTIMAL when sampled
+    pub fn register(&mut self, image: &Image, sampler: &Sampler) -> VkResult<u32> {
+        let index = self.slots.allocate().ok_or(vk::Result::ERROR_OUT_OF_POOL_MEMORY)?;
+
+        DescriptorWriter::new()
+            .write_image(
+                self.descriptor_set,
+                Self::BINDING,
+                index,
+                vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                *image.view(),
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                *sampler.sampler()
+            )
+            .flush(&self.descriptor_pool.device);
+
+        Ok(index)
+    }
+
+    //The slot may be handed out again right away, so it must no longer be accessed by in flight frames.
+    //Freeing a slot that isn't registered is an error, a double free would let two textures share one slot
+    #[inline]
+    pub fn free(&mut self, index: u32) -> Result<()> {
+        self.slots.free(index)
+    }
+
+    #[inline]
+    pub fn descriptor_set_layout(&self) -> &DescriptorSetLayout {
+        &self.descriptor_set_layout
+    }
+
+    #[inline]
+    pub fn descriptor_set(&self) -> &vk::DescriptorSet {
+        &self.descriptor_set
+    }
+
+    #[inline]
+    pub fn capacity(&self) -> u32 {
+        self.slots.capacity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slots_are_monotonic_and_reused() {
+        let mut slots = SlotAllocator::new(4);
+
+        assert_eq!(slots.allocate(), Some(0));
+        assert_eq!(slots.allocate(), Some(1));
+        assert_eq!(slots.allocate(), Some(2));
+
+        slots.free(1).unwrap();
+        assert_eq!(slots.allocate(), Some(1));
+        assert_eq!(slots.allocate(), Some(3));
+        assert_eq!(slots.allocate(), None);
+    }
+
+    #[test]
+    fn double_free_is_rejected() {
+        let mut slots = SlotAllocator::new(4);
+
+        let index = slots.allocate().unwrap();
+        slots.free(index).unwrap();
+
+        assert!(slots.free(index).is_err());
+        assert!(slots.free(3).is_err());
+        assert_eq!(slots.allocate(), Some(index));
+        assert_eq!(slots.allocate(), Some(1));
+    }
+}
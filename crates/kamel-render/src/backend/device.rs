@@ -1,31 +1,57 @@
-use std::{os::raw::c_char, sync::Arc};
+use std::{
+    collections::HashMap,
+    env,
+    ffi::CString,
+    mem::{self, ManuallyDrop},
+    os::raw::c_char,
+    ptr, slice,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, Weak
+    }
+};
 
 use anyhow::Result;
 use ash::{
-    extensions::{khr::Swapchain, nv::MeshShader},
+    extensions::{
+        khr::{AccelerationStructure, BufferDeviceAddress, DynamicRendering, RayTracingPipeline, Swapchain, Synchronization2, TimelineSemaphore},
+        nv::MeshShader
+    },
     prelude::VkResult,
     vk
 };
-use vk_mem::{Allocator, AllocatorCreateInfo};
+use log::debug;
+use vk_mem::{ffi, Allocator, AllocatorCreateFlags, AllocatorCreateInfo};
 
-use crate::backend::{Instance, Surface};
+use crate::backend::{
+    resource::Buffer, util::string::from_c_char_array, AdapterInfo, BarrierBuilder, CommandPool, Fence, Instance, ShaderModuleCache, ShaderModuleHandle, Surface
+};
 
 pub struct Properties {
     pub properties: vk::PhysicalDeviceProperties,
-    pub mesh_shader_properties: vk::PhysicalDeviceMeshShaderPropertiesNV<'static>
+    pub mesh_shader_properties: vk::PhysicalDeviceMeshShaderPropertiesNV<'static>,
+    pub acceleration_structure_properties: vk::PhysicalDeviceAccelerationStructurePropertiesKHR<'static>,
+    pub ray_tracing_pipeline_properties: vk::PhysicalDeviceRayTracingPipelinePropertiesKHR<'static>
 }
 
 impl Properties {
     #[inline]
     unsafe fn new(instance: &Instance, physical_device: vk::PhysicalDevice) -> Self {
         let mut mesh_shader_properties = vk::PhysicalDeviceMeshShaderPropertiesNV::default();
-        let mut properties = vk::PhysicalDeviceProperties2::default().push_next(&mut mesh_shader_properties);
+        let mut acceleration_structure_properties = vk::PhysicalDeviceAccelerationStructurePropertiesKHR::default();
+        let mut ray_tracing_pipeline_properties = vk::PhysicalDeviceRayTracingPipelinePropertiesKHR::default();
+        let mut properties = vk::PhysicalDeviceProperties2::default()
+            .push_next(&mut mesh_shader_properties)
+            .push_next(&mut acceleration_structure_properties)
+            .push_next(&mut ray_tracing_pipeline_properties);
 
         instance.loader().get_physical_device_properties2(physical_device, &mut properties);
 
         Self {
             properties: properties.properties,
-            mesh_shader_properties
+            mesh_shader_properties,
+            acceleration_structure_properties,
+            ray_tracing_pipeline_properties
         }
     }
 }
@@ -50,6 +76,28 @@ impl MemoryProperties {
     }
 }
 
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+pub struct HeapBudget {
+    pub budget: vk::DeviceSize,
+    pub usage: Option<vk::DeviceSize>
+}
+
+#[derive(Copy, Clone, Debug, Default, Hash, Eq, PartialEq)]
+pub struct AllocatorStats {
+    pub allocation_count: u32,
+    pub block_count: u32,
+    pub allocated_bytes: vk::DeviceSize,
+    pub used_bytes: vk::DeviceSize
+}
+
+#[derive(Copy, Clone, Debug, Default, Hash, Eq, PartialEq)]
+pub struct DefragStats {
+    pub allocations_moved: u32,
+    pub bytes_moved: vk::DeviceSize,
+    pub bytes_freed: vk::DeviceSize,
+    pub device_memory_blocks_freed: u32
+}
+
 pub struct QueueFamilyProperties {
     pub queue_family_properties: Vec<vk::QueueFamilyProperties>
 }
@@ -77,33 +125,134 @@ impl QueueFamilyProperties {
 #[derive(Default)]
 pub struct Features {
     pub features: vk::PhysicalDeviceFeatures,
-    pub mesh_shader_features: vk::PhysicalDeviceMeshShaderFeaturesNV<'static>
+    pub mesh_shader_features: vk::PhysicalDeviceMeshShaderFeaturesNV<'static>,
+    pub timeline_semaphore_features: vk::PhysicalDeviceTimelineSemaphoreFeatures<'static>,
+    pub buffer_device_address_features: vk::PhysicalDeviceBufferDeviceAddressFeatures<'static>,
+    pub dynamic_rendering_features: vk::PhysicalDeviceDynamicRenderingFeatures<'static>,
+    pub descriptor_indexing_features: vk::PhysicalDeviceDescriptorIndexingFeatures<'static>,
+    pub synchronization2_features: vk::PhysicalDeviceSynchronization2Features<'static>,
+    pub acceleration_structure_features: vk::PhysicalDeviceAccelerationStructureFeaturesKHR<'static>,
+    pub ray_tracing_pipeline_features: vk::PhysicalDeviceRayTracingPipelineFeaturesKHR<'static>,
+    //Only queried on Vulkan 1.2 devices, where core features such as timeline semaphores are enabled through it
+    pub vulkan12_features: vk::PhysicalDeviceVulkan12Features<'static>
 }
 
 impl Features {
     #[inline]
     unsafe fn new(instance: &Instance, physical_device: vk::PhysicalDevice) -> Self {
         let mut mesh_shader_features = vk::PhysicalDeviceMeshShaderFeaturesNV::default();
-        let mut features = vk::PhysicalDeviceFeatures2::default().push_next(&mut mesh_shader_features);
+        let mut timeline_semaphore_features = vk::PhysicalDeviceTimelineSemaphoreFeatures::default();
+        let mut buffer_device_address_features = vk::PhysicalDeviceBufferDeviceAddressFeatures::default();
+        let mut dynamic_rendering_features = vk::PhysicalDeviceDynamicRenderingFeatures::default();
+        let mut descriptor_indexing_features = vk::PhysicalDeviceDescriptorIndexingFeatures::default();
+        let mut synchronization2_features = vk::PhysicalDeviceSynchronization2Features::default();
+        let mut acceleration_structure_features = vk::PhysicalDeviceAccelerationStructureFeaturesKHR::default();
+        let mut ray_tracing_pipeline_features = vk::PhysicalDeviceRayTracingPipelineFeaturesKHR::default();
+        let mut vulkan12_features = vk::PhysicalDeviceVulkan12Features::default();
+        let mut features = vk::PhysicalDeviceFeatures2::default()
+            .push_next(&mut mesh_shader_features)
+            .push_next(&mut timeline_semaphore_features)
+            .push_next(&mut buffer_device_address_features)
+            .push_next(&mut dynamic_rendering_features)
+            .push_next(&mut descriptor_indexing_features)
+            .push_next(&mut synchronization2_features)
+            .push_next(&mut acceleration_structure_features)
+            .push_next(&mut ray_tracing_pipeline_features);
+        if is_vulkan12(instance.loader().get_physical_device_properties(physical_device).api_version) {
+            features = features.push_next(&mut vulkan12_features);
+        }
 
         instance.loader().get_physical_device_features2(physical_device, &mut features);
 
+        //The chain points into this stack frame, so it must not be kept
+        mesh_shader_features.p_next = ptr::null_mut();
+        timeline_semaphore_features.p_next = ptr::null_mut();
+        buffer_device_address_features.p_next = ptr::null_mut();
+        dynamic_rendering_features.p_next = ptr::null_mut();
+        descriptor_indexing_features.p_next = ptr::null_mut();
+        synchronization2_features.p_next = ptr::null_mut();
+        acceleration_structure_features.p_next = ptr::null_mut();
+        ray_tracing_pipeline_features.p_next = ptr::null_mut();
+        vulkan12_features.p_next = ptr::null_mut();
+
         Self {
             features: features.features,
-            mesh_shader_features
+            mesh_shader_features,
+            timeline_semaphore_features,
+            buffer_device_address_features,
+            dynamic_rendering_features,
+            descriptor_indexing_features,
+            synchronization2_features,
+            acceleration_structure_features,
+            ray_tracing_pipeline_features,
+            vulkan12_features
         }
     }
+
+    //Vulkan 1.2 forbids chaining the structs it promoted next to PhysicalDeviceVulkan12Features, so they are folded into it
+    fn promoted_to_vulkan12(&self) -> vk::PhysicalDeviceVulkan12Features<'static> {
+        let descriptor_indexing = &self.descriptor_indexing_features;
+        let buffer_device_address = &self.buffer_device_address_features;
+
+        let mut features = self.vulkan12_features;
+        features.timeline_semaphore |= self.timeline_semaphore_features.timeline_semaphore;
+        features.buffer_device_address |= buffer_device_address.buffer_device_address;
+        features.buffer_device_address_capture_replay |= buffer_device_address.buffer_device_address_capture_replay;
+        features.buffer_device_address_multi_device |= buffer_device_address.buffer_device_address_multi_device;
+        features.shader_input_attachment_array_dynamic_indexing |= descriptor_indexing.shader_input_attachment_array_dynamic_indexing;
+        features.shader_uniform_texel_buffer_array_dynamic_indexing |= descriptor_indexing.shader_uniform_texel_buffer_array_dynamic_indexing;
+        features.shader_storage_texel_buffer_array_dynamic_indexing |= descriptor_indexing.shader_storage_texel_buffer_array_dynamic_indexing;
+        features.shader_uniform_buffer_array_non_uniform_indexing |= descriptor_indexing.shader_uniform_buffer_array_non_uniform_indexing;
+        features.shader_sampled_image_array_non_uniform_indexing |= descriptor_indexing.shader_sampled_image_array_non_uniform_indexing;
+        features.shader_storage_buffer_array_non_uniform_indexing |= descriptor_indexing.shader_storage_buffer_array_non_uniform_indexing;
+        features.shader_storage_image_array_non_uniform_indexing |= descriptor_indexing.shader_storage_image_array_non_uniform_indexing;
+        features.shader_input_attachment_array_non_uniform_indexing |= descriptor_indexing.shader_input_attachment_array_non_uniform_indexing;
+        features.shader_uniform_texel_buffer_array_non_uniform_indexing |= descriptor_indexing.shader_uniform_texel_buffer_array_non_uniform_indexing;
+        features.shader_storage_texel_buffer_array_non_uniform_indexing |= descriptor_indexing.shader_storage_texel_buffer_array_non_uniform_indexing;
+        features.descriptor_binding_uniform_buffer_update_after_bind |= descriptor_indexing.descriptor_binding_uniform_buffer_update_after_bind;
+        features.descriptor_binding_sampled_image_update_after_bind |= descriptor_indexing.descriptor_binding_sampled_image_update_after_bind;
+        features.descriptor_binding_storage_image_update_after_bind |= descriptor_indexing.descriptor_binding_storage_image_update_after_bind;
+        features.descriptor_binding_storage_buffer_update_after_bind |= descriptor_indexing.descriptor_binding_storage_buffer_update_after_bind;
+        features.descriptor_binding_uniform_texel_buffer_update_after_bind |= descriptor_indexing.descriptor_binding_uniform_texel_buffer_update_after_bind;
+        features.descriptor_binding_storage_texel_buffer_update_after_bind |= descriptor_indexing.descriptor_binding_storage_texel_buffer_update_after_bind;
+        features.descriptor_binding_update_unused_while_pending |= descriptor_indexing.descriptor_binding_update_unused_while_pending;
+        features.descriptor_binding_partially_bound |= descriptor_indexing.descriptor_binding_partially_bound;
+        features.descriptor_binding_variable_descriptor_count |= descriptor_indexing.descriptor_binding_variable_descriptor_count;
+        features.runtime_descriptor_array |= descriptor_indexing.runtime_descriptor_array;
+        features.p_next = ptr::null_mut();
+
+        features
+    }
+}
+
+#[inline]
+pub fn is_vulkan12(api_version: u32) -> bool {
+    vk::api_version_major(api_version) > 1 || vk::api_version_minor(api_version) >= 2
 }
 
 unsafe impl Send for Features {}
 unsafe impl Sync for Features {}
 
+pub const EXT_MEMORY_BUDGET_NAME: &[u8] = b"VK_EXT_memory_budget\0";
+pub const EXT_DESCRIPTOR_INDEXING_NAME: &[u8] = b"VK_EXT_descriptor_indexing\0";
+pub const KHR_MAINTENANCE3_NAME: &[u8] = b"VK_KHR_maintenance3\0";
+
 pub struct Extensions {
     supported: Vec<vk::ExtensionProperties>,
     enabled: Vec<*const c_char>,
-
+    owned_names: Vec<CString>,
+
+    ext_descriptor_indexing: bool,
+    ext_hdr_metadata: bool,
+    ext_memory_budget: bool,
+    khr_acceleration_structure: bool,
+    khr_buffer_device_address: bool,
+    khr_dynamic_rendering: bool,
     khr_portability_subset: bool,
+    khr_ray_tracing_pipeline: bool,
     khr_swapchain: bool,
+    khr_synchronization2: bool,
+    khr_timeline_semaphore: bool,
     nv_mesh_shader: bool
 }
 
@@ -115,8 +264,18 @@ impl Extensions {
         Ok(Self {
             supported,
             enabled: Vec::new(),
+            owned_names: Vec::new(),
+            ext_descriptor_indexing: false,
+            ext_hdr_metadata: false,
+            ext_memory_budget: false,
+            khr_acceleration_structure: false,
+            khr_buffer_device_address: false,
+            khr_dynamic_rendering: false,
             khr_portability_subset: false,
+            khr_ray_tracing_pipeline: false,
             khr_swapchain: false,
+            khr_synchronization2: false,
+            khr_timeline_semaphore: false,
             nv_mesh_shader: false
         })
     }
@@ -126,6 +285,16 @@ impl Extensions {
         self.supported.iter().any(|e| libc::strcmp(e.extension_name.as_ptr(), name) == 0)
     }
 
+    #[inline]
+    pub fn is_supported_str(&self, name: &str) -> bool {
+        self.supported.iter().any(|e| from_c_char_array(&e.extension_name) == name)
+    }
+
+    #[inline]
+    pub fn supported_names(&self) -> Vec<String> {
+        self.supported.iter().map(|e| from_c_char_array(&e.extension_name)).collect()
+    }
+
     #[inline]
     pub unsafe fn is_enabled(&self, name: *const c_char) -> bool {
         self.enabled.iter().any(|e| libc::strcmp(*e, name) == 0)
@@ -139,10 +308,28 @@ impl Extensions {
 
         self.enabled.push(name);
 
-        if libc::strcmp(name, b"VK_KHR_portability_subset\0".as_ptr().cast()) == 0 {
+        if libc::strcmp(name, EXT_DESCRIPTOR_INDEXING_NAME.as_ptr().cast()) == 0 {
+            self.ext_descriptor_indexing = true;
+        } else if libc::strcmp(name, vk::ExtHdrMetadataFn::name().as_ptr()) == 0 {
+            self.ext_hdr_metadata = true;
+        } else if libc::strcmp(name, EXT_MEMORY_BUDGET_NAME.as_ptr().cast()) == 0 {
+            self.ext_memory_budget = true;
+        } else if libc::strcmp(name, AccelerationStructure::name().as_ptr()) == 0 {
+            self.khr_acceleration_structure = true;
+        } else if libc::strcmp(name, BufferDeviceAddress::name().as_ptr()) == 0 {
+            self.khr_buffer_device_address = true;
+        } else if libc::strcmp(name, DynamicRendering::name().as_ptr()) == 0 {
+            self.khr_dynamic_rendering = true;
+        } else if libc::strcmp(name, b"VK_KHR_portability_subset\0".as_ptr().cast()) == 0 {
             self.khr_portability_subset = true;
+        } else if libc::strcmp(name, RayTracingPipeline::name().as_ptr()) == 0 {
+            self.khr_ray_tracing_pipeline = true;
         } else if libc::strcmp(name, Swapchain::name().as_ptr()) == 0 {
             self.khr_swapchain = true;
+        } else if libc::strcmp(name, Synchronization2::name().as_ptr()) == 0 {
+            self.khr_synchronization2 = true;
+        } else if libc::strcmp(name, TimelineSemaphore::name().as_ptr()) == 0 {
+            self.khr_timeline_semaphore = true;
         } else if libc::strcmp(name, MeshShader::name().as_ptr()) == 0 {
             self.nv_mesh_shader = true;
         }
@@ -155,6 +342,26 @@ impl Extensions {
         assert!(self.try_push(name))
     }
 
+    pub fn try_push_str(&mut self, name: &str) -> bool {
+        let name = match CString::new(name) {
+            Ok(name) => name,
+            Err(_) => return false
+        };
+
+        if !unsafe { self.try_push(name.as_ptr()) } {
+            return false
+        }
+
+        self.owned_names.push(name);
+
+        true
+    }
+
+    #[inline]
+    pub fn push_str(&mut self, name: &str) {
+        assert!(self.try_push_str(name))
+    }
+
     #[inline]
     pub fn supported(&self) -> &Vec<vk::ExtensionProperties> {
         &self.supported
@@ -165,11 +372,56 @@ impl Extensions {
         &self.enabled
     }
 
+    #[inline]
+    pub fn ext_descriptor_indexing(&self) -> bool {
+        self.ext_descriptor_indexing
+    }
+
+    #[inline]
+    pub fn ext_hdr_metadata(&self) -> bool {
+        self.ext_hdr_metadata
+    }
+
+    #[inline]
+    pub fn ext_memory_budget(&self) -> bool {
+        self.ext_memory_budget
+    }
+
+    #[inline]
+    pub fn khr_acceleration_structure(&self) -> bool {
+        self.khr_acceleration_structure
+    }
+
+    #[inline]
+    pub fn khr_buffer_device_address(&self) -> bool {
+        self.khr_buffer_device_address
+    }
+
+    #[inline]
+    pub fn khr_dynamic_rendering(&self) -> bool {
+        self.khr_dynamic_rendering
+    }
+
+    #[inline]
+    pub fn khr_ray_tracing_pipeline(&self) -> bool {
+        self.khr_ray_tracing_pipeline
+    }
+
     #[inline]
     pub fn khr_swapchain(&self) -> bool {
         self.khr_swapchain
     }
 
+    #[inline]
+    pub fn khr_synchronization2(&self) -> bool {
+        self.khr_synchronization2
+    }
+
+    #[inline]
+    pub fn khr_timeline_semaphore(&self) -> bool {
+        self.khr_timeline_semaphore
+    }
+
     #[inline]
     pub fn nv_mesh_shader(&self) -> bool {
         self.nv_mesh_shader
@@ -192,6 +444,46 @@ impl Queue {
         }
     }
 
+    #[inline]
+    pub fn wait_idle(&self, device: &Device) -> VkResult<()> {
+        unsafe { device.loader().queue_wait_idle(self.queue) }
+    }
+
+    pub fn submit(
+        &self,
+        device: &Device,
+        command_buffers: &[vk::CommandBuffer],
+        wait: &[(vk::Semaphore, vk::PipelineStageFlags)],
+        signal: &[vk::Semaphore],
+        fence: Option<&Fence>
+    ) -> VkResult<()> {
+        self.submit_raw(device.loader(), command_buffers, wait, signal, fence.map_or_else(vk::Fence::null, |fence| *fence.fence()))
+    }
+
+    fn submit_raw(
+        &self,
+        device_loader: &ash::Device,
+        command_buffers: &[vk::CommandBuffer],
+        wait: &[(vk::Semaphore, vk::PipelineStageFlags)],
+        signal: &[vk::Semaphore],
+        fence: vk::Fence
+    ) -> VkResult<()> {
+        //An empty submit is still needed to signal semaphores or a fence, otherwise it's a no-op
+        if command_buffers.is_empty() && signal.is_empty() && fence == vk::Fence::null() {
+            return Ok(())
+        }
+
+        let (wait_semaphores, wait_stage_masks): (Vec<_>, Vec<_>) = wait.iter().copied().unzip();
+
+        let submit_info = vk::SubmitInfo::default()
+            .wait_semaphores(&wait_semaphores)
+            .wait_dst_stage_mask(&wait_stage_masks)
+            .command_buffers(command_buffers)
+            .signal_semaphores(signal);
+
+        unsafe { device_loader.queue_submit(self.queue, slice::from_ref(&submit_info), fence) }
+    }
+
     #[inline]
     pub fn queue(&self) -> &vk::Queue {
         &self.queue
@@ -203,14 +495,39 @@ impl Queue {
     }
 }
 
+static NEXT_DEVICE_GENERATION: AtomicU64 = AtomicU64::new(1);
+
+//Stored by resources at creation and checked against their device on drop, a zero sized type in release builds
+#[derive(Copy, Clone, Debug, Default, Hash, Eq, PartialEq)]
+pub struct DeviceGeneration {
+    #[cfg(debug_assertions)]
+    generation: u64
+}
+
+impl DeviceGeneration {
+    #[inline]
+    fn next() -> Self {
+        Self {
+            #[cfg(debug_assertions)]
+            generation: NEXT_DEVICE_GENERATION.fetch_add(1, Ordering::Relaxed)
+        }
+    }
+}
+
 pub struct Device {
     physical_device: vk::PhysicalDevice,
 
     loader: Arc<ash::Device>,
     swapchain_loader: Swapchain,
     mesh_shader_loader: MeshShader,
+    dynamic_rendering_loader: DynamicRendering,
+    synchronization2_loader: Synchronization2,
+    acceleration_structure_loader: AccelerationStructure,
+    ray_tracing_pipeline_loader: RayTracingPipeline,
+    hdr_metadata_fn: vk::ExtHdrMetadataFn,
+    timeline_semaphore_loader: TimelineSemaphore,
 
-    allocator: Arc<Allocator>,
+    allocator: ManuallyDrop<Allocator>,
 
     extensions: Extensions,
 
@@ -227,26 +544,40 @@ pub struct Device {
 
     transfer_queue: Queue,
 
-    _instance: Arc<Instance>,
-    _surface: Arc<Surface>
+    present_queue: Queue,
+    split_present: bool,
+
+    command_pools: HashMap<u32, Mutex<CommandPool>>,
+    fence_pool: Mutex<Vec<vk::Fence>>,
+    semaphore_pool: Mutex<Vec<vk::Semaphore>>,
+    shader_module_cache: ShaderModuleCache,
+    generation: DeviceGeneration,
+
+    instance: Arc<Instance>,
+    _surface: Option<Arc<Surface>>
+}
+
+const DIRECT_QUEUE_FLAGS: vk::QueueFlags = vk::QueueFlags::from_raw(vk::QueueFlags::GRAPHICS.as_raw() | vk::QueueFlags::COMPUTE.as_raw() | vk::QueueFlags::TRANSFER.as_raw());
+
+#[inline]
+unsafe fn supports_present(instance: &Instance, surface: Option<&Surface>, physical_device: vk::PhysicalDevice, family_index: u32) -> bool {
+    //Without a surface there is nothing to present to, so every family qualifies
+    surface.map_or(true, |surface| {
+        instance
+            .surface_loader()
+            .get_physical_device_surface_support(physical_device, family_index, *surface.surface())
+            .unwrap_or(false)
+    })
 }
 
-unsafe fn find_direct_queue_family_index(instance: &Instance, surface: &Surface, physical_device: vk::PhysicalDevice, properties: &[vk::QueueFamilyProperties]) -> Option<u32> {
+unsafe fn find_direct_queue_family_index(instance: &Instance, surface: Option<&Surface>, physical_device: vk::PhysicalDevice, properties: &[vk::QueueFamilyProperties]) -> Option<u32> {
     let mut queue_count: u32 = 0;
     let mut family_index: u32 = 0;
 
-    let direct_flags: vk::QueueFlags = vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE | vk::QueueFlags::TRANSFER;
-
     for (i, properties) in properties.iter().enumerate() {
         let i = i as u32;
 
-        if (properties.queue_flags & direct_flags) == direct_flags
-            && properties.queue_count > queue_count
-            && instance
-                .surface_loader()
-                .get_physical_device_surface_support(physical_device, i, *surface.surface())
-                .unwrap_or(false)
-        {
+        if (properties.queue_flags & DIRECT_QUEUE_FLAGS) == DIRECT_QUEUE_FLAGS && properties.queue_count > queue_count && supports_present(instance, surface, physical_device, i) {
             queue_count = properties.queue_count;
             family_index = i;
         }
@@ -255,10 +586,25 @@ unsafe fn find_direct_queue_family_index(instance: &Instance, surface: &Surface,
     if queue_count > 0 {
         Some(family_index)
     } else {
-        None
+        find_queue_family_index(properties, DIRECT_QUEUE_FLAGS, vk::QueueFlags::empty())
     }
 }
 
+//With force_split_present another family that can present is preferred over the direct one
+fn find_present_queue_family_index(family_count: u32, direct_index: u32, force_split_present: bool, supports_present: impl Fn(u32) -> bool) -> Option<u32> {
+    if force_split_present {
+        if let Some(family_index) = (0..family_count).find(|i| *i != direct_index && supports_present(*i)) {
+            return Some(family_index)
+        }
+    }
+
+    if supports_present(direct_index) {
+        return Some(direct_index)
+    }
+
+    (0..family_count).find(|i| supports_present(*i))
+}
+
 unsafe fn find_queue_family_index(properties: &[vk::QueueFamilyProperties], desired_flags: vk::QueueFlags, undesired_flags: vk::QueueFlags) -> Option<u32> {
     let mut queue_count: u32 = 0;
     let mut family_index: u32 = 0;
@@ -279,8 +625,16 @@ unsafe fn find_queue_family_index(properties: &[vk::QueueFamilyProperties], desi
     }
 }
 
-unsafe fn find_queue_family_indices(instance: &Instance, surface: &Surface, physical_device: vk::PhysicalDevice, properties: &[vk::QueueFamilyProperties]) -> Option<(u32, u32, u32)> {
+unsafe fn find_queue_family_indices(
+    instance: &Instance,
+    surface: Option<&Surface>,
+    physical_device: vk::PhysicalDevice,
+    properties: &[vk::QueueFamilyProperties],
+    force_split_present: bool
+) -> Option<(u32, u32, u32, u32)> {
     let direct_index = find_direct_queue_family_index(instance, surface, physical_device, properties)?;
+    let present_index = find_present_queue_family_index(properties.len() as u32, direct_index, force_split_present, |i| supports_present(instance, surface, physical_device, i))?;
+
     let compute_index = find_queue_family_index(properties, vk::QueueFlags::COMPUTE, vk::QueueFlags::GRAPHICS | vk::QueueFlags::TRANSFER)
         .or_else(|| find_queue_family_index(properties, vk::QueueFlags::COMPUTE, vk::QueueFlags::GRAPHICS))
         .or_else(|| find_queue_family_index(properties, vk::QueueFlags::COMPUTE, vk::QueueFlags::TRANSFER))
@@ -291,13 +645,13 @@ unsafe fn find_queue_family_indices(instance: &Instance, surface: &Surface, phys
         .or_else(|| find_queue_family_index(properties, vk::QueueFlags::TRANSFER, vk::QueueFlags::COMPUTE))
         .unwrap_or(direct_index);
 
-    Some((direct_index, compute_index, transfer_index))
+    Some((direct_index, compute_index, transfer_index, present_index))
 }
 
 impl Device {
     pub unsafe fn new(
         instance: Arc<Instance>,
-        surface: Arc<Surface>,
+        surface: Option<Arc<Surface>>,
         physical_device: vk::PhysicalDevice,
         callback: impl FnOnce(&Properties, &MemoryProperties, &QueueFamilyProperties, &mut Extensions, &Features, &mut Features) -> Result<()>
     ) -> Result<Arc<Self>> {
@@ -319,36 +673,58 @@ impl Device {
             &mut enabled_features
         )?;
 
-        //Queue families
-        let (direct_queue_family_index, compute_queue_family_index, transfer_queue_family_index) =
-            find_queue_family_indices(&instance, &surface, physical_device, &queue_family_properties.queue_family_properties)
-                .ok_or_else(|| anyhow::anyhow!("Failed to find queue family indices"))?;
+        extensions.try_push(EXT_MEMORY_BUDGET_NAME.as_ptr().cast());
 
-        let queue_priorities = [1.0];
+        //Queue families, KAMEL_FORCE_SPLIT_PRESENT=1 takes the cross queue present path even if the direct queue can present
+        let force_split_present = surface.is_some() && env::var("KAMEL_FORCE_SPLIT_PRESENT").map_or(false, |value| value == "1");
 
-        let mut device_queue_create_infos = vec![vk::DeviceQueueCreateInfo::default()
-            .queue_family_index(direct_queue_family_index)
-            .queue_priorities(&queue_priorities)];
+        let (direct_queue_family_index, compute_queue_family_index, transfer_queue_family_index, present_queue_family_index) = find_queue_family_indices(
+            &instance,
+            surface.as_deref(),
+            physical_device,
+            &queue_family_properties.queue_family_properties,
+            force_split_present
+        )
+        .ok_or_else(|| anyhow::anyhow!("Failed to find queue family indices"))?;
+        //A forced split without another family that can present falls back to presenting from the direct family
+        let split_present = present_queue_family_index != direct_queue_family_index;
 
-        if compute_queue_family_index != direct_queue_family_index {
-            device_queue_create_infos.push(
-                vk::DeviceQueueCreateInfo::default()
-                    .queue_family_index(compute_queue_family_index)
-                    .queue_priorities(&queue_priorities)
-            );
-        }
+        let queue_priorities = [1.0];
 
-        if transfer_queue_family_index != direct_queue_family_index {
-            device_queue_create_infos.push(
-                vk::DeviceQueueCreateInfo::default()
-                    .queue_family_index(transfer_queue_family_index)
-                    .queue_priorities(&queue_priorities)
-            );
-        }
+        let mut queue_family_indices = vec![direct_queue_family_index, compute_queue_family_index, transfer_queue_family_index, present_queue_family_index];
+        queue_family_indices.sort_unstable();
+        queue_family_indices.dedup();
+
+        let device_queue_create_infos: Vec<_> = queue_family_indices
+            .iter()
+            .map(|family_index| vk::DeviceQueueCreateInfo::default().queue_family_index(*family_index).queue_priorities(&queue_priorities))
+            .collect();
 
         //Features
         let mut mesh_shader_features = enabled_features.mesh_shader_features;
-        let mut features = vk::PhysicalDeviceFeatures2::default().features(enabled_features.features).push_next(&mut mesh_shader_features);
+        let mut timeline_semaphore_features = enabled_features.timeline_semaphore_features;
+        let mut buffer_device_address_features = enabled_features.buffer_device_address_features;
+        let mut dynamic_rendering_features = enabled_features.dynamic_rendering_features;
+        let mut descriptor_indexing_features = enabled_features.descriptor_indexing_features;
+        let mut synchronization2_features = enabled_features.synchronization2_features;
+        let mut acceleration_structure_features = enabled_features.acceleration_structure_features;
+        let mut ray_tracing_pipeline_features = enabled_features.ray_tracing_pipeline_features;
+        let mut vulkan12_features = enabled_features.promoted_to_vulkan12();
+        let mut features = vk::PhysicalDeviceFeatures2::default()
+            .features(enabled_features.features)
+            .push_next(&mut mesh_shader_features)
+            .push_next(&mut dynamic_rendering_features)
+            .push_next(&mut synchronization2_features)
+            .push_next(&mut acceleration_structure_features)
+            .push_next(&mut ray_tracing_pipeline_features);
+        if is_vulkan12(properties.properties.api_version) {
+            features = features.push_next(&mut vulkan12_features);
+        } else {
+            features = features
+                .push_next(&mut timeline_semaphore_features)
+                .push_next(&mut buffer_device_address_features)
+                .push_next(&mut descriptor_indexing_features);
+        }
 
         //Create device
         let device_create_info = vk::DeviceCreateInfo::default()
@@ -360,19 +736,46 @@ impl Device {
         let loader = Arc::new(instance_loader.create_device(physical_device, &device_create_info, None)?);
         let swapchain_loader = Swapchain::new(instance_loader, &loader);
         let mesh_shader_loader = MeshShader::new(instance_loader, &loader);
-
-        let allocator = Arc::new(Allocator::new(AllocatorCreateInfo::new(instance.loader(), &loader, &physical_device))?);
+        let dynamic_rendering_loader = DynamicRendering::new(instance_loader, &loader);
+        let synchronization2_loader = Synchronization2::new(instance_loader, &loader);
+        let acceleration_structure_loader = AccelerationStructure::new(instance_loader, &loader);
+        let ray_tracing_pipeline_loader = RayTracingPipeline::new(instance_loader, &loader);
+        let hdr_metadata_fn = vk::ExtHdrMetadataFn::load(|name| mem::transmute(instance_loader.get_device_proc_addr(loader.handle(), name.as_ptr())));
+        let timeline_semaphore_loader = TimelineSemaphore::new(instance_loader, &loader);
+
+        //Acceleration structure and shader binding table buffers are referenced by device address
+        let mut allocator_create_info = AllocatorCreateInfo::new(instance.loader(), &loader, &physical_device);
+        if enabled_features.buffer_device_address_features.buffer_device_address == vk::TRUE {
+            allocator_create_info = allocator_create_info.flags(AllocatorCreateFlags::BUFFER_DEVICE_ADDRESS);
+        }
+        let allocator = ManuallyDrop::new(Allocator::new(allocator_create_info)?);
 
         let direct_queue = Queue::new(&loader, direct_queue_family_index);
         let compute_queue = Queue::new(&loader, compute_queue_family_index);
         let transfer_queue = Queue::new(&loader, transfer_queue_family_index);
+        let present_queue = Queue::new(&loader, present_queue_family_index);
+
+        //One pool per family with a queue, recording and freeing have to lock it
+        let command_pool_create_info = vk::CommandPoolCreateInfo::default().flags(vk::CommandPoolCreateFlags::TRANSIENT | vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER);
+        let mut raw_command_pools = Vec::with_capacity(queue_family_indices.len());
+        for family_index in queue_family_indices.iter() {
+            raw_command_pools.push((*family_index, loader.create_command_pool(&command_pool_create_info.queue_family_index(*family_index), None)?));
+        }
+
+        let generation = DeviceGeneration::next();
 
-        Ok(Arc::new(Self {
+        let device = Arc::new_cyclic(|device: &Weak<Self>| Self {
             physical_device,
 
             loader,
             swapchain_loader,
             mesh_shader_loader,
+            dynamic_rendering_loader,
+            synchronization2_loader,
+            acceleration_structure_loader,
+            ray_tracing_pipeline_loader,
+            hdr_metadata_fn,
+            timeline_semaphore_loader,
 
             allocator,
             extensions,
@@ -387,10 +790,207 @@ impl Device {
             direct_queue,
             compute_queue,
             transfer_queue,
-
-            _instance: instance,
+            present_queue,
+            split_present,
+
+            command_pools: raw_command_pools
+                .into_iter()
+                .map(|(family_index, command_pool)| (family_index, Mutex::new(CommandPool::new_owned_by(device.as_ptr(), command_pool, family_index, generation))))
+                .collect(),
+            fence_pool: Mutex::new(Vec::new()),
+            semaphore_pool: Mutex::new(Vec::new()),
+            shader_module_cache: ShaderModuleCache::default(),
+            generation,
+
+            instance,
             _surface: surface
-        }))
+        });
+
+        //Queues can alias, so the direct queue is named last to win
+        device.set_debug_name(device.present_queue.queue, "Present queue")?;
+        device.set_debug_name(device.transfer_queue.queue, "Transfer queue")?;
+        device.set_debug_name(device.compute_queue.queue, "Compute queue")?;
+        device.set_debug_name(device.direct_queue.queue, "Direct queue")?;
+
+        for (family_index, command_pool) in device.command_pools.iter() {
+            device.set_debug_name(*command_pool.lock().unwrap().command_pool(), &format!("Command pool (family {})", family_index))?;
+        }
+
+        Ok(device)
+    }
+
+    pub fn set_debug_name<T: vk::Handle>(&self, handle: T, name: &str) -> VkResult<()> {
+        if !self.instance.extensions().ext_debug_utils() {
+            return Ok(())
+        }
+
+        let name = CString::new(name).unwrap_or_default();
+        let debug_utils_object_name_info = vk::DebugUtilsObjectNameInfoEXT::default().object_handle(handle).object_name(&name);
+
+        unsafe {
+            self.instance
+                .debug_utils_loader()
+                .set_debug_utils_object_name(self.loader.handle(), &debug_utils_object_name_info)
+        }
+    }
+
+    #[inline]
+    pub fn wait_idle(&self) -> VkResult<()> {
+        unsafe { self.loader.device_wait_idle() }
+    }
+
+    //Pools are owned by the device, only families the device has a queue for have one. Command pools are externally synchronized,
+    //so the pool stays locked while a command buffer allocated from it is recorded and freed. It must not be reset
+    pub fn command_pool(&self, family_index: u32) -> VkResult<MutexGuard<'_, CommandPool>> {
+        match self.command_pools.get(&family_index) {
+            Some(command_pool) => Ok(command_pool.lock().unwrap()),
+            None => Err(vk::Result::ERROR_INITIALIZATION_FAILED)
+        }
+    }
+
+    //Returns an unsignaled fence, pass it to recycle_fence once it signaled instead of destroying it
+    pub fn acquire_fence(&self) -> VkResult<vk::Fence> {
+        if let Some(fence) = self.fence_pool.lock().unwrap().pop() {
+            return Ok(fence)
+        }
+
+        unsafe { self.loader.create_fence(&vk::FenceCreateInfo::default(), None) }
+    }
+
+    //The fence must be signaled or never submitted
+    pub fn recycle_fence(&self, fence: vk::Fence) -> VkResult<()> {
+        if let Err(e) = unsafe { self.loader.reset_fences(slice::from_ref(&fence)) } {
+            unsafe { self.loader.destroy_fence(fence, None) };
+            return Err(e)
+        }
+
+        self.fence_pool.lock().unwrap().push(fence);
+
+        Ok(())
+    }
+
+    //Binary semaphore, pass it to recycle_semaphore once the wait on it completed
+    pub fn acquire_semaphore(&self) -> VkResult<vk::Semaphore> {
+        if let Some(semaphore) = self.semaphore_pool.lock().unwrap().pop() {
+            return Ok(semaphore)
+        }
+
+        unsafe { self.loader.create_semaphore(&vk::SemaphoreCreateInfo::default(), None) }
+    }
+
+    //The semaphore must be unsignaled with no pending wait, e.g. after the fence of the waiting submit signaled
+    #[inline]
+    pub fn recycle_semaphore(&self, semaphore: vk::Semaphore) {
+        self.semaphore_pool.lock().unwrap().push(semaphore);
+    }
+
+    //Identical SPIR-V shares one module, which is destroyed when the last handle is dropped
+    #[inline]
+    pub fn shader_module(self: &Arc<Self>, spirv: &[u32]) -> VkResult<Arc<ShaderModuleHandle>> {
+        self.shader_module_cache.get_or_create(self, spirv)
+    }
+
+    //The pool of the queue family stays locked while recording, so record must not submit immediately to the same family.
+    //Other threads don't wait on the submit itself
+    pub fn submit_immediate(&self, queue: &Queue, record: impl FnOnce(vk::CommandBuffer)) -> VkResult<()> {
+        let command_pool = self.command_pool(queue.family_index())?;
+        let command_buffer = command_pool.allocate(1, vk::CommandBufferLevel::PRIMARY)?[0];
+
+        unsafe {
+            let recorded = (|| {
+                self.loader.begin_command_buffer(
+                    command_buffer,
+                    &vk::CommandBufferBeginInfo::default().flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT)
+                )?;
+                record(command_buffer);
+                self.loader.end_command_buffer(command_buffer)
+            })();
+
+            if let Err(e) = recorded {
+                command_pool.free(slice::from_ref(&command_buffer));
+                return Err(e)
+            }
+
+            drop(command_pool);
+
+            let result = (|| {
+                let fence = self.acquire_fence()?;
+
+                let result = queue
+                    .submit_raw(&self.loader, slice::from_ref(&command_buffer), &[], &[], fence)
+                    .and_then(|_| self.loader.wait_for_fences(slice::from_ref(&fence), true, u64::MAX));
+
+                match result {
+                    Ok(()) => self.recycle_fence(fence),
+                    //The fence may still be pending, e.g. after a device loss
+                    Err(e) => {
+                        self.loader.destroy_fence(fence, None);
+                        Err(e)
+                    }
+                }
+            })();
+
+            self.command_pool(queue.family_index())?.free(slice::from_ref(&command_buffer));
+
+            result
+        }
+    }
+
+    #[inline]
+    pub fn format_properties(&self, format: vk::Format) -> vk::FormatProperties {
+        unsafe { self.instance.loader().get_physical_device_format_properties(self.physical_device, format) }
+    }
+
+    pub fn supports_format_features(&self, format: vk::Format, tiling: vk::ImageTiling, features: vk::FormatFeatureFlags) -> bool {
+        let format_properties = self.format_properties(format);
+
+        let supported_features = match tiling {
+            vk::ImageTiling::LINEAR => format_properties.linear_tiling_features,
+            vk::ImageTiling::OPTIMAL => format_properties.optimal_tiling_features,
+            _ => vk::FormatFeatureFlags::empty()
+        };
+
+        supported_features.contains(features)
+    }
+
+    #[inline]
+    pub fn adapter_info(&self) -> AdapterInfo {
+        AdapterInfo::from_properties(&self.properties.properties, &self.memory_properties.memory_properties, self._surface.is_some())
+    }
+
+    pub fn cmd_begin_debug_label(&self, command_buffer: vk::CommandBuffer, name: &str, color: [f32; 4]) {
+        if !self.instance.extensions().ext_debug_utils() {
+            return
+        }
+
+        let name = CString::new(name).unwrap_or_default();
+        let debug_utils_label = vk::DebugUtilsLabelEXT::default().label_name(&name).color(color);
+
+        unsafe { self.instance.debug_utils_loader().cmd_begin_debug_utils_label(command_buffer, &debug_utils_label) }
+    }
+
+    pub fn cmd_end_debug_label(&self, command_buffer: vk::CommandBuffer) {
+        if !self.instance.extensions().ext_debug_utils() {
+            return
+        }
+
+        unsafe { self.instance.debug_utils_loader().cmd_end_debug_utils_label(command_buffer) }
+    }
+
+    pub fn cmd_insert_debug_label(&self, command_buffer: vk::CommandBuffer, name: &str, color: [f32; 4]) {
+        if !self.instance.extensions().ext_debug_utils() {
+            return
+        }
+
+        let name = CString::new(name).unwrap_or_default();
+        let debug_utils_label = vk::DebugUtilsLabelEXT::default().label_name(&name).color(color);
+
+        unsafe { self.instance.debug_utils_loader().cmd_insert_debug_utils_label(command_buffer, &debug_utils_label) }
+    }
+
+    #[inline]
+    pub fn instance(&self) -> &Arc<Instance> {
+        &self.instance
     }
 
     #[inline]
@@ -398,6 +998,16 @@ impl Device {
         &self.physical_device
     }
 
+    #[inline]
+    pub fn generation(&self) -> DeviceGeneration {
+        self.generation
+    }
+
+    #[inline]
+    pub fn assert_generation(&self, generation: DeviceGeneration) {
+        debug_assert_eq!(self.generation, generation, "Resource was created by a different device than the one it is destroyed with");
+    }
+
     #[inline]
     pub fn loader(&self) -> &Arc<ash::Device> {
         &self.loader
@@ -414,10 +1024,270 @@ impl Device {
     }
 
     #[inline]
-    pub fn allocator(&self) -> &Arc<Allocator> {
+    pub fn timeline_semaphore_loader(&self) -> &TimelineSemaphore {
+        &self.timeline_semaphore_loader
+    }
+
+    #[inline]
+    pub fn supports_timeline_semaphores(&self) -> bool {
+        self.enabled_features.vulkan12_features.timeline_semaphore == vk::TRUE
+            || (self.extensions.khr_timeline_semaphore && self.enabled_features.timeline_semaphore_features.timeline_semaphore == vk::TRUE)
+    }
+
+    //Uses VK_KHR_timeline_semaphore when enabled and the Vulkan 1.2 core entry points otherwise
+    pub fn signal_semaphore(&self, signal_info: &vk::SemaphoreSignalInfo) -> VkResult<()> {
+        unsafe {
+            if self.extensions.khr_timeline_semaphore {
+                self.timeline_semaphore_loader.signal_semaphore(signal_info)
+            } else {
+                self.loader.signal_semaphore(signal_info)
+            }
+        }
+    }
+
+    pub fn wait_semaphores(&self, wait_info: &vk::SemaphoreWaitInfo, timeout: u64) -> VkResult<()> {
+        unsafe {
+            if self.extensions.khr_timeline_semaphore {
+                self.timeline_semaphore_loader.wait_semaphores(wait_info, timeout)
+            } else {
+                self.loader.wait_semaphores(wait_info, timeout)
+            }
+        }
+    }
+
+    pub fn get_semaphore_counter_value(&self, semaphore: vk::Semaphore) -> VkResult<u64> {
+        unsafe {
+            if self.extensions.khr_timeline_semaphore {
+                self.timeline_semaphore_loader.get_semaphore_counter_value(semaphore)
+            } else {
+                self.loader.get_semaphore_counter_value(semaphore)
+            }
+        }
+    }
+
+    #[inline]
+    pub fn supports_buffer_device_address(&self) -> bool {
+        self.extensions.khr_buffer_device_address && self.enabled_features.buffer_device_address_features.buffer_device_address == vk::TRUE
+    }
+
+    pub fn set_hdr_metadata(&self, swapchain: vk::SwapchainKHR, hdr_metadata: &vk::HdrMetadataEXT) -> VkResult<()> {
+        if !self.extensions.ext_hdr_metadata {
+            return Err(vk::Result::ERROR_EXTENSION_NOT_PRESENT)
+        }
+
+        unsafe { (self.hdr_metadata_fn.set_hdr_metadata_ext)(self.loader.handle(), 1, &swapchain, hdr_metadata) };
+
+        Ok(())
+    }
+
+    #[inline]
+    pub fn supports_bindless(&self) -> bool {
+        let descriptor_indexing_features = &self.enabled_features.descriptor_indexing_features;
+
+        descriptor_indexing_features.descriptor_binding_partially_bound == vk::TRUE
+            && descriptor_indexing_features.runtime_descriptor_array == vk::TRUE
+            && descriptor_indexing_features.shader_sampled_image_array_non_uniform_indexing == vk::TRUE
+            && descriptor_indexing_features.descriptor_binding_sampled_image_update_after_bind == vk::TRUE
+    }
+
+    #[inline]
+    pub fn supports_dynamic_rendering(&self) -> bool {
+        self.enabled_features.dynamic_rendering_features.dynamic_rendering == vk::TRUE
+    }
+
+    //Uses VK_KHR_dynamic_rendering when enabled and the Vulkan 1.3 core entry points otherwise
+    pub fn cmd_begin_rendering(&self, command_buffer: vk::CommandBuffer, rendering_info: &vk::RenderingInfo) {
+        unsafe {
+            if self.extensions.khr_dynamic_rendering {
+                self.dynamic_rendering_loader.cmd_begin_rendering(command_buffer, rendering_info);
+            } else {
+                self.loader.cmd_begin_rendering(command_buffer, rendering_info);
+            }
+        }
+    }
+
+    pub fn cmd_end_rendering(&self, command_buffer: vk::CommandBuffer) {
+        unsafe {
+            if self.extensions.khr_dynamic_rendering {
+                self.dynamic_rendering_loader.cmd_end_rendering(command_buffer);
+            } else {
+                self.loader.cmd_end_rendering(command_buffer);
+            }
+        }
+    }
+
+    #[inline]
+    pub fn supports_synchronization2(&self) -> bool {
+        self.enabled_features.synchronization2_features.synchronization2 == vk::TRUE
+    }
+
+    //Falls back to vkCmdPipelineBarrier with combined stage masks without synchronization2
+    pub fn cmd_pipeline_barrier2(&self, command_buffer: vk::CommandBuffer, barriers: &BarrierBuilder) {
+        if barriers.is_empty() {
+            return
+        }
+
+        unsafe {
+            if !self.supports_synchronization2() {
+                barriers.cmd_pipeline_barrier_legacy(&self.loader, command_buffer);
+            } else if self.extensions.khr_synchronization2 {
+                self.synchronization2_loader.cmd_pipeline_barrier2(command_buffer, &barriers.dependency_info());
+            } else {
+                self.loader.cmd_pipeline_barrier2(command_buffer, &barriers.dependency_info());
+            }
+        }
+    }
+
+    #[inline]
+    pub fn acceleration_structure_loader(&self) -> &AccelerationStructure {
+        &self.acceleration_structure_loader
+    }
+
+    #[inline]
+    pub fn ray_tracing_pipeline_loader(&self) -> &RayTracingPipeline {
+        &self.ray_tracing_pipeline_loader
+    }
+
+    #[inline]
+    pub fn supports_ray_tracing(&self) -> bool {
+        self.extensions.khr_acceleration_structure
+            && self.extensions.khr_ray_tracing_pipeline
+            && self.enabled_features.acceleration_structure_features.acceleration_structure == vk::TRUE
+            && self.enabled_features.ray_tracing_pipeline_features.ray_tracing_pipeline == vk::TRUE
+            && self.supports_buffer_device_address()
+    }
+
+    #[inline]
+    pub fn supports_timestamps(&self) -> bool {
+        self.timestamp_valid_bits() > 0
+    }
+
+    //Of timestamps written on the direct queue, the remaining high bits are undefined
+    #[inline]
+    pub fn timestamp_valid_bits(&self) -> u32 {
+        self.queue_family_properties.queue_family_properties[self.direct_queue.family_index() as usize].timestamp_valid_bits
+    }
+
+    #[inline]
+    pub fn allocator(&self) -> &Allocator {
         &self.allocator
     }
 
+    pub fn allocator_stats(&self) -> VkResult<AllocatorStats> {
+        let statistics = self.allocator.calculate_statistics()?.total.statistics;
+
+        Ok(AllocatorStats {
+            allocation_count: statistics.allocationCount,
+            block_count: statistics.blockCount,
+            allocated_bytes: statistics.blockBytes,
+            used_bytes: statistics.allocationBytes
+        })
+    }
+
+    //Runs vk_mem defragmentation passes and moves buffers into the compacted memory, which gives them new vk::Buffer handles and device addresses.
+    //Callers must not use the old handles during defragmentation or have them in flight, e.g. wait for the device to be idle first.
+    //Other allocations and GPU only buffers without TRANSFER_SRC and TRANSFER_DST usage stay where they are
+    pub fn defragment(&self, buffers: &mut [&mut Buffer]) -> Result<DefragStats> {
+        //Zeroed means the default pools without per pass limits
+        let defragmentation_info = unsafe { mem::zeroed::<ffi::VmaDefragmentationInfo>() };
+        let context = unsafe { self.allocator.begin_defragmentation(&defragmentation_info)? };
+
+        let mut result = Ok(());
+        let mut moved = Vec::new();
+
+        loop {
+            let more_passes = context.begin_pass(|moves| match self.copy_defragmentation_moves(buffers, moves) {
+                Ok(pass_moved) => moved = pass_moved,
+                Err(e) => result = Err(e)
+            });
+
+            for (index, buffer) in moved.drain(..) {
+                if let Err(e) = unsafe { buffers[index].finish_move(buffer) } {
+                    result = Err(e.into());
+                }
+            }
+
+            if !more_passes || result.is_err() {
+                break
+            }
+        }
+
+        let stats = context.end();
+        result?;
+
+        Ok(DefragStats {
+            allocations_moved: stats.allocationsMoved,
+            bytes_moved: stats.bytesMoved,
+            bytes_freed: stats.bytesFreed,
+            device_memory_blocks_freed: stats.deviceMemoryBlocksFreed
+        })
+    }
+
+    //Binds a new buffer to the destination of every move of a buffer in buffers and copies its contents, every other move is ignored.
+    //GPU copies run on the direct queue which owns the buffers, so no ownership transfer is needed
+    fn copy_defragmentation_moves(&self, buffers: &[&mut Buffer], moves: &mut [ffi::VmaDefragmentationMove]) -> Result<Vec<(usize, vk::Buffer)>> {
+        let mut moved = Vec::new();
+        let mut result = Ok(());
+
+        for defragmentation_move in moves.iter_mut() {
+            let index = buffers
+                .iter()
+                .position(|buffer| buffer.is_movable() && buffer.raw_allocation() == defragmentation_move.srcAllocation);
+
+            let created = match index {
+                Some(index) if result.is_ok() => unsafe { buffers[index].create_moved(defragmentation_move.dstTmpAllocation) }.map(|buffer| (index, buffer)),
+                _ => {
+                    defragmentation_move.operation = ffi::VmaDefragmentationMoveOperation::VMA_DEFRAGMENTATION_MOVE_OPERATION_IGNORE;
+                    continue
+                }
+            };
+
+            match created {
+                Ok(buffer_moved) => moved.push(buffer_moved),
+                Err(e) => {
+                    defragmentation_move.operation = ffi::VmaDefragmentationMoveOperation::VMA_DEFRAGMENTATION_MOVE_OPERATION_IGNORE;
+                    result = Err(e);
+                }
+            }
+        }
+
+        let gpu_copies: Vec<_> = moved.iter().filter(|(index, _)| !buffers[*index].is_host_visible()).collect();
+        if result.is_ok() && !gpu_copies.is_empty() {
+            result = self
+                .submit_immediate(&self.direct_queue, |command_buffer| unsafe {
+                    for (index, buffer) in gpu_copies.iter() {
+                        let buffer_copy = vk::BufferCopy::default().size(buffers[*index].desc().size);
+                        self.loader.cmd_copy_buffer(command_buffer, *buffers[*index].buffer(), *buffer, slice::from_ref(&buffer_copy));
+                    }
+                })
+                .map_err(anyhow::Error::from);
+        }
+
+        if let Err(e) = result {
+            //Ignored moves leave every allocation in place
+            for defragmentation_move in moves.iter_mut() {
+                defragmentation_move.operation = ffi::VmaDefragmentationMoveOperation::VMA_DEFRAGMENTATION_MOVE_OPERATION_IGNORE;
+            }
+            for (_, buffer) in moved {
+                unsafe { self.loader.destroy_buffer(buffer, None) };
+            }
+
+            return Err(e)
+        }
+
+        Ok(moved)
+    }
+
+    pub fn log_allocator_stats(&self) {
+        match self.allocator_stats() {
+            Ok(stats) => debug!(
+                "Allocator: {} allocations in {} blocks, {} of {} bytes used",
+                stats.allocation_count, stats.block_count, stats.used_bytes, stats.allocated_bytes
+            ),
+            Err(e) => debug!("Failed to query allocator statistics: {:?}", e)
+        }
+    }
+
     #[inline]
     pub fn extensions(&self) -> &Extensions {
         &self.extensions
@@ -433,6 +1303,33 @@ impl Device {
         &self.memory_properties
     }
 
+    pub fn memory_budget(&self) -> Vec<HeapBudget> {
+        let memory_heap_count = self.memory_properties.memory_properties.memory_heap_count as usize;
+
+        if !self.extensions.ext_memory_budget {
+            return self.memory_properties.memory_properties.memory_heaps[..memory_heap_count]
+                .iter()
+                .map(|heap| HeapBudget { budget: heap.size, usage: None })
+                .collect()
+        }
+
+        let mut memory_budget_properties = vk::PhysicalDeviceMemoryBudgetPropertiesEXT::default();
+        let mut memory_properties = vk::PhysicalDeviceMemoryProperties2::default().push_next(&mut memory_budget_properties);
+
+        unsafe {
+            self.instance
+                .loader()
+                .get_physical_device_memory_properties2(self.physical_device, &mut memory_properties);
+        }
+
+        (0..memory_heap_count)
+            .map(|i| HeapBudget {
+                budget: memory_budget_properties.heap_budget[i],
+                usage: Some(memory_budget_properties.heap_usage[i])
+            })
+            .collect()
+    }
+
     #[inline]
     pub fn queue_family_properties(&self) -> &QueueFamilyProperties {
         &self.queue_family_properties
@@ -462,13 +1359,213 @@ impl Device {
     pub fn transfer_queue(&self) -> &Queue {
         &self.transfer_queue
     }
+
+    //Same family as the direct queue unless it can't present or the split is forced
+    #[inline]
+    pub fn present_queue(&self) -> &Queue {
+        &self.present_queue
+    }
+
+    //Swapchain images are released by the direct queue and acquired by the present queue before presenting
+    #[inline]
+    pub fn splits_present(&self) -> bool {
+        self.split_present
+    }
 }
 
 impl Drop for Device {
     #[inline]
     fn drop(&mut self) {
         unsafe {
+            self.command_pools
+                .drain()
+                .for_each(|(_, command_pool)| self.loader.destroy_command_pool(*command_pool.into_inner().unwrap().command_pool(), None));
+            self.fence_pool.get_mut().unwrap().drain(..).for_each(|fence| self.loader.destroy_fence(fence, None));
+            self.semaphore_pool
+                .get_mut()
+                .unwrap()
+                .drain(..)
+                .for_each(|semaphore| self.loader.destroy_semaphore(semaphore, None));
+
+            ManuallyDrop::drop(&mut self.allocator);
+
             self.loader.destroy_device(None);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backend::resource::{Buffer, BufferDesc},
+        renderer::test_device
+    };
+
+    #[test]
+    fn forced_split_present_prefers_another_family() {
+        let all_present = |_| true;
+        assert_eq!(find_present_queue_family_index(3, 0, false, all_present), Some(0));
+        assert_eq!(find_present_queue_family_index(3, 0, true, all_present), Some(1));
+        assert_eq!(find_present_queue_family_index(3, 1, true, all_present), Some(0));
+
+        //Single queue hardware falls back to the direct family
+        let direct_only = |i| i == 0;
+        assert_eq!(find_present_queue_family_index(3, 0, true, direct_only), Some(0));
+
+        let other_only = |i| i == 2;
+        assert_eq!(find_present_queue_family_index(3, 0, false, other_only), Some(2));
+        assert_eq!(find_present_queue_family_index(3, 0, false, |_| false), None);
+    }
+
+    #[test]
+    fn present_is_split_only_across_families() {
+        let device = match test_device() {
+            Some(device) => device,
+            None => return
+        };
+
+        assert_eq!(device.splits_present(), device.present_queue().family_index() != device.direct_queue().family_index());
+    }
+
+    #[test]
+    fn promoted_features_fold_into_vulkan12() {
+        let mut features = Features::default();
+        features.timeline_semaphore_features.timeline_semaphore = vk::TRUE;
+        features.descriptor_indexing_features.runtime_descriptor_array = vk::TRUE;
+
+        let vulkan12_features = features.promoted_to_vulkan12();
+        assert_eq!(vulkan12_features.timeline_semaphore, vk::TRUE);
+        assert_eq!(vulkan12_features.runtime_descriptor_array, vk::TRUE);
+        assert_eq!(vulkan12_features.buffer_device_address, vk::FALSE);
+
+        assert!(is_vulkan12(vk::API_VERSION_1_2));
+        assert!(!is_vulkan12(vk::API_VERSION_1_1));
+    }
+
+    #[test]
+    fn submit_immediate_reuses_fences() {
+        let device = match test_device() {
+            Some(device) => device,
+            None => return
+        };
+
+        device.submit_immediate(device.direct_queue(), |_| {}).unwrap();
+        let fences = device.fence_pool.lock().unwrap().clone();
+        assert_eq!(fences.len(), 1);
+
+        for _ in 0..64 {
+            device.submit_immediate(device.direct_queue(), |_| {}).unwrap();
+        }
+
+        assert_eq!(*device.fence_pool.lock().unwrap(), fences);
+    }
+
+    #[test]
+    fn submit_immediate_from_several_threads() {
+        let device = match test_device() {
+            Some(device) => device,
+            None => return
+        };
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let device = device.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..16 {
+                        device.submit_immediate(device.direct_queue(), |_| {}).unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let family_index = device.direct_queue().family_index();
+        assert_eq!(device.command_pool(family_index).unwrap().queue_family_index(), family_index);
+        assert!(device.command_pool(u32::MAX).is_err());
+    }
+
+    #[test]
+    fn defragmented_buffers_keep_their_contents() {
+        let device = match test_device() {
+            Some(device) => device,
+            None => return
+        };
+
+        let usage = if device.supports_buffer_device_address() {
+            vk::BufferUsageFlags::TRANSFER_SRC | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS
+        } else {
+            vk::BufferUsageFlags::TRANSFER_SRC
+        };
+        let mut buffers: Vec<_> = (0..32u32)
+            .map(|i| Buffer::new_staged(device.clone(), usage, &vec![i; 16 * 1024]).unwrap())
+            .collect();
+        //Freeing every other buffer leaves holes to compact
+        let mut i = 0;
+        buffers.retain(|_| {
+            i += 1;
+            i % 2 == 0
+        });
+
+        let mut buffer_refs: Vec<_> = buffers.iter_mut().collect();
+        let stats = device.defragment(&mut buffer_refs).unwrap();
+        assert!(stats.bytes_moved >= stats.allocations_moved as vk::DeviceSize * 64 * 1024);
+
+        for (i, buffer) in buffers.iter().enumerate() {
+            let read_back_buffer = Buffer::new(device.clone(), &BufferDesc::new_gpu_to_cpu(buffer.desc().size, vk::BufferUsageFlags::TRANSFER_DST)).unwrap();
+            device
+                .submit_immediate(device.direct_queue(), |command_buffer| unsafe {
+                    let buffer_copy = vk::BufferCopy::default().size(buffer.desc().size);
+                    device
+                        .loader()
+                        .cmd_copy_buffer(command_buffer, *buffer.buffer(), *read_back_buffer.buffer(), slice::from_ref(&buffer_copy));
+                })
+                .unwrap();
+
+            let expected = vec![(i * 2 + 1) as u32; 16 * 1024];
+            assert_eq!(read_back_buffer.read_bytes().unwrap(), bytemuck::cast_slice::<u32, u8>(&expected));
+
+            if device.supports_buffer_device_address() {
+                assert_eq!(
+                    *buffer.device_address(),
+                    unsafe { device.loader().get_buffer_device_address(&vk::BufferDeviceAddressInfo::default().buffer(*buffer.buffer())) }
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn set_debug_name_succeeds() {
+        let device = match test_device() {
+            Some(device) => device,
+            None => return
+        };
+
+        let fence = device.acquire_fence().unwrap();
+        assert_eq!(device.set_debug_name(fence, "Test fence"), Ok(()));
+        //Interior nul bytes fall back to an empty name instead of failing
+        assert_eq!(device.set_debug_name(fence, "Test\0fence"), Ok(()));
+        device.recycle_fence(fence).unwrap();
+    }
+
+    #[test]
+    fn allocator_stats_track_buffer_lifetime() {
+        let device = match test_device() {
+            Some(device) => device,
+            None => return
+        };
+
+        let allocation_count = device.allocator_stats().unwrap().allocation_count;
+
+        let buffer = Buffer::new(device.clone(), &BufferDesc::new_gpu_only(1024, vk::BufferUsageFlags::STORAGE_BUFFER)).unwrap();
+        let stats = device.allocator_stats().unwrap();
+        assert_eq!(stats.allocation_count, allocation_count + 1);
+        assert!(stats.used_bytes >= 1024);
+
+        drop(buffer);
+        assert_eq!(device.allocator_stats().unwrap().allocation_count, allocation_count);
+    }
+}
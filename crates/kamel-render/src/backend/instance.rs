@@ -1,7 +1,10 @@
 use std::{
-    ffi::CStr,
+    ffi::{CStr, CString},
     os::raw::{c_char, c_void},
-    sync::Arc
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc
+    }
 };
 
 use anyhow::Result;
@@ -16,33 +19,40 @@ use ash::{
 use log::log;
 use raw_window_handle::HasRawWindowHandle;
 
-use crate::backend::util::message_severity;
+use crate::backend::util::{message_severity, string::from_c_char_array};
+
+const ENGINE_NAME: &CStr = unsafe { CStr::from_bytes_with_nul_unchecked(b"kamel\0") };
 
 #[inline]
-fn application_info_from_cargo_toml(api_version: u32) -> vk::ApplicationInfo<'static> {
-    let version = vk::make_api_version(
-        0,
+fn cargo_version() -> (u32, u32, u32) {
+    (
         env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap(),
         env!("CARGO_PKG_VERSION_MINOR").parse().unwrap(),
         env!("CARGO_PKG_VERSION_PATCH").parse().unwrap()
-    );
+    )
+}
 
-    let application_name = concat!(env!("CARGO_PKG_NAME"), "_game\0");
-    let engine_name = concat!(env!("CARGO_PKG_NAME"), "\0");
+//Drivers key application profiles off the name, so games should set their own
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct ApplicationInfo {
+    pub name: String,
+    pub version: (u32, u32, u32)
+}
 
-    unsafe {
-        vk::ApplicationInfo::default()
-            .application_name(CStr::from_bytes_with_nul_unchecked(application_name.as_bytes()))
-            .application_version(version)
-            .engine_name(CStr::from_bytes_with_nul_unchecked(engine_name.as_bytes()))
-            .engine_version(version)
-            .api_version(api_version)
+impl Default for ApplicationInfo {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            name: concat!(env!("CARGO_PKG_NAME"), "_game").to_owned(),
+            version: cargo_version()
+        }
     }
 }
 
 pub struct Layers {
     supported: Vec<vk::LayerProperties>,
     enabled: Vec<*const c_char>,
+    owned_names: Vec<CString>,
 
     khronos_validation: bool
 }
@@ -54,6 +64,7 @@ impl Layers {
         Ok(Self {
             supported,
             enabled: Vec::new(),
+            owned_names: Vec::new(),
 
             khronos_validation: false
         })
@@ -64,6 +75,16 @@ impl Layers {
         self.supported.iter().any(|e| libc::strcmp(e.layer_name.as_ptr(), name) == 0)
     }
 
+    #[inline]
+    pub fn is_supported_str(&self, name: &str) -> bool {
+        self.supported.iter().any(|e| from_c_char_array(&e.layer_name) == name)
+    }
+
+    #[inline]
+    pub fn supported_names(&self) -> Vec<String> {
+        self.supported.iter().map(|e| from_c_char_array(&e.layer_name)).collect()
+    }
+
     #[inline]
     pub unsafe fn is_enabled(&self, name: *const c_char) -> bool {
         self.enabled.iter().any(|e| libc::strcmp(*e, name) == 0)
@@ -89,6 +110,26 @@ impl Layers {
         assert!(self.try_push(name))
     }
 
+    pub fn try_push_str(&mut self, name: &str) -> bool {
+        let name = match CString::new(name) {
+            Ok(name) => name,
+            Err(_) => return false
+        };
+
+        if !unsafe { self.try_push(name.as_ptr()) } {
+            return false
+        }
+
+        self.owned_names.push(name);
+
+        true
+    }
+
+    #[inline]
+    pub fn push_str(&mut self, name: &str) {
+        assert!(self.try_push_str(name))
+    }
+
     #[inline]
     pub fn supported(&self) -> &Vec<vk::LayerProperties> {
         &self.supported
@@ -105,12 +146,16 @@ impl Layers {
     }
 }
 
+pub const KHR_PORTABILITY_ENUMERATION_NAME: &[u8] = b"VK_KHR_portability_enumeration\0";
+
 pub struct Extensions {
     supported: Vec<vk::ExtensionProperties>,
     enabled: Vec<*const c_char>,
+    owned_names: Vec<CString>,
 
     ext_debug_utils: bool,
     khr_get_surface_capabilities2: bool,
+    khr_portability_enumeration: bool,
     khr_surface: bool
 }
 
@@ -122,9 +167,11 @@ impl Extensions {
         Ok(Self {
             supported,
             enabled: Vec::new(),
+            owned_names: Vec::new(),
 
             ext_debug_utils: false,
             khr_get_surface_capabilities2: false,
+            khr_portability_enumeration: false,
             khr_surface: false
         })
     }
@@ -134,6 +181,16 @@ impl Extensions {
         self.supported.iter().any(|e| libc::strcmp(e.extension_name.as_ptr(), name) == 0)
     }
 
+    #[inline]
+    pub fn is_supported_str(&self, name: &str) -> bool {
+        self.supported.iter().any(|e| from_c_char_array(&e.extension_name) == name)
+    }
+
+    #[inline]
+    pub fn supported_names(&self) -> Vec<String> {
+        self.supported.iter().map(|e| from_c_char_array(&e.extension_name)).collect()
+    }
+
     #[inline]
     pub unsafe fn is_enabled(&self, name: *const c_char) -> bool {
         self.enabled.iter().any(|e| libc::strcmp(*e, name) == 0)
@@ -151,6 +208,8 @@ impl Extensions {
             self.ext_debug_utils = true;
         } else if libc::strcmp(name, GetSurfaceCapabilities2::name().as_ptr()) == 0 {
             self.khr_get_surface_capabilities2 = true;
+        } else if libc::strcmp(name, KHR_PORTABILITY_ENUMERATION_NAME.as_ptr().cast()) == 0 {
+            self.khr_portability_enumeration = true;
         } else if libc::strcmp(name, Surface::name().as_ptr()) == 0 {
             self.khr_surface = true;
         }
@@ -163,6 +222,26 @@ impl Extensions {
         assert!(self.try_push(name))
     }
 
+    pub fn try_push_str(&mut self, name: &str) -> bool {
+        let name = match CString::new(name) {
+            Ok(name) => name,
+            Err(_) => return false
+        };
+
+        if !unsafe { self.try_push(name.as_ptr()) } {
+            return false
+        }
+
+        self.owned_names.push(name);
+
+        true
+    }
+
+    #[inline]
+    pub fn push_str(&mut self, name: &str) {
+        assert!(self.try_push_str(name))
+    }
+
     #[inline]
     pub fn supported(&self) -> &Vec<vk::ExtensionProperties> {
         &self.supported
@@ -183,12 +262,110 @@ impl Extensions {
         self.khr_get_surface_capabilities2
     }
 
+    #[inline]
+    pub fn khr_portability_enumeration(&self) -> bool {
+        self.khr_portability_enumeration
+    }
+
     #[inline]
     pub fn khr_surface(&self) -> bool {
         self.khr_surface
     }
 }
 
+#[inline]
+fn device_local_memory_size(memory_properties: &vk::PhysicalDeviceMemoryProperties) -> u64 {
+    memory_properties.memory_heaps[..memory_properties.memory_heap_count as usize]
+        .iter()
+        .filter(|heap| (heap.flags & vk::MemoryHeapFlags::DEVICE_LOCAL) == vk::MemoryHeapFlags::DEVICE_LOCAL)
+        .map(|heap| heap.size)
+        .sum()
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AdapterInfo {
+    pub name: String,
+    pub vendor_id: u32,
+    pub device_id: u32,
+    pub device_type: vk::PhysicalDeviceType,
+    pub driver_version: u32,
+    pub api_version: (u32, u32, u32),
+    pub device_local_memory: u64,
+    pub supports_present: bool
+}
+
+impl AdapterInfo {
+    pub fn from_properties(properties: &vk::PhysicalDeviceProperties, memory_properties: &vk::PhysicalDeviceMemoryProperties, supports_present: bool) -> Self {
+        Self {
+            name: from_c_char_array(&properties.device_name),
+            vendor_id: properties.vendor_id,
+            device_id: properties.device_id,
+            device_type: properties.device_type,
+            driver_version: properties.driver_version,
+            api_version: (
+                vk::api_version_major(properties.api_version),
+                vk::api_version_minor(properties.api_version),
+                vk::api_version_patch(properties.api_version)
+            ),
+            device_local_memory: device_local_memory_size(memory_properties),
+            supports_present
+        }
+    }
+
+    #[inline]
+    pub fn vendor_name(&self) -> &'static str {
+        match self.vendor_id {
+            0x1002 => "AMD",
+            0x106b => "Apple",
+            0x10de => "NVIDIA",
+            0x13b5 => "ARM",
+            0x5143 => "Qualcomm",
+            0x8086 => "Intel",
+            0x10005 => "Mesa",
+            _ => "Unknown"
+        }
+    }
+
+    #[inline]
+    pub fn device_type_name(&self) -> &'static str {
+        match self.device_type {
+            vk::PhysicalDeviceType::DISCRETE_GPU => "Discrete GPU",
+            vk::PhysicalDeviceType::INTEGRATED_GPU => "Integrated GPU",
+            vk::PhysicalDeviceType::VIRTUAL_GPU => "Virtual GPU",
+            vk::PhysicalDeviceType::CPU => "CPU",
+            _ => "Other"
+        }
+    }
+}
+
+#[inline]
+fn physical_device_type_rank(device_type: vk::PhysicalDeviceType, prefer_software: bool) -> u32 {
+    match device_type {
+        vk::PhysicalDeviceType::CPU if prefer_software => 5,
+        vk::PhysicalDeviceType::DISCRETE_GPU => 4,
+        vk::PhysicalDeviceType::INTEGRATED_GPU => 3,
+        vk::PhysicalDeviceType::VIRTUAL_GPU => 2,
+        vk::PhysicalDeviceType::CPU => 1,
+        _ => 0
+    }
+}
+
+//Case-insensitive substring match, the first matching name wins
+fn position_by_name<'a>(names: impl IntoIterator<Item = &'a str>, substr: &str) -> Option<usize> {
+    let substr = substr.to_lowercase();
+
+    names.into_iter().position(|name| name.to_lowercase().contains(&substr))
+}
+
+//Candidates are (device type, device local memory), the best rank wins and more memory breaks ties
+fn optimal_position(candidates: impl IntoIterator<Item = (vk::PhysicalDeviceType, u64)>, prefer_software: bool) -> Option<usize> {
+    candidates
+        .into_iter()
+        .enumerate()
+        .max_by_key(|(_, (device_type, memory_size))| (physical_device_type_rank(*device_type, prefer_software), *memory_size))
+        .map(|(index, _)| index)
+}
+
 pub struct Instance {
     entry_loader: Entry,
 
@@ -201,23 +378,67 @@ pub struct Instance {
     extensions: Extensions,
 
     debug_utils_messenger: vk::DebugUtilsMessengerEXT,
+    debug_message_severity: Box<AtomicU32>,
 
     physical_devices: Vec<vk::PhysicalDevice>
 }
 
 impl Instance {
-    pub fn new(window: &impl HasRawWindowHandle, callback: impl FnOnce(&Entry, &mut Layers, &mut Extensions) -> Result<u32>) -> Result<Arc<Self>> {
+    pub fn new(
+        window: &dyn HasRawWindowHandle,
+        application_info: &ApplicationInfo,
+        debug_message_severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+        callback: impl FnOnce(&Entry, &mut Layers, &mut Extensions) -> Result<u32>
+    ) -> Result<Arc<Self>> {
+        Self::create(Some(window), application_info, debug_message_severity, callback)
+    }
+
+    //Skips the surface extensions, for compute only tools without a window
+    pub fn new_headless(
+        application_info: &ApplicationInfo,
+        debug_message_severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+        callback: impl FnOnce(&Entry, &mut Layers, &mut Extensions) -> Result<u32>
+    ) -> Result<Arc<Self>> {
+        Self::create(None, application_info, debug_message_severity, callback)
+    }
+
+    fn create(
+        window: Option<&dyn HasRawWindowHandle>,
+        application_info: &ApplicationInfo,
+        debug_message_severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+        callback: impl FnOnce(&Entry, &mut Layers, &mut Extensions) -> Result<u32>
+    ) -> Result<Arc<Self>> {
         unsafe {
             let entry_loader = Entry::load()?;
 
             //Layers
             let mut layers = Layers::new(&entry_loader)?;
             let mut extensions = Extensions::new(&entry_loader)?;
-            ash_window::enumerate_required_extensions(&window)?.iter().for_each(|e| extensions.push(*e));
+            if let Some(window) = window {
+                ash_window::enumerate_required_extensions(window)?.iter().for_each(|e| extensions.push(*e));
+            }
+
+            let api_version = callback(&entry_loader, &mut layers, &mut extensions)?;
+
+            let application_name = CString::new(application_info.name.as_str())?;
+            let (major, minor, patch) = application_info.version;
+            let (engine_major, engine_minor, engine_patch) = cargo_version();
 
-            let application_info = application_info_from_cargo_toml(callback(&entry_loader, &mut layers, &mut extensions)?);
+            let application_info = vk::ApplicationInfo::default()
+                .application_name(&application_name)
+                .application_version(vk::make_api_version(0, major, minor, patch))
+                .engine_name(ENGINE_NAME)
+                .engine_version(vk::make_api_version(0, engine_major, engine_minor, engine_patch))
+                .api_version(api_version);
+
+            let flags = if extensions.khr_portability_enumeration() {
+                vk::InstanceCreateFlags::ENUMERATE_PORTABILITY_KHR
+            } else {
+                vk::InstanceCreateFlags::empty()
+            };
 
             let instance_create_info = vk::InstanceCreateInfo::default()
+                .flags(flags)
                 .application_info(&application_info)
                 .enabled_extension_names(extensions.enabled())
                 .enabled_layer_names(layers.enabled());
@@ -227,6 +448,8 @@ impl Instance {
             let get_surface_capabilities2_loader = GetSurfaceCapabilities2::new(&entry_loader, &loader);
             let surface_loader = Surface::new(&entry_loader, &loader);
 
+            let debug_message_severity = Box::new(AtomicU32::new(debug_message_severity.as_raw()));
+
             let debug_utils_messenger = if extensions.ext_debug_utils() {
                 let debug_utils_messenger_create_info = vk::DebugUtilsMessengerCreateInfoEXT::default()
                     .message_severity(
@@ -236,7 +459,8 @@ impl Instance {
                             | vk::DebugUtilsMessageSeverityFlagsEXT::ERROR
                     )
                     .message_type(vk::DebugUtilsMessageTypeFlagsEXT::GENERAL | vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION | vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE)
-                    .pfn_user_callback(Some(debug_callback));
+                    .pfn_user_callback(Some(debug_callback))
+                    .user_data(debug_message_severity.as_ref() as *const AtomicU32 as *mut c_void);
 
                 debug_utils_loader.create_debug_utils_messenger(&debug_utils_messenger_create_info, None)?
             } else {
@@ -257,45 +481,61 @@ impl Instance {
                 extensions,
 
                 debug_utils_messenger,
+                debug_message_severity,
 
                 physical_devices
             }))
         }
     }
 
-    pub fn find_optimal_physical_device(&self) -> vk::PhysicalDevice {
-        let mut heap_size: u64 = 0;
-        let mut physical_device = vk::PhysicalDevice::null();
-
-        for current_physical_device in self.physical_devices.iter() {
-            let properties = unsafe { self.loader.get_physical_device_properties(*current_physical_device) };
+    //prefer_software ranks CPU devices such as lavapipe or SwiftShader above all GPUs
+    pub fn find_optimal_physical_device(&self, prefer_software: bool) -> vk::PhysicalDevice {
+        let candidates = self.physical_devices.iter().map(|physical_device| unsafe {
+            let properties = self.loader.get_physical_device_properties(*physical_device);
+            let memory_properties = self.loader.get_physical_device_memory_properties(*physical_device);
 
-            if properties.device_type != vk::PhysicalDeviceType::DISCRETE_GPU {
-                continue
-            }
+            (properties.device_type, device_local_memory_size(&memory_properties))
+        });
 
-            let memory_properties = unsafe { self.loader.get_physical_device_memory_properties(*current_physical_device) };
-            let mut current_heap_size: u64 = 0;
+        optimal_position(candidates, prefer_software).map_or_else(vk::PhysicalDevice::null, |index| self.physical_devices[index])
+    }
 
-            for i in 0..memory_properties.memory_heap_count as usize {
-                let current_heap = &memory_properties.memory_heaps[i];
+    #[inline]
+    pub fn device_name(&self, physical_device: vk::PhysicalDevice) -> String {
+        let properties = unsafe { self.loader.get_physical_device_properties(physical_device) };
+        from_c_char_array(&properties.device_name)
+    }
 
-                if (current_heap.flags & vk::MemoryHeapFlags::DEVICE_LOCAL) == vk::MemoryHeapFlags::DEVICE_LOCAL {
-                    current_heap_size += current_heap.size;
-                }
-            }
+    //In the order of physical_devices, so a position in the list can be passed back as RenderPlugin::adapter_index
+    pub fn enumerate_adapters(&self, surface: Option<vk::SurfaceKHR>) -> Vec<AdapterInfo> {
+        self.physical_devices
+            .iter()
+            .map(|physical_device| unsafe {
+                let properties = self.loader.get_physical_device_properties(*physical_device);
+                let memory_properties = self.loader.get_physical_device_memory_properties(*physical_device);
+
+                let supports_present = surface.map_or(false, |surface| {
+                    (0..self.loader.get_physical_device_queue_family_properties(*physical_device).len() as u32).any(|family_index| {
+                        self.surface_loader
+                            .get_physical_device_surface_support(*physical_device, family_index, surface)
+                            .unwrap_or(false)
+                    })
+                });
+
+                AdapterInfo::from_properties(&properties, &memory_properties, supports_present)
+            })
+            .collect()
+    }
 
-            if current_heap_size > heap_size {
-                heap_size = current_heap_size;
-                physical_device = *current_physical_device;
-            }
-        }
+    pub fn find_physical_device_by_name(&self, substr: &str) -> Option<vk::PhysicalDevice> {
+        let names: Vec<_> = self.physical_devices.iter().map(|physical_device| self.device_name(*physical_device)).collect();
 
-        if physical_device == vk::PhysicalDevice::null() {
-            physical_device = self.physical_devices[0];
-        }
+        position_by_name(names.iter().map(String::as_str), substr).map(|index| self.physical_devices[index])
+    }
 
-        physical_device
+    #[inline]
+    pub fn physical_devices(&self) -> &[vk::PhysicalDevice] {
+        &self.physical_devices
     }
 
     #[inline]
@@ -354,8 +594,13 @@ unsafe extern "system" fn debug_callback(
     message_severity: vk::DebugUtilsMessageSeverityFlagsEXT,
     message_types: vk::DebugUtilsMessageTypeFlagsEXT,
     callback_data: *const vk::DebugUtilsMessengerCallbackDataEXT,
-    _user_data: *mut c_void
+    user_data: *mut c_void
 ) -> vk::Bool32 {
+    let min_message_severity = (*user_data.cast::<AtomicU32>()).load(Ordering::Relaxed);
+    if message_severity.as_raw() < min_message_severity {
+        return vk::FALSE
+    }
+
     log!(
         message_severity::to_log_level(message_severity),
         "[{:?}]{}",
@@ -365,3 +610,62 @@ unsafe extern "system" fn debug_callback(
 
     vk::FALSE
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAMES: [&str; 3] = ["NVIDIA GeForce RTX 3080", "Intel(R) UHD Graphics 630", "llvmpipe (LLVM 15.0.7, 256 bits)"];
+
+    #[test]
+    fn name_matching_is_a_case_insensitive_substring() {
+        assert_eq!(position_by_name(NAMES, "geforce"), Some(0));
+        assert_eq!(position_by_name(NAMES, "UHD GRAPHICS"), Some(1));
+        assert_eq!(position_by_name(NAMES, "LLVMpipe"), Some(2));
+        assert_eq!(position_by_name(NAMES, "radeon"), None);
+    }
+
+    #[test]
+    fn first_matching_name_wins() {
+        assert_eq!(position_by_name(NAMES, "i"), Some(0));
+    }
+
+    const GIB: u64 = 1 << 30;
+
+    #[test]
+    fn discrete_gpus_rank_above_integrated() {
+        let candidates = [
+            (vk::PhysicalDeviceType::INTEGRATED_GPU, 16 * GIB),
+            (vk::PhysicalDeviceType::DISCRETE_GPU, 8 * GIB),
+            (vk::PhysicalDeviceType::CPU, 32 * GIB)
+        ];
+
+        assert_eq!(optimal_position(candidates, false), Some(1));
+    }
+
+    #[test]
+    fn integrated_gpu_is_used_without_a_discrete_one() {
+        let candidates = [(vk::PhysicalDeviceType::CPU, 32 * GIB), (vk::PhysicalDeviceType::INTEGRATED_GPU, 2 * GIB), (vk::PhysicalDeviceType::VIRTUAL_GPU, 4 * GIB)];
+
+        assert_eq!(optimal_position(candidates, false), Some(1));
+    }
+
+    #[test]
+    fn memory_breaks_ties_between_equal_types() {
+        let candidates = [(vk::PhysicalDeviceType::DISCRETE_GPU, 4 * GIB), (vk::PhysicalDeviceType::DISCRETE_GPU, 12 * GIB)];
+
+        assert_eq!(optimal_position(candidates, false), Some(1));
+        assert_eq!(optimal_position([], false), None);
+    }
+
+    #[test]
+    fn forced_software_prefers_cpu_devices() {
+        let candidates = [(vk::PhysicalDeviceType::DISCRETE_GPU, 8 * GIB), (vk::PhysicalDeviceType::CPU, 0), (vk::PhysicalDeviceType::INTEGRATED_GPU, 2 * GIB)];
+
+        assert_eq!(optimal_position(candidates, true), Some(1));
+        assert_eq!(optimal_position(candidates, false), Some(0));
+
+        //Without a CPU device the usual ranking applies
+        assert_eq!(optimal_position([candidates[0], candidates[2]], true), Some(0));
+    }
+}
@@ -1,12 +1,24 @@
 pub mod resource;
 pub mod util;
 
+pub mod command;
+pub mod descriptor;
 pub mod device;
 pub mod instance;
+pub mod pipeline;
+pub mod query;
 mod surface;
 pub mod swapchain;
+pub mod sync;
+pub mod vertex;
 
+pub use command::*;
+pub use descriptor::*;
 pub use device::*;
 pub use instance::*;
+pub use pipeline::*;
+pub use query::*;
 pub use surface::*;
 pub use swapchain::*;
+pub use sync::*;
+pub use vertex::*;
@@ -0,0 +1,929 @@
+use std::{
+    collections::HashMap,
+    ffi::CStr,
+    fs,
+    path::Path,
+    slice,
+    sync::{Arc, Mutex, Weak}
+};
+
+use anyhow::{ensure, Result};
+use ash::{prelude::VkResult, vk};
+use log::warn;
+
+use crate::backend::{
+    resource::{Buffer, BufferDesc},
+    DescriptorSetLayout, Device, DeviceGeneration, VertexLayout
+};
+
+pub const ENTRY_POINT: &CStr = unsafe { CStr::from_bytes_with_nul_unchecked(b"main\0") };
+
+#[derive(Copy, Clone)]
+pub struct GraphicsPipelineDesc<'a> {
+    pub vertex_spirv: &'a [u32],
+    pub vertex_entry_point: Option<&'a CStr>,
+    pub fragment_spirv: &'a [u32],
+    pub fragment_entry_point: Option<&'a CStr>,
+    pub vertex_layout: &'a VertexLayout,
+    pub topology: vk::PrimitiveTopology,
+    pub cull_mode: vk::CullModeFlags,
+    pub depth_test: bool,
+    //With a null render pass the pipeline targets dynamic rendering with these attachment formats
+    pub render_pass: vk::RenderPass,
+    pub color_formats: &'a [vk::Format],
+    pub depth_format: vk::Format,
+    pub layout: vk::PipelineLayout
+}
+
+#[derive(Copy, Clone)]
+pub struct MeshPipelineDesc<'a> {
+    pub task_spirv: Option<&'a [u32]>,
+    pub task_entry_point: Option<&'a CStr>,
+    pub mesh_spirv: &'a [u32],
+    pub mesh_entry_point: Option<&'a CStr>,
+    pub fragment_spirv: &'a [u32],
+    pub fragment_entry_point: Option<&'a CStr>,
+    pub cull_mode: vk::CullModeFlags,
+    pub depth_test: bool,
+    //With a null render pass the pipeline targets dynamic rendering with these attachment formats
+    pub render_pass: vk::RenderPass,
+    pub color_formats: &'a [vk::Format],
+    pub depth_format: vk::Format,
+    pub layout: vk::PipelineLayout
+}
+
+#[derive(Copy, Clone)]
+pub struct ComputePipelineDesc<'a> {
+    pub spirv: &'a [u32],
+    pub entry_point: &'a CStr,
+    pub layout: vk::PipelineLayout
+}
+
+#[derive(Copy, Clone)]
+pub struct RayTracingMissShader<'a> {
+    pub spirv: &'a [u32],
+    pub entry_point: Option<&'a CStr>
+}
+
+//Triangle hit group, at least one of the shaders has to be set
+#[derive(Copy, Clone, Default)]
+pub struct RayTracingHitGroup<'a> {
+    pub closest_hit_spirv: Option<&'a [u32]>,
+    pub closest_hit_entry_point: Option<&'a CStr>,
+    pub any_hit_spirv: Option<&'a [u32]>,
+    pub any_hit_entry_point: Option<&'a CStr>
+}
+
+//Shader groups are ordered raygen, miss, hit, which is the layout ShaderBindingTable::new expects
+#[derive(Copy, Clone)]
+pub struct RayTracingPipelineDesc<'a> {
+    pub raygen_spirv: &'a [u32],
+    pub raygen_entry_point: Option<&'a CStr>,
+    pub misses: &'a [RayTracingMissShader<'a>],
+    pub hit_groups: &'a [RayTracingHitGroup<'a>],
+    pub max_recursion_depth: u32,
+    pub layout: vk::PipelineLayout
+}
+
+//Save with save_to_disk at shutdown and reload with load_from_disk at startup, naming the file after Device::adapter_info.
+//Keying by adapter guards against a blob from another adapter or driver, which is ignored and replaced by an empty cache
+pub struct PipelineCache {
+    pipeline_cache: vk::PipelineCache,
+
+    device_generation: DeviceGeneration,
+    device: Arc<Device>
+}
+
+impl PipelineCache {
+    const HEADER_SIZE: usize = 16 + vk::UUID_SIZE;
+
+    #[inline]
+    pub fn new(device: Arc<Device>) -> VkResult<Self> {
+        Self::with_data(device, &[])
+    }
+
+    pub fn with_data(device: Arc<Device>, data: &[u8]) -> VkResult<Self> {
+        let data = if data.is_empty() || Self::is_compatible(&device, data) {
+            data
+        } else {
+            warn!("Ignoring pipeline cache data created by a different device or driver");
+            &[]
+        };
+
+        let pipeline_cache_create_info = vk::PipelineCacheCreateInfo::default().initial_data(data);
+
+        let pipeline_cache = match unsafe { device.loader().create_pipeline_cache(&pipeline_cache_create_info, None) } {
+            Ok(pipeline_cache) => pipeline_cache,
+            Err(e) if !data.is_empty() => {
+                warn!("Failed to create pipeline cache from existing data, starting with an empty cache: {:?}", e);
+                unsafe { device.loader().create_pipeline_cache(&vk::PipelineCacheCreateInfo::default(), None)? }
+            }
+            Err(e) => return Err(e)
+        };
+
+        Ok(Self {
+            pipeline_cache,
+
+            device_generation: device.generation(),
+            device
+        })
+    }
+
+    pub fn load_from_disk(device: Arc<Device>, path: impl AsRef<Path>) -> Result<Self> {
+        let data = match fs::read(path.as_ref()) {
+            Ok(data) => data,
+            Err(e) => {
+                warn!("Failed to read pipeline cache {}: {}", path.as_ref().display(), e);
+                Vec::new()
+            }
+        };
+
+        Ok(Self::with_data(device, &data)?)
+    }
+
+    pub fn save_to_disk(&self, path: impl AsRef<Path>) -> Result<()> {
+        fs::write(path, self.data()?)?;
+
+        Ok(())
+    }
+
+    #[inline]
+    pub fn data(&self) -> VkResult<Vec<u8>> {
+        unsafe { self.device.loader().get_pipeline_cache_data(self.pipeline_cache) }
+    }
+
+    pub fn merge(&self, sources: &[&PipelineCache]) -> VkResult<()> {
+        let sources: Vec<_> = sources.iter().map(|source| source.pipeline_cache).collect();
+
+        unsafe { self.device.loader().merge_pipeline_caches(self.pipeline_cache, &sources) }
+    }
+
+    fn is_compatible(device: &Device, data: &[u8]) -> bool {
+        if data.len() < Self::HEADER_SIZE {
+            return false
+        }
+
+        let read_u32 = |offset: usize| u32::from_ne_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]);
+        let properties = &device.properties().properties;
+
+        read_u32(4) == vk::PipelineCacheHeaderVersion::ONE.as_raw() as u32
+            && read_u32(8) == properties.vendor_id
+            && read_u32(12) == properties.device_id
+            && data[16..Self::HEADER_SIZE] == properties.pipeline_cache_uuid
+    }
+
+    #[inline]
+    pub fn pipeline_cache(&self) -> &vk::PipelineCache {
+        &self.pipeline_cache
+    }
+}
+
+impl Drop for PipelineCache {
+    #[inline]
+    fn drop(&mut self) {
+        self.device.assert_generation(self.device_generation);
+
+        unsafe {
+            self.device.loader().destroy_pipeline_cache(self.pipeline_cache, None);
+        }
+    }
+}
+
+fn validate_push_constant_ranges(push_constant_ranges: &[vk::PushConstantRange], max_push_constants_size: u32) -> Result<()> {
+    let push_constants_size = push_constant_ranges.iter().map(|range| range.offset + range.size).max().unwrap_or(0);
+    ensure!(
+        push_constants_size <= max_push_constants_size,
+        "Push constant ranges span {} bytes, but the device only supports {} bytes",
+        push_constants_size,
+        max_push_constants_size
+    );
+
+    Ok(())
+}
+
+//Every pushed stage needs a range covering the bytes, and every range overlapping them has to be pushed for all of its stages
+fn validate_push_constants(push_constant_ranges: &[vk::PushConstantRange], stages: vk::ShaderStageFlags, offset: u32, size: u32) -> Result<()> {
+    let end = offset.checked_add(size).unwrap_or(u32::MAX);
+
+    for range in push_constant_ranges {
+        let overlaps = offset < range.offset + range.size && range.offset < end;
+        ensure!(
+            !overlaps || stages.contains(range.stage_flags),
+            "Push constants at {}..{} overlap a range for {:?}, but are only pushed for {:?}",
+            offset,
+            end,
+            range.stage_flags,
+            stages
+        );
+    }
+
+    let covered = |stage: vk::ShaderStageFlags| {
+        push_constant_ranges
+            .iter()
+            .any(|range| range.stage_flags.contains(stage) && range.offset <= offset && end <= range.offset + range.size)
+    };
+    for bit in 0..32 {
+        let stage = vk::ShaderStageFlags::from_raw(1 << bit);
+        ensure!(
+            !stages.contains(stage) || covered(stage),
+            "Push constants at {}..{} for {:?} are not covered by the pipeline layout",
+            offset,
+            end,
+            stage
+        );
+    }
+
+    Ok(())
+}
+
+pub struct PipelineLayout {
+    pipeline_layout: vk::PipelineLayout,
+    push_constant_ranges: Vec<vk::PushConstantRange>,
+
+    device_generation: DeviceGeneration,
+    device: Arc<Device>
+}
+
+impl PipelineLayout {
+    pub fn new(device: Arc<Device>, set_layouts: &[&DescriptorSetLayout], push_constant_ranges: &[vk::PushConstantRange]) -> Result<Self> {
+        validate_push_constant_ranges(push_constant_ranges, device.properties().properties.limits.max_push_constants_size)?;
+
+        let set_layouts: Vec<_> = set_layouts.iter().map(|set_layout| *set_layout.descriptor_set_layout()).collect();
+
+        let pipeline_layout_create_info = vk::PipelineLayoutCreateInfo::default()
+            .set_layouts(&set_layouts)
+            .push_constant_ranges(push_constant_ranges);
+
+        let pipeline_layout = unsafe { device.loader().create_pipeline_layout(&pipeline_layout_create_info, None)? };
+
+        Ok(Self {
+            pipeline_layout,
+            push_constant_ranges: push_constant_ranges.to_vec(),
+
+            device_generation: device.generation(),
+            device
+        })
+    }
+
+    #[inline]
+    pub fn validate_push_constants(&self, stages: vk::ShaderStageFlags, offset: u32, size: u32) -> Result<()> {
+        validate_push_constants(&self.push_constant_ranges, stages, offset, size)
+    }
+
+    #[inline]
+    pub fn pipeline_layout(&self) -> &vk::PipelineLayout {
+        &self.pipeline_layout
+    }
+
+    #[inline]
+    pub fn push_constant_ranges(&self) -> &[vk::PushConstantRange] {
+        &self.push_constant_ranges
+    }
+}
+
+impl Drop for PipelineLayout {
+    #[inline]
+    fn drop(&mut self) {
+        self.device.assert_generation(self.device_generation);
+
+        unsafe {
+            self.device.loader().destroy_pipeline_layout(self.pipeline_layout, None);
+        }
+    }
+}
+
+pub struct ShaderModuleHandle {
+    shader_module: vk::ShaderModule,
+
+    device_generation: DeviceGeneration,
+    device: Arc<Device>
+}
+
+impl ShaderModuleHandle {
+    #[inline]
+    pub fn shader_module(&self) -> &vk::ShaderModule {
+        &self.shader_module
+    }
+}
+
+impl Drop for ShaderModuleHandle {
+    #[inline]
+    fn drop(&mut self) {
+        self.device.assert_generation(self.device_generation);
+
+        unsafe {
+            self.device.loader().destroy_shader_module(self.shader_module, None);
+        }
+    }
+}
+
+//Holds weak references only, so the cache doesn't keep modules (or the device) alive.
+//Keyed by the SPIR-V itself, so a hash collision can't hand out the module of another shader
+#[derive(Default)]
+pub struct ShaderModuleCache {
+    shader_modules: Mutex<HashMap<Arc<[u32]>, Weak<ShaderModuleHandle>>>
+}
+
+impl ShaderModuleCache {
+    pub fn get_or_create(&self, device: &Arc<Device>, spirv: &[u32]) -> VkResult<Arc<ShaderModuleHandle>> {
+        let mut shader_modules = self.shader_modules.lock().unwrap();
+
+        if let Some(shader_module) = shader_modules.get(spirv).and_then(Weak::upgrade) {
+            return Ok(shader_module)
+        }
+
+        let shader_module = unsafe { device.loader().create_shader_module(&vk::ShaderModuleCreateInfo::default().code(spirv), None)? };
+        let shader_module = Arc::new(ShaderModuleHandle {
+            shader_module,
+
+            device_generation: device.generation(),
+            device: device.clone()
+        });
+
+        shader_modules.retain(|_, shader_module| shader_module.strong_count() > 0);
+        shader_modules.insert(spirv.into(), Arc::downgrade(&shader_module));
+
+        Ok(shader_module)
+    }
+}
+
+pub struct Pipeline {
+    pipeline: vk::Pipeline,
+    layout: vk::PipelineLayout,
+    bind_point: vk::PipelineBindPoint,
+    _shader_modules: Vec<Arc<ShaderModuleHandle>>,
+
+    device_generation: DeviceGeneration,
+    device: Arc<Device>
+}
+
+impl Pipeline {
+    fn create_shader_modules(device: &Arc<Device>, spirvs: &[&[u32]]) -> VkResult<Vec<Arc<ShaderModuleHandle>>> {
+        spirvs.iter().map(|spirv| device.shader_module(spirv)).collect()
+    }
+
+    unsafe fn create_graphics_pipeline(
+        device: Arc<Device>,
+        shader_modules: Vec<Arc<ShaderModuleHandle>>,
+        stages: &[vk::PipelineShaderStageCreateInfo],
+        desc: &GraphicsPipelineDesc,
+        pipeline_cache: Option<&PipelineCache>
+    ) -> VkResult<Self> {
+        let vertex_input_state = vk::PipelineVertexInputStateCreateInfo::default()
+            .vertex_binding_descriptions(desc.vertex_layout.bindings())
+            .vertex_attribute_descriptions(desc.vertex_layout.attributes());
+
+        let input_assembly_state = vk::PipelineInputAssemblyStateCreateInfo::default().topology(desc.topology);
+
+        let viewport_state = vk::PipelineViewportStateCreateInfo::default().viewport_count(1).scissor_count(1);
+
+        let rasterization_state = vk::PipelineRasterizationStateCreateInfo::default()
+            .polygon_mode(vk::PolygonMode::FILL)
+            .cull_mode(desc.cull_mode)
+            .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
+            .line_width(1.0);
+
+        let multisample_state = vk::PipelineMultisampleStateCreateInfo::default().rasterization_samples(vk::SampleCountFlags::TYPE_1);
+
+        let depth_stencil_state = vk::PipelineDepthStencilStateCreateInfo::default()
+            .depth_test_enable(desc.depth_test)
+            .depth_write_enable(desc.depth_test)
+            .depth_compare_op(vk::CompareOp::LESS);
+
+        let dynamic_rendering = desc.render_pass == vk::RenderPass::null();
+        if dynamic_rendering && !device.supports_dynamic_rendering() {
+            return Err(vk::Result::ERROR_FEATURE_NOT_PRESENT)
+        }
+
+        //The swapchain render pass has a single color attachment
+        let color_attachment_count = if dynamic_rendering { desc.color_formats.len() } else { 1 };
+        let color_blend_attachment_states = vec![vk::PipelineColorBlendAttachmentState::default().color_write_mask(vk::ColorComponentFlags::RGBA); color_attachment_count];
+        let color_blend_state = vk::PipelineColorBlendStateCreateInfo::default().attachments(&color_blend_attachment_states);
+
+        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic_state = vk::PipelineDynamicStateCreateInfo::default().dynamic_states(&dynamic_states);
+
+        let mut rendering_create_info = vk::PipelineRenderingCreateInfo::default()
+            .color_attachment_formats(desc.color_formats)
+            .depth_attachment_format(desc.depth_format);
+
+        let mut graphics_pipeline_create_info = vk::GraphicsPipelineCreateInfo::default()
+            .stages(stages)
+            .vertex_input_state(&vertex_input_state)
+            .input_assembly_state(&input_assembly_state)
+            .viewport_state(&viewport_state)
+            .rasterization_state(&rasterization_state)
+            .multisample_state(&multisample_state)
+            .depth_stencil_state(&depth_stencil_state)
+            .color_blend_state(&color_blend_state)
+            .dynamic_state(&dynamic_state)
+            .layout(desc.layout)
+            .render_pass(desc.render_pass);
+
+        if dynamic_rendering {
+            graphics_pipeline_create_info = graphics_pipeline_create_info.push_next(&mut rendering_create_info);
+        }
+
+        let pipeline = device
+            .loader()
+            .create_graphics_pipelines(
+                pipeline_cache.map_or(vk::PipelineCache::null(), |pipeline_cache| pipeline_cache.pipeline_cache),
+                slice::from_ref(&graphics_pipeline_create_info),
+                None
+            )
+            .map_err(|(_, e)| e)?[0];
+
+        Ok(Self {
+            pipeline,
+            layout: desc.layout,
+            bind_point: vk::PipelineBindPoint::GRAPHICS,
+            _shader_modules: shader_modules,
+
+            device_generation: device.generation(),
+            device
+        })
+    }
+
+    pub fn new_graphics(device: Arc<Device>, desc: &GraphicsPipelineDesc, pipeline_cache: Option<&PipelineCache>) -> VkResult<Self> {
+        unsafe {
+            let shader_modules = Self::create_shader_modules(&device, &[desc.vertex_spirv, desc.fragment_spirv])?;
+
+            let stages = [
+                vk::PipelineShaderStageCreateInfo::default()
+                    .stage(vk::ShaderStageFlags::VERTEX)
+                    .module(*shader_modules[0].shader_module())
+                    .name(desc.vertex_entry_point.unwrap_or(ENTRY_POINT)),
+                vk::PipelineShaderStageCreateInfo::default()
+                    .stage(vk::ShaderStageFlags::FRAGMENT)
+                    .module(*shader_modules[1].shader_module())
+                    .name(desc.fragment_entry_point.unwrap_or(ENTRY_POINT))
+            ];
+
+            Self::create_graphics_pipeline(device, shader_modules, &stages, desc, pipeline_cache)
+        }
+    }
+
+    pub fn new_mesh(device: Arc<Device>, desc: &MeshPipelineDesc, pipeline_cache: Option<&PipelineCache>) -> VkResult<Self> {
+        if !device.extensions().nv_mesh_shader() {
+            return Err(vk::Result::ERROR_EXTENSION_NOT_PRESENT)
+        }
+
+        unsafe {
+            let mut spirvs = Vec::with_capacity(3);
+            let mut stage_entry_points = Vec::with_capacity(3);
+
+            if let Some(task_spirv) = desc.task_spirv {
+                spirvs.push(task_spirv);
+                stage_entry_points.push((vk::ShaderStageFlags::TASK_NV, desc.task_entry_point));
+            }
+
+            spirvs.push(desc.mesh_spirv);
+            stage_entry_points.push((vk::ShaderStageFlags::MESH_NV, desc.mesh_entry_point));
+
+            spirvs.push(desc.fragment_spirv);
+            stage_entry_points.push((vk::ShaderStageFlags::FRAGMENT, desc.fragment_entry_point));
+
+            let shader_modules = Self::create_shader_modules(&device, &spirvs)?;
+
+            let stages: Vec<_> = shader_modules
+                .iter()
+                .zip(stage_entry_points.iter())
+                .map(|(shader_module, (stage, entry_point))| {
+                    vk::PipelineShaderStageCreateInfo::default()
+                        .stage(*stage)
+                        .module(*shader_module.shader_module())
+                        .name(entry_point.unwrap_or(ENTRY_POINT))
+                })
+                .collect();
+
+            //Mesh pipelines ignore vertex input and input assembly state
+            let vertex_layout = VertexLayout::new();
+            let graphics_desc = GraphicsPipelineDesc {
+                vertex_spirv: &[],
+                vertex_entry_point: None,
+                fragment_spirv: desc.fragment_spirv,
+                fragment_entry_point: desc.fragment_entry_point,
+                vertex_layout: &vertex_layout,
+                topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+                cull_mode: desc.cull_mode,
+                depth_test: desc.depth_test,
+                render_pass: desc.render_pass,
+                color_formats: desc.color_formats,
+                depth_format: desc.depth_format,
+                layout: desc.layout
+            };
+
+            Self::create_graphics_pipeline(device, shader_modules, &stages, &graphics_desc, pipeline_cache)
+        }
+    }
+
+    pub fn new_compute(device: Arc<Device>, desc: &ComputePipelineDesc, pipeline_cache: Option<&PipelineCache>) -> VkResult<Self> {
+        unsafe {
+            let shader_modules = Self::create_shader_modules(&device, &[desc.spirv])?;
+
+            let stage = vk::PipelineShaderStageCreateInfo::default()
+                .stage(vk::ShaderStageFlags::COMPUTE)
+                .module(*shader_modules[0].shader_module())
+                .name(desc.entry_point);
+
+            let compute_pipeline_create_info = vk::ComputePipelineCreateInfo::default().stage(stage).layout(desc.layout);
+
+            let pipeline = device
+                .loader()
+                .create_compute_pipelines(
+                    pipeline_cache.map_or(vk::PipelineCache::null(), |pipeline_cache| pipeline_cache.pipeline_cache),
+                    slice::from_ref(&compute_pipeline_create_info),
+                    None
+                )
+                .map_err(|(_, e)| e)?[0];
+
+            Ok(Self {
+                pipeline,
+                layout: desc.layout,
+                bind_point: vk::PipelineBindPoint::COMPUTE,
+                _shader_modules: shader_modules,
+
+                device_generation: device.generation(),
+                device
+            })
+        }
+    }
+
+    pub fn new_ray_tracing(device: Arc<Device>, desc: &RayTracingPipelineDesc, pipeline_cache: Option<&PipelineCache>) -> Result<Self> {
+        ensure!(device.supports_ray_tracing(), "Ray tracing is not supported by the device");
+
+        //Shaders recursing deeper than the device limit are undefined behavior
+        let max_ray_recursion_depth = device.properties().ray_tracing_pipeline_properties.max_ray_recursion_depth;
+        ensure!(
+            desc.max_recursion_depth <= max_ray_recursion_depth,
+            "Ray tracing pipeline recursion depth {} exceeds the device limit of {}",
+            desc.max_recursion_depth,
+            max_ray_recursion_depth
+        );
+
+        unsafe {
+            let mut spirvs = vec![desc.raygen_spirv];
+            let mut stage_flags = vec![vk::ShaderStageFlags::RAYGEN_KHR];
+            let mut entry_points = vec![desc.raygen_entry_point];
+            let mut groups = vec![vk::RayTracingShaderGroupCreateInfoKHR::default()
+                .ty(vk::RayTracingShaderGroupTypeKHR::GENERAL)
+                .general_shader(0)
+                .closest_hit_shader(vk::SHADER_UNUSED_KHR)
+                .any_hit_shader(vk::SHADER_UNUSED_KHR)
+                .intersection_shader(vk::SHADER_UNUSED_KHR)];
+
+            for miss in desc.misses {
+                groups.push(
+                    vk::RayTracingShaderGroupCreateInfoKHR::default()
+                        .ty(vk::RayTracingShaderGroupTypeKHR::GENERAL)
+                        .general_shader(spirvs.len() as u32)
+                        .closest_hit_shader(vk::SHADER_UNUSED_KHR)
+                        .any_hit_shader(vk::SHADER_UNUSED_KHR)
+                        .intersection_shader(vk::SHADER_UNUSED_KHR)
+                );
+
+                spirvs.push(miss.spirv);
+                stage_flags.push(vk::ShaderStageFlags::MISS_KHR);
+                entry_points.push(miss.entry_point);
+            }
+
+            for hit_group in desc.hit_groups {
+                let hit_stages = [
+                    (hit_group.closest_hit_spirv, hit_group.closest_hit_entry_point, vk::ShaderStageFlags::CLOSEST_HIT_KHR),
+                    (hit_group.any_hit_spirv, hit_group.any_hit_entry_point, vk::ShaderStageFlags::ANY_HIT_KHR)
+                ];
+
+                let mut shader_indices = [vk::SHADER_UNUSED_KHR; 2];
+                for (shader_index, (spirv, entry_point, stage)) in shader_indices.iter_mut().zip(hit_stages) {
+                    if let Some(spirv) = spirv {
+                        spirvs.push(spirv);
+                        stage_flags.push(stage);
+                        entry_points.push(entry_point);
+                        *shader_index = spirvs.len() as u32 - 1;
+                    }
+                }
+                let [closest_hit_shader, any_hit_shader] = shader_indices;
+
+                groups.push(
+                    vk::RayTracingShaderGroupCreateInfoKHR::default()
+                        .ty(vk::RayTracingShaderGroupTypeKHR::TRIANGLES_HIT_GROUP)
+                        .general_shader(vk::SHADER_UNUSED_KHR)
+                        .closest_hit_shader(closest_hit_shader)
+                        .any_hit_shader(any_hit_shader)
+                        .intersection_shader(vk::SHADER_UNUSED_KHR)
+                );
+            }
+
+            let shader_modules = Self::create_shader_modules(&device, &spirvs)?;
+
+            let stages: Vec<_> = shader_modules
+                .iter()
+                .zip(stage_flags.iter().zip(entry_points.iter()))
+                .map(|(shader_module, (stage, entry_point))| {
+                    vk::PipelineShaderStageCreateInfo::default()
+                        .stage(*stage)
+                        .module(*shader_module.shader_module())
+                        .name(entry_point.unwrap_or(ENTRY_POINT))
+                })
+                .collect();
+
+            let ray_tracing_pipeline_create_info = vk::RayTracingPipelineCreateInfoKHR::default()
+                .stages(&stages)
+                .groups(&groups)
+                .max_pipeline_ray_recursion_depth(desc.max_recursion_depth)
+                .layout(desc.layout);
+
+            let pipeline = device.ray_tracing_pipeline_loader().create_ray_tracing_pipelines(
+                vk::DeferredOperationKHR::null(),
+                pipeline_cache.map_or(vk::PipelineCache::null(), |pipeline_cache| pipeline_cache.pipeline_cache),
+                slice::from_ref(&ray_tracing_pipeline_create_info),
+                None
+            )?[0];
+
+            Ok(Self {
+                pipeline,
+                layout: desc.layout,
+                bind_point: vk::PipelineBindPoint::RAY_TRACING_KHR,
+                _shader_modules: shader_modules,
+
+                device_generation: device.generation(),
+                device
+            })
+        }
+    }
+
+    #[inline]
+    pub fn pipeline(&self) -> &vk::Pipeline {
+        &self.pipeline
+    }
+
+    #[inline]
+    pub fn layout(&self) -> &vk::PipelineLayout {
+        &self.layout
+    }
+
+    #[inline]
+    pub fn bind_point(&self) -> vk::PipelineBindPoint {
+        self.bind_point
+    }
+}
+
+impl Drop for Pipeline {
+    #[inline]
+    fn drop(&mut self) {
+        self.device.assert_generation(self.device_generation);
+
+        unsafe {
+            self.device.loader().destroy_pipeline(self.pipeline, None);
+        }
+    }
+}
+
+#[inline]
+fn align_up(value: vk::DeviceSize, alignment: vk::DeviceSize) -> vk::DeviceSize {
+    (value + alignment - 1) / alignment * alignment
+}
+
+pub struct ShaderBindingTable {
+    raygen_region: vk::StridedDeviceAddressRegionKHR,
+    miss_region: vk::StridedDeviceAddressRegionKHR,
+    hit_region: vk::StridedDeviceAddressRegionKHR,
+    callable_region: vk::StridedDeviceAddressRegionKHR,
+    _buffer: Buffer
+}
+
+impl ShaderBindingTable {
+    //Expects the group layout of Pipeline::new_ray_tracing created from the same desc
+    pub fn new(device: Arc<Device>, pipeline: &Pipeline, desc: &RayTracingPipelineDesc) -> Result<Self> {
+        ensure!(pipeline.bind_point == vk::PipelineBindPoint::RAY_TRACING_KHR, "Shader binding tables need a ray tracing pipeline");
+
+        let properties = &device.properties().ray_tracing_pipeline_properties;
+        let handle_size = properties.shader_group_handle_size as usize;
+        let handle_stride = align_up(handle_size as _, properties.shader_group_handle_alignment as _);
+        let base_alignment = properties.shader_group_base_alignment as vk::DeviceSize;
+
+        let miss_count = desc.misses.len() as vk::DeviceSize;
+        let hit_count = desc.hit_groups.len() as vk::DeviceSize;
+        let group_count = 1 + miss_count + hit_count;
+
+        let handles = unsafe {
+            device
+                .ray_tracing_pipeline_loader()
+                .get_ray_tracing_shader_group_handles(pipeline.pipeline, 0, group_count as u32, group_count as usize * handle_size)?
+        };
+
+        let raygen_size = align_up(handle_stride, base_alignment);
+        let miss_size = align_up(miss_count * handle_stride, base_alignment);
+        let hit_size = align_up(hit_count * handle_stride, base_alignment);
+
+        //Regions have to start at a multiple of the base alignment, which the allocator doesn't guarantee
+        let buffer = Buffer::new(
+            device.clone(),
+            &BufferDesc::new_cpu_to_gpu(
+                raygen_size + miss_size + hit_size + base_alignment,
+                vk::BufferUsageFlags::SHADER_BINDING_TABLE_KHR | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS
+            )
+        )?;
+        ensure!(*buffer.device_address() != 0, "Shader binding table buffer has no device address");
+
+        let base_address = align_up(*buffer.device_address(), base_alignment);
+        let base_offset = base_address - *buffer.device_address();
+
+        let mut data = vec![0u8; (raygen_size + miss_size + hit_size) as usize];
+        let region_offsets = [0, raygen_size, raygen_size + miss_size];
+        let region_index = |group: vk::DeviceSize| match group {
+            0 => (0, 0),
+            group if group <= miss_count => (1, group - 1),
+            group => (2, group - 1 - miss_count)
+        };
+
+        for (group, handle) in handles.chunks_exact(handle_size).enumerate() {
+            let (region, index) = region_index(group as vk::DeviceSize);
+            let offset = (region_offsets[region] + index * handle_stride) as usize;
+            data[offset..offset + handle_size].copy_from_slice(handle);
+        }
+
+        buffer.write_slice(base_offset, &data)?;
+
+        let region = |offset: vk::DeviceSize, count: vk::DeviceSize, size: vk::DeviceSize| {
+            if count == 0 {
+                vk::StridedDeviceAddressRegionKHR::default()
+            } else {
+                vk::StridedDeviceAddressRegionKHR::default()
+                    .device_address(base_address + offset)
+                    .stride(handle_stride)
+                    .size(size)
+            }
+        };
+
+        Ok(Self {
+            //The raygen region size has to equal its stride
+            raygen_region: vk::StridedDeviceAddressRegionKHR::default()
+                .device_address(base_address)
+                .stride(raygen_size)
+                .size(raygen_size),
+            miss_region: region(region_offsets[1], miss_count, miss_size),
+            hit_region: region(region_offsets[2], hit_count, hit_size),
+            callable_region: vk::StridedDeviceAddressRegionKHR::default(),
+            _buffer: buffer
+        })
+    }
+
+    #[inline]
+    pub fn raygen_region(&self) -> &vk::StridedDeviceAddressRegionKHR {
+        &self.raygen_region
+    }
+
+    #[inline]
+    pub fn miss_region(&self) -> &vk::StridedDeviceAddressRegionKHR {
+        &self.miss_region
+    }
+
+    #[inline]
+    pub fn hit_region(&self) -> &vk::StridedDeviceAddressRegionKHR {
+        &self.hit_region
+    }
+
+    #[inline]
+    pub fn callable_region(&self) -> &vk::StridedDeviceAddressRegionKHR {
+        &self.callable_region
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        renderer::test_device,
+        resource::{Shader, ShaderStage}
+    };
+
+    const COMPUTE_GLSL: &str = "#version 450\nlayout(local_size_x = 1) in;\nvoid main() {}\n";
+
+    #[test]
+    fn oversized_push_constant_range_is_rejected() {
+        let ranges = [
+            vk::PushConstantRange::default().stage_flags(vk::ShaderStageFlags::VERTEX).size(64),
+            vk::PushConstantRange::default().stage_flags(vk::ShaderStageFlags::FRAGMENT).offset(64).size(128)
+        ];
+
+        assert!(validate_push_constant_ranges(&ranges, 256).is_ok());
+        assert!(validate_push_constant_ranges(&ranges, 128).is_err());
+    }
+
+    #[test]
+    fn push_constants_must_fit_the_layout_ranges() {
+        let ranges = [
+            vk::PushConstantRange::default().stage_flags(vk::ShaderStageFlags::VERTEX).size(64),
+            vk::PushConstantRange::default().stage_flags(vk::ShaderStageFlags::FRAGMENT).offset(64).size(16)
+        ];
+
+        assert!(validate_push_constants(&ranges, vk::ShaderStageFlags::VERTEX, 0, 64).is_ok());
+        assert!(validate_push_constants(&ranges, vk::ShaderStageFlags::FRAGMENT, 64, 16).is_ok());
+        assert!(validate_push_constants(&ranges, vk::ShaderStageFlags::VERTEX, 32, 64).is_err());
+        assert!(validate_push_constants(&ranges, vk::ShaderStageFlags::FRAGMENT, 0, 16).is_err());
+        assert!(validate_push_constants(&ranges, vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT, 0, 16).is_err());
+    }
+
+    #[test]
+    fn identical_spirv_shares_a_shader_module() {
+        let device = match test_device() {
+            Some(device) => device,
+            None => return
+        };
+
+        let spirv = Shader::from_glsl(COMPUTE_GLSL).compile(ShaderStage::Compute, "main").unwrap();
+        let copy = spirv.clone();
+
+        let shader_module = device.shader_module(&spirv).unwrap();
+        assert!(Arc::ptr_eq(&shader_module, &device.shader_module(&copy).unwrap()));
+
+        let other_spirv = Shader::from_glsl(COMPUTE_GLSL.replace("local_size_x = 1", "local_size_x = 2"))
+            .compile(ShaderStage::Compute, "main")
+            .unwrap();
+        assert!(!Arc::ptr_eq(&shader_module, &device.shader_module(&other_spirv).unwrap()));
+    }
+
+    #[test]
+    fn compute_pipeline_is_created_headless() {
+        let device = match test_device() {
+            Some(device) => device,
+            None => return
+        };
+
+        let spirv = Shader::from_glsl(COMPUTE_GLSL).compile(ShaderStage::Compute, "main").unwrap();
+        let layout = PipelineLayout::new(device.clone(), &[], &[]).unwrap();
+
+        let desc = ComputePipelineDesc {
+            spirv: &spirv,
+            entry_point: ENTRY_POINT,
+            layout: *layout.pipeline_layout()
+        };
+        let pipeline = Pipeline::new_compute(device, &desc, None).unwrap();
+
+        assert_ne!(*pipeline.pipeline(), vk::Pipeline::null());
+        assert_eq!(pipeline.bind_point(), vk::PipelineBindPoint::COMPUTE);
+    }
+
+    #[test]
+    fn mesh_pipeline_is_created_when_supported() {
+        let device = match test_device() {
+            Some(device) if device.extensions().nv_mesh_shader() && device.supports_dynamic_rendering() => device,
+            _ => return
+        };
+
+        let mesh_spirv = Shader::from_glsl(
+            "#version 450
+#extension GL_NV_mesh_shader : require
+layout(local_size_x = 1) in;
+layout(triangles, max_vertices = 3, max_primitives = 1) out;
+void main() { gl_PrimitiveCountNV = 0; }
+"
+        )
+        .compile(ShaderStage::Mesh, "main")
+        .unwrap();
+        let fragment_spirv = Shader::from_glsl("#version 450\nlayout(location = 0) out vec4 color;\nvoid main() { color = vec4(1.0); }\n")
+            .compile(ShaderStage::Fragment, "main")
+            .unwrap();
+        let layout = PipelineLayout::new(device.clone(), &[], &[]).unwrap();
+
+        let desc = MeshPipelineDesc {
+            task_spirv: None,
+            task_entry_point: None,
+            mesh_spirv: &mesh_spirv,
+            mesh_entry_point: None,
+            fragment_spirv: &fragment_spirv,
+            fragment_entry_point: None,
+            cull_mode: vk::CullModeFlags::NONE,
+            depth_test: false,
+            render_pass: vk::RenderPass::null(),
+            color_formats: &[vk::Format::R8G8B8A8_UNORM],
+            depth_format: vk::Format::UNDEFINED,
+            layout: *layout.pipeline_layout()
+        };
+        let pipeline = Pipeline::new_mesh(device, &desc, None).unwrap();
+
+        assert_eq!(pipeline.bind_point(), vk::PipelineBindPoint::GRAPHICS);
+    }
+
+    #[test]
+    fn ray_tracing_recursion_depth_is_checked_against_the_limit() {
+        let device = match test_device() {
+            Some(device) if device.supports_ray_tracing() => device,
+            _ => return
+        };
+
+        let layout = PipelineLayout::new(device.clone(), &[], &[]).unwrap();
+        let desc = RayTracingPipelineDesc {
+            raygen_spirv: &[],
+            raygen_entry_point: None,
+            misses: &[],
+            hit_groups: &[],
+            max_recursion_depth: device.properties().ray_tracing_pipeline_properties.max_ray_recursion_depth + 1,
+            layout: *layout.pipeline_layout()
+        };
+
+        let error = Pipeline::new_ray_tracing(device, &desc, None).err().unwrap();
+        assert!(error.to_string().contains("exceeds the device limit"));
+    }
+}
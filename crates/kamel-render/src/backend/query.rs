@@ -0,0 +1,99 @@
+use std::sync::Arc;
+
+use ash::{prelude::VkResult, vk};
+
+use crate::backend::{Device, DeviceGeneration};
+
+pub struct QueryPool {
+    query_pool: vk::QueryPool,
+    query_type: vk::QueryType,
+    count: u32,
+
+    device_generation: DeviceGeneration,
+    device: Arc<Device>
+}
+
+impl QueryPool {
+    pub fn new(device: Arc<Device>, query_type: vk::QueryType, count: u32) -> VkResult<Self> {
+        if query_type == vk::QueryType::TIMESTAMP && !device.supports_timestamps() {
+            return Err(vk::Result::ERROR_FEATURE_NOT_PRESENT)
+        }
+
+        let query_pool_create_info = vk::QueryPoolCreateInfo::default().query_type(query_type).query_count(count);
+
+        let query_pool = unsafe { device.loader().create_query_pool(&query_pool_create_info, None)? };
+
+        Ok(Self {
+            query_pool,
+            query_type,
+            count,
+
+            device_generation: device.generation(),
+            device
+        })
+    }
+
+    #[inline]
+    pub fn cmd_reset(&self, command_buffer: vk::CommandBuffer) {
+        unsafe { self.device.loader().cmd_reset_query_pool(command_buffer, self.query_pool, 0, self.count) }
+    }
+
+    #[inline]
+    pub fn cmd_write_timestamp(&self, command_buffer: vk::CommandBuffer, stage: vk::PipelineStageFlags, index: u32) {
+        assert!(index < self.count);
+
+        unsafe { self.device.loader().cmd_write_timestamp(command_buffer, stage, self.query_pool, index) }
+    }
+
+    //Doesn't block, timestamps the GPU hasn't written yet are None
+    pub fn read_timestamps(&self) -> VkResult<Vec<Option<u64>>> {
+        //Each timestamp is followed by its availability
+        let mut results = vec![[0u64; 2]; self.count as usize];
+
+        let result = unsafe {
+            self.device
+                .loader()
+                .get_query_pool_results(self.query_pool, 0, &mut results, vk::QueryResultFlags::TYPE_64 | vk::QueryResultFlags::WITH_AVAILABILITY)
+        };
+        match result {
+            //Some queries are unavailable, the available ones were still written
+            Ok(()) | Err(vk::Result::NOT_READY) => {}
+            Err(e) => return Err(e)
+        }
+
+        let valid_bits = self.device.timestamp_valid_bits();
+        let valid_mask = if valid_bits >= u64::BITS { u64::MAX } else { (1 << valid_bits) - 1 };
+        let timestamp_period = self.device.properties().properties.limits.timestamp_period as f64;
+
+        Ok(results
+            .into_iter()
+            .map(|[ticks, available]| (available != 0).then(|| ((ticks & valid_mask) as f64 * timestamp_period) as u64))
+            .collect())
+    }
+
+    #[inline]
+    pub fn query_pool(&self) -> &vk::QueryPool {
+        &self.query_pool
+    }
+
+    #[inline]
+    pub fn query_type(&self) -> vk::QueryType {
+        self.query_type
+    }
+
+    #[inline]
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+}
+
+impl Drop for QueryPool {
+    #[inline]
+    fn drop(&mut self) {
+        self.device.assert_generation(self.device_generation);
+
+        unsafe {
+            self.device.loader().destroy_query_pool(self.query_pool, None);
+        }
+    }
+}
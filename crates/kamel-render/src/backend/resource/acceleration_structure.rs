@@ -0,0 +1,206 @@
+use std::{mem, slice, sync::Arc};
+
+use anyhow::{ensure, Result};
+use ash::vk;
+
+use crate::backend::{
+    resource::{Buffer, BufferDesc},
+    Device, DeviceGeneration
+};
+
+#[derive(Copy, Clone)]
+pub struct BottomLevelDesc<'a> {
+    pub vertex_buffer: &'a Buffer,
+    pub vertex_format: vk::Format,
+    pub vertex_stride: vk::DeviceSize,
+    pub vertex_count: u32,
+    pub index_buffer: Option<(&'a Buffer, vk::IndexType)>,
+    pub primitive_count: u32
+}
+
+pub struct AccelerationStructure {
+    acceleration_structure: vk::AccelerationStructureKHR,
+    device_address: vk::DeviceAddress,
+    ty: vk::AccelerationStructureTypeKHR,
+    _buffer: Buffer,
+
+    device_generation: DeviceGeneration,
+    device: Arc<Device>
+}
+
+impl AccelerationStructure {
+    fn build(device: Arc<Device>, ty: vk::AccelerationStructureTypeKHR, geometry: &vk::AccelerationStructureGeometryKHR, primitive_count: u32) -> Result<Self> {
+        ensure!(device.supports_ray_tracing(), "Ray tracing is not supported by the device");
+
+        let mut build_info = vk::AccelerationStructureBuildGeometryInfoKHR::default()
+            .ty(ty)
+            .flags(vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE)
+            .mode(vk::BuildAccelerationStructureModeKHR::BUILD)
+            .geometries(slice::from_ref(geometry));
+
+        let build_sizes = unsafe {
+            device
+                .acceleration_structure_loader()
+                .get_acceleration_structure_build_sizes(vk::AccelerationStructureBuildTypeKHR::DEVICE, &build_info, slice::from_ref(&primitive_count))
+        };
+
+        let buffer = Buffer::new(
+            device.clone(),
+            &BufferDesc::new_gpu_only(
+                build_sizes.acceleration_structure_size,
+                vk::BufferUsageFlags::ACCELERATION_STRUCTURE_STORAGE_KHR | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS
+            )
+        )?;
+
+        let acceleration_structure_create_info = vk::AccelerationStructureCreateInfoKHR::default()
+            .buffer(*buffer.buffer())
+            .size(build_sizes.acceleration_structure_size)
+            .ty(ty);
+
+        let acceleration_structure = unsafe {
+            device
+                .acceleration_structure_loader()
+                .create_acceleration_structure(&acceleration_structure_create_info, None)?
+        };
+
+        let mut acceleration_structure = Self {
+            acceleration_structure,
+            device_address: 0,
+            ty,
+            _buffer: buffer,
+
+            device_generation: device.generation(),
+            device
+        };
+
+        let device = &acceleration_structure.device;
+
+        //Scratch addresses have to be aligned, which the allocator doesn't guarantee
+        let scratch_alignment = (device.properties().acceleration_structure_properties.min_acceleration_structure_scratch_offset_alignment as vk::DeviceSize).max(1);
+        let scratch_buffer = Buffer::new(
+            device.clone(),
+            &BufferDesc::new_gpu_only(
+                build_sizes.build_scratch_size + scratch_alignment,
+                vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS
+            )
+        )?;
+        let scratch_address = (*scratch_buffer.device_address() + scratch_alignment - 1) / scratch_alignment * scratch_alignment;
+
+        build_info = build_info
+            .dst_acceleration_structure(acceleration_structure.acceleration_structure)
+            .scratch_data(vk::DeviceOrHostAddressKHR { device_address: scratch_address });
+
+        let build_range_info = vk::AccelerationStructureBuildRangeInfoKHR::default().primitive_count(primitive_count);
+
+        device.submit_immediate(device.direct_queue(), |command_buffer| unsafe {
+            device
+                .acceleration_structure_loader()
+                .cmd_build_acceleration_structures(command_buffer, slice::from_ref(&build_info), &[slice::from_ref(&build_range_info)]);
+        })?;
+
+        let device_address_info = vk::AccelerationStructureDeviceAddressInfoKHR::default().acceleration_structure(acceleration_structure.acceleration_structure);
+        acceleration_structure.device_address = unsafe { device.acceleration_structure_loader().get_acceleration_structure_device_address(&device_address_info) };
+
+        Ok(acceleration_structure)
+    }
+
+    //Buffers used as build input need SHADER_DEVICE_ADDRESS and ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR usage
+    pub fn new_bottom_level(device: Arc<Device>, desc: &BottomLevelDesc) -> Result<Self> {
+        ensure!(*desc.vertex_buffer.device_address() != 0, "Vertex buffer has no device address");
+
+        let mut triangles = vk::AccelerationStructureGeometryTrianglesDataKHR::default()
+            .vertex_format(desc.vertex_format)
+            .vertex_data(vk::DeviceOrHostAddressConstKHR {
+                device_address: *desc.vertex_buffer.device_address()
+            })
+            .vertex_stride(desc.vertex_stride)
+            .max_vertex(desc.vertex_count.saturating_sub(1))
+            .index_type(vk::IndexType::NONE_KHR);
+
+        if let Some((index_buffer, index_type)) = desc.index_buffer {
+            ensure!(*index_buffer.device_address() != 0, "Index buffer has no device address");
+
+            triangles = triangles
+                .index_type(index_type)
+                .index_data(vk::DeviceOrHostAddressConstKHR {
+                    device_address: *index_buffer.device_address()
+                });
+        }
+
+        let geometry = vk::AccelerationStructureGeometryKHR::default()
+            .geometry_type(vk::GeometryTypeKHR::TRIANGLES)
+            .geometry(vk::AccelerationStructureGeometryDataKHR { triangles })
+            .flags(vk::GeometryFlagsKHR::OPAQUE);
+
+        Self::build(device, vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL, &geometry, desc.primitive_count)
+    }
+
+    pub fn new_top_level(device: Arc<Device>, instances: &[vk::AccelerationStructureInstanceKHR]) -> Result<Self> {
+        ensure!(!instances.is_empty(), "Top level acceleration structure needs at least one instance");
+
+        let instance_buffer = Buffer::new(
+            device.clone(),
+            &BufferDesc::new_cpu_to_gpu(
+                mem::size_of_val(instances) as vk::DeviceSize,
+                vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS
+            )
+        )?;
+        instance_buffer.write_slice(0, instances)?;
+
+        let instances_data = vk::AccelerationStructureGeometryInstancesDataKHR::default()
+            .array_of_pointers(false)
+            .data(vk::DeviceOrHostAddressConstKHR {
+                device_address: *instance_buffer.device_address()
+            });
+
+        let geometry = vk::AccelerationStructureGeometryKHR::default()
+            .geometry_type(vk::GeometryTypeKHR::INSTANCES)
+            .geometry(vk::AccelerationStructureGeometryDataKHR { instances: instances_data });
+
+        //The build waits for completion, so the instance buffer can be dropped afterwards
+        Self::build(device, vk::AccelerationStructureTypeKHR::TOP_LEVEL, &geometry, instances.len() as u32)
+    }
+
+    //Transform is a row-major 3x4 matrix, hit_group_offset indexes into the hit groups of the shader binding table
+    pub fn instance(&self, transform: [f32; 12], custom_index: u32, mask: u8, hit_group_offset: u32) -> vk::AccelerationStructureInstanceKHR {
+        vk::AccelerationStructureInstanceKHR {
+            transform: vk::TransformMatrixKHR { matrix: transform },
+            instance_custom_index_and_mask: vk::Packed24_8::new(custom_index, mask),
+            instance_shader_binding_table_record_offset_and_flags: vk::Packed24_8::new(
+                hit_group_offset,
+                vk::GeometryInstanceFlagsKHR::TRIANGLE_FACING_CULL_DISABLE.as_raw() as u8
+            ),
+            acceleration_structure_reference: vk::AccelerationStructureReferenceKHR {
+                device_handle: self.device_address
+            }
+        }
+    }
+
+    #[inline]
+    pub fn acceleration_structure(&self) -> &vk::AccelerationStructureKHR {
+        &self.acceleration_structure
+    }
+
+    #[inline]
+    pub fn device_address(&self) -> &vk::DeviceAddress {
+        &self.device_address
+    }
+
+    #[inline]
+    pub fn ty(&self) -> vk::AccelerationStructureTypeKHR {
+        self.ty
+    }
+}
+
+impl Drop for AccelerationStructure {
+    #[inline]
+    fn drop(&mut self) {
+        self.device.assert_generation(self.device_generation);
+
+        unsafe {
+            self.device
+                .acceleration_structure_loader()
+                .destroy_acceleration_structure(self.acceleration_structure, None);
+        }
+    }
+}
@@ -1,15 +1,18 @@
-use std::sync::Arc;
+use std::{borrow::Cow, marker::PhantomData, mem, ptr, slice, sync::Arc};
 
+use anyhow::{ensure, Result};
 use ash::{prelude::VkResult, vk};
-use vk_mem::{Allocation, AllocationCreateInfo, AllocationInfo, MemoryUsage};
+use log::error;
+use vk_mem::{ffi, Allocation, AllocationCreateInfo, AllocationInfo, MemoryUsage};
 
-use crate::backend::Device;
+use crate::backend::{resource::TransferContext, Device, DeviceGeneration, Fence, Semaphore};
 
-#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
 pub struct BufferDesc {
     pub size: vk::DeviceSize,
     pub usage: vk::BufferUsageFlags,
-    pub memory_usage: MemoryUsage
+    pub memory_usage: MemoryUsage,
+    pub name: Option<Cow<'static, str>>
 }
 
 impl BufferDesc {
@@ -18,7 +21,8 @@ impl BufferDesc {
         Self {
             size,
             usage,
-            memory_usage: MemoryUsage::GpuOnly
+            memory_usage: MemoryUsage::GpuOnly,
+            name: None
         }
     }
 
@@ -27,7 +31,8 @@ impl BufferDesc {
         Self {
             size,
             usage,
-            memory_usage: MemoryUsage::CpuOnly
+            memory_usage: MemoryUsage::CpuOnly,
+            name: None
         }
     }
 
@@ -36,7 +41,8 @@ impl BufferDesc {
         Self {
             size,
             usage,
-            memory_usage: MemoryUsage::CpuToGpu
+            memory_usage: MemoryUsage::CpuToGpu,
+            name: None
         }
     }
 
@@ -45,7 +51,8 @@ impl BufferDesc {
         Self {
             size,
             usage,
-            memory_usage: MemoryUsage::GpuToCpu
+            memory_usage: MemoryUsage::GpuToCpu,
+            name: None
         }
     }
 
@@ -54,43 +61,474 @@ impl BufferDesc {
         Self {
             size,
             usage,
-            memory_usage: MemoryUsage::GpuLazy
+            memory_usage: MemoryUsage::GpuLazy,
+            name: None
+        }
+    }
+
+    #[inline]
+    pub fn with_name(mut self, name: impl Into<Cow<'static, str>>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+}
+
+#[repr(transparent)]
+pub struct BufferDeviceAddress<T> {
+    address: vk::DeviceAddress,
+    _marker: PhantomData<T>
+}
+
+impl<T> BufferDeviceAddress<T> {
+    #[inline]
+    pub fn address(&self) -> vk::DeviceAddress {
+        self.address
+    }
+}
+
+impl<T> Clone for BufferDeviceAddress<T> {
+    #[inline]
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for BufferDeviceAddress<T> {}
+
+//Keeps the staging buffer and command buffer of Buffer::upload_async alive until the transfer queue is done with them
+pub struct BufferUpload {
+    fence: Fence,
+    semaphore: Semaphore,
+
+    command_buffer: vk::CommandBuffer,
+    _staging_buffer: Buffer,
+
+    device: Arc<Device>
+}
+
+impl BufferUpload {
+    #[inline]
+    pub fn wait(&self, timeout: u64) -> VkResult<()> {
+        self.fence.wait(timeout)
+    }
+
+    #[inline]
+    pub fn is_complete(&self) -> VkResult<bool> {
+        self.fence.is_signaled()
+    }
+
+    #[inline]
+    pub fn fence(&self) -> &Fence {
+        &self.fence
+    }
+
+    #[inline]
+    pub fn semaphore(&self) -> &Semaphore {
+        &self.semaphore
+    }
+}
+
+impl Drop for BufferUpload {
+    #[inline]
+    fn drop(&mut self) {
+        if let Err(e) = self.fence.wait(u64::MAX) {
+            error!("Failed to wait for buffer upload: {:?}", e);
+        }
+
+        if let Ok(command_pool) = self.device.command_pool(self.device.transfer_queue().family_index()) {
+            command_pool.free(slice::from_ref(&self.command_buffer));
         }
     }
 }
 
 pub struct Buffer {
+    desc: BufferDesc,
+
     buffer: vk::Buffer,
     allocation: Allocation,
     allocation_info: AllocationInfo,
     device_address: vk::DeviceAddress,
 
+    device_generation: DeviceGeneration,
     device: Arc<Device>
 }
 
 impl Buffer {
     pub fn new(device: Arc<Device>, desc: &BufferDesc) -> VkResult<Self> {
-        let buffer_create_info = vk::BufferCreateInfo::default().size(desc.size).usage(buffer_desc.usage);
+        let buffer_create_info = vk::BufferCreateInfo::default().size(desc.size).usage(desc.usage);
 
         let allocation_create_info = AllocationCreateInfo::new().usage(desc.memory_usage);
 
         let (buffer, allocation, allocation_info) = unsafe { device.allocator().create_buffer(&buffer_create_info, &allocation_create_info)? };
 
-        let device_address = if (desc.usage & vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS) == vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS {
+        let device_address = if device.supports_buffer_device_address()
+            && (desc.usage & vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS) == vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS
+        {
             unsafe { device.loader().get_buffer_device_address(&vk::BufferDeviceAddressInfo::default().buffer(buffer)) }
         } else {
             0
         };
 
-        Ok(Self {
+        let buffer = Self {
+            desc: desc.clone(),
+
             buffer,
             allocation,
             allocation_info,
             device_address,
+
+            device_generation: device.generation(),
             device
+        };
+
+        if let Some(name) = &desc.name {
+            buffer.device.set_debug_name(buffer.buffer, name)?;
+            //Makes allocator statistics dumps readable
+            unsafe { buffer.device.allocator().set_allocation_name(&buffer.allocation, name) };
+        }
+
+        Ok(buffer)
+    }
+
+    pub fn new_staged<T: Copy>(device: Arc<Device>, usage: vk::BufferUsageFlags, data: &[T]) -> Result<Self> {
+        let size = mem::size_of_val(data) as vk::DeviceSize;
+        ensure!(size > 0, "Staged buffers can't be empty");
+
+        let buffer = Self::new(device.clone(), &BufferDesc::new_gpu_only(size, usage | vk::BufferUsageFlags::TRANSFER_DST))?;
+
+        let upload = buffer.upload_async(data)?;
+        upload.wait(u64::MAX)?;
+
+        if device.transfer_queue().family_index() != device.direct_queue().family_index() {
+            device.submit_immediate(device.direct_queue(), |command_buffer| {
+                buffer.cmd_acquire_from_transfer_queue(command_buffer, vk::PipelineStageFlags::ALL_COMMANDS, vk::AccessFlags::MEMORY_READ);
+            })?;
+        }
+
+        Ok(buffer)
+    }
+
+    //Copies data on the transfer queue and releases ownership to the direct queue family.
+    //Submissions on the direct queue must wait for the returned semaphore and call cmd_acquire_from_transfer_queue before first use
+    pub fn upload_async<T: Copy>(&self, data: &[T]) -> Result<BufferUpload> {
+        let size = mem::size_of_val(data) as vk::DeviceSize;
+        ensure!(size > 0, "Uploads can't be empty");
+        ensure!(size <= self.desc.size, "Upload of {} bytes exceeds the buffer size of {} bytes", size, self.desc.size);
+        ensure!(
+            (self.desc.usage & vk::BufferUsageFlags::TRANSFER_DST) == vk::BufferUsageFlags::TRANSFER_DST,
+            "Uploading to a buffer requires TRANSFER_DST usage"
+        );
+
+        let device = &self.device;
+        let transfer_queue = device.transfer_queue();
+
+        let staging_buffer = Self::new(device.clone(), &BufferDesc::new_cpu_only(size, vk::BufferUsageFlags::TRANSFER_SRC))?;
+        staging_buffer.write_slice(0, data)?;
+
+        //The shared pool stays locked until the command buffer is recorded
+        let command_pool = device.command_pool(transfer_queue.family_index())?;
+        let command_buffer = command_pool.allocate(1, vk::CommandBufferLevel::PRIMARY)?[0];
+
+        if let Err(e) = self.record_upload(command_buffer, &staging_buffer, size) {
+            command_pool.free(slice::from_ref(&command_buffer));
+            return Err(e.into())
+        }
+
+        drop(command_pool);
+
+        let result = (|| -> VkResult<_> {
+            let fence = Fence::new(device.clone(), false)?;
+            let semaphore = Semaphore::new(device.clone())?;
+
+            transfer_queue.submit(device, slice::from_ref(&command_buffer), &[], slice::from_ref(semaphore.semaphore()), Some(&fence))?;
+
+            Ok((fence, semaphore))
+        })();
+
+        let (fence, semaphore) = match result {
+            Ok(result) => result,
+            Err(e) => {
+                device.command_pool(transfer_queue.family_index())?.free(slice::from_ref(&command_buffer));
+                return Err(e.into())
+            }
+        };
+
+        Ok(BufferUpload {
+            fence,
+            semaphore,
+
+            command_buffer,
+            _staging_buffer: staging_buffer,
+
+            device: device.clone()
         })
     }
 
+    fn record_upload(&self, command_buffer: vk::CommandBuffer, staging_buffer: &Buffer, size: vk::DeviceSize) -> VkResult<()> {
+        let device = &self.device;
+        let transfer_queue = device.transfer_queue();
+        let direct_family_index = device.direct_queue().family_index();
+
+        unsafe {
+            device.loader().begin_command_buffer(
+                command_buffer,
+                &vk::CommandBufferBeginInfo::default().flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT)
+            )?;
+
+            let buffer_copy = vk::BufferCopy::default().size(size);
+            device
+                .loader()
+                .cmd_copy_buffer(command_buffer, staging_buffer.buffer, self.buffer, slice::from_ref(&buffer_copy));
+
+            if transfer_queue.family_index() != direct_family_index {
+                let release_barrier = vk::BufferMemoryBarrier::default()
+                    .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                    .src_queue_family_index(transfer_queue.family_index())
+                    .dst_queue_family_index(direct_family_index)
+                    .buffer(self.buffer)
+                    .size(vk::WHOLE_SIZE);
+
+                device.loader().cmd_pipeline_barrier(
+                    command_buffer,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    slice::from_ref(&release_barrier),
+                    &[]
+                );
+            }
+
+            device.loader().end_command_buffer(command_buffer)
+        }
+    }
+
+    //Recorded into ctx, the data is only written once ctx is flushed and the returned batch completes
+    pub fn queue_upload<T: Copy>(&self, ctx: &mut TransferContext, data: &[T]) -> Result<()> {
+        let size = mem::size_of_val(data) as vk::DeviceSize;
+        ensure!(size <= self.desc.size, "Upload of {} bytes exceeds the buffer size of {} bytes", size, self.desc.size);
+        ensure!(
+            (self.desc.usage & vk::BufferUsageFlags::TRANSFER_DST) == vk::BufferUsageFlags::TRANSFER_DST,
+            "Uploading to a buffer requires TRANSFER_DST usage"
+        );
+
+        let (staging_buffer, command_buffer) = ctx.stage(data)?;
+
+        unsafe {
+            let buffer_copy = vk::BufferCopy::default().size(size);
+            self.device
+                .loader()
+                .cmd_copy_buffer(command_buffer, staging_buffer, self.buffer, slice::from_ref(&buffer_copy));
+        }
+
+        Ok(())
+    }
+
+    //Acquire half of the ownership transfer recorded by upload_async, a no-op when transfer and direct queue share a family
+    pub fn cmd_acquire_from_transfer_queue(&self, command_buffer: vk::CommandBuffer, dst_stage_mask: vk::PipelineStageFlags, dst_access_mask: vk::AccessFlags) {
+        let transfer_family_index = self.device.transfer_queue().family_index();
+        let direct_family_index = self.device.direct_queue().family_index();
+
+        if transfer_family_index == direct_family_index {
+            return
+        }
+
+        let acquire_barrier = vk::BufferMemoryBarrier::default()
+            .dst_access_mask(dst_access_mask)
+            .src_queue_family_index(transfer_family_index)
+            .dst_queue_family_index(direct_family_index)
+            .buffer(self.buffer)
+            .size(vk::WHOLE_SIZE);
+
+        unsafe {
+            self.device.loader().cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                dst_stage_mask,
+                vk::DependencyFlags::empty(),
+                &[],
+                slice::from_ref(&acquire_barrier),
+                &[]
+            );
+        }
+    }
+
+    #[inline]
+    pub(crate) fn is_host_visible(&self) -> bool {
+        self.desc.memory_usage != MemoryUsage::GpuOnly && self.desc.memory_usage != MemoryUsage::GpuLazy
+    }
+
+    #[inline]
+    fn is_host_coherent(&self) -> bool {
+        let memory_type = &self.device.memory_properties().memory_properties.memory_types[self.allocation_info.get_memory_type() as usize];
+
+        (memory_type.property_flags & vk::MemoryPropertyFlags::HOST_COHERENT) == vk::MemoryPropertyFlags::HOST_COHERENT
+    }
+
+    pub fn map(&self) -> VkResult<*mut u8> {
+        if !self.is_host_visible() {
+            return Err(vk::Result::ERROR_MEMORY_MAP_FAILED)
+        }
+
+        unsafe { self.device.allocator().map_memory(&self.allocation) }
+    }
+
+    #[inline]
+    pub fn unmap(&self) {
+        unsafe { self.device.allocator().unmap_memory(&self.allocation) }
+    }
+
+    pub fn write_slice<T: Copy>(&self, offset: vk::DeviceSize, data: &[T]) -> VkResult<()> {
+        let size = mem::size_of_val(data);
+        assert!(offset.checked_add(size as vk::DeviceSize).map_or(false, |end| end <= self.desc.size));
+
+        let mapped = self.map()?;
+
+        unsafe {
+            ptr::copy_nonoverlapping(data.as_ptr().cast::<u8>(), mapped.add(offset as usize), size);
+        }
+
+        let result = if self.is_host_coherent() {
+            Ok(())
+        } else {
+            unsafe { self.device.allocator().flush_allocation(&self.allocation, offset as usize, size) }
+        };
+
+        self.unmap();
+
+        result
+    }
+
+    pub fn read_bytes(&self) -> VkResult<Vec<u8>> {
+        let mapped = self.map()?;
+
+        let result = if self.is_host_coherent() {
+            Ok(())
+        } else {
+            unsafe { self.device.allocator().invalidate_allocation(&self.allocation, 0, self.desc.size as usize) }
+        };
+
+        let bytes = result.map(|_| unsafe { slice::from_raw_parts(mapped, self.desc.size as usize) }.to_vec());
+
+        self.unmap();
+
+        bytes
+    }
+
+    pub fn resize(&mut self, new_size: vk::DeviceSize) -> Result<()> {
+        if new_size == self.desc.size {
+            return Ok(())
+        }
+
+        self.reallocate(new_size)
+    }
+
+    //Moves the contents into a new buffer and allocation, the old vk::Buffer and device address become invalid
+    fn reallocate(&mut self, new_size: vk::DeviceSize) -> Result<()> {
+        let copy_size = self.desc.size.min(new_size);
+        let mut buffer = Self::new(self.device.clone(), &BufferDesc { size: new_size, ..self.desc.clone() })?;
+
+        if copy_size > 0 {
+            if self.is_host_visible() {
+                let mapped = self.map()?;
+                let result = buffer.write_slice(0, unsafe { slice::from_raw_parts(mapped, copy_size as usize) });
+                self.unmap();
+
+                result?;
+            } else {
+                let transfer_usage = vk::BufferUsageFlags::TRANSFER_SRC | vk::BufferUsageFlags::TRANSFER_DST;
+                ensure!(
+                    (self.desc.usage & transfer_usage) == transfer_usage,
+                    "Reallocating a GPU only buffer requires TRANSFER_SRC and TRANSFER_DST usage"
+                );
+
+                self.device.submit_immediate(self.device.direct_queue(), |command_buffer| unsafe {
+                    let buffer_copy = vk::BufferCopy::default().size(copy_size);
+                    self.device
+                        .loader()
+                        .cmd_copy_buffer(command_buffer, self.buffer, buffer.buffer, slice::from_ref(&buffer_copy));
+                })?;
+            }
+        }
+
+        //The old buffer is freed when the swapped out value is dropped
+        mem::swap(self, &mut buffer);
+
+        Ok(())
+    }
+
+    //Device::defragment copies host visible buffers on the CPU, others need transfer usage for a GPU copy
+    #[inline]
+    pub(crate) fn is_movable(&self) -> bool {
+        let transfer_usage = vk::BufferUsageFlags::TRANSFER_SRC | vk::BufferUsageFlags::TRANSFER_DST;
+
+        self.is_host_visible() || (self.desc.usage & transfer_usage) == transfer_usage
+    }
+
+    //Allocation only wraps the VmaAllocation handle that defragmentation moves refer to
+    #[inline]
+    pub(crate) fn raw_allocation(&self) -> ffi::VmaAllocation {
+        unsafe { *(&self.allocation as *const Allocation).cast::<ffi::VmaAllocation>() }
+    }
+
+    //Creates a buffer like this one bound to the temporary allocation of a defragmentation move, host visible contents are copied right away
+    pub(crate) unsafe fn create_moved(&self, dst_allocation: ffi::VmaAllocation) -> Result<vk::Buffer> {
+        let dst_allocation = *(&dst_allocation as *const ffi::VmaAllocation).cast::<Allocation>();
+        let allocator = self.device.allocator();
+
+        let buffer_create_info = vk::BufferCreateInfo::default().size(self.desc.size).usage(self.desc.usage);
+        let buffer = self.device.loader().create_buffer(&buffer_create_info, None)?;
+
+        let result = allocator.bind_buffer_memory(&dst_allocation, buffer).map_err(anyhow::Error::from).and_then(|_| {
+            if !self.is_host_visible() {
+                return Ok(())
+            }
+
+            let bytes = self.read_bytes()?;
+
+            let mapped = allocator.map_memory(&dst_allocation)?;
+            ptr::copy_nonoverlapping(bytes.as_ptr(), mapped, bytes.len());
+            //Moves stay within the memory type
+            let result = if self.is_host_coherent() {
+                Ok(())
+            } else {
+                allocator.flush_allocation(&dst_allocation, 0, bytes.len())
+            };
+            allocator.unmap_memory(&dst_allocation);
+
+            Ok(result?)
+        });
+
+        if let Err(e) = result {
+            self.device.loader().destroy_buffer(buffer, None);
+            return Err(e)
+        }
+
+        Ok(buffer)
+    }
+
+    //Called once the defragmentation pass ended, the allocation then refers to the memory buffer is bound to
+    pub(crate) unsafe fn finish_move(&mut self, buffer: vk::Buffer) -> VkResult<()> {
+        self.device.loader().destroy_buffer(self.buffer, None);
+        self.buffer = buffer;
+
+        self.allocation_info = self.device.allocator().get_allocation_info(&self.allocation)?;
+        if self.device_address != 0 {
+            self.device_address = self.device.loader().get_buffer_device_address(&vk::BufferDeviceAddressInfo::default().buffer(buffer));
+        }
+
+        if let Some(name) = &self.desc.name {
+            self.device.set_debug_name(buffer, name)?;
+        }
+
+        Ok(())
+    }
+
+    #[inline]
+    pub fn desc(&self) -> &BufferDesc {
+        &self.desc
+    }
+
     #[inline]
     pub fn buffer(&self) -> &vk::Buffer {
         &self.buffer
@@ -110,13 +548,147 @@ impl Buffer {
     pub fn device_address(&self) -> &vk::DeviceAddress {
         &self.device_address
     }
+
+    #[inline]
+    pub fn device_address_typed<T>(&self) -> Option<BufferDeviceAddress<T>> {
+        if self.device_address == 0 {
+            return None
+        }
+
+        Some(BufferDeviceAddress {
+            address: self.device_address,
+            _marker: PhantomData
+        })
+    }
 }
 
 impl Drop for Buffer {
     #[inline]
     fn drop(&mut self) {
+        self.device.assert_generation(self.device_generation);
+
         unsafe {
             self.device.allocator().destroy_buffer(self.buffer, self.allocation)
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{self, AssertUnwindSafe};
+
+    use super::*;
+    use crate::renderer::test_device;
+
+    fn read_back(device: &Arc<Device>, buffer: &Buffer) -> Vec<u8> {
+        let read_back_buffer = Buffer::new(device.clone(), &BufferDesc::new_gpu_to_cpu(buffer.desc().size, vk::BufferUsageFlags::TRANSFER_DST)).unwrap();
+
+        device
+            .submit_immediate(device.direct_queue(), |command_buffer| unsafe {
+                let buffer_copy = vk::BufferCopy::default().size(buffer.desc().size);
+                device
+                    .loader()
+                    .cmd_copy_buffer(command_buffer, buffer.buffer, read_back_buffer.buffer, slice::from_ref(&buffer_copy));
+            })
+            .unwrap();
+
+        read_back_buffer.read_bytes().unwrap()
+    }
+
+    #[test]
+    fn write_slice_round_trip() {
+        let device = match test_device() {
+            Some(device) => device,
+            None => return
+        };
+
+        let data = [1.0f32, 2.0, 3.0, 4.0];
+        let buffer = Buffer::new(device.clone(), &BufferDesc::new_cpu_to_gpu(mem::size_of_val(&data) as _, vk::BufferUsageFlags::TRANSFER_SRC)).unwrap();
+        buffer.write_slice(0, &data).unwrap();
+
+        assert_eq!(read_back(&device, &buffer), bytemuck::cast_slice::<f32, u8>(&data));
+    }
+
+    #[test]
+    fn names_can_be_built_at_runtime() {
+        let device = match test_device() {
+            Some(device) => device,
+            None => return
+        };
+
+        let index = 3;
+        let desc = BufferDesc::new_cpu_only(16, vk::BufferUsageFlags::TRANSFER_SRC).with_name(format!("Instance buffer {}", index));
+        let buffer = Buffer::new(device, &desc).unwrap();
+
+        assert_eq!(buffer.desc().name.as_deref(), Some("Instance buffer 3"));
+    }
+
+    #[test]
+    fn write_slice_rejects_gpu_only() {
+        let device = match test_device() {
+            Some(device) => device,
+            None => return
+        };
+
+        let buffer = Buffer::new(device, &BufferDesc::new_gpu_only(16, vk::BufferUsageFlags::TRANSFER_SRC)).unwrap();
+
+        assert!(buffer.write_slice(0, &[0u32; 4]).is_err());
+    }
+
+    #[test]
+    fn write_slice_rejects_overflowing_offset() {
+        let device = match test_device() {
+            Some(device) => device,
+            None => return
+        };
+
+        let buffer = Buffer::new(device, &BufferDesc::new_cpu_to_gpu(16, vk::BufferUsageFlags::TRANSFER_SRC)).unwrap();
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| buffer.write_slice(vk::DeviceSize::MAX - 1, &[0u32; 4])));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn new_staged_round_trip() {
+        let device = match test_device() {
+            Some(device) => device,
+            None => return
+        };
+
+        let data = [7u32, 11, 13, 17, 19];
+        let buffer = Buffer::new_staged(device.clone(), vk::BufferUsageFlags::TRANSFER_SRC, &data).unwrap();
+
+        assert_eq!(buffer.desc().size, mem::size_of_val(&data) as vk::DeviceSize);
+        assert_eq!(read_back(&device, &buffer), bytemuck::cast_slice::<u32, u8>(&data));
+    }
+
+    #[test]
+    fn empty_uploads_are_rejected() {
+        let device = match test_device() {
+            Some(device) => device,
+            None => return
+        };
+
+        assert!(Buffer::new_staged::<u32>(device.clone(), vk::BufferUsageFlags::TRANSFER_SRC, &[]).is_err());
+
+        let buffer = Buffer::new(device, &BufferDesc::new_gpu_only(16, vk::BufferUsageFlags::TRANSFER_DST)).unwrap();
+        assert!(buffer.upload_async::<u32>(&[]).is_err());
+    }
+
+    #[test]
+    fn resize_preserves_prefix() {
+        let device = match test_device() {
+            Some(device) => device,
+            None => return
+        };
+
+        let data = [1u8, 2, 3, 4, 5, 6, 7, 8];
+        let mut buffer = Buffer::new(device, &BufferDesc::new_gpu_to_cpu(data.len() as _, vk::BufferUsageFlags::TRANSFER_SRC)).unwrap();
+        buffer.write_slice(0, &data).unwrap();
+
+        buffer.resize(64).unwrap();
+
+        assert_eq!(buffer.desc().size, 64);
+        assert_eq!(&buffer.read_bytes().unwrap()[..data.len()], &data);
+    }
+}
@@ -0,0 +1,483 @@
+use std::{borrow::Cow, slice, sync::Arc};
+
+use anyhow::{bail, ensure, Result};
+use ash::{prelude::VkResult, vk};
+use vk_mem::{Allocation, AllocationCreateInfo, AllocationInfo, MemoryUsage};
+
+use crate::backend::{
+    resource::{Buffer, BufferDesc, TransferContext},
+    BarrierBuilder, Device, DeviceGeneration
+};
+
+#[inline]
+pub fn aspect_mask_from_format(format: vk::Format) -> vk::ImageAspectFlags {
+    match format {
+        vk::Format::D16_UNORM | vk::Format::X8_D24_UNORM_PACK32 | vk::Format::D32_SFLOAT => vk::ImageAspectFlags::DEPTH,
+        vk::Format::S8_UINT => vk::ImageAspectFlags::STENCIL,
+        vk::Format::D16_UNORM_S8_UINT | vk::Format::D24_UNORM_S8_UINT | vk::Format::D32_SFLOAT_S8_UINT => vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL,
+        _ => vk::ImageAspectFlags::COLOR
+    }
+}
+
+//A buffer to image copy addresses a single aspect, so combined depth stencil formats would need one region per aspect with separately packed data
+fn upload_aspect_mask(format: vk::Format) -> Result<vk::ImageAspectFlags> {
+    let aspect_mask = aspect_mask_from_format(format);
+    ensure!(
+        aspect_mask != vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL,
+        "Uploading to an image of combined depth stencil format {:?} is not supported",
+        format
+    );
+
+    Ok(aspect_mask)
+}
+
+//Bytes per texel of uncompressed formats, None for block compressed and uncommon formats
+pub fn format_texel_size(format: vk::Format) -> Option<usize> {
+    match format {
+        vk::Format::R8_UNORM | vk::Format::R8_SNORM | vk::Format::R8_UINT | vk::Format::R8_SINT | vk::Format::R8_SRGB | vk::Format::S8_UINT => Some(1),
+        vk::Format::R8G8_UNORM | vk::Format::R8G8_SNORM | vk::Format::R8G8_UINT | vk::Format::R8G8_SINT | vk::Format::R8G8_SRGB => Some(2),
+        vk::Format::R16_SFLOAT | vk::Format::R16_UNORM | vk::Format::R16_UINT | vk::Format::R16_SINT | vk::Format::D16_UNORM => Some(2),
+        vk::Format::R8G8B8A8_UNORM
+        | vk::Format::R8G8B8A8_SNORM
+        | vk::Format::R8G8B8A8_UINT
+        | vk::Format::R8G8B8A8_SINT
+        | vk::Format::R8G8B8A8_SRGB
+        | vk::Format::B8G8R8A8_UNORM
+        | vk::Format::B8G8R8A8_SRGB
+        | vk::Format::A2B10G10R10_UNORM_PACK32
+        | vk::Format::A2R10G10B10_UNORM_PACK32
+        | vk::Format::B10G11R11_UFLOAT_PACK32
+        | vk::Format::E5B9G9R9_UFLOAT_PACK32 => Some(4),
+        vk::Format::R16G16_SFLOAT | vk::Format::R16G16_UNORM | vk::Format::R16G16_UINT | vk::Format::R16G16_SINT => Some(4),
+        vk::Format::R32_SFLOAT | vk::Format::R32_UINT | vk::Format::R32_SINT | vk::Format::D32_SFLOAT | vk::Format::X8_D24_UNORM_PACK32 => Some(4),
+        vk::Format::R16G16B16A16_SFLOAT | vk::Format::R16G16B16A16_UNORM | vk::Format::R16G16B16A16_UINT | vk::Format::R16G16B16A16_SINT => Some(8),
+        vk::Format::R32G32_SFLOAT | vk::Format::R32G32_UINT | vk::Format::R32G32_SINT => Some(8),
+        vk::Format::R32G32B32A32_SFLOAT | vk::Format::R32G32B32A32_UINT | vk::Format::R32G32B32A32_SINT => Some(16),
+        _ => None
+    }
+}
+
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct ImageDesc {
+    pub extent: vk::Extent3D,
+    pub format: vk::Format,
+    pub usage: vk::ImageUsageFlags,
+    pub mip_levels: u32,
+    pub memory_usage: MemoryUsage,
+    pub name: Option<Cow<'static, str>>
+}
+
+impl ImageDesc {
+    #[inline]
+    pub fn new_2d(extent: vk::Extent2D, format: vk::Format, usage: vk::ImageUsageFlags) -> Self {
+        Self {
+            extent: vk::Extent3D {
+                width: extent.width,
+                height: extent.height,
+                depth: 1
+            },
+            format,
+            usage,
+            mip_levels: 1,
+            memory_usage: MemoryUsage::GpuOnly,
+            name: None
+        }
+    }
+
+    #[inline]
+    pub fn new_2d_sampled(extent: vk::Extent2D, format: vk::Format, mip_levels: u32) -> Self {
+        Self {
+            mip_levels,
+            ..Self::new_2d(extent, format, vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST)
+        }
+    }
+
+    #[inline]
+    pub fn with_name(mut self, name: impl Into<Cow<'static, str>>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+}
+
+pub struct Image {
+    desc: ImageDesc,
+
+    image: vk::Image,
+    view: vk::ImageView,
+    allocation: Allocation,
+    allocation_info: AllocationInfo,
+
+    device_generation: DeviceGeneration,
+    device: Arc<Device>
+}
+
+impl Image {
+    pub fn new(device: Arc<Device>, desc: &ImageDesc) -> VkResult<Self> {
+        let image_type = if desc.extent.depth > 1 { vk::ImageType::TYPE_3D } else { vk::ImageType::TYPE_2D };
+
+        let image_create_info = vk::ImageCreateInfo::default()
+            .image_type(image_type)
+            .format(desc.format)
+            .extent(desc.extent)
+            .mip_levels(desc.mip_levels)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(desc.usage)
+            .initial_layout(vk::ImageLayout::UNDEFINED);
+
+        let allocation_create_info = AllocationCreateInfo::new().usage(desc.memory_usage);
+
+        let (image, allocation, allocation_info) = unsafe { device.allocator().create_image(&image_create_info, &allocation_create_info)? };
+
+        let view_type = if desc.extent.depth > 1 { vk::ImageViewType::TYPE_3D } else { vk::ImageViewType::TYPE_2D };
+
+        let image_view_create_info = vk::ImageViewCreateInfo::default()
+            .image(image)
+            .view_type(view_type)
+            .format(desc.format)
+            .subresource_range(
+                vk::ImageSubresourceRange::default()
+                    .aspect_mask(aspect_mask_from_format(desc.format))
+                    .level_count(desc.mip_levels)
+                    .layer_count(1)
+            );
+
+        let view = match unsafe { device.loader().create_image_view(&image_view_create_info, None) } {
+            Ok(view) => view,
+            Err(e) => {
+                unsafe { device.allocator().destroy_image(image, allocation) };
+                return Err(e)
+            }
+        };
+
+        let image = Self {
+            desc: desc.clone(),
+
+            image,
+            view,
+            allocation,
+            allocation_info,
+
+            device_generation: device.generation(),
+            device
+        };
+
+        if let Some(name) = &desc.name {
+            image.device.set_debug_name(image.image, name)?;
+            image.device.set_debug_name(image.view, &format!("{} view", name))?;
+            unsafe { image.device.allocator().set_allocation_name(&image.allocation, name) };
+        }
+
+        Ok(image)
+    }
+
+    //Uploads tightly packed sRGB RGBA8 pixels, the image is left in SHADER_READ_ONLY_OPTIMAL
+    pub fn new_from_rgba8(device: Arc<Device>, width: u32, height: u32, pixels: &[u8], generate_mips: bool) -> Result<Self> {
+        const FORMAT: vk::Format = vk::Format::R8G8B8A8_SRGB;
+
+        ensure!(width > 0 && height > 0, "Image extent must not be zero");
+        ensure!(
+            pixels.len() == width as usize * height as usize * 4,
+            "Expected {} bytes of RGBA8 pixels, got {}",
+            width as usize * height as usize * 4,
+            pixels.len()
+        );
+
+        let mip_levels = if generate_mips { 32 - width.max(height).leading_zeros() } else { 1 };
+        if mip_levels > 1 {
+            ensure!(
+                device.supports_format_features(FORMAT, vk::ImageTiling::OPTIMAL, vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR),
+                "Generating mips requires linear filtering support for {:?}",
+                FORMAT
+            );
+        }
+
+        let mut desc = ImageDesc::new_2d_sampled(vk::Extent2D { width, height }, FORMAT, mip_levels);
+        if mip_levels > 1 {
+            desc.usage |= vk::ImageUsageFlags::TRANSFER_SRC;
+        }
+
+        let image = Self::new(device.clone(), &desc)?;
+
+        let staging_buffer = Buffer::new(device.clone(), &BufferDesc::new_cpu_only(pixels.len() as _, vk::BufferUsageFlags::TRANSFER_SRC))?;
+        staging_buffer.write_slice(0, pixels)?;
+
+        let device_loader = device.loader();
+
+        let barrier = |command_buffer: vk::CommandBuffer,
+                       base_mip_level: u32,
+                       level_count: u32,
+                       (old_layout, src_access_mask, src_stage_mask): (vk::ImageLayout, vk::AccessFlags, vk::PipelineStageFlags),
+                       (new_layout, dst_access_mask, dst_stage_mask): (vk::ImageLayout, vk::AccessFlags, vk::PipelineStageFlags)| {
+            let image_memory_barrier = vk::ImageMemoryBarrier::default()
+                .src_access_mask(src_access_mask)
+                .dst_access_mask(dst_access_mask)
+                .old_layout(old_layout)
+                .new_layout(new_layout)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(image.image)
+                .subresource_range(
+                    vk::ImageSubresourceRange::default()
+                        .aspect_mask(vk::ImageAspectFlags::COLOR)
+                        .base_mip_level(base_mip_level)
+                        .level_count(level_count)
+                        .layer_count(1)
+                );
+
+            unsafe {
+                device_loader.cmd_pipeline_barrier(
+                    command_buffer,
+                    src_stage_mask,
+                    dst_stage_mask,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &[],
+                    slice::from_ref(&image_memory_barrier)
+                )
+            };
+        };
+
+        let undefined = (vk::ImageLayout::UNDEFINED, vk::AccessFlags::empty(), vk::PipelineStageFlags::TOP_OF_PIPE);
+        let transfer_dst = (vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::AccessFlags::TRANSFER_WRITE, vk::PipelineStageFlags::TRANSFER);
+        let transfer_src = (vk::ImageLayout::TRANSFER_SRC_OPTIMAL, vk::AccessFlags::TRANSFER_READ, vk::PipelineStageFlags::TRANSFER);
+        let shader_read = (vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, vk::AccessFlags::SHADER_READ, vk::PipelineStageFlags::FRAGMENT_SHADER);
+
+        device.submit_immediate(device.direct_queue(), |command_buffer| unsafe {
+            barrier(command_buffer, 0, mip_levels, undefined, transfer_dst);
+
+            let buffer_image_copy = vk::BufferImageCopy::default()
+                .image_subresource(vk::ImageSubresourceLayers::default().aspect_mask(vk::ImageAspectFlags::COLOR).layer_count(1))
+                .image_extent(desc.extent);
+            device_loader.cmd_copy_buffer_to_image(
+                command_buffer,
+                *staging_buffer.buffer(),
+                image.image,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                slice::from_ref(&buffer_image_copy)
+            );
+
+            let mip_extent = |mip_level: u32| vk::Offset3D {
+                x: (width >> mip_level).max(1) as i32,
+                y: (height >> mip_level).max(1) as i32,
+                z: 1
+            };
+
+            for mip_level in 1..mip_levels {
+                barrier(command_buffer, mip_level - 1, 1, transfer_dst, transfer_src);
+
+                let image_blit = vk::ImageBlit::default()
+                    .src_subresource(
+                        vk::ImageSubresourceLayers::default()
+                            .aspect_mask(vk::ImageAspectFlags::COLOR)
+                            .mip_level(mip_level - 1)
+                            .layer_count(1)
+                    )
+                    .src_offsets([vk::Offset3D::default(), mip_extent(mip_level - 1)])
+                    .dst_subresource(vk::ImageSubresourceLayers::default().aspect_mask(vk::ImageAspectFlags::COLOR).mip_level(mip_level).layer_count(1))
+                    .dst_offsets([vk::Offset3D::default(), mip_extent(mip_level)]);
+
+                device_loader.cmd_blit_image(
+                    command_buffer,
+                    image.image,
+                    vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    image.image,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    slice::from_ref(&image_blit),
+                    vk::Filter::LINEAR
+                );
+            }
+
+            //Every level but the last was a blit source
+            if mip_levels > 1 {
+                barrier(command_buffer, 0, mip_levels - 1, transfer_src, shader_read);
+            }
+            barrier(command_buffer, mip_levels - 1, 1, transfer_dst, shader_read);
+        })?;
+
+        Ok(image)
+    }
+
+    //Uploads tightly packed pixels to the first mip level, which is left in SHADER_READ_ONLY_OPTIMAL once ctx is flushed.
+    //Other mip levels are left untouched, new_from_rgba8 generates them
+    pub fn queue_upload(&self, ctx: &mut TransferContext, pixels: &[u8]) -> Result<()> {
+        let aspect_mask = upload_aspect_mask(self.desc.format)?;
+        let texel_size = match format_texel_size(self.desc.format) {
+            Some(texel_size) => texel_size,
+            None => bail!("Uploading to an image of format {:?} is not supported", self.desc.format)
+        };
+        let texel_count = self.desc.extent.width as usize * self.desc.extent.height as usize * self.desc.extent.depth as usize;
+        ensure!(
+            pixels.len() == texel_count * texel_size,
+            "{} bytes of pixels don't match the image extent of {:?} with {} bytes per texel",
+            pixels.len(),
+            self.desc.extent,
+            texel_size
+        );
+        ensure!(
+            (self.desc.usage & vk::ImageUsageFlags::TRANSFER_DST) == vk::ImageUsageFlags::TRANSFER_DST,
+            "Uploading to an image requires TRANSFER_DST usage"
+        );
+
+        let (staging_buffer, command_buffer) = ctx.stage(pixels)?;
+
+        let subresource_range = vk::ImageSubresourceRange::default().aspect_mask(aspect_mask).level_count(1).layer_count(1);
+
+        let to_transfer_dst = vk::ImageMemoryBarrier2::default()
+            .src_stage_mask(vk::PipelineStageFlags2::NONE)
+            .dst_stage_mask(vk::PipelineStageFlags2::COPY)
+            .dst_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
+            .old_layout(vk::ImageLayout::UNDEFINED)
+            .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(self.image)
+            .subresource_range(subresource_range);
+
+        let to_shader_read = to_transfer_dst
+            .src_stage_mask(vk::PipelineStageFlags2::COPY)
+            .src_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
+            .dst_stage_mask(vk::PipelineStageFlags2::FRAGMENT_SHADER | vk::PipelineStageFlags2::COMPUTE_SHADER)
+            .dst_access_mask(vk::AccessFlags2::SHADER_SAMPLED_READ)
+            .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+            .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL);
+
+        let buffer_image_copy = vk::BufferImageCopy::default()
+            .image_subresource(vk::ImageSubresourceLayers::default().aspect_mask(aspect_mask).layer_count(1))
+            .image_extent(self.desc.extent);
+
+        self.device.cmd_pipeline_barrier2(command_buffer, &BarrierBuilder::new().image(to_transfer_dst));
+        unsafe {
+            self.device.loader().cmd_copy_buffer_to_image(
+                command_buffer,
+                staging_buffer,
+                self.image,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                slice::from_ref(&buffer_image_copy)
+            );
+        }
+        self.device.cmd_pipeline_barrier2(command_buffer, &BarrierBuilder::new().image(to_shader_read));
+
+        Ok(())
+    }
+
+    #[inline]
+    pub fn desc(&self) -> &ImageDesc {
+        &self.desc
+    }
+
+    #[inline]
+    pub fn image(&self) -> &vk::Image {
+        &self.image
+    }
+
+    #[inline]
+    pub fn view(&self) -> &vk::ImageView {
+        &self.view
+    }
+
+    #[inline]
+    pub fn format(&self) -> vk::Format {
+        self.desc.format
+    }
+
+    #[inline]
+    pub fn extent(&self) -> vk::Extent3D {
+        self.desc.extent
+    }
+
+    #[inline]
+    pub fn allocation(&self) -> &Allocation {
+        &self.allocation
+    }
+
+    #[inline]
+    pub fn allocation_info(&self) -> &AllocationInfo {
+        &self.allocation_info
+    }
+}
+
+impl Drop for Image {
+    #[inline]
+    fn drop(&mut self) {
+        self.device.assert_generation(self.device_generation);
+
+        unsafe {
+            self.device.loader().destroy_image_view(self.view, None);
+            self.device.allocator().destroy_image(self.image, self.allocation)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::test_device;
+
+    #[test]
+    fn uploads_address_a_single_aspect() {
+        assert_eq!(upload_aspect_mask(vk::Format::R8G8B8A8_UNORM).unwrap(), vk::ImageAspectFlags::COLOR);
+        assert_eq!(upload_aspect_mask(vk::Format::D32_SFLOAT).unwrap(), vk::ImageAspectFlags::DEPTH);
+        assert_eq!(upload_aspect_mask(vk::Format::S8_UINT).unwrap(), vk::ImageAspectFlags::STENCIL);
+        assert!(upload_aspect_mask(vk::Format::D24_UNORM_S8_UINT).is_err());
+    }
+
+    #[test]
+    fn upload_4x4_with_mips() {
+        let device = match test_device() {
+            Some(device) => device,
+            None => return
+        };
+
+        let texel = [200u8, 100, 50, 255];
+        let pixels = texel.repeat(16);
+
+        assert!(Image::new_from_rgba8(device.clone(), 4, 4, &pixels[..60], true).is_err());
+
+        let image = Image::new_from_rgba8(device.clone(), 4, 4, &pixels, true).unwrap();
+        assert_eq!(image.desc().mip_levels, 3);
+
+        //A solid color stays the same in every generated level, read back the 1x1 level
+        let read_back_buffer = Buffer::new(device.clone(), &BufferDesc::new_gpu_to_cpu(4, vk::BufferUsageFlags::TRANSFER_DST)).unwrap();
+        device
+            .submit_immediate(device.direct_queue(), |command_buffer| unsafe {
+                let subresource_range = vk::ImageSubresourceRange::default()
+                    .aspect_mask(vk::ImageAspectFlags::COLOR)
+                    .base_mip_level(2)
+                    .level_count(1)
+                    .layer_count(1);
+                let image_memory_barrier = vk::ImageMemoryBarrier::default()
+                    .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
+                    .old_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                    .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+                    .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .image(*image.image())
+                    .subresource_range(subresource_range);
+                device.loader().cmd_pipeline_barrier(
+                    command_buffer,
+                    vk::PipelineStageFlags::TOP_OF_PIPE,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &[],
+                    slice::from_ref(&image_memory_barrier)
+                );
+
+                let buffer_image_copy = vk::BufferImageCopy::default()
+                    .image_subresource(vk::ImageSubresourceLayers::default().aspect_mask(vk::ImageAspectFlags::COLOR).mip_level(2).layer_count(1))
+                    .image_extent(vk::Extent3D { width: 1, height: 1, depth: 1 });
+                device.loader().cmd_copy_image_to_buffer(
+                    command_buffer,
+                    *image.image(),
+                    vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    *read_back_buffer.buffer(),
+                    slice::from_ref(&buffer_image_copy)
+                );
+            })
+            .unwrap();
+
+        assert_eq!(read_back_buffer.read_bytes().unwrap(), texel);
+    }
+}
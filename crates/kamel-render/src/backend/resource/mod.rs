@@ -1,3 +1,11 @@
+mod acceleration_structure;
 mod buffer;
+mod image;
+mod sampler;
+mod transfer;
 
+pub use acceleration_structure::*;
 pub use buffer::*;
+pub use image::*;
+pub use sampler::*;
+pub use transfer::*;
@@ -0,0 +1,130 @@
+use std::sync::Arc;
+
+use ash::{prelude::VkResult, vk};
+
+use crate::backend::{Device, DeviceGeneration};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SamplerDesc {
+    pub mag_filter: vk::Filter,
+    pub min_filter: vk::Filter,
+    pub mipmap_mode: vk::SamplerMipmapMode,
+    pub address_mode: vk::SamplerAddressMode,
+    pub anisotropy: Option<f32>
+}
+
+impl SamplerDesc {
+    #[inline]
+    pub fn linear_repeat() -> Self {
+        Self {
+            mag_filter: vk::Filter::LINEAR,
+            min_filter: vk::Filter::LINEAR,
+            mipmap_mode: vk::SamplerMipmapMode::LINEAR,
+            address_mode: vk::SamplerAddressMode::REPEAT,
+            anisotropy: None
+        }
+    }
+
+    #[inline]
+    pub fn nearest_clamp() -> Self {
+        Self {
+            mag_filter: vk::Filter::NEAREST,
+            min_filter: vk::Filter::NEAREST,
+            mipmap_mode: vk::SamplerMipmapMode::NEAREST,
+            address_mode: vk::SamplerAddressMode::CLAMP_TO_EDGE,
+            anisotropy: None
+        }
+    }
+
+    #[inline]
+    pub fn mag_filter(mut self, mag_filter: vk::Filter) -> Self {
+        self.mag_filter = mag_filter;
+        self
+    }
+
+    #[inline]
+    pub fn min_filter(mut self, min_filter: vk::Filter) -> Self {
+        self.min_filter = min_filter;
+        self
+    }
+
+    #[inline]
+    pub fn mipmap_mode(mut self, mipmap_mode: vk::SamplerMipmapMode) -> Self {
+        self.mipmap_mode = mipmap_mode;
+        self
+    }
+
+    #[inline]
+    pub fn address_mode(mut self, address_mode: vk::SamplerAddressMode) -> Self {
+        self.address_mode = address_mode;
+        self
+    }
+
+    #[inline]
+    pub fn anisotropy(mut self, anisotropy: f32) -> Self {
+        self.anisotropy = Some(anisotropy);
+        self
+    }
+}
+
+impl Default for SamplerDesc {
+    #[inline]
+    fn default() -> Self {
+        Self::linear_repeat()
+    }
+}
+
+pub struct Sampler {
+    sampler: vk::Sampler,
+
+    device_generation: DeviceGeneration,
+    device: Arc<Device>
+}
+
+impl Sampler {
+    pub fn new(device: Arc<Device>, desc: &SamplerDesc) -> VkResult<Self> {
+        let anisotropy = if device.enabled_features().features.sampler_anisotropy == vk::TRUE {
+            desc.anisotropy
+                .map(|anisotropy| anisotropy.min(device.properties().properties.limits.max_sampler_anisotropy))
+                .filter(|anisotropy| *anisotropy > 1.0)
+        } else {
+            None
+        };
+
+        let sampler_create_info = vk::SamplerCreateInfo::default()
+            .mag_filter(desc.mag_filter)
+            .min_filter(desc.min_filter)
+            .mipmap_mode(desc.mipmap_mode)
+            .address_mode_u(desc.address_mode)
+            .address_mode_v(desc.address_mode)
+            .address_mode_w(desc.address_mode)
+            .anisotropy_enable(anisotropy.is_some())
+            .max_anisotropy(anisotropy.unwrap_or(1.0))
+            .max_lod(vk::LOD_CLAMP_NONE);
+
+        let sampler = unsafe { device.loader().create_sampler(&sampler_create_info, None)? };
+
+        Ok(Self {
+            sampler,
+
+            device_generation: device.generation(),
+            device
+        })
+    }
+
+    #[inline]
+    pub fn sampler(&self) -> &vk::Sampler {
+        &self.sampler
+    }
+}
+
+impl Drop for Sampler {
+    #[inline]
+    fn drop(&mut self) {
+        self.device.assert_generation(self.device_generation);
+
+        unsafe {
+            self.device.loader().destroy_sampler(self.sampler, None);
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use std::{mem, slice, sync::Arc};
+
+use anyhow::Result;
+use ash::{prelude::VkResult, vk};
+use log::error;
+
+use crate::backend::{
+    resource::{Buffer, BufferDesc},
+    BarrierBuilder, CommandBuffer, CommandPool, Device, Fence
+};
+
+//Keeps the staging buffers and command buffer of a flushed TransferContext alive until the direct queue is done with them
+pub struct TransferBatch {
+    fence: Fence,
+
+    _command_buffer: Option<CommandBuffer>,
+    _staging_buffers: Vec<Buffer>
+}
+
+impl TransferBatch {
+    #[inline]
+    pub fn wait(&self, timeout: u64) -> VkResult<()> {
+        self.fence.wait(timeout)
+    }
+
+    #[inline]
+    pub fn is_complete(&self) -> VkResult<bool> {
+        self.fence.is_signaled()
+    }
+
+    #[inline]
+    pub fn fence(&self) -> &Fence {
+        &self.fence
+    }
+}
+
+impl Drop for TransferBatch {
+    #[inline]
+    fn drop(&mut self) {
+        if let Err(e) = self.fence.wait(u64::MAX) {
+            error!("Failed to wait for transfer batch: {:?}", e);
+        }
+    }
+}
+
+//Records the copies of Buffer::queue_upload and Image::queue_upload into one command buffer which flush submits with a single fence.
+//Copies run on the direct queue, so images can be transitioned for sampling without an ownership transfer
+pub struct TransferContext {
+    command_buffer: Option<CommandBuffer>,
+    staging_buffers: Vec<Buffer>,
+    staged_size: vk::DeviceSize,
+
+    device: Arc<Device>
+}
+
+impl TransferContext {
+    #[inline]
+    pub fn new(device: Arc<Device>) -> Self {
+        Self {
+            command_buffer: None,
+            staging_buffers: Vec::new(),
+            staged_size: 0,
+
+            device
+        }
+    }
+
+    //Every batch records into a command pool of its own, which the flushed batch owns, so the shared pools of the device are never touched
+    fn command_buffer(&mut self) -> VkResult<vk::CommandBuffer> {
+        if let Some(command_buffer) = &self.command_buffer {
+            return Ok(*command_buffer.command_buffer())
+        }
+
+        let command_pool = CommandPool::new(self.device.clone(), self.device.direct_queue().family_index(), vk::CommandPoolCreateFlags::TRANSIENT)?;
+        let command_buffer = CommandBuffer::new(command_pool, vk::CommandBufferLevel::PRIMARY)?;
+        command_buffer.begin_one_time_submit()?;
+
+        Ok(*self.command_buffer.insert(command_buffer).command_buffer())
+    }
+
+    //Copies data into a new staging buffer and returns it together with the command buffer to record the copy into
+    pub(crate) fn stage<T: Copy>(&mut self, data: &[T]) -> Result<(vk::Buffer, vk::CommandBuffer)> {
+        let size = mem::size_of_val(data) as vk::DeviceSize;
+
+        let staging_buffer = Buffer::new(self.device.clone(), &BufferDesc::new_cpu_only(size, vk::BufferUsageFlags::TRANSFER_SRC))?;
+        staging_buffer.write_slice(0, data)?;
+
+        let command_buffer = self.command_buffer()?;
+        let buffer = *staging_buffer.buffer();
+
+        self.staging_buffers.push(staging_buffer);
+        self.staged_size += size;
+
+        Ok((buffer, command_buffer))
+    }
+
+    //Submits every upload queued since the last flush, the returned batch waits for completion when dropped
+    pub fn flush(&mut self) -> VkResult<TransferBatch> {
+        let command_buffer = self.command_buffer.take();
+        let staging_buffers = mem::take(&mut self.staging_buffers);
+        self.staged_size = 0;
+
+        if let Some(command_buffer) = &command_buffer {
+            //Makes the copies visible to everything submitted to the direct queue afterwards and to host reads after the fence
+            let memory_barrier = vk::MemoryBarrier2::default()
+                .src_stage_mask(vk::PipelineStageFlags2::TRANSFER)
+                .src_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
+                .dst_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS | vk::PipelineStageFlags2::HOST)
+                .dst_access_mask(vk::AccessFlags2::MEMORY_READ | vk::AccessFlags2::HOST_READ);
+            command_buffer.pipeline_barrier2(&BarrierBuilder::new().memory(memory_barrier));
+
+            command_buffer.end()?;
+        }
+
+        let fence = Fence::new(self.device.clone(), false)?;
+        let command_buffers = command_buffer.as_ref().map_or(&[][..], |command_buffer| slice::from_ref(command_buffer.command_buffer()));
+
+        self.device.direct_queue().submit(&self.device, command_buffers, &[], &[], Some(&fence))?;
+
+        Ok(TransferBatch {
+            fence,
+
+            _command_buffer: command_buffer,
+            _staging_buffers: staging_buffers
+        })
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.command_buffer.is_none()
+    }
+
+    //Bytes held in staging buffers since the last flush, useful to flush before staging memory grows too large
+    #[inline]
+    pub fn staged_size(&self) -> vk::DeviceSize {
+        self.staged_size
+    }
+
+    #[inline]
+    pub fn device(&self) -> &Arc<Device> {
+        &self.device
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::test_device;
+
+    #[test]
+    fn flushed_batches_upload_every_buffer() {
+        let device = match test_device() {
+            Some(device) => device,
+            None => return
+        };
+
+        let buffers: Vec<_> = (0..2)
+            .map(|_| Buffer::new(device.clone(), &BufferDesc::new_gpu_to_cpu(16, vk::BufferUsageFlags::TRANSFER_DST)).unwrap())
+            .collect();
+
+        let mut ctx = TransferContext::new(device);
+        buffers[0].queue_upload(&mut ctx, &[1u32, 2, 3, 4]).unwrap();
+        let first_batch = ctx.flush().unwrap();
+
+        buffers[1].queue_upload(&mut ctx, &[5u32, 6, 7, 8]).unwrap();
+        assert_eq!(ctx.staged_size(), 16);
+        let second_batch = ctx.flush().unwrap();
+        assert!(ctx.is_empty());
+
+        second_batch.wait(u64::MAX).unwrap();
+        first_batch.wait(u64::MAX).unwrap();
+
+        assert_eq!(buffers[0].read_bytes().unwrap(), bytemuck::cast_slice::<u32, u8>(&[1, 2, 3, 4]));
+        assert_eq!(buffers[1].read_bytes().unwrap(), bytemuck::cast_slice::<u32, u8>(&[5, 6, 7, 8]));
+    }
+}
@@ -1,10 +1,40 @@
-use std::sync::Arc;
+use std::{error::Error, fmt, sync::Arc};
 
 use anyhow::Result;
-use ash::vk;
+use ash::{prelude::VkResult, vk};
 use raw_window_handle::HasRawWindowHandle;
 
-use crate::backend::Instance;
+use crate::backend::{Device, Instance};
+
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+pub enum SurfaceError {
+    OutOfDate,
+    Lost,
+    Other(vk::Result)
+}
+
+impl From<vk::Result> for SurfaceError {
+    #[inline]
+    fn from(result: vk::Result) -> Self {
+        match result {
+            vk::Result::ERROR_OUT_OF_DATE_KHR => Self::OutOfDate,
+            vk::Result::ERROR_SURFACE_LOST_KHR => Self::Lost,
+            result => Self::Other(result)
+        }
+    }
+}
+
+impl fmt::Display for SurfaceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutOfDate => write!(f, "Swapchain is out of date"),
+            Self::Lost => write!(f, "Surface was lost"),
+            Self::Other(result) => write!(f, "{}", result)
+        }
+    }
+}
+
+impl Error for SurfaceError {}
 
 pub struct Surface {
     surface: vk::SurfaceKHR,
@@ -19,6 +49,27 @@ impl Surface {
         }
     }
 
+    pub fn recreate(&mut self, window: &impl HasRawWindowHandle) -> Result<()> {
+        unsafe {
+            self.instance.surface_loader().destroy_surface(self.surface, None);
+            self.surface = vk::SurfaceKHR::null();
+
+            self.surface = ash_window::create_surface(self.instance.entry_loader(), self.instance.loader(), window, None)?;
+        }
+
+        Ok(())
+    }
+
+    //Whether the device's present queue family can present to the surface, required before creating a swapchain for it
+    #[inline]
+    pub fn supports_present(&self, device: &Device) -> VkResult<bool> {
+        unsafe {
+            self.instance
+                .surface_loader()
+                .get_physical_device_surface_support(*device.physical_device(), device.present_queue().family_index(), self.surface)
+        }
+    }
+
     #[inline]
     pub fn surface(&self) -> &vk::SurfaceKHR {
         &self.surface
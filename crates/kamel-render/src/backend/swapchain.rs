@@ -1,9 +1,20 @@
-use std::{slice, sync::Arc};
+use std::{
+    slice,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc
+    }
+};
 
-use anyhow::Result;
+use anyhow::{bail, ensure, Result};
 use ash::{prelude::VkResult, vk};
+use log::warn;
+use raw_window_handle::HasRawWindowHandle;
 
-use crate::backend::{Device, Instance, Surface};
+use crate::backend::{
+    resource::{Buffer, BufferDesc, Image, ImageDesc},
+    BarrierBuilder, CommandBuffer, CommandPool, Device, DeviceGeneration, Fence, Instance, Queue, Semaphore, Surface, SurfaceError
+};
 
 pub struct SurfaceCapabilities {
     pub surface_capabilities: vk::SurfaceCapabilitiesKHR
@@ -43,10 +54,29 @@ impl SurfaceFormats {
     }
 
     #[inline]
-    pub fn find_ldr_format(&self) -> Option<vk::SurfaceFormatKHR> {
-        const FORMATS: [vk::Format; 4] = [vk::Format::R8G8B8A8_SRGB, vk::Format::B8G8R8A8_SRGB, vk::Format::R8G8B8A8_UNORM, vk::Format::B8G8R8A8_UNORM];
+    fn find_format(&self, formats: &[vk::Format]) -> Option<vk::SurfaceFormatKHR> {
+        formats
+            .iter()
+            .find_map(|format| self.supported_formats.iter().find(|f| f.format == *format && f.color_space == vk::ColorSpaceKHR::SRGB_NONLINEAR))
+            .copied()
+    }
+
+    //The hardware encodes shader output to sRGB on store
+    #[inline]
+    pub fn find_srgb_format(&self) -> Option<vk::SurfaceFormatKHR> {
+        self.find_format(&[vk::Format::R8G8B8A8_SRGB, vk::Format::B8G8R8A8_SRGB])
+    }
+
+    //Shaders have to apply the sRGB transfer function themselves
+    #[inline]
+    pub fn find_unorm_format(&self) -> Option<vk::SurfaceFormatKHR> {
+        self.find_format(&[vk::Format::R8G8B8A8_UNORM, vk::Format::B8G8R8A8_UNORM])
+    }
 
-        self.supported_formats.iter().find(|f| FORMATS.contains(&f.format)).copied()
+    //Prefers _SRGB formats so gamma is correct without shader changes, see Swapchain::requires_manual_gamma
+    #[inline]
+    pub fn find_ldr_format(&self) -> Option<vk::SurfaceFormatKHR> {
+        self.find_srgb_format().or_else(|| self.find_unorm_format())
     }
 
     #[inline]
@@ -58,6 +88,152 @@ impl SurfaceFormats {
     }
 }
 
+#[inline]
+pub fn is_srgb_format(format: vk::Format) -> bool {
+    matches!(
+        format,
+        vk::Format::R8G8B8A8_SRGB | vk::Format::B8G8R8A8_SRGB | vk::Format::A8B8G8R8_SRGB_PACK32 | vk::Format::R8G8B8_SRGB | vk::Format::B8G8R8_SRGB
+    )
+}
+
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+pub enum ColorPreference {
+    PreferHdr,
+    ForceLdr
+}
+
+impl Default for ColorPreference {
+    #[inline]
+    fn default() -> Self {
+        Self::PreferHdr
+    }
+}
+
+//Chromaticities are CIE 1931 xy coordinates, luminance is in nits
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct HdrMetadata {
+    pub display_primary_red: [f32; 2],
+    pub display_primary_green: [f32; 2],
+    pub display_primary_blue: [f32; 2],
+    pub white_point: [f32; 2],
+    pub max_luminance: f32,
+    pub min_luminance: f32,
+    pub max_content_light_level: f32,
+    pub max_frame_average_light_level: f32
+}
+
+impl HdrMetadata {
+    #[inline]
+    fn to_vk(self) -> vk::HdrMetadataEXT<'static> {
+        let xy = |[x, y]: [f32; 2]| vk::XYColorEXT { x, y };
+
+        vk::HdrMetadataEXT::default()
+            .display_primary_red(xy(self.display_primary_red))
+            .display_primary_green(xy(self.display_primary_green))
+            .display_primary_blue(xy(self.display_primary_blue))
+            .white_point(xy(self.white_point))
+            .max_luminance(self.max_luminance)
+            .min_luminance(self.min_luminance)
+            .max_content_light_level(self.max_content_light_level)
+            .max_frame_average_light_level(self.max_frame_average_light_level)
+    }
+}
+
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct ImageData {
+    pub width: u32,
+    pub height: u32,
+    pub format: vk::Format,
+    pub bytes: Vec<u8>
+}
+
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+pub struct SwapchainDesc {
+    pub vsync_enabled: bool,
+    pub color_preference: ColorPreference,
+    pub frames_in_flight: u32,
+    pub desired_image_count: Option<u32>,
+    pub use_dynamic_rendering: bool,
+    //e.g. PRE_MULTIPLIED for transparent windows, ignored if the surface doesn't support it
+    pub composite_alpha: Option<vk::CompositeAlphaFlagsKHR>,
+    pub image_usage: vk::ImageUsageFlags
+}
+
+pub const FRAMES_IN_FLIGHT: u32 = 2;
+
+impl Default for SwapchainDesc {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            vsync_enabled: true,
+            color_preference: ColorPreference::default(),
+            frames_in_flight: FRAMES_IN_FLIGHT,
+            desired_image_count: None,
+            use_dynamic_rendering: true,
+            composite_alpha: None,
+            image_usage: vk::ImageUsageFlags::COLOR_ATTACHMENT
+        }
+    }
+}
+
+//Acquire half of the swapchain image ownership transfer, submitted on the present queue between rendering and presenting
+struct PresentTransfer {
+    command_buffer: CommandBuffer,
+    present_ready_semaphore: Semaphore,
+    fence: Fence
+}
+
+impl PresentTransfer {
+    fn new(device: &Arc<Device>, command_pool: &Arc<CommandPool>) -> VkResult<Self> {
+        Ok(Self {
+            command_buffer: CommandBuffer::new(command_pool.clone(), vk::CommandBufferLevel::PRIMARY)?,
+            present_ready_semaphore: Semaphore::new(device.clone())?,
+            fence: Fence::new(device.clone(), true)?
+        })
+    }
+}
+
+struct FrameSync {
+    image_available_semaphore: Semaphore,
+    render_finished_semaphore: Semaphore,
+    in_flight_fence: Fence,
+    present_transfer: Option<PresentTransfer>
+}
+
+impl FrameSync {
+    fn new(device: &Arc<Device>, present_command_pool: Option<&Arc<CommandPool>>) -> VkResult<Self> {
+        Ok(Self {
+            image_available_semaphore: Semaphore::new(device.clone())?,
+            render_finished_semaphore: Semaphore::new(device.clone())?,
+            in_flight_fence: Fence::new(device.clone(), true)?,
+            present_transfer: present_command_pool.map(|command_pool| PresentTransfer::new(device, command_pool)).transpose()?
+        })
+    }
+}
+
+const DEPTH_FORMATS: [vk::Format; 2] = [vk::Format::D32_SFLOAT, vk::Format::D24_UNORM_S8_UINT];
+
+#[derive(Default)]
+struct SwapchainImages {
+    //What select_image_usage chose when the images were created
+    image_usage: vk::ImageUsageFlags,
+    images: Vec<vk::Image>,
+    image_views: Vec<vk::ImageView>,
+    depth_images: Vec<Image>,
+    framebuffers: Vec<vk::Framebuffer>
+}
+
+impl SwapchainImages {
+    unsafe fn destroy(&mut self, device: &Device) {
+        let device_loader = device.loader();
+
+        self.framebuffers.drain(..).for_each(|framebuffer| device_loader.destroy_framebuffer(framebuffer, None));
+        self.image_views.drain(..).for_each(|image_view| device_loader.destroy_image_view(image_view, None));
+        self.depth_images.clear();
+        self.images.clear();
+    }
+}
+
 pub struct Swapchain {
     surface_capabilities: SurfaceCapabilities,
 
@@ -65,24 +241,42 @@ pub struct Swapchain {
     present_modes: Vec<vk::PresentModeKHR>,
 
     render_pass: vk::RenderPass,
-    _images: Vec<vk::Image>,
-    image_views: Vec<vk::ImageView>,
-    framebuffers: Vec<vk::Framebuffer>,
+    swapchain_images: SwapchainImages,
 
     used_surface_format: vk::SurfaceFormatKHR,
     used_present_mode: vk::PresentModeKHR,
-    vsync_enabled: bool,
+    desc: SwapchainDesc,
+    depth_format: vk::Format,
+    extent: vk::Extent2D,
+    window_extent: vk::Extent2D,
+    pre_transform: vk::SurfaceTransformFlagsKHR,
 
     swapchain: vk::SwapchainKHR,
+    hdr_metadata: Option<HdrMetadata>,
+    //Atomic so present_all can update the swapchains it presents through shared references
+    needs_recreate: AtomicBool,
+    surface_lost: AtomicBool,
+    minimized: bool,
 
-    _instance: Arc<Instance>,
-    _surface: Arc<Surface>,
+    frame_syncs: Vec<FrameSync>,
+    current_frame: AtomicUsize,
+
+    instance: Arc<Instance>,
+    surface: Arc<Surface>,
+    device_generation: DeviceGeneration,
     device: Arc<Device>
 }
 
 impl Swapchain {
-    unsafe fn create_render_pass(device: &Device, format: vk::Format) -> VkResult<vk::RenderPass> {
-        let attachment_description = vk::AttachmentDescription::default()
+    fn find_depth_format(device: &Device) -> Option<vk::Format> {
+        DEPTH_FORMATS
+            .iter()
+            .copied()
+            .find(|format| device.supports_format_features(*format, vk::ImageTiling::OPTIMAL, vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT))
+    }
+
+    unsafe fn create_render_pass(device: &Device, format: vk::Format, depth_format: vk::Format) -> VkResult<vk::RenderPass> {
+        let color_attachment_description = vk::AttachmentDescription::default()
             .format(format)
             .samples(vk::SampleCountFlags::TYPE_1)
             .load_op(vk::AttachmentLoadOp::CLEAR)
@@ -92,42 +286,172 @@ impl Swapchain {
             .initial_layout(vk::ImageLayout::UNDEFINED)
             .final_layout(vk::ImageLayout::PRESENT_SRC_KHR);
 
+        let depth_attachment_description = vk::AttachmentDescription::default()
+            .format(depth_format)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .load_op(vk::AttachmentLoadOp::CLEAR)
+            .store_op(vk::AttachmentStoreOp::DONT_CARE)
+            .stencil_load_op(vk::AttachmentLoadOp::CLEAR)
+            .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+            .initial_layout(vk::ImageLayout::UNDEFINED)
+            .final_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL);
+
+        let attachment_descriptions = [color_attachment_description, depth_attachment_description];
+
         let color_attachment_reference = vk::AttachmentReference::default().layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
+        let depth_attachment_reference = vk::AttachmentReference::default().attachment(1).layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL);
 
-        let subpass_description = vk::SubpassDescription::default().color_attachments(slice::from_ref(&color_attachment_reference));
+        let subpass_description = vk::SubpassDescription::default()
+            .color_attachments(slice::from_ref(&color_attachment_reference))
+            .depth_stencil_attachment(&depth_attachment_reference);
+
+        let subpass_dependency = vk::SubpassDependency::default()
+            .src_subpass(vk::SUBPASS_EXTERNAL)
+            .dst_subpass(0)
+            .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS)
+            .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS)
+            .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE);
 
         let render_pass_create_info = vk::RenderPassCreateInfo::default()
-            .attachments(slice::from_ref(&attachment_description))
-            .subpasses(slice::from_ref(&subpass_description));
+            .attachments(&attachment_descriptions)
+            .subpasses(slice::from_ref(&subpass_description))
+            .dependencies(slice::from_ref(&subpass_dependency));
 
         device.loader().create_render_pass(&render_pass_create_info, None)
     }
 
-    #[allow(clippy::type_complexity)]
+    fn select_present_mode(present_modes: &[vk::PresentModeKHR], vsync_enabled: bool) -> vk::PresentModeKHR {
+        let get_present_mode_if_supported = |present_mode: vk::PresentModeKHR| present_modes.iter().find(|p| **p == present_mode).copied();
+
+        if vsync_enabled {
+            get_present_mode_if_supported(vk::PresentModeKHR::FIFO_RELAXED).unwrap_or(vk::PresentModeKHR::FIFO)
+        } else {
+            get_present_mode_if_supported(vk::PresentModeKHR::MAILBOX)
+                .or_else(|| get_present_mode_if_supported(vk::PresentModeKHR::IMMEDIATE))
+                .unwrap_or(vk::PresentModeKHR::FIFO)
+        }
+    }
+
+    #[inline]
+    fn select_pre_transform(surface_capabilities: &vk::SurfaceCapabilitiesKHR) -> vk::SurfaceTransformFlagsKHR {
+        if surface_capabilities.supported_transforms.contains(surface_capabilities.current_transform) {
+            surface_capabilities.current_transform
+        } else {
+            vk::SurfaceTransformFlagsKHR::IDENTITY
+        }
+    }
+
+    #[inline]
+    fn is_rotated_90(pre_transform: vk::SurfaceTransformFlagsKHR) -> bool {
+        pre_transform.intersects(
+            vk::SurfaceTransformFlagsKHR::ROTATE_90
+                | vk::SurfaceTransformFlagsKHR::ROTATE_270
+                | vk::SurfaceTransformFlagsKHR::HORIZONTAL_MIRROR_ROTATE_90
+                | vk::SurfaceTransformFlagsKHR::HORIZONTAL_MIRROR_ROTATE_270
+        )
+    }
+
+    //With a 90/270 degree pre-transform the images are in the display's native orientation.
+    //A current extent of u32::MAX (e.g. on Wayland) lets the swapchain decide, then the window size is clamped to the supported range
+    fn swapchain_extent(surface_capabilities: &vk::SurfaceCapabilitiesKHR, pre_transform: vk::SurfaceTransformFlagsKHR, window_extent: vk::Extent2D) -> vk::Extent2D {
+        let extent = surface_capabilities.current_extent;
+
+        if extent.width == u32::MAX && extent.height == u32::MAX {
+            //Keep a minimized window at zero instead of growing it to min_image_extent
+            if window_extent.width == 0 || window_extent.height == 0 {
+                return vk::Extent2D::default()
+            }
+
+            let min_extent = surface_capabilities.min_image_extent;
+            let max_extent = surface_capabilities.max_image_extent;
+
+            return vk::Extent2D {
+                width: window_extent.width.clamp(min_extent.width, max_extent.width.max(min_extent.width)),
+                height: window_extent.height.clamp(min_extent.height, max_extent.height.max(min_extent.height))
+            }
+        }
+
+        if Self::is_rotated_90(pre_transform) {
+            vk::Extent2D {
+                width: extent.height,
+                height: extent.width
+            }
+        } else {
+            extent
+        }
+    }
+
+    fn select_composite_alpha(surface_capabilities: &vk::SurfaceCapabilitiesKHR, preferred: Option<vk::CompositeAlphaFlagsKHR>) -> vk::CompositeAlphaFlagsKHR {
+        const COMPOSITE_ALPHAS: [vk::CompositeAlphaFlagsKHR; 4] = [
+            vk::CompositeAlphaFlagsKHR::OPAQUE,
+            vk::CompositeAlphaFlagsKHR::PRE_MULTIPLIED,
+            vk::CompositeAlphaFlagsKHR::POST_MULTIPLIED,
+            vk::CompositeAlphaFlagsKHR::INHERIT
+        ];
+
+        let supported = surface_capabilities.supported_composite_alpha;
+
+        preferred
+            .into_iter()
+            .chain(COMPOSITE_ALPHAS)
+            .find(|composite_alpha| supported.contains(*composite_alpha))
+            .unwrap_or(vk::CompositeAlphaFlagsKHR::OPAQUE)
+    }
+
+    fn select_image_usage(surface_capabilities: &vk::SurfaceCapabilitiesKHR, requested: vk::ImageUsageFlags) -> vk::ImageUsageFlags {
+        let image_usage = requested | vk::ImageUsageFlags::COLOR_ATTACHMENT;
+
+        let unsupported = image_usage & !surface_capabilities.supported_usage_flags;
+        if !unsupported.is_empty() {
+            warn!("Swapchain image usage {:?} is not supported by the surface", unsupported);
+        }
+
+        (image_usage & surface_capabilities.supported_usage_flags) | vk::ImageUsageFlags::COLOR_ATTACHMENT
+    }
+
+    #[inline]
+    fn clamp_image_count(surface_capabilities: &vk::SurfaceCapabilitiesKHR, image_count: u32) -> u32 {
+        let max_image_count = if surface_capabilities.max_image_count == 0 {
+            u32::MAX
+        } else {
+            surface_capabilities.max_image_count
+        };
+
+        image_count.clamp(surface_capabilities.min_image_count, max_image_count)
+    }
+
+    #[allow(clippy::too_many_arguments)]
     unsafe fn create_swapchain(
-        device: &Device,
+        device: &Arc<Device>,
         surface: vk::SurfaceKHR,
         render_pass: vk::RenderPass,
         surface_capabilities: &SurfaceCapabilities,
         used_surface_format: &vk::SurfaceFormatKHR,
         used_present_mode: vk::PresentModeKHR,
+        depth_format: vk::Format,
+        extent: vk::Extent2D,
+        desc: &SwapchainDesc,
         old_swapchain: vk::SwapchainKHR
-    ) -> Result<(vk::SwapchainKHR, Vec<vk::Image>, Vec<vk::ImageView>, Vec<vk::Framebuffer>)> {
+    ) -> Result<(vk::SwapchainKHR, SwapchainImages)> {
         let device_loader = device.loader();
         let surface_capabilities = &surface_capabilities.surface_capabilities;
 
-        let min_image_count = 3.max(surface_capabilities.min_image_count);
+        //One image more than frames in flight so acquire doesn't stall on the image being presented
+        let desired_image_count = desc.desired_image_count.unwrap_or(desc.frames_in_flight + 1);
+        let min_image_count = Self::clamp_image_count(surface_capabilities, desired_image_count);
+
+        let image_usage = Self::select_image_usage(surface_capabilities, desc.image_usage);
 
         let swapchain_create_info = vk::SwapchainCreateInfoKHR::default()
             .surface(surface)
             .min_image_count(min_image_count)
             .image_format(used_surface_format.format)
             .image_color_space(used_surface_format.color_space)
-            .image_extent(surface_capabilities.current_extent)
+            .image_extent(extent)
             .image_array_layers(1)
-            .image_usage(vk::ImageUsageFlags::COLOR_ATTACHMENT)
-            .pre_transform(vk::SurfaceTransformFlagsKHR::IDENTITY)
-            .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
+            .image_usage(image_usage)
+            .pre_transform(Self::select_pre_transform(surface_capabilities))
+            .composite_alpha(Self::select_composite_alpha(surface_capabilities, desc.composite_alpha))
             .present_mode(used_present_mode)
             .old_swapchain(old_swapchain);
 
@@ -135,8 +459,13 @@ impl Swapchain {
         let swapchain = swapchain_loader.create_swapchain(&swapchain_create_info, None)?;
 
         let images = swapchain_loader.get_swapchain_images(swapchain)?;
-        let mut image_views = Vec::with_capacity(images.len());
-        let mut framebuffers = Vec::with_capacity(images.len());
+        let mut swapchain_images = SwapchainImages {
+            image_usage,
+            image_views: Vec::with_capacity(images.len()),
+            depth_images: Vec::with_capacity(images.len()),
+            framebuffers: Vec::with_capacity(images.len()),
+            ..Default::default()
+        };
 
         let mut image_view_create_info = vk::ImageViewCreateInfo::default()
             .view_type(vk::ImageViewType::TYPE_2D)
@@ -148,21 +477,38 @@ impl Swapchain {
             .width(swapchain_create_info.image_extent.width)
             .height(swapchain_create_info.image_extent.height)
             .layers(1);
-        framebuffer_create_info.attachment_count = 1;
+        framebuffer_create_info.attachment_count = 2;
+
+        for (i, image) in images.iter().enumerate() {
+            device.set_debug_name(*image, &format!("Swapchain image {}", i))?;
 
-        for image in images.iter() {
             image_view_create_info.image = *image;
             let image_view = device_loader.create_image_view(&image_view_create_info, None)?;
-            image_views.push(image_view);
+            swapchain_images.image_views.push(image_view);
+
+            let depth_image = Image::new(
+                device.clone(),
+                &ImageDesc::new_2d(swapchain_create_info.image_extent, depth_format, vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT)
+            )?;
 
-            framebuffer_create_info.p_attachments = &image_view;
-            framebuffers.push(device_loader.create_framebuffer(&framebuffer_create_info, None)?);
+            //No framebuffers are needed with dynamic rendering
+            if render_pass != vk::RenderPass::null() {
+                let attachments = [image_view, *depth_image.view()];
+                framebuffer_create_info.p_attachments = attachments.as_ptr();
+                swapchain_images.framebuffers.push(device_loader.create_framebuffer(&framebuffer_create_info, None)?);
+            }
+            swapchain_images.depth_images.push(depth_image);
         }
 
-        Ok((swapchain, images, image_views, framebuffers))
+        swapchain_images.images = images;
+
+        Ok((swapchain, swapchain_images))
     }
 
-    pub fn new(instance: Arc<Instance>, surface: Arc<Surface>, device: Arc<Device>, vsync_enabled: bool) -> Result<Arc<Self>> {
+    //window_extent is the window's framebuffer size, only used if the surface leaves the extent up to the swapchain
+    pub fn new(instance: Arc<Instance>, surface: Arc<Surface>, device: Arc<Device>, desc: &SwapchainDesc, window_extent: vk::Extent2D) -> Result<Self> {
+        ensure!(desc.frames_in_flight >= 1, "At least one frame in flight is required");
+
         let surface_handle = *surface.surface();
         let surface_info = vk::PhysicalDeviceSurfaceInfo2KHR::default().surface(surface_handle);
 
@@ -171,56 +517,436 @@ impl Swapchain {
 
             let surface_formats = SurfaceFormats::new(&instance, &device, &surface_info)?;
             let present_modes = instance.surface_loader().get_physical_device_surface_present_modes(*device.physical_device(), surface_handle)?;
-            let get_present_mode_if_supported = |present_mode: vk::PresentModeKHR| present_modes.iter().find(|p| **p == present_mode).copied();
 
-            let used_surface_format = surface_formats
-                .find_hdr_format()
+            let hdr_format = match desc.color_preference {
+                ColorPreference::PreferHdr => surface_formats.find_hdr_format(),
+                ColorPreference::ForceLdr => None
+            };
+
+            let used_surface_format = hdr_format
                 .or_else(|| surface_formats.find_ldr_format())
                 .ok_or_else(|| anyhow::anyhow!("Failed to find surface format"))?;
 
-            let used_present_mode = if vsync_enabled {
-                get_present_mode_if_supported(vk::PresentModeKHR::FIFO_RELAXED).unwrap_or(vk::PresentModeKHR::FIFO)
+            let used_present_mode = Self::select_present_mode(&present_modes, desc.vsync_enabled);
+
+            let depth_format = Self::find_depth_format(&device).ok_or_else(|| anyhow::anyhow!("Failed to find depth format"))?;
+
+            let pre_transform = Self::select_pre_transform(&surface_capabilities.surface_capabilities);
+            let extent = Self::swapchain_extent(&surface_capabilities.surface_capabilities, pre_transform, window_extent);
+
+            //Created minimized, the swapchain is only created once the window is restored, see recreate
+            let minimized = extent.width == 0 || extent.height == 0;
+
+            let render_pass = if desc.use_dynamic_rendering && device.supports_dynamic_rendering() {
+                vk::RenderPass::null()
             } else {
-                get_present_mode_if_supported(vk::PresentModeKHR::MAILBOX)
-                    .or_else(|| get_present_mode_if_supported(vk::PresentModeKHR::IMMEDIATE))
-                    .unwrap_or(vk::PresentModeKHR::FIFO)
+                let render_pass = Self::create_render_pass(&device, used_surface_format.format, depth_format)?;
+                device.set_debug_name(render_pass, "Swapchain render pass")?;
+                render_pass
+            };
+            let (swapchain, swapchain_images) = if minimized {
+                (vk::SwapchainKHR::null(), SwapchainImages::default())
+            } else {
+                Self::create_swapchain(
+                    &device,
+                    surface_handle,
+                    render_pass,
+                    &surface_capabilities,
+                    &used_surface_format,
+                    used_present_mode,
+                    depth_format,
+                    extent,
+                    desc,
+                    vk::SwapchainKHR::null()
+                )?
             };
 
-            let render_pass = Self::create_render_pass(&device, used_surface_format.format)?;
-            let (swapchain, images, image_views, framebuffers) = Self::create_swapchain(
-                &device,
-                surface_handle,
-                render_pass,
-                &surface_capabilities,
-                &used_surface_format,
-                used_present_mode,
-                vk::SwapchainKHR::null()
-            )?;
+            let present_command_pool = if device.splits_present() {
+                Some(CommandPool::new(
+                    device.clone(),
+                    device.present_queue().family_index(),
+                    vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER
+                )?)
+            } else {
+                None
+            };
 
-            Ok(Arc::new(Self {
+            let frame_syncs = (0..desc.frames_in_flight)
+                .map(|_| FrameSync::new(&device, present_command_pool.as_ref()))
+                .collect::<VkResult<_>>()?;
+
+            let swapchain = Self {
                 surface_capabilities,
 
                 surface_formats,
                 present_modes,
 
                 render_pass,
-                _images: images,
-                image_views,
-                framebuffers,
+                swapchain_images,
 
                 used_present_mode,
                 used_surface_format,
-                vsync_enabled,
+                desc: *desc,
+                depth_format,
+                extent,
+                window_extent,
+                pre_transform,
 
                 swapchain,
+                hdr_metadata: None,
+                needs_recreate: AtomicBool::new(false),
+                surface_lost: AtomicBool::new(false),
+                minimized,
+
+                frame_syncs,
+                current_frame: AtomicUsize::new(0),
 
-                _instance: instance,
-                _surface: surface,
+                instance,
+                surface,
+                device_generation: device.generation(),
                 device
-            }))
+            };
+
+            ensure!(
+                minimized || desc.frames_in_flight as usize <= swapchain.image_count(),
+                "Frames in flight ({}) exceeds the swapchain image count ({})",
+                desc.frames_in_flight,
+                swapchain.image_count()
+            );
+
+            Ok(swapchain)
+        }
+    }
+
+    pub fn recreate(&mut self) -> Result<()> {
+        let surface_handle = *self.surface.surface();
+        let surface_info = vk::PhysicalDeviceSurfaceInfo2KHR::default().surface(surface_handle);
+
+        self.device.wait_idle()?;
+
+        unsafe {
+            let surface_capabilities = SurfaceCapabilities::new(&self.instance, &self.device, &surface_info)?;
+
+            //Minimized window, keep the old swapchain around and don't render until the window is restored
+            let pre_transform = Self::select_pre_transform(&surface_capabilities.surface_capabilities);
+            let extent = Self::swapchain_extent(&surface_capabilities.surface_capabilities, pre_transform, self.window_extent);
+            if extent.width == 0 || extent.height == 0 {
+                self.minimized = true;
+                *self.needs_recreate.get_mut() = false;
+                return Ok(())
+            }
+
+            self.swapchain_images.destroy(&self.device);
+
+            let (swapchain, swapchain_images) = Self::create_swapchain(
+                &self.device,
+                surface_handle,
+                self.render_pass,
+                &surface_capabilities,
+                &self.used_surface_format,
+                self.used_present_mode,
+                self.depth_format,
+                extent,
+                &self.desc,
+                self.swapchain
+            )?;
+
+            self.device.swapchain_loader().destroy_swapchain(self.swapchain, None);
+
+            self.surface_capabilities = surface_capabilities;
+            self.swapchain = swapchain;
+            self.swapchain_images = swapchain_images;
+            self.extent = extent;
+            self.pre_transform = pre_transform;
+            *self.needs_recreate.get_mut() = false;
+            self.minimized = false;
+
+            //Metadata belongs to the swapchain handle, so it has to be set again
+            if let Some(hdr_metadata) = self.hdr_metadata {
+                self.set_hdr_metadata(hdr_metadata)?;
+            }
+
+            Ok(())
+        }
+    }
+
+    //No-op if VK_EXT_hdr_metadata is unavailable or the surface format isn't HDR
+    pub fn set_hdr_metadata(&mut self, hdr_metadata: HdrMetadata) -> Result<()> {
+        if !self.device.extensions().ext_hdr_metadata() || !self.is_hdr() {
+            return Ok(())
+        }
+
+        self.device.set_hdr_metadata(self.swapchain, &hdr_metadata.to_vk())?;
+        self.hdr_metadata = Some(hdr_metadata);
+
+        Ok(())
+    }
+
+    //Deferred until the next RenderStage::Prepare, so several resize events or suboptimal results within a frame only recreate once
+    #[inline]
+    pub fn request_recreate(&self) {
+        self.needs_recreate.store(true, Ordering::Relaxed);
+    }
+
+    pub fn set_window_extent(&mut self, window_extent: vk::Extent2D) {
+        if window_extent != self.window_extent {
+            self.window_extent = window_extent;
+            self.request_recreate();
         }
     }
 
+    #[inline]
+    pub fn needs_recreate(&self) -> bool {
+        self.needs_recreate.load(Ordering::Relaxed)
+    }
+
+    //Set when present_all found the surface lost, cleared by recreate_surface
+    #[inline]
+    pub fn is_surface_lost(&self) -> bool {
+        self.surface_lost.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn is_minimized(&self) -> bool {
+        self.minimized
+    }
+
+    #[inline]
+    pub fn hdr_metadata(&self) -> Option<&HdrMetadata> {
+        self.hdr_metadata.as_ref()
+    }
+
+    pub fn set_present_mode(&mut self, present_mode: vk::PresentModeKHR) -> Result<()> {
+        ensure!(self.present_modes.contains(&present_mode), "Present mode {:?} is not supported by the surface", present_mode);
+
+        self.desc.vsync_enabled = present_mode == vk::PresentModeKHR::FIFO || present_mode == vk::PresentModeKHR::FIFO_RELAXED;
+
+        if present_mode == self.used_present_mode {
+            return Ok(())
+        }
+
+        self.used_present_mode = present_mode;
+        self.recreate()
+    }
+
+    #[inline]
+    pub fn set_vsync(&mut self, enabled: bool) -> Result<()> {
+        self.set_present_mode(Self::select_present_mode(&self.present_modes, enabled))
+    }
+
+    pub fn recreate_surface(&mut self, window: &impl HasRawWindowHandle) -> Result<()> {
+        self.device.wait_idle()?;
+
+        unsafe {
+            self.swapchain_images.destroy(&self.device);
+
+            self.device.swapchain_loader().destroy_swapchain(self.swapchain, None);
+            self.swapchain = vk::SwapchainKHR::null();
+        }
+
+        //The device may still share the lost surface, in that case it keeps the old handle alive until it is dropped
+        match Arc::get_mut(&mut self.surface) {
+            Some(surface) => surface.recreate(window)?,
+            None => self.surface = Surface::new(self.instance.clone(), window)?
+        }
+
+        ensure!(
+            self.surface.supports_present(&self.device)?,
+            "Present queue family {} can't present to the recreated surface",
+            self.device.present_queue().family_index()
+        );
+        *self.surface_lost.get_mut() = false;
+
+        self.recreate()
+    }
+
+    pub fn acquire_next_image(&mut self) -> Result<(u32, bool), SurfaceError> {
+        let frame_sync = &self.frame_syncs[self.current_frame()];
+
+        frame_sync.in_flight_fence.wait(u64::MAX)?;
+
+        let result = unsafe {
+            self.device
+                .swapchain_loader()
+                .acquire_next_image(self.swapchain, u64::MAX, *frame_sync.image_available_semaphore.semaphore(), vk::Fence::null())
+        };
+
+        match result {
+            Ok((image_index, suboptimal)) => {
+                frame_sync.in_flight_fence.reset()?;
+                Ok((image_index, suboptimal))
+            }
+            Err(e) => Err(e.into())
+        }
+    }
+
+    #[inline]
+    fn present_transfer_barrier(&self, image_index: u32) -> vk::ImageMemoryBarrier2<'static> {
+        vk::ImageMemoryBarrier2::default()
+            .old_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+            .new_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+            .src_queue_family_index(self.device.direct_queue().family_index())
+            .dst_queue_family_index(self.device.present_queue().family_index())
+            .image(self.swapchain_images.images[image_index as usize])
+            .subresource_range(vk::ImageSubresourceRange::default().aspect_mask(vk::ImageAspectFlags::COLOR).level_count(1).layer_count(1))
+    }
+
+    //Must be recorded last in the frame's direct queue submission, after the image was transitioned to PRESENT_SRC_KHR.
+    //A no-op unless Device::splits_present
+    pub fn cmd_release_to_present_queue(&self, command_buffer: &CommandBuffer, image_index: u32) {
+        if self.frame_syncs[self.current_frame()].present_transfer.is_none() {
+            return
+        }
+
+        let release_barrier = self
+            .present_transfer_barrier(image_index)
+            .src_stage_mask(vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT)
+            .src_access_mask(vk::AccessFlags2::COLOR_ATTACHMENT_WRITE);
+
+        command_buffer.pipeline_barrier2(&BarrierBuilder::new().image(release_barrier));
+    }
+
+    //Returns the semaphore the present has to wait on, with a split present queue the acquire barrier is submitted first
+    fn submit_present_acquire(&self, image_index: u32) -> VkResult<vk::Semaphore> {
+        let frame_sync = &self.frame_syncs[self.current_frame()];
+
+        let present_transfer = match &frame_sync.present_transfer {
+            Some(present_transfer) => present_transfer,
+            None => return Ok(*frame_sync.render_finished_semaphore.semaphore())
+        };
+
+        present_transfer.fence.wait(u64::MAX)?;
+        present_transfer.fence.reset()?;
+
+        let acquire_barrier = self.present_transfer_barrier(image_index).dst_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS);
+
+        let command_buffer = &present_transfer.command_buffer;
+        command_buffer.begin_one_time_submit()?;
+        command_buffer.pipeline_barrier2(&BarrierBuilder::new().image(acquire_barrier));
+        command_buffer.end()?;
+
+        self.device.present_queue().submit(
+            &self.device,
+            slice::from_ref(command_buffer.command_buffer()),
+            &[(*frame_sync.render_finished_semaphore.semaphore(), vk::PipelineStageFlags::ALL_COMMANDS)],
+            slice::from_ref(present_transfer.present_ready_semaphore.semaphore()),
+            Some(&present_transfer.fence)
+        )?;
+
+        Ok(*present_transfer.present_ready_semaphore.semaphore())
+    }
+
+    pub fn present(&mut self, image_index: u32) -> Result<bool, SurfaceError> {
+        let wait_semaphore = self.submit_present_acquire(image_index);
+
+        let result = wait_semaphore.and_then(|wait_semaphore| {
+            let present_info = vk::PresentInfoKHR::default()
+                .wait_semaphores(slice::from_ref(&wait_semaphore))
+                .swapchains(slice::from_ref(&self.swapchain))
+                .image_indices(slice::from_ref(&image_index));
+
+            unsafe { self.device.swapchain_loader().queue_present(*self.device.present_queue().queue(), &present_info) }
+        });
+
+        self.advance_frame();
+
+        result.map_err(SurfaceError::from)
+    }
+
+    #[inline]
+    fn advance_frame(&self) {
+        self.current_frame.store((self.current_frame() + 1) % self.frame_syncs.len(), Ordering::Relaxed);
+    }
+
+    //Presents several swapchains with a single vkQueuePresentKHR on the present queue of self's device, self doesn't have to be one of them.
+    //Returns per swapchain whether it was suboptimal or out of date, those are recreated on the next RenderStage::Prepare and lost surfaces are flagged, see is_surface_lost
+    pub fn present_all(&self, presents: &[(u32, &Swapchain)]) -> VkResult<Vec<bool>> {
+        debug_assert!(presents.iter().all(|(_, swapchain)| Arc::ptr_eq(&swapchain.device, &self.device)));
+
+        if presents.is_empty() {
+            return Ok(Vec::new())
+        }
+
+        let wait_semaphores = presents
+            .iter()
+            .map(|(image_index, swapchain)| swapchain.submit_present_acquire(*image_index))
+            .collect::<VkResult<Vec<_>>>();
+
+        let results = wait_semaphores.map(|wait_semaphores| {
+            let swapchains: Vec<_> = presents.iter().map(|(_, swapchain)| swapchain.swapchain).collect();
+            let image_indices: Vec<_> = presents.iter().map(|(image_index, _)| *image_index).collect();
+            let mut results = vec![vk::Result::SUCCESS; presents.len()];
+
+            let present_info = vk::PresentInfoKHR::default()
+                .wait_semaphores(&wait_semaphores)
+                .swapchains(&swapchains)
+                .image_indices(&image_indices)
+                .results(&mut results);
+
+            //The per swapchain results are written even if the call as a whole fails
+            let _ = unsafe { self.device.swapchain_loader().queue_present(*self.device.present_queue().queue(), &present_info) };
+
+            results
+        });
+
+        for (_, swapchain) in presents.iter() {
+            swapchain.advance_frame();
+        }
+
+        //One out of date or lost window doesn't affect the others
+        results?
+            .into_iter()
+            .zip(presents.iter())
+            .map(|(result, (_, swapchain))| match result {
+                vk::Result::SUCCESS => Ok(false),
+                vk::Result::SUBOPTIMAL_KHR | vk::Result::ERROR_OUT_OF_DATE_KHR => {
+                    swapchain.request_recreate();
+                    Ok(true)
+                }
+                vk::Result::ERROR_SURFACE_LOST_KHR => {
+                    swapchain.surface_lost.store(true, Ordering::Relaxed);
+                    Ok(true)
+                }
+                result => Err(result)
+            })
+            .collect()
+    }
+
+    //Must be called after the frame was submitted but before it is presented, BGRA formats are swizzled to RGBA
+    pub fn capture_frame(&self, image_index: u32) -> Result<ImageData> {
+        ensure!(
+            self.image_usage().contains(vk::ImageUsageFlags::TRANSFER_SRC),
+            "Capturing frames requires TRANSFER_SRC swapchain image usage"
+        );
+
+        //With a split present the frame released the image to the present family, the release has to execute before ownership is taken back
+        let released_to = if self.device.splits_present() {
+            self.in_flight_fence().wait(u64::MAX)?;
+            Some(self.device.present_queue())
+        } else {
+            None
+        };
+
+        capture_image(
+            &self.device,
+            self.swapchain_images.images[image_index as usize],
+            vk::ImageLayout::PRESENT_SRC_KHR,
+            self.extent,
+            self.used_surface_format.format,
+            released_to
+        )
+    }
+
+    #[inline]
+    pub fn device(&self) -> &Arc<Device> {
+        &self.device
+    }
+
+    #[inline]
+    pub fn surface(&self) -> &Arc<Surface> {
+        &self.surface
+    }
+
     #[inline]
     pub fn surface_capabilities(&self) -> &SurfaceCapabilities {
         &self.surface_capabilities
@@ -232,7 +958,7 @@ impl Swapchain {
     }
 
     #[inline]
-    pub fn present_modes(&self) -> &[vk::PresentModeKHR] {
+    pub fn supported_present_modes(&self) -> &[vk::PresentModeKHR] {
         &self.present_modes
     }
 
@@ -241,9 +967,29 @@ impl Swapchain {
         &self.render_pass
     }
 
+    #[inline]
+    pub fn uses_dynamic_rendering(&self) -> bool {
+        self.render_pass == vk::RenderPass::null()
+    }
+
+    #[inline]
+    pub fn image_view_at(&self, index: usize) -> &vk::ImageView {
+        &self.swapchain_images.image_views[index]
+    }
+
+    #[inline]
+    pub fn depth_image_at(&self, index: usize) -> &Image {
+        &self.swapchain_images.depth_images[index]
+    }
+
+    #[inline]
+    pub fn images(&self) -> &[vk::Image] {
+        &self.swapchain_images.images
+    }
+
     #[inline]
     pub fn framebuffer_at(&self, index: usize) -> &vk::Framebuffer {
-        &self.framebuffers[index]
+        &self.swapchain_images.framebuffers[index]
     }
 
     #[inline]
@@ -258,27 +1004,448 @@ impl Swapchain {
 
     #[inline]
     pub fn vsync_enabled(&self) -> bool {
-        self.vsync_enabled
+        self.desc.vsync_enabled
+    }
+
+    #[inline]
+    pub fn is_hdr(&self) -> bool {
+        self.used_surface_format.color_space != vk::ColorSpaceKHR::SRGB_NONLINEAR
+    }
+
+    //True for _UNORM formats in the sRGB color space, shaders must then encode their output to sRGB themselves
+    #[inline]
+    pub fn requires_manual_gamma(&self) -> bool {
+        !self.is_hdr() && !is_srgb_format(self.used_surface_format.format)
+    }
+
+    #[inline]
+    pub fn desc(&self) -> &SwapchainDesc {
+        &self.desc
+    }
+
+    #[inline]
+    pub fn depth_format(&self) -> vk::Format {
+        self.depth_format
+    }
+
+    #[inline]
+    pub fn extent(&self) -> vk::Extent2D {
+        self.extent
+    }
+
+    #[inline]
+    pub fn window_extent(&self) -> vk::Extent2D {
+        self.window_extent
+    }
+
+    //Empty while the swapchain was created minimized and has no images yet
+    #[inline]
+    pub fn image_usage(&self) -> vk::ImageUsageFlags {
+        self.swapchain_images.image_usage
+    }
+
+    #[inline]
+    pub fn composite_alpha(&self) -> vk::CompositeAlphaFlagsKHR {
+        Self::select_composite_alpha(&self.surface_capabilities.surface_capabilities, self.desc.composite_alpha)
+    }
+
+    #[inline]
+    pub fn pre_transform(&self) -> vk::SurfaceTransformFlagsKHR {
+        self.pre_transform
+    }
+
+    //Column major 2x2 rotation to apply to clip space xy so the image appears upright after the pre-transform
+    pub fn pre_rotation(&self) -> [[f32; 2]; 2] {
+        if self.pre_transform.intersects(vk::SurfaceTransformFlagsKHR::ROTATE_90 | vk::SurfaceTransformFlagsKHR::HORIZONTAL_MIRROR_ROTATE_90) {
+            [[0.0, 1.0], [-1.0, 0.0]]
+        } else if self.pre_transform.intersects(vk::SurfaceTransformFlagsKHR::ROTATE_180 | vk::SurfaceTransformFlagsKHR::HORIZONTAL_MIRROR_ROTATE_180) {
+            [[-1.0, 0.0], [0.0, -1.0]]
+        } else if self.pre_transform.intersects(vk::SurfaceTransformFlagsKHR::ROTATE_270 | vk::SurfaceTransformFlagsKHR::HORIZONTAL_MIRROR_ROTATE_270) {
+            [[0.0, -1.0], [1.0, 0.0]]
+        } else {
+            [[1.0, 0.0], [0.0, 1.0]]
+        }
     }
 
     #[inline]
     pub fn swapchain(&self) -> &vk::SwapchainKHR {
         &self.swapchain
     }
+
+    #[inline]
+    pub fn current_frame(&self) -> usize {
+        self.current_frame.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn image_count(&self) -> usize {
+        self.swapchain_images.images.len()
+    }
+
+    #[inline]
+    pub fn frames_in_flight(&self) -> usize {
+        self.frame_syncs.len()
+    }
+
+    #[inline]
+    pub fn image_available_semaphore(&self) -> &vk::Semaphore {
+        self.frame_syncs[self.current_frame()].image_available_semaphore.semaphore()
+    }
+
+    #[inline]
+    pub fn render_finished_semaphore(&self) -> &vk::Semaphore {
+        self.frame_syncs[self.current_frame()].render_finished_semaphore.semaphore()
+    }
+
+    #[inline]
+    pub fn in_flight_fence(&self) -> &Fence {
+        &self.frame_syncs[self.current_frame()].in_flight_fence
+    }
 }
 
 impl Drop for Swapchain {
     #[inline]
     fn drop(&mut self) {
-        unsafe {
-            let device_loader = self.device.loader();
+        self.device.assert_generation(self.device_generation);
 
-            self.framebuffers.iter().for_each(|framebuffer| device_loader.destroy_framebuffer(*framebuffer, None));
-            self.image_views.iter().for_each(|image_view| device_loader.destroy_image_view(*image_view, None));
+        unsafe {
+            self.swapchain_images.destroy(&self.device);
 
             self.device.swapchain_loader().destroy_swapchain(self.swapchain, None);
 
-            device_loader.destroy_render_pass(self.render_pass, None);
+            self.device.loader().destroy_render_pass(self.render_pass, None);
+        }
+    }
+}
+
+//Copies the image into a new buffer and returns it to layout afterwards.
+//released_to is the queue the direct queue released the image to, it is taken back for the copy and released to that queue again
+pub(crate) fn capture_image(
+    device: &Arc<Device>,
+    image: vk::Image,
+    layout: vk::ImageLayout,
+    extent: vk::Extent2D,
+    format: vk::Format,
+    released_to: Option<&Queue>
+) -> Result<ImageData> {
+    let (format, bytes_per_pixel, swizzle) = match format {
+        vk::Format::R8G8B8A8_SRGB | vk::Format::R8G8B8A8_UNORM => (format, 4, false),
+        vk::Format::B8G8R8A8_SRGB => (vk::Format::R8G8B8A8_SRGB, 4, true),
+        vk::Format::B8G8R8A8_UNORM => (vk::Format::R8G8B8A8_UNORM, 4, true),
+        vk::Format::R16G16B16A16_SFLOAT => (vk::Format::R16G16B16A16_SFLOAT, 8, false),
+        format => bail!("Capturing frames in {:?} is not supported", format)
+    };
+
+    let size = extent.width as vk::DeviceSize * extent.height as vk::DeviceSize * bytes_per_pixel;
+
+    let buffer = Buffer::new(device.clone(), &BufferDesc::new_gpu_to_cpu(size, vk::BufferUsageFlags::TRANSFER_DST))?;
+
+    let device_loader = device.loader();
+    let subresource_range = vk::ImageSubresourceRange::default().aspect_mask(vk::ImageAspectFlags::COLOR).level_count(1).layer_count(1);
+
+    let direct_family_index = device.direct_queue().family_index();
+    let ownership_transfer = |src_family_index, dst_family_index| {
+        vk::ImageMemoryBarrier::default()
+            .old_layout(layout)
+            .new_layout(layout)
+            .src_queue_family_index(src_family_index)
+            .dst_queue_family_index(dst_family_index)
+            .image(image)
+            .subresource_range(subresource_range)
+    };
+    let cmd_ownership_transfer = |command_buffer, barrier: vk::ImageMemoryBarrier, src_stage_mask, dst_stage_mask| unsafe {
+        device_loader.cmd_pipeline_barrier(command_buffer, src_stage_mask, dst_stage_mask, vk::DependencyFlags::empty(), &[], &[], slice::from_ref(&barrier));
+    };
+
+    //The owning queue acquires the image and immediately releases it back to the direct queue
+    if let Some(queue) = released_to {
+        device.submit_immediate(queue, |command_buffer| {
+            let family_index = queue.family_index();
+
+            cmd_ownership_transfer(
+                command_buffer,
+                ownership_transfer(direct_family_index, family_index),
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                vk::PipelineStageFlags::BOTTOM_OF_PIPE
+            );
+            cmd_ownership_transfer(
+                command_buffer,
+                ownership_transfer(family_index, direct_family_index),
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                vk::PipelineStageFlags::BOTTOM_OF_PIPE
+            );
+        })?;
+    }
+
+    device.submit_immediate(device.direct_queue(), |command_buffer| unsafe {
+        if let Some(queue) = released_to {
+            cmd_ownership_transfer(
+                command_buffer,
+                ownership_transfer(queue.family_index(), direct_family_index),
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                vk::PipelineStageFlags::TRANSFER
+            );
         }
+
+        let to_transfer_src = vk::ImageMemoryBarrier::default()
+            .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+            .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
+            .old_layout(layout)
+            .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(image)
+            .subresource_range(subresource_range);
+
+        device_loader.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::ALL_COMMANDS,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            slice::from_ref(&to_transfer_src)
+        );
+
+        let buffer_image_copy = vk::BufferImageCopy::default()
+            .image_subresource(vk::ImageSubresourceLayers::default().aspect_mask(vk::ImageAspectFlags::COLOR).layer_count(1))
+            .image_extent(vk::Extent3D {
+                width: extent.width,
+                height: extent.height,
+                depth: 1
+            });
+        device_loader.cmd_copy_image_to_buffer(
+            command_buffer,
+            image,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            *buffer.buffer(),
+            slice::from_ref(&buffer_image_copy)
+        );
+
+        let to_original_layout = vk::ImageMemoryBarrier::default()
+            .src_access_mask(vk::AccessFlags::TRANSFER_READ)
+            .old_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+            .new_layout(layout)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(image)
+            .subresource_range(subresource_range);
+
+        device_loader.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            slice::from_ref(&to_original_layout)
+        );
+
+        if let Some(queue) = released_to {
+            cmd_ownership_transfer(
+                command_buffer,
+                ownership_transfer(direct_family_index, queue.family_index()),
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::BOTTOM_OF_PIPE
+            );
+        }
+    })?;
+
+    let mut bytes = buffer.read_bytes()?;
+    if swizzle {
+        bytes.chunks_exact_mut(4).for_each(|pixel| pixel.swap(0, 2));
+    }
+
+    Ok(ImageData {
+        width: extent.width,
+        height: extent.height,
+        format,
+        bytes
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::test_device;
+
+    fn capabilities(current_extent: vk::Extent2D) -> vk::SurfaceCapabilitiesKHR {
+        vk::SurfaceCapabilitiesKHR {
+            current_extent,
+            min_image_extent: vk::Extent2D { width: 1, height: 200 },
+            max_image_extent: vk::Extent2D { width: 2048, height: 2048 },
+            ..Default::default()
+        }
+    }
+
+    const UNDEFINED_EXTENT: vk::Extent2D = vk::Extent2D {
+        width: u32::MAX,
+        height: u32::MAX
+    };
+
+    #[test]
+    fn selected_image_usage_keeps_color_attachment() {
+        let surface_capabilities = vk::SurfaceCapabilitiesKHR {
+            supported_usage_flags: vk::ImageUsageFlags::TRANSFER_SRC,
+            ..Default::default()
+        };
+
+        let image_usage = Swapchain::select_image_usage(&surface_capabilities, vk::ImageUsageFlags::TRANSFER_SRC | vk::ImageUsageFlags::STORAGE);
+        assert_eq!(image_usage, vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC);
+    }
+
+    #[test]
+    fn undefined_extent_clamps_window_size() {
+        let extent = Swapchain::swapchain_extent(&capabilities(UNDEFINED_EXTENT), vk::SurfaceTransformFlagsKHR::IDENTITY, vk::Extent2D { width: 4000, height: 100 });
+
+        assert_eq!(extent, vk::Extent2D { width: 2048, height: 200 });
+    }
+
+    #[test]
+    fn minimized_window_keeps_zero_extent() {
+        let extent = Swapchain::swapchain_extent(&capabilities(UNDEFINED_EXTENT), vk::SurfaceTransformFlagsKHR::IDENTITY, vk::Extent2D { width: 0, height: 600 });
+
+        assert_eq!(extent, vk::Extent2D::default());
+    }
+
+    #[test]
+    fn current_extent_is_swapped_when_rotated() {
+        let current_extent = vk::Extent2D { width: 800, height: 600 };
+
+        let extent = Swapchain::swapchain_extent(&capabilities(current_extent), vk::SurfaceTransformFlagsKHR::IDENTITY, vk::Extent2D { width: 1, height: 1 });
+        assert_eq!(extent, current_extent);
+
+        let extent = Swapchain::swapchain_extent(&capabilities(current_extent), vk::SurfaceTransformFlagsKHR::ROTATE_90, vk::Extent2D { width: 1, height: 1 });
+        assert_eq!(extent, vk::Extent2D { width: 600, height: 800 });
+    }
+
+    //Swapchains need a window, so captures use an offscreen BGRA image like a typical swapchain image, cleared to red and left in GENERAL
+    fn red_image(device: &Arc<Device>, extent: vk::Extent2D) -> Image {
+        let image = Image::new(
+            device.clone(),
+            &ImageDesc::new_2d(extent, vk::Format::B8G8R8A8_UNORM, vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::TRANSFER_SRC)
+        )
+        .unwrap();
+        let subresource_range = vk::ImageSubresourceRange::default().aspect_mask(vk::ImageAspectFlags::COLOR).level_count(1).layer_count(1);
+
+        device
+            .submit_immediate(device.direct_queue(), |command_buffer| unsafe {
+                let barrier = |old_layout, new_layout, src_access_mask, dst_access_mask| {
+                    vk::ImageMemoryBarrier::default()
+                        .src_access_mask(src_access_mask)
+                        .dst_access_mask(dst_access_mask)
+                        .old_layout(old_layout)
+                        .new_layout(new_layout)
+                        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .image(*image.image())
+                        .subresource_range(subresource_range)
+                };
+
+                let to_transfer_dst = barrier(vk::ImageLayout::UNDEFINED, vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::AccessFlags::empty(), vk::AccessFlags::TRANSFER_WRITE);
+                device.loader().cmd_pipeline_barrier(
+                    command_buffer,
+                    vk::PipelineStageFlags::TOP_OF_PIPE,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &[],
+                    slice::from_ref(&to_transfer_dst)
+                );
+
+                let clear_color = vk::ClearColorValue { float32: [1.0, 0.0, 0.0, 1.0] };
+                device.loader().cmd_clear_color_image(
+                    command_buffer,
+                    *image.image(),
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    &clear_color,
+                    slice::from_ref(&subresource_range)
+                );
+
+                let to_general = barrier(vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::ImageLayout::GENERAL, vk::AccessFlags::TRANSFER_WRITE, vk::AccessFlags::TRANSFER_READ);
+                device.loader().cmd_pipeline_barrier(
+                    command_buffer,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &[],
+                    slice::from_ref(&to_general)
+                );
+            })
+            .unwrap();
+
+        image
+    }
+
+    #[test]
+    fn captured_pixels_match_the_clear_color() {
+        let device = match test_device() {
+            Some(device) => device,
+            None => return
+        };
+
+        let extent = vk::Extent2D { width: 2, height: 2 };
+        let image = red_image(&device, extent);
+
+        let image_data = capture_image(&device, *image.image(), vk::ImageLayout::GENERAL, extent, vk::Format::B8G8R8A8_UNORM, None).unwrap();
+
+        assert_eq!(image_data.format, vk::Format::R8G8B8A8_UNORM);
+        assert_eq!((image_data.width, image_data.height), (2, 2));
+        assert!(image_data.bytes.chunks_exact(4).all(|pixel| pixel == [255u8, 0, 0, 255]));
+    }
+
+    #[test]
+    fn capture_takes_back_an_image_released_to_another_family() {
+        //Stands in for a split present queue, which headless devices don't have
+        let device = match test_device() {
+            Some(device) if device.transfer_queue().family_index() != device.direct_queue().family_index() => device,
+            _ => return
+        };
+
+        let extent = vk::Extent2D { width: 2, height: 2 };
+        let image = red_image(&device, extent);
+        let subresource_range = vk::ImageSubresourceRange::default().aspect_mask(vk::ImageAspectFlags::COLOR).level_count(1).layer_count(1);
+
+        //Released like cmd_release_to_present_queue does at the end of a frame
+        let release = vk::ImageMemoryBarrier::default()
+            .old_layout(vk::ImageLayout::GENERAL)
+            .new_layout(vk::ImageLayout::GENERAL)
+            .src_queue_family_index(device.direct_queue().family_index())
+            .dst_queue_family_index(device.transfer_queue().family_index())
+            .image(*image.image())
+            .subresource_range(subresource_range);
+        device
+            .submit_immediate(device.direct_queue(), |command_buffer| unsafe {
+                device.loader().cmd_pipeline_barrier(
+                    command_buffer,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &[],
+                    slice::from_ref(&release)
+                );
+            })
+            .unwrap();
+
+        let image_data = capture_image(&device, *image.image(), vk::ImageLayout::GENERAL, extent, vk::Format::B8G8R8A8_UNORM, Some(device.transfer_queue())).unwrap();
+        assert!(image_data.bytes.chunks_exact(4).all(|pixel| pixel == [255u8, 0, 0, 255]));
+
+        //Ownership went back to the transfer family, which can acquire it like the present queue would
+        let acquire = release;
+        device
+            .submit_immediate(device.transfer_queue(), |command_buffer| unsafe {
+                device.loader().cmd_pipeline_barrier(
+                    command_buffer,
+                    vk::PipelineStageFlags::TOP_OF_PIPE,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &[],
+                    slice::from_ref(&acquire)
+                );
+            })
+            .unwrap();
     }
 }
@@ -0,0 +1,371 @@
+use std::{slice, sync::Arc};
+
+use ash::{prelude::VkResult, vk};
+
+use crate::backend::{Device, DeviceGeneration};
+
+pub struct Fence {
+    fence: vk::Fence,
+
+    device_generation: DeviceGeneration,
+    device: Arc<Device>
+}
+
+impl Fence {
+    pub fn new(device: Arc<Device>, signaled: bool) -> VkResult<Self> {
+        let flags = if signaled { vk::FenceCreateFlags::SIGNALED } else { vk::FenceCreateFlags::empty() };
+        let fence_create_info = vk::FenceCreateInfo::default().flags(flags);
+
+        let fence = unsafe { device.loader().create_fence(&fence_create_info, None)? };
+
+        Ok(Self {
+            fence,
+
+            device_generation: device.generation(),
+            device
+        })
+    }
+
+    #[inline]
+    pub fn wait(&self, timeout: u64) -> VkResult<()> {
+        unsafe { self.device.loader().wait_for_fences(slice::from_ref(&self.fence), true, timeout) }
+    }
+
+    pub fn wait_all(fences: &[&Fence], timeout: u64) -> VkResult<()> {
+        let device = match fences.first() {
+            Some(fence) => &fence.device,
+            None => return Ok(())
+        };
+
+        let fences: Vec<_> = fences.iter().map(|fence| fence.fence).collect();
+
+        unsafe { device.loader().wait_for_fences(&fences, true, timeout) }
+    }
+
+    #[inline]
+    pub fn reset(&self) -> VkResult<()> {
+        unsafe { self.device.loader().reset_fences(slice::from_ref(&self.fence)) }
+    }
+
+    #[inline]
+    pub fn is_signaled(&self) -> VkResult<bool> {
+        unsafe { self.device.loader().get_fence_status(self.fence) }
+    }
+
+    #[inline]
+    pub fn fence(&self) -> &vk::Fence {
+        &self.fence
+    }
+}
+
+impl Drop for Fence {
+    #[inline]
+    fn drop(&mut self) {
+        self.device.assert_generation(self.device_generation);
+
+        unsafe {
+            self.device.loader().destroy_fence(self.fence, None);
+        }
+    }
+}
+
+pub struct Semaphore {
+    semaphore: vk::Semaphore,
+
+    device_generation: DeviceGeneration,
+    device: Arc<Device>
+}
+
+impl Semaphore {
+    pub fn new(device: Arc<Device>) -> VkResult<Self> {
+        let semaphore_create_info = vk::SemaphoreCreateInfo::default();
+
+        let semaphore = unsafe { device.loader().create_semaphore(&semaphore_create_info, None)? };
+
+        Ok(Self {
+            semaphore,
+
+            device_generation: device.generation(),
+            device
+        })
+    }
+
+    #[inline]
+    pub fn semaphore(&self) -> &vk::Semaphore {
+        &self.semaphore
+    }
+}
+
+impl Drop for Semaphore {
+    #[inline]
+    fn drop(&mut self) {
+        self.device.assert_generation(self.device_generation);
+
+        unsafe {
+            self.device.loader().destroy_semaphore(self.semaphore, None);
+        }
+    }
+}
+
+pub struct TimelineSemaphore {
+    semaphore: vk::Semaphore,
+
+    device_generation: DeviceGeneration,
+    device: Arc<Device>
+}
+
+impl TimelineSemaphore {
+    pub fn new(device: Arc<Device>, initial_value: u64) -> VkResult<Self> {
+        if !device.supports_timeline_semaphores() {
+            return Err(vk::Result::ERROR_FEATURE_NOT_PRESENT)
+        }
+
+        let mut semaphore_type_create_info = vk::SemaphoreTypeCreateInfo::default()
+            .semaphore_type(vk::SemaphoreType::TIMELINE)
+            .initial_value(initial_value);
+        let semaphore_create_info = vk::SemaphoreCreateInfo::default().push_next(&mut semaphore_type_create_info);
+
+        let semaphore = unsafe { device.loader().create_semaphore(&semaphore_create_info, None)? };
+
+        Ok(Self {
+            semaphore,
+
+            device_generation: device.generation(),
+            device
+        })
+    }
+
+    #[inline]
+    pub fn signal_value(&self, value: u64) -> VkResult<()> {
+        let semaphore_signal_info = vk::SemaphoreSignalInfo::default().semaphore(self.semaphore).value(value);
+
+        self.device.signal_semaphore(&semaphore_signal_info)
+    }
+
+    #[inline]
+    pub fn wait(&self, value: u64, timeout: u64) -> VkResult<()> {
+        let semaphore_wait_info = vk::SemaphoreWaitInfo::default()
+            .semaphores(slice::from_ref(&self.semaphore))
+            .values(slice::from_ref(&value));
+
+        self.device.wait_semaphores(&semaphore_wait_info, timeout)
+    }
+
+    #[inline]
+    pub fn current_value(&self) -> VkResult<u64> {
+        self.device.get_semaphore_counter_value(self.semaphore)
+    }
+
+    #[inline]
+    pub fn semaphore(&self) -> &vk::Semaphore {
+        &self.semaphore
+    }
+}
+
+impl Drop for TimelineSemaphore {
+    #[inline]
+    fn drop(&mut self) {
+        self.device.assert_generation(self.device_generation);
+
+        unsafe {
+            self.device.loader().destroy_semaphore(self.semaphore, None);
+        }
+    }
+}
+
+#[inline]
+fn legacy_stage_mask(stage_mask: vk::PipelineStageFlags2, empty: vk::PipelineStageFlags) -> vk::PipelineStageFlags {
+    let mut legacy = vk::PipelineStageFlags::from_raw(stage_mask.as_raw() as u32);
+
+    if stage_mask.intersects(vk::PipelineStageFlags2::COPY | vk::PipelineStageFlags2::RESOLVE | vk::PipelineStageFlags2::BLIT | vk::PipelineStageFlags2::CLEAR) {
+        legacy |= vk::PipelineStageFlags::TRANSFER;
+    }
+    if stage_mask.intersects(vk::PipelineStageFlags2::INDEX_INPUT | vk::PipelineStageFlags2::VERTEX_ATTRIBUTE_INPUT) {
+        legacy |= vk::PipelineStageFlags::VERTEX_INPUT;
+    }
+    if stage_mask.contains(vk::PipelineStageFlags2::PRE_RASTERIZATION_SHADERS) {
+        legacy |= vk::PipelineStageFlags::VERTEX_SHADER
+            | vk::PipelineStageFlags::TESSELLATION_CONTROL_SHADER
+            | vk::PipelineStageFlags::TESSELLATION_EVALUATION_SHADER
+            | vk::PipelineStageFlags::GEOMETRY_SHADER;
+    }
+
+    if legacy.is_empty() {
+        empty
+    } else {
+        legacy
+    }
+}
+
+#[inline]
+fn legacy_access_mask(access_mask: vk::AccessFlags2) -> vk::AccessFlags {
+    let mut legacy = vk::AccessFlags::from_raw(access_mask.as_raw() as u32);
+
+    if access_mask.intersects(vk::AccessFlags2::SHADER_SAMPLED_READ | vk::AccessFlags2::SHADER_STORAGE_READ) {
+        legacy |= vk::AccessFlags::SHADER_READ;
+    }
+    if access_mask.contains(vk::AccessFlags2::SHADER_STORAGE_WRITE) {
+        legacy |= vk::AccessFlags::SHADER_WRITE;
+    }
+
+    legacy
+}
+
+//Recorded with vkCmdPipelineBarrier2 if VK_KHR_synchronization2 or Vulkan 1.3 is available, see Device::cmd_pipeline_barrier2
+#[derive(Clone, Debug, Default)]
+pub struct BarrierBuilder {
+    memory_barriers: Vec<vk::MemoryBarrier2<'static>>,
+    buffer_memory_barriers: Vec<vk::BufferMemoryBarrier2<'static>>,
+    image_memory_barriers: Vec<vk::ImageMemoryBarrier2<'static>>,
+    dependency_flags: vk::DependencyFlags
+}
+
+impl BarrierBuilder {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[inline]
+    pub fn memory(mut self, memory_barrier: vk::MemoryBarrier2<'static>) -> Self {
+        self.memory_barriers.push(memory_barrier);
+        self
+    }
+
+    #[inline]
+    pub fn buffer(mut self, buffer_memory_barrier: vk::BufferMemoryBarrier2<'static>) -> Self {
+        self.buffer_memory_barriers.push(buffer_memory_barrier);
+        self
+    }
+
+    #[inline]
+    pub fn image(mut self, image_memory_barrier: vk::ImageMemoryBarrier2<'static>) -> Self {
+        self.image_memory_barriers.push(image_memory_barrier);
+        self
+    }
+
+    #[inline]
+    pub fn dependency_flags(mut self, dependency_flags: vk::DependencyFlags) -> Self {
+        self.dependency_flags = dependency_flags;
+        self
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.memory_barriers.is_empty() && self.buffer_memory_barriers.is_empty() && self.image_memory_barriers.is_empty()
+    }
+
+    #[inline]
+    pub fn dependency_info(&self) -> vk::DependencyInfo<'_> {
+        vk::DependencyInfo::default()
+            .dependency_flags(self.dependency_flags)
+            .memory_barriers(&self.memory_barriers)
+            .buffer_memory_barriers(&self.buffer_memory_barriers)
+            .image_memory_barriers(&self.image_memory_barriers)
+    }
+
+    //Legacy barriers share one pair of stage masks, so the masks of all barriers are combined
+    pub(crate) unsafe fn cmd_pipeline_barrier_legacy(&self, device: &ash::Device, command_buffer: vk::CommandBuffer) {
+        let mut src_stage_mask = vk::PipelineStageFlags2::empty();
+        let mut dst_stage_mask = vk::PipelineStageFlags2::empty();
+
+        let memory_barriers: Vec<_> = self
+            .memory_barriers
+            .iter()
+            .map(|barrier| {
+                src_stage_mask |= barrier.src_stage_mask;
+                dst_stage_mask |= barrier.dst_stage_mask;
+
+                vk::MemoryBarrier::default()
+                    .src_access_mask(legacy_access_mask(barrier.src_access_mask))
+                    .dst_access_mask(legacy_access_mask(barrier.dst_access_mask))
+            })
+            .collect();
+
+        let buffer_memory_barriers: Vec<_> = self
+            .buffer_memory_barriers
+            .iter()
+            .map(|barrier| {
+                src_stage_mask |= barrier.src_stage_mask;
+                dst_stage_mask |= barrier.dst_stage_mask;
+
+                vk::BufferMemoryBarrier::default()
+                    .src_access_mask(legacy_access_mask(barrier.src_access_mask))
+                    .dst_access_mask(legacy_access_mask(barrier.dst_access_mask))
+                    .src_queue_family_index(barrier.src_queue_family_index)
+                    .dst_queue_family_index(barrier.dst_queue_family_index)
+                    .buffer(barrier.buffer)
+                    .offset(barrier.offset)
+                    .size(barrier.size)
+            })
+            .collect();
+
+        let image_memory_barriers: Vec<_> = self
+            .image_memory_barriers
+            .iter()
+            .map(|barrier| {
+                src_stage_mask |= barrier.src_stage_mask;
+                dst_stage_mask |= barrier.dst_stage_mask;
+
+                vk::ImageMemoryBarrier::default()
+                    .src_access_mask(legacy_access_mask(barrier.src_access_mask))
+                    .dst_access_mask(legacy_access_mask(barrier.dst_access_mask))
+                    .old_layout(barrier.old_layout)
+                    .new_layout(barrier.new_layout)
+                    .src_queue_family_index(barrier.src_queue_family_index)
+                    .dst_queue_family_index(barrier.dst_queue_family_index)
+                    .image(barrier.image)
+                    .subresource_range(barrier.subresource_range)
+            })
+            .collect();
+
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            legacy_stage_mask(src_stage_mask, vk::PipelineStageFlags::TOP_OF_PIPE),
+            legacy_stage_mask(dst_stage_mask, vk::PipelineStageFlags::BOTTOM_OF_PIPE),
+            self.dependency_flags,
+            &memory_barriers,
+            &buffer_memory_barriers,
+            &image_memory_barriers
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::test_device;
+
+    #[test]
+    fn signaled_fence_wait_returns_immediately() {
+        let device = match test_device() {
+            Some(device) => device,
+            None => return
+        };
+
+        //A zero timeout only succeeds if the fence is already signaled
+        let fence = Fence::new(device, true).unwrap();
+        assert_eq!(fence.wait(0), Ok(()));
+        assert_eq!(fence.is_signaled(), Ok(true));
+
+        fence.reset().unwrap();
+        assert_eq!(fence.wait(0), Err(vk::Result::TIMEOUT));
+    }
+
+    #[test]
+    fn timeline_semaphore_counts_signaled_values() {
+        let device = match test_device() {
+            Some(device) => device,
+            None => return
+        };
+        //Core on Vulkan 1.2 devices even without VK_KHR_timeline_semaphore
+        if !device.supports_timeline_semaphores() {
+            return
+        }
+
+        let semaphore = TimelineSemaphore::new(device, 1).unwrap();
+        semaphore.signal_value(2).unwrap();
+        assert_eq!(semaphore.wait(2, 0), Ok(()));
+        assert_eq!(semaphore.current_value(), Ok(2));
+    }
+}
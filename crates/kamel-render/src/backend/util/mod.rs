@@ -1 +1,2 @@
 pub mod message_severity;
+pub mod string;
@@ -0,0 +1,8 @@
+use std::os::raw::c_char;
+
+#[inline]
+pub fn from_c_char_array(chars: &[c_char]) -> String {
+    let bytes: Vec<_> = chars.iter().take_while(|c| **c != 0).map(|c| *c as u8).collect();
+
+    String::from_utf8_lossy(&bytes).into_owned()
+}
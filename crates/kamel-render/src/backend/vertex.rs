@@ -0,0 +1,114 @@
+use std::mem;
+
+use ash::vk;
+use bytemuck::{Pod, Zeroable};
+
+//Sizes of the formats commonly used for vertex attributes, None for everything else
+fn vertex_format_size(format: vk::Format) -> Option<u32> {
+    match format {
+        vk::Format::R8G8B8A8_UNORM | vk::Format::R8G8B8A8_SNORM | vk::Format::R8G8B8A8_UINT | vk::Format::R8G8B8A8_SINT => Some(4),
+        vk::Format::R16G16_SFLOAT | vk::Format::R16G16_UNORM | vk::Format::R16G16_SNORM => Some(4),
+        vk::Format::R16G16B16A16_SFLOAT | vk::Format::R16G16B16A16_UNORM | vk::Format::R16G16B16A16_SNORM => Some(8),
+        vk::Format::R32_SFLOAT | vk::Format::R32_UINT | vk::Format::R32_SINT => Some(4),
+        vk::Format::R32G32_SFLOAT | vk::Format::R32G32_UINT | vk::Format::R32G32_SINT => Some(8),
+        vk::Format::R32G32B32_SFLOAT | vk::Format::R32G32B32_UINT | vk::Format::R32G32B32_SINT => Some(12),
+        vk::Format::R32G32B32A32_SFLOAT | vk::Format::R32G32B32A32_UINT | vk::Format::R32G32B32A32_SINT => Some(16),
+        _ => None
+    }
+}
+
+//e.g. VertexLayout::new().binding(32).attr(0, vk::Format::R32G32B32_SFLOAT, 0).attr(1, vk::Format::R32G32_SFLOAT, 12)
+//Attributes belong to the most recently added binding
+#[derive(Clone, Debug, Default)]
+pub struct VertexLayout {
+    bindings: Vec<vk::VertexInputBindingDescription>,
+    attributes: Vec<vk::VertexInputAttributeDescription>
+}
+
+impl VertexLayout {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[inline]
+    pub fn binding(self, stride: u32) -> Self {
+        self.binding_with_rate(stride, vk::VertexInputRate::VERTEX)
+    }
+
+    #[inline]
+    pub fn instance_binding(self, stride: u32) -> Self {
+        self.binding_with_rate(stride, vk::VertexInputRate::INSTANCE)
+    }
+
+    pub fn binding_with_rate(mut self, stride: u32, input_rate: vk::VertexInputRate) -> Self {
+        let binding = self.bindings.len() as u32;
+
+        self.bindings.push(vk::VertexInputBindingDescription::default().binding(binding).stride(stride).input_rate(input_rate));
+        self
+    }
+
+    pub fn attr(mut self, location: u32, format: vk::Format, offset: u32) -> Self {
+        let binding = self.bindings.last().expect("VertexLayout::attr called before VertexLayout::binding");
+
+        let end = offset + vertex_format_size(format).unwrap_or(1);
+        assert!(
+            end <= binding.stride,
+            "Vertex attribute at location {} ({:?} at offset {}) exceeds the stride {} of binding {}",
+            location,
+            format,
+            offset,
+            binding.stride,
+            binding.binding
+        );
+        assert!(
+            self.attributes.iter().all(|attribute| attribute.location != location),
+            "Vertex attribute location {} is used more than once",
+            location
+        );
+
+        self.attributes.push(
+            vk::VertexInputAttributeDescription::default()
+                .location(location)
+                .binding(binding.binding)
+                .format(format)
+                .offset(offset)
+        );
+        self
+    }
+
+    #[inline]
+    pub fn bindings(&self) -> &[vk::VertexInputBindingDescription] {
+        &self.bindings
+    }
+
+    #[inline]
+    pub fn attributes(&self) -> &[vk::VertexInputAttributeDescription] {
+        &self.attributes
+    }
+}
+
+pub trait Vertex: Pod {
+    fn layout() -> VertexLayout;
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct StandardVertex {
+    pub pos: [f32; 3],
+    pub normal: [f32; 3],
+    pub uv: [f32; 2]
+}
+
+unsafe impl Zeroable for StandardVertex {}
+unsafe impl Pod for StandardVertex {}
+
+impl Vertex for StandardVertex {
+    fn layout() -> VertexLayout {
+        VertexLayout::new()
+            .binding(mem::size_of::<Self>() as u32)
+            .attr(0, vk::Format::R32G32B32_SFLOAT, 0)
+            .attr(1, vk::Format::R32G32B32_SFLOAT, 12)
+            .attr(2, vk::Format::R32G32_SFLOAT, 24)
+    }
+}
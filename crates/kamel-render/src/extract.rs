@@ -0,0 +1,66 @@
+use kamel_bevy::{app::App, ecs::world::World};
+
+use crate::{time::FrameTimings, RenderApp};
+
+pub trait ExtractResource: Send + Sync + 'static {
+    fn extract_resource(main: &Self) -> Self;
+}
+
+pub fn extract_resource<R: ExtractResource>(main_world: &World, render_world: &mut World) {
+    if let Some(resource) = main_world.get_resource::<R>() {
+        render_world.insert_resource(R::extract_resource(resource));
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct ResourceExtractors(pub Vec<fn(&World, &mut World)>);
+
+impl ResourceExtractors {
+    pub fn run(main_world: &World, render_world: &mut World) {
+        let extractors = render_world.resource::<Self>().0.clone();
+
+        for extractor in extractors {
+            extractor(main_world, render_world);
+        }
+    }
+}
+
+pub trait AddExtractResource {
+    fn add_extract_resource<R: ExtractResource>(&mut self) -> &mut Self;
+}
+
+impl AddExtractResource for App {
+    fn add_extract_resource<R: ExtractResource>(&mut self) -> &mut Self {
+        self.sub_app_mut(RenderApp)
+            .world
+            .get_resource_or_insert_with(ResourceExtractors::default)
+            .0
+            .push(extract_resource::<R>);
+
+        self
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ClearColor(pub [f32; 4]);
+
+impl Default for ClearColor {
+    #[inline]
+    fn default() -> Self {
+        Self([0.392, 0.584, 0.929, 1.0])
+    }
+}
+
+impl ExtractResource for ClearColor {
+    #[inline]
+    fn extract_resource(main: &Self) -> Self {
+        *main
+    }
+}
+
+impl ExtractResource for FrameTimings {
+    #[inline]
+    fn extract_resource(main: &Self) -> Self {
+        main.clone()
+    }
+}
@@ -1,16 +1,1116 @@
+use std::{collections::VecDeque, fmt::Write, mem, sync::Arc};
+
+use anyhow::{bail, Result};
+use ash::{prelude::VkResult, vk};
+
+use crate::backend::{
+    resource::{aspect_mask_from_format, Image, ImageDesc},
+    BarrierBuilder, Device, QueryPool
+};
+
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+pub struct ResourceHandle(usize);
+
+impl ResourceHandle {
+    #[inline]
+    pub fn index(&self) -> usize {
+        self.0
+    }
+}
+
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+pub struct BufferHandle(usize);
+
+impl BufferHandle {
+    #[inline]
+    pub fn index(&self) -> usize {
+        self.0
+    }
+}
+
+//Images and buffers the graph didn't allocate, e.g. the acquired swapchain image, the graph never destroys them
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+pub struct ImportedImage {
+    pub image: vk::Image,
+    pub view: vk::ImageView,
+    pub layout: vk::ImageLayout,
+    pub format: vk::Format
+}
+
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+pub struct ImportedBuffer {
+    pub buffer: vk::Buffer,
+    pub offset: vk::DeviceSize,
+    pub size: vk::DeviceSize
+}
+
+#[derive(Clone, Debug)]
+enum GraphImage {
+    Transient(ImageDesc),
+    Imported(ImportedImage)
+}
+
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+pub struct PassImage {
+    image: vk::Image,
+    view: vk::ImageView,
+    format: vk::Format
+}
+
+impl PassImage {
+    #[inline]
+    pub fn image(&self) -> &vk::Image {
+        &self.image
+    }
+
+    #[inline]
+    pub fn view(&self) -> &vk::ImageView {
+        &self.view
+    }
+
+    #[inline]
+    pub fn format(&self) -> vk::Format {
+        self.format
+    }
+}
+
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+pub enum ImageUsage {
+    Sampled,
+    ColorAttachment,
+    DepthAttachment,
+    Storage,
+    TransferSrc,
+    TransferDst,
+    Present
+}
+
+impl ImageUsage {
+    #[inline]
+    pub fn layout(self) -> vk::ImageLayout {
+        match self {
+            Self::Sampled => vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            Self::ColorAttachment => vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            Self::DepthAttachment => vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+            Self::Storage => vk::ImageLayout::GENERAL,
+            Self::TransferSrc => vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            Self::TransferDst => vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            Self::Present => vk::ImageLayout::PRESENT_SRC_KHR
+        }
+    }
+
+    #[inline]
+    pub fn access_mask(self) -> vk::AccessFlags {
+        match self {
+            Self::Sampled => vk::AccessFlags::SHADER_READ,
+            Self::ColorAttachment => vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+            Self::DepthAttachment => vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            Self::Storage => vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+            Self::TransferSrc => vk::AccessFlags::TRANSFER_READ,
+            Self::TransferDst => vk::AccessFlags::TRANSFER_WRITE,
+            Self::Present => vk::AccessFlags::empty()
+        }
+    }
+
+    #[inline]
+    pub fn stage_mask(self) -> vk::PipelineStageFlags {
+        match self {
+            Self::Sampled | Self::Storage => vk::PipelineStageFlags::VERTEX_SHADER | vk::PipelineStageFlags::FRAGMENT_SHADER | vk::PipelineStageFlags::COMPUTE_SHADER,
+            Self::ColorAttachment => vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            Self::DepthAttachment => vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+            Self::TransferSrc | Self::TransferDst => vk::PipelineStageFlags::TRANSFER,
+            Self::Present => vk::PipelineStageFlags::BOTTOM_OF_PIPE
+        }
+    }
+
+    #[inline]
+    pub fn is_write(self) -> bool {
+        !matches!(self, Self::Sampled | Self::TransferSrc | Self::Present)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+pub enum BufferUsage {
+    Vertex,
+    Index,
+    Indirect,
+    Uniform,
+    Storage,
+    TransferSrc,
+    TransferDst
+}
+
+impl BufferUsage {
+    #[inline]
+    pub fn access_mask(self) -> vk::AccessFlags {
+        match self {
+            Self::Vertex => vk::AccessFlags::VERTEX_ATTRIBUTE_READ,
+            Self::Index => vk::AccessFlags::INDEX_READ,
+            Self::Indirect => vk::AccessFlags::INDIRECT_COMMAND_READ,
+            Self::Uniform => vk::AccessFlags::UNIFORM_READ,
+            Self::Storage => vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+            Self::TransferSrc => vk::AccessFlags::TRANSFER_READ,
+            Self::TransferDst => vk::AccessFlags::TRANSFER_WRITE
+        }
+    }
+
+    #[inline]
+    pub fn stage_mask(self) -> vk::PipelineStageFlags {
+        match self {
+            Self::Vertex | Self::Index => vk::PipelineStageFlags::VERTEX_INPUT,
+            Self::Indirect => vk::PipelineStageFlags::DRAW_INDIRECT,
+            Self::Uniform | Self::Storage => vk::PipelineStageFlags::VERTEX_SHADER | vk::PipelineStageFlags::FRAGMENT_SHADER | vk::PipelineStageFlags::COMPUTE_SHADER,
+            Self::TransferSrc | Self::TransferDst => vk::PipelineStageFlags::TRANSFER
+        }
+    }
+
+    #[inline]
+    pub fn is_write(self) -> bool {
+        matches!(self, Self::Storage | Self::TransferDst)
+    }
+}
+
+//Returns None when no barrier is needed, i.e. two reads in the same layout
+pub fn image_transition(previous: Option<ImageUsage>, next: ImageUsage) -> Option<vk::ImageMemoryBarrier<'static>> {
+    match previous {
+        Some(previous) if previous == next && !next.is_write() => None,
+        Some(previous) => Some(transition_from(
+            previous.layout(),
+            if previous.is_write() { previous.access_mask() } else { vk::AccessFlags::empty() },
+            next
+        )),
+        None => Some(transition_from(vk::ImageLayout::UNDEFINED, vk::AccessFlags::empty(), next))
+    }
+}
+
+fn transition_from(old_layout: vk::ImageLayout, src_access_mask: vk::AccessFlags, next: ImageUsage) -> vk::ImageMemoryBarrier<'static> {
+    vk::ImageMemoryBarrier::default()
+        .src_access_mask(src_access_mask)
+        .dst_access_mask(next.access_mask())
+        .old_layout(old_layout)
+        .new_layout(next.layout())
+        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+}
+
+pub struct PassContext {
+    pub device: Arc<Device>,
+    pub command_buffer: vk::CommandBuffer,
+
+    images: Vec<PassImage>,
+    image_slots: Vec<usize>,
+    //Last usage per physical image, aliased handles share a slot
+    slot_states: Vec<Option<(ResourceHandle, ImageUsage)>>,
+    imported_layouts: Vec<Option<vk::ImageLayout>>,
+
+    buffers: Vec<ImportedBuffer>,
+    buffer_states: Vec<Option<BufferUsage>>
+}
+
+impl PassContext {
+    #[inline]
+    pub fn new(device: Arc<Device>) -> Self {
+        Self {
+            device,
+            command_buffer: vk::CommandBuffer::null(),
+
+            images: Vec::new(),
+            image_slots: Vec::new(),
+            slot_states: Vec::new(),
+            imported_layouts: Vec::new(),
+
+            buffers: Vec::new(),
+            buffer_states: Vec::new()
+        }
+    }
+
+    #[inline]
+    pub fn image(&self, handle: ResourceHandle) -> &PassImage {
+        &self.images[handle.0]
+    }
+
+    #[inline]
+    pub fn read_image(&mut self, handle: ResourceHandle) -> &PassImage {
+        self.access_image(handle, ImageUsage::Sampled)
+    }
+
+    #[inline]
+    pub fn write_image(&mut self, handle: ResourceHandle, usage: ImageUsage) -> &PassImage {
+        self.access_image(handle, usage)
+    }
+
+    //Records the barrier from the image's previous usage in this frame, call before recording commands that use it
+    pub fn access_image(&mut self, handle: ResourceHandle, usage: ImageUsage) -> &PassImage {
+        let slot = self.image_slots[handle.0];
+
+        //A different handle in the same slot is an alias whose contents are discarded
+        let (image_memory_barrier, src_stage_mask) = match self.slot_states[slot] {
+            Some((previous_handle, previous_usage)) if previous_handle == handle => (image_transition(Some(previous_usage), usage), previous_usage.stage_mask()),
+            Some((_, previous_usage)) => (image_transition(None, usage), previous_usage.stage_mask()),
+            //Imported images keep their contents and may have been written outside the graph
+            None => match self.imported_layouts[handle.0] {
+                Some(layout) => (Some(transition_from(layout, vk::AccessFlags::MEMORY_WRITE, usage)), vk::PipelineStageFlags::ALL_COMMANDS),
+                None => (image_transition(None, usage), vk::PipelineStageFlags::TOP_OF_PIPE)
+            }
+        };
+
+        if let Some(image_memory_barrier) = image_memory_barrier {
+            let image = &self.images[handle.0];
+            let image_memory_barrier = image_memory_barrier.image(image.image).subresource_range(
+                vk::ImageSubresourceRange::default()
+                    .aspect_mask(aspect_mask_from_format(image.format))
+                    .level_count(vk::REMAINING_MIP_LEVELS)
+                    .layer_count(vk::REMAINING_ARRAY_LAYERS)
+            );
+
+            let image_memory_barrier = vk::ImageMemoryBarrier2::default()
+                .src_stage_mask(vk::PipelineStageFlags2::from_raw(src_stage_mask.as_raw() as u64))
+                .src_access_mask(vk::AccessFlags2::from_raw(image_memory_barrier.src_access_mask.as_raw() as u64))
+                .dst_stage_mask(vk::PipelineStageFlags2::from_raw(usage.stage_mask().as_raw() as u64))
+                .dst_access_mask(vk::AccessFlags2::from_raw(image_memory_barrier.dst_access_mask.as_raw() as u64))
+                .old_layout(image_memory_barrier.old_layout)
+                .new_layout(image_memory_barrier.new_layout)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(image_memory_barrier.image)
+                .subresource_range(image_memory_barrier.subresource_range);
+
+            self.device.cmd_pipeline_barrier2(self.command_buffer, &BarrierBuilder::new().image(image_memory_barrier));
+        }
+
+        self.slot_states[slot] = Some((handle, usage));
+
+        &self.images[handle.0]
+    }
+
+    #[inline]
+    pub fn buffer(&self, handle: BufferHandle) -> &ImportedBuffer {
+        &self.buffers[handle.0]
+    }
+
+    //Like access_image, reads after reads don't need a barrier
+    pub fn access_buffer(&mut self, handle: BufferHandle, usage: BufferUsage) -> &ImportedBuffer {
+        let (src_access_mask, src_stage_mask) = match self.buffer_states[handle.0] {
+            Some(previous) if !previous.is_write() && !usage.is_write() => (None, previous.stage_mask()),
+            Some(previous) => (
+                Some(if previous.is_write() { previous.access_mask() } else { vk::AccessFlags::empty() }),
+                previous.stage_mask()
+            ),
+            None => (Some(vk::AccessFlags::MEMORY_WRITE), vk::PipelineStageFlags::ALL_COMMANDS)
+        };
+
+        if let Some(src_access_mask) = src_access_mask {
+            let buffer = &self.buffers[handle.0];
+            let buffer_memory_barrier = vk::BufferMemoryBarrier2::default()
+                .src_stage_mask(vk::PipelineStageFlags2::from_raw(src_stage_mask.as_raw() as u64))
+                .src_access_mask(vk::AccessFlags2::from_raw(src_access_mask.as_raw() as u64))
+                .dst_stage_mask(vk::PipelineStageFlags2::from_raw(usage.stage_mask().as_raw() as u64))
+                .dst_access_mask(vk::AccessFlags2::from_raw(usage.access_mask().as_raw() as u64))
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .buffer(buffer.buffer)
+                .offset(buffer.offset)
+                .size(buffer.size);
+
+            self.device.cmd_pipeline_barrier2(self.command_buffer, &BarrierBuilder::new().buffer(buffer_memory_barrier));
+        }
+
+        self.buffer_states[handle.0] = Some(usage);
+
+        &self.buffers[handle.0]
+    }
+}
+
+pub type RecordFn = Box<dyn FnMut(&mut PassContext) + Send + Sync>;
+
 pub struct RecordedPass {
     pub name: String,
-    pub index: usize
+    pub index: usize,
+    pub dependencies: Vec<usize>,
+    pub images: Vec<(ResourceHandle, ImageUsage)>,
+    pub buffers: Vec<(BufferHandle, BufferUsage)>,
+    pub record: RecordFn
 }
 
 impl RecordedPass {
-    fn new(name: impl Into<String>, index: usize) -> Self {
+    fn new(name: impl Into<String>, index: usize, record: RecordFn) -> Self {
         Self {
-            name: name.into(), index
+            name: name.into(),
+            index,
+            dependencies: Vec::new(),
+            images: Vec::new(),
+            buffers: Vec::new(),
+            record
         }
     }
 }
 
+#[derive(Default)]
 pub struct RenderGraph {
-    passes: Vec<RecordedPass>
+    passes: Vec<RecordedPass>,
+
+    images: Vec<GraphImage>,
+    outputs: Vec<ResourceHandle>,
+    buffers: Vec<ImportedBuffer>,
+    physical_images: Vec<Arc<Image>>,
+    //Physical images and query pools a graph change left unused, a frame in flight may still reference them
+    retired_images: Vec<Arc<Image>>,
+    retired_query_pools: Vec<QueryPool>,
+    resolved_images: Vec<PassImage>,
+    image_slots: Vec<usize>,
+
+    timestamps_enabled: bool,
+    timestamp_query_pool: Option<QueryPool>,
+    //Pass index per query pair, culled passes get no queries
+    timestamp_passes: Vec<usize>
+}
+
+impl RenderGraph {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_pass(&mut self, name: impl Into<String>, record: impl FnMut(&mut PassContext) + Send + Sync + 'static) -> usize {
+        let index = self.passes.len();
+        self.passes.push(RecordedPass::new(name, index, Box::new(record)));
+        self.resolved_images.clear();
+
+        index
+    }
+
+    pub fn add_dependency(&mut self, before: usize, after: usize) {
+        assert!(before < self.passes.len() && after < self.passes.len());
+
+        let dependencies = &mut self.passes[after].dependencies;
+        if !dependencies.contains(&before) {
+            dependencies.push(before);
+        }
+
+        self.resolved_images.clear();
+    }
+
+    pub fn create_transient_image(&mut self, desc: ImageDesc) -> ResourceHandle {
+        let handle = ResourceHandle(self.images.len());
+        self.images.push(GraphImage::Transient(desc));
+        self.resolved_images.clear();
+
+        handle
+    }
+
+    //The graph tracks the layout across executions, the first access transitions from current_layout
+    pub fn import_image(&mut self, image: vk::Image, view: vk::ImageView, current_layout: vk::ImageLayout, format: vk::Format) -> ResourceHandle {
+        let handle = ResourceHandle(self.images.len());
+        self.images.push(GraphImage::Imported(ImportedImage {
+            image,
+            view,
+            layout: current_layout,
+            format
+        }));
+        self.resolved_images.clear();
+
+        handle
+    }
+
+    pub fn import_buffer(&mut self, buffer: vk::Buffer, offset: vk::DeviceSize, size: vk::DeviceSize) -> BufferHandle {
+        let handle = BufferHandle(self.buffers.len());
+        self.buffers.push(ImportedBuffer { buffer, offset, size });
+
+        handle
+    }
+
+    #[inline]
+    pub fn imported_image(&self, handle: ResourceHandle) -> Option<&ImportedImage> {
+        match &self.images[handle.0] {
+            GraphImage::Imported(imported_image) => Some(imported_image),
+            GraphImage::Transient(_) => None
+        }
+    }
+
+    //Passes writing an output or an imported image are never culled, see linearize
+    pub fn mark_output(&mut self, handle: ResourceHandle) {
+        assert!(handle.0 < self.images.len());
+
+        if !self.outputs.contains(&handle) {
+            self.outputs.push(handle);
+        }
+
+        self.resolved_images.clear();
+    }
+
+    //Explicit dependencies plus an edge from the last earlier pass writing a resource to each pass reading it, in the order passes were added
+    fn pass_dependencies(&self) -> Vec<Vec<usize>> {
+        let mut image_writers = vec![None; self.images.len()];
+        let mut buffer_writers = vec![None; self.buffers.len()];
+
+        let mut dependencies = Vec::with_capacity(self.passes.len());
+        for pass in self.passes.iter() {
+            let mut pass_dependencies = pass.dependencies.clone();
+
+            //Storage usage reads as well as writes
+            let image_reads = pass.images.iter().filter(|(_, usage)| !usage.is_write() || *usage == ImageUsage::Storage);
+            let buffer_reads = pass.buffers.iter().filter(|(_, usage)| !usage.is_write() || *usage == BufferUsage::Storage);
+
+            let writers = image_reads
+                .map(|(handle, _)| image_writers[handle.0])
+                .chain(buffer_reads.map(|(handle, _)| buffer_writers[handle.0]));
+            for writer in writers.flatten() {
+                if !pass_dependencies.contains(&writer) {
+                    pass_dependencies.push(writer);
+                }
+            }
+
+            //Updated after the reads, so a pass reading and writing a resource doesn't depend on itself
+            for (handle, _) in pass.images.iter().filter(|(_, usage)| usage.is_write()) {
+                image_writers[handle.0] = Some(pass.index);
+            }
+            for (handle, _) in pass.buffers.iter().filter(|(_, usage)| usage.is_write()) {
+                buffer_writers[handle.0] = Some(pass.index);
+            }
+
+            dependencies.push(pass_dependencies);
+        }
+
+        dependencies
+    }
+
+    //Passes that neither reach a sink nor are a dependency of one are dead, without any sink nothing is culled
+    fn live_passes(&self) -> Vec<bool> {
+        //Reading an imported image has no effect outside the graph, only writing or presenting it does
+        let is_sink = |(handle, usage): &(ResourceHandle, ImageUsage)| {
+            (usage.is_write() || *usage == ImageUsage::Present) && (self.outputs.contains(handle) || matches!(self.images[handle.0], GraphImage::Imported(_)))
+        };
+
+        //Buffers are always imported, so writing one is visible outside the graph
+        let mut stack: Vec<_> = self
+            .passes
+            .iter()
+            .filter(|pass| pass.images.iter().any(is_sink) || pass.buffers.iter().any(|(_, usage)| usage.is_write()))
+            .map(|pass| pass.index)
+            .collect();
+        if stack.is_empty() {
+            return vec![true; self.passes.len()]
+        }
+
+        let dependencies = self.pass_dependencies();
+
+        let mut live = vec![false; self.passes.len()];
+        while let Some(index) = stack.pop() {
+            if live[index] {
+                continue
+            }
+
+            live[index] = true;
+            stack.extend(dependencies[index].iter().copied());
+        }
+
+        live
+    }
+
+    //Declares how a pass uses an image for aliasing and culling, the barrier itself is recorded by PassContext::access_image
+    pub fn use_image(&mut self, pass: usize, handle: ResourceHandle, usage: ImageUsage) {
+        assert!(handle.0 < self.images.len());
+
+        let images = &mut self.passes[pass].images;
+        if !images.contains(&(handle, usage)) {
+            images.push((handle, usage));
+        }
+
+        self.resolved_images.clear();
+    }
+
+    //Declares how a pass uses a buffer so culling can see it, the barrier itself is recorded by PassContext::access_buffer
+    pub fn use_buffer(&mut self, pass: usize, handle: BufferHandle, usage: BufferUsage) {
+        assert!(handle.0 < self.buffers.len());
+
+        let buffers = &mut self.passes[pass].buffers;
+        if !buffers.contains(&(handle, usage)) {
+            buffers.push((handle, usage));
+        }
+    }
+
+    pub fn linearize(&self) -> Result<Vec<usize>> {
+        let dependencies = self.pass_dependencies();
+
+        let mut in_degrees: Vec<_> = dependencies.iter().map(Vec::len).collect();
+        let mut dependents = vec![Vec::new(); self.passes.len()];
+
+        for (index, pass_dependencies) in dependencies.iter().enumerate() {
+            for dependency in pass_dependencies.iter() {
+                dependents[*dependency].push(index);
+            }
+        }
+
+        let mut queue: VecDeque<_> = in_degrees.iter().enumerate().filter(|(_, in_degree)| **in_degree == 0).map(|(i, _)| i).collect();
+        let mut order = Vec::with_capacity(self.passes.len());
+
+        while let Some(index) = queue.pop_front() {
+            order.push(index);
+
+            for dependent in dependents[index].iter() {
+                in_degrees[*dependent] -= 1;
+
+                if in_degrees[*dependent] == 0 {
+                    queue.push_back(*dependent);
+                }
+            }
+        }
+
+        if order.len() != self.passes.len() {
+            let cyclic: Vec<_> = in_degrees
+                .iter()
+                .enumerate()
+                .filter(|(_, in_degree)| **in_degree > 0)
+                .map(|(i, _)| self.passes[i].name.as_str())
+                .collect();
+
+            bail!("Render graph contains a cycle between passes: {:?}", cyclic);
+        }
+
+        let live = self.live_passes();
+        order.retain(|index| live[*index]);
+
+        Ok(order)
+    }
+
+    fn image_lifetimes(&self, order: &[usize]) -> Vec<Option<(usize, usize)>> {
+        let mut lifetimes: Vec<Option<(usize, usize)>> = vec![None; self.images.len()];
+
+        for (position, index) in order.iter().enumerate() {
+            for (handle, _) in self.passes[*index].images.iter() {
+                lifetimes[handle.0] = Some(lifetimes[handle.0].map_or((position, position), |(first, _)| (first, position)));
+            }
+        }
+
+        lifetimes
+    }
+
+    //First-fit: each image takes the first slot with an identical desc whose last use ends before its first use, imported images are never aliased
+    pub fn alias_slots(&self) -> Result<Vec<usize>> {
+        let order = self.linearize()?;
+        let lifetimes = self.image_lifetimes(&order);
+
+        let mut handles: Vec<_> = (0..self.images.len()).collect();
+        handles.sort_by_key(|handle| lifetimes[*handle].map_or(usize::MAX, |(first, _)| first));
+
+        let mut slot_ends: Vec<(Option<&ImageDesc>, usize)> = Vec::new();
+        let mut slots = vec![0; self.images.len()];
+
+        for handle in handles {
+            let desc = match &self.images[handle] {
+                GraphImage::Transient(desc) => desc,
+                GraphImage::Imported(_) => {
+                    slots[handle] = slot_ends.len();
+                    slot_ends.push((None, usize::MAX));
+                    continue
+                }
+            };
+
+            match lifetimes[handle] {
+                Some((first, last)) => {
+                    match slot_ends.iter().position(|(slot_desc, end)| *slot_desc == Some(desc) && *end < first) {
+                        Some(slot) => {
+                            slot_ends[slot].1 = last;
+                            slots[handle] = slot;
+                        }
+                        None => {
+                            slots[handle] = slot_ends.len();
+                            slot_ends.push((Some(desc), last));
+                        }
+                    }
+                }
+                None => {
+                    slots[handle] = slot_ends.len();
+                    slot_ends.push((Some(desc), usize::MAX));
+                }
+            }
+        }
+
+        Ok(slots)
+    }
+
+    //Physical images of the previous resolve are reused for slots with the same desc, the rest are retired
+    fn resolve_images(&mut self, device: &Arc<Device>) -> Result<()> {
+        let slots = self.alias_slots()?;
+        let mut physical_images: Vec<Option<Arc<Image>>> = vec![None; slots.iter().max().map_or(0, |max| max + 1)];
+        let mut reusable_images = mem::take(&mut self.physical_images);
+
+        let mut resolved_images = Vec::with_capacity(slots.len());
+        for (handle, slot) in slots.iter().copied().enumerate() {
+            let desc = match &self.images[handle] {
+                GraphImage::Transient(desc) => desc,
+                GraphImage::Imported(imported_image) => {
+                    resolved_images.push(PassImage {
+                        image: imported_image.image,
+                        view: imported_image.view,
+                        format: imported_image.format
+                    });
+                    continue
+                }
+            };
+
+            let image = match &physical_images[slot] {
+                Some(image) => image.clone(),
+                None => {
+                    let image = match reusable_images.iter().position(|image| image.desc() == desc) {
+                        Some(i) => reusable_images.swap_remove(i),
+                        None => Arc::new(Image::new(device.clone(), desc)?)
+                    };
+                    physical_images[slot] = Some(image.clone());
+                    image
+                }
+            };
+
+            resolved_images.push(PassImage {
+                image: *image.image(),
+                view: *image.view(),
+                format: image.format()
+            });
+        }
+
+        self.physical_images = physical_images.into_iter().flatten().collect();
+        self.retired_images.append(&mut reusable_images);
+        self.resolved_images = resolved_images;
+        self.image_slots = slots;
+
+        Ok(())
+    }
+
+    //Destroys the images and query pools retired by graph changes, call once the fence of the last frame executed before the change has signaled
+    #[inline]
+    pub fn release_retired_resources(&mut self) {
+        self.retired_images.clear();
+        self.retired_query_pools.clear();
+    }
+
+    pub fn execute(&mut self, command_buffer: vk::CommandBuffer, ctx: &mut PassContext) -> Result<()> {
+        if self.resolved_images.len() != self.images.len() {
+            self.resolve_images(&ctx.device)?;
+        }
+
+        let order = self.linearize()?;
+
+        //The pool only grows, so adding and removing passes doesn't recreate it every frame
+        let timestamp_query_count = 2 * order.len() as u32;
+        let timestamps_active = self.timestamps_enabled && timestamp_query_count > 0 && ctx.device.supports_timestamps();
+        if timestamps_active && self.timestamp_query_pool.as_ref().map_or(true, |query_pool| query_pool.count() < timestamp_query_count) {
+            let query_pool = QueryPool::new(ctx.device.clone(), vk::QueryType::TIMESTAMP, timestamp_query_count)?;
+            self.retired_query_pools.extend(self.timestamp_query_pool.replace(query_pool));
+        }
+
+        if timestamps_active {
+            self.timestamp_passes.clone_from(&order);
+        } else {
+            self.timestamp_passes.clear();
+        }
+        let timestamp_query_pool = self.timestamp_query_pool.as_ref().filter(|_| timestamps_active);
+
+        ctx.command_buffer = command_buffer;
+        ctx.images.clone_from(&self.resolved_images);
+        ctx.image_slots.clone_from(&self.image_slots);
+        ctx.slot_states = vec![None; self.image_slots.iter().max().map_or(0, |max| max + 1)];
+        ctx.imported_layouts = self
+            .images
+            .iter()
+            .map(|image| match image {
+                GraphImage::Imported(imported_image) => Some(imported_image.layout),
+                GraphImage::Transient(_) => None
+            })
+            .collect();
+        ctx.buffers.clone_from(&self.buffers);
+        ctx.buffer_states = vec![None; self.buffers.len()];
+
+        if let Some(query_pool) = timestamp_query_pool {
+            query_pool.cmd_reset(command_buffer);
+        }
+
+        for (position, index) in order.into_iter().enumerate() {
+            if let Some(query_pool) = timestamp_query_pool {
+                query_pool.cmd_write_timestamp(command_buffer, vk::PipelineStageFlags::TOP_OF_PIPE, 2 * position as u32);
+            }
+
+            let pass = &mut self.passes[index];
+
+            ctx.device.cmd_begin_debug_label(command_buffer, &pass.name, [1.0, 1.0, 1.0, 1.0]);
+            (pass.record)(ctx);
+            ctx.device.cmd_end_debug_label(command_buffer);
+
+            if let Some(query_pool) = timestamp_query_pool {
+                query_pool.cmd_write_timestamp(command_buffer, vk::PipelineStageFlags::BOTTOM_OF_PIPE, 2 * position as u32 + 1);
+            }
+        }
+
+        //The next execution starts from the layout the passes left imported images in
+        for (handle, image) in self.images.iter_mut().enumerate() {
+            if let GraphImage::Imported(imported_image) = image {
+                if let Some((last_handle, usage)) = ctx.slot_states[self.image_slots[handle]] {
+                    if last_handle.0 == handle {
+                        imported_image.layout = usage.layout();
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[inline]
+    pub fn set_timestamps_enabled(&mut self, enabled: bool) {
+        self.timestamps_enabled = enabled;
+    }
+
+    #[inline]
+    pub fn timestamps_enabled(&self) -> bool {
+        self.timestamps_enabled
+    }
+
+    //Pass index and duration in execution order of the last execute, culled passes and passes the GPU hasn't finished yet are missing
+    pub fn pass_durations_ns(&self) -> VkResult<Vec<(usize, u64)>> {
+        let query_pool = match &self.timestamp_query_pool {
+            Some(query_pool) if !self.timestamp_passes.is_empty() => query_pool,
+            _ => return Ok(Vec::new())
+        };
+
+        let timestamps = query_pool.read_timestamps()?;
+
+        Ok(self
+            .timestamp_passes
+            .iter()
+            .copied()
+            .zip(timestamps.chunks_exact(2))
+            .filter_map(|(index, timestamps)| Some((index, timestamps[1]?.saturating_sub(timestamps[0]?))))
+            .collect())
+    }
+
+    //Graphviz digraph with passes as ellipses and images as boxes
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph RenderGraph {\n");
+
+        let live = self.live_passes();
+        for pass in self.passes.iter() {
+            let style = if live[pass.index] { "solid" } else { "dotted" };
+            writeln!(dot, "    pass{} [style={}, label=\"{} ({})\"];", pass.index, style, pass.name.replace('"', "\\\""), pass.index).unwrap();
+        }
+
+        for (i, image) in self.images.iter().enumerate() {
+            match image {
+                GraphImage::Transient(desc) => {
+                    writeln!(dot, "    image{} [shape=box, label=\"Image {}\\n{}x{} {:?}\"];", i, i, desc.extent.width, desc.extent.height, desc.format).unwrap()
+                }
+                GraphImage::Imported(imported_image) => {
+                    writeln!(dot, "    image{} [shape=box, style=bold, label=\"Imported image {}\\n{:?}\"];", i, i, imported_image.format).unwrap()
+                }
+            }
+        }
+
+        for (i, buffer) in self.buffers.iter().enumerate() {
+            writeln!(dot, "    buffer{} [shape=box, style=bold, label=\"Imported buffer {}\\n{} bytes\"];", i, i, buffer.size).unwrap();
+        }
+
+        for pass in self.passes.iter() {
+            for dependency in pass.dependencies.iter() {
+                writeln!(dot, "    pass{} -> pass{};", dependency, pass.index).unwrap();
+            }
+
+            for (handle, usage) in pass.images.iter() {
+                if usage.is_write() {
+                    writeln!(dot, "    pass{} -> image{} [style=dashed];", pass.index, handle.0).unwrap();
+                } else {
+                    writeln!(dot, "    image{} -> pass{} [style=dashed];", handle.0, pass.index).unwrap();
+                }
+            }
+
+            for (handle, usage) in pass.buffers.iter() {
+                if usage.is_write() {
+                    writeln!(dot, "    pass{} -> buffer{} [style=dashed];", pass.index, handle.0).unwrap();
+                } else {
+                    writeln!(dot, "    buffer{} -> pass{} [style=dashed];", handle.0, pass.index).unwrap();
+                }
+            }
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+
+    #[inline]
+    pub fn passes(&self) -> &[RecordedPass] {
+        &self.passes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::renderer::test_device;
+
+    fn transient_desc() -> ImageDesc {
+        ImageDesc::new_2d(vk::Extent2D { width: 64, height: 64 }, vk::Format::R8G8B8A8_UNORM, vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED)
+    }
+
+    #[test]
+    fn diamond_dependencies_are_ordered() {
+        let mut graph = RenderGraph::new();
+
+        let top = graph.add_pass("top", |_| {});
+        let left = graph.add_pass("left", |_| {});
+        let right = graph.add_pass("right", |_| {});
+        let bottom = graph.add_pass("bottom", |_| {});
+
+        graph.add_dependency(left, bottom);
+        graph.add_dependency(right, bottom);
+        graph.add_dependency(top, left);
+        graph.add_dependency(top, right);
+
+        let order = graph.linearize().unwrap();
+        let position = |pass: usize| order.iter().position(|index| *index == pass).unwrap();
+
+        assert_eq!(order.len(), 4);
+        assert_eq!(position(top), 0);
+        assert_eq!(position(bottom), 3);
+    }
+
+    #[test]
+    fn cycles_are_rejected() {
+        let mut graph = RenderGraph::new();
+
+        let first = graph.add_pass("first", |_| {});
+        let second = graph.add_pass("second", |_| {});
+        let third = graph.add_pass("third", |_| {});
+
+        graph.add_dependency(first, second);
+        graph.add_dependency(second, third);
+        graph.add_dependency(third, second);
+
+        let error = graph.linearize().unwrap_err().to_string();
+        assert!(error.contains("second") && error.contains("third") && !error.contains("first"));
+    }
+
+    #[test]
+    fn unreferenced_transient_pass_is_culled() {
+        let mut graph = RenderGraph::new();
+
+        let debug_target = graph.create_transient_image(transient_desc());
+        let scene_target = graph.create_transient_image(transient_desc());
+        let backbuffer = graph.import_image(vk::Image::null(), vk::ImageView::null(), vk::ImageLayout::UNDEFINED, vk::Format::B8G8R8A8_UNORM);
+        let readback = graph.import_buffer(vk::Buffer::null(), 0, 256);
+
+        let debug = graph.add_pass("debug", |_| {});
+        graph.use_image(debug, debug_target, ImageUsage::ColorAttachment);
+
+        let scene = graph.add_pass("scene", |_| {});
+        graph.use_image(scene, scene_target, ImageUsage::ColorAttachment);
+
+        let present = graph.add_pass("present", |_| {});
+        graph.use_image(present, scene_target, ImageUsage::Sampled);
+        graph.use_image(present, backbuffer, ImageUsage::ColorAttachment);
+        graph.add_dependency(scene, present);
+
+        //Only reads the imported backbuffer, so nothing outside the graph sees it
+        let inspect = graph.add_pass("inspect", |_| {});
+        graph.use_image(inspect, backbuffer, ImageUsage::Sampled);
+        graph.add_dependency(present, inspect);
+
+        let copy = graph.add_pass("copy", |_| {});
+        graph.use_buffer(copy, readback, BufferUsage::TransferDst);
+
+        assert_eq!(graph.linearize().unwrap(), [scene, copy, present]);
+    }
+
+    #[test]
+    fn resource_declarations_link_producer_and_consumer() {
+        let mut graph = RenderGraph::new();
+
+        let shadow_map = graph.create_transient_image(transient_desc());
+        let unused_target = graph.create_transient_image(transient_desc());
+        let backbuffer = graph.import_image(vk::Image::null(), vk::ImageView::null(), vk::ImageLayout::UNDEFINED, vk::Format::B8G8R8A8_UNORM);
+
+        let unused = graph.add_pass("unused", |_| {});
+        graph.use_image(unused, unused_target, ImageUsage::ColorAttachment);
+
+        let shadows = graph.add_pass("shadows", |_| {});
+        graph.use_image(shadows, shadow_map, ImageUsage::DepthAttachment);
+
+        //Only the declarations connect the passes, there is no add_dependency
+        let lighting = graph.add_pass("lighting", |_| {});
+        graph.use_image(lighting, shadow_map, ImageUsage::Sampled);
+        graph.use_image(lighting, backbuffer, ImageUsage::ColorAttachment);
+
+        assert_eq!(graph.linearize().unwrap(), [shadows, lighting]);
+        assert!(graph.passes()[lighting].dependencies.is_empty());
+    }
+
+    #[test]
+    fn execute_runs_passes_in_dependency_order() {
+        let device = match test_device() {
+            Some(device) => device,
+            None => return
+        };
+
+        let executed = Arc::new(Mutex::new(Vec::new()));
+        let mut graph = RenderGraph::new();
+
+        let lighting = {
+            let executed = executed.clone();
+            graph.add_pass("lighting", move |_| executed.lock().unwrap().push("lighting"))
+        };
+        let gbuffer = {
+            let executed = executed.clone();
+            graph.add_pass("gbuffer", move |_| executed.lock().unwrap().push("gbuffer"))
+        };
+        graph.add_dependency(gbuffer, lighting);
+
+        let mut ctx = PassContext::new(device.clone());
+        let mut result = Ok(());
+        device.submit_immediate(device.direct_queue(), |command_buffer| result = graph.execute(command_buffer, &mut ctx)).unwrap();
+        result.unwrap();
+
+        assert_eq!(*executed.lock().unwrap(), ["gbuffer", "lighting"]);
+    }
+
+    #[test]
+    fn sequential_transients_share_an_allocation() {
+        let mut graph = RenderGraph::new();
+
+        let first_target = graph.create_transient_image(transient_desc());
+        let history = graph.create_transient_image(transient_desc());
+        let second_target = graph.create_transient_image(transient_desc());
+
+        let first = graph.add_pass("first", |_| {});
+        graph.use_image(first, first_target, ImageUsage::ColorAttachment);
+        graph.use_image(first, history, ImageUsage::ColorAttachment);
+
+        let second = graph.add_pass("second", |_| {});
+        graph.use_image(second, history, ImageUsage::Sampled);
+        graph.use_image(second, second_target, ImageUsage::ColorAttachment);
+        graph.add_dependency(first, second);
+
+        let slots = graph.alias_slots().unwrap();
+        assert_eq!(slots[first_target.index()], slots[second_target.index()]);
+        assert_ne!(slots[first_target.index()], slots[history.index()]);
+
+        let device = match test_device() {
+            Some(device) => device,
+            None => return
+        };
+
+        let mut ctx = PassContext::new(device.clone());
+        let mut result = Ok(());
+        device.submit_immediate(device.direct_queue(), |command_buffer| result = graph.execute(command_buffer, &mut ctx)).unwrap();
+        result.unwrap();
+
+        assert_eq!(ctx.image(first_target).image(), ctx.image(second_target).image());
+        assert_ne!(ctx.image(first_target).image(), ctx.image(history).image());
+    }
+
+    #[test]
+    fn graph_changes_reuse_images_with_the_same_desc() {
+        let device = match test_device() {
+            Some(device) => device,
+            None => return
+        };
+
+        let mut graph = RenderGraph::new();
+        let target = graph.create_transient_image(transient_desc());
+        let draw = graph.add_pass("draw", |_| {});
+        graph.use_image(draw, target, ImageUsage::ColorAttachment);
+
+        let mut ctx = PassContext::new(device.clone());
+        let execute = |graph: &mut RenderGraph, ctx: &mut PassContext| {
+            let mut result = Ok(());
+            device.submit_immediate(device.direct_queue(), |command_buffer| result = graph.execute(command_buffer, ctx)).unwrap();
+            result.unwrap();
+        };
+
+        execute(&mut graph, &mut ctx);
+        let first_image = *ctx.image(target).image();
+
+        //Adding a pass with a differently sized image re-resolves the graph
+        let small_target = graph.create_transient_image(ImageDesc::new_2d(vk::Extent2D { width: 8, height: 8 }, vk::Format::R8G8B8A8_UNORM, vk::ImageUsageFlags::COLOR_ATTACHMENT));
+        let blur = graph.add_pass("blur", |_| {});
+        graph.use_image(blur, target, ImageUsage::Sampled);
+        graph.use_image(blur, small_target, ImageUsage::ColorAttachment);
+
+        execute(&mut graph, &mut ctx);
+        assert_eq!(*ctx.image(target).image(), first_image);
+        assert_ne!(*ctx.image(small_target).image(), first_image);
+        assert!(graph.retired_images.is_empty());
+    }
+
+    #[test]
+    fn timestamp_pool_keeps_its_high_water_mark() {
+        let device = match test_device() {
+            Some(device) if device.supports_timestamps() => device,
+            _ => return
+        };
+
+        let mut graph = RenderGraph::new();
+        graph.set_timestamps_enabled(true);
+        let first = graph.add_pass("first", |_| {});
+        let second = graph.add_pass("second", |_| {});
+        graph.add_dependency(first, second);
+
+        let mut ctx = PassContext::new(device.clone());
+        let mut result = Ok(());
+        device.submit_immediate(device.direct_queue(), |command_buffer| result = graph.execute(command_buffer, &mut ctx)).unwrap();
+        result.unwrap();
+
+        let durations = graph.pass_durations_ns().unwrap();
+        assert_eq!(durations.iter().map(|(index, _)| *index).collect::<Vec<_>>(), [first, second]);
+
+        //Culling the second pass needs fewer queries, the pool is kept
+        let output = graph.import_image(vk::Image::null(), vk::ImageView::null(), vk::ImageLayout::UNDEFINED, vk::Format::B8G8R8A8_UNORM);
+        graph.use_image(first, output, ImageUsage::TransferDst);
+        let query_pool = *graph.timestamp_query_pool.as_ref().unwrap().query_pool();
+
+        let mut result = Ok(());
+        device.submit_immediate(device.direct_queue(), |command_buffer| result = graph.execute(command_buffer, &mut ctx)).unwrap();
+        result.unwrap();
+
+        assert_eq!(*graph.timestamp_query_pool.as_ref().unwrap().query_pool(), query_pool);
+        assert!(graph.retired_query_pools.is_empty());
+        assert_eq!(graph.pass_durations_ns().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn dot_has_a_node_per_pass_and_resource() {
+        let mut graph = RenderGraph::new();
+
+        let target = graph.create_transient_image(transient_desc());
+        let readback = graph.import_buffer(vk::Buffer::null(), 0, 256);
+
+        let draw = graph.add_pass("draw", |_| {});
+        graph.use_image(draw, target, ImageUsage::ColorAttachment);
+
+        let copy = graph.add_pass("copy", |_| {});
+        graph.use_image(copy, target, ImageUsage::TransferSrc);
+        graph.use_buffer(copy, readback, BufferUsage::TransferDst);
+        graph.add_dependency(draw, copy);
+
+        let dot = graph.to_dot();
+        let edges = dot.lines().filter(|line| line.contains("->")).count();
+        let nodes = dot.lines().filter(|line| line.contains('[') && !line.contains("->")).count();
+
+        assert!(dot.starts_with("digraph RenderGraph {"));
+        assert_eq!(nodes, 4);
+        assert_eq!(edges, 4);
+        assert!(dot.contains("pass0 -> image0") && dot.contains("image0 -> pass1") && dot.contains("pass1 -> buffer0"));
+    }
+
+    #[test]
+    fn render_target_then_sampled_transitions_layouts() {
+        let first_use = image_transition(None, ImageUsage::ColorAttachment).unwrap();
+        assert_eq!(first_use.old_layout, vk::ImageLayout::UNDEFINED);
+        assert_eq!(first_use.new_layout, vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
+
+        let sampled = image_transition(Some(ImageUsage::ColorAttachment), ImageUsage::Sampled).unwrap();
+        assert_eq!(sampled.old_layout, vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
+        assert_eq!(sampled.new_layout, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL);
+        assert!(sampled.src_access_mask.contains(vk::AccessFlags::COLOR_ATTACHMENT_WRITE));
+        assert_eq!(sampled.dst_access_mask, vk::AccessFlags::SHADER_READ);
+
+        assert!(image_transition(Some(ImageUsage::Sampled), ImageUsage::Sampled).is_none());
+    }
 }
@@ -1,27 +1,55 @@
 #![allow(clippy::missing_safety_doc)]
 
 pub mod backend;
+pub mod extract;
 pub mod graph;
 pub mod renderer;
 pub mod resource;
+pub mod time;
 
 use std::ops::{Deref, DerefMut};
 
+use ash::vk;
 use kamel_bevy::{
-    app::{self as bevy_app, App, AppLabel, Plugin},
-    asset::AddAsset,
-    ecs::{self as bevy_ecs, schedule::StageLabel, world::World},
+    app::{self as bevy_app, App, AppLabel, CoreStage, Plugin},
+    asset::{AddAsset, AssetServer},
+    ecs::{
+        self as bevy_ecs,
+        schedule::{IntoSystemDescriptor, StageLabel, SystemStage},
+        world::World
+    },
     window::Windows
 };
-
-use crate::resource::{Shader, ShaderLoader};
+use log::warn;
+
+use crate::{
+    backend::{ApplicationInfo, ColorPreference, FRAMES_IN_FLIGHT},
+    extract::{AddExtractResource, ClearColor, ResourceExtractors},
+    renderer::{clear_frame_system, swapchain_resize_system, window_surface_system, FrameCommandBuffers, GpuCapabilities, RequestedFeatures, Surfaces},
+    resource::{shader_reload_system, Shader, ShaderLoader, ShaderReloaded},
+    time::{frame_timings_system, FrameTimings}
+};
 
 #[derive(Debug, Hash, PartialEq, Eq, Clone, StageLabel)]
 pub enum RenderStage {
+    Prepare,
     Render,
     Cleanup
 }
 
+//e.g. app.add_render_system_to_stage(RenderStage::Render, my_render_system)
+pub trait AddRenderSystem {
+    fn add_render_system_to_stage<Params>(&mut self, stage: RenderStage, system: impl IntoSystemDescriptor<Params>) -> &mut Self;
+}
+
+impl AddRenderSystem for App {
+    fn add_render_system_to_stage<Params>(&mut self, stage: RenderStage, system: impl IntoSystemDescriptor<Params>) -> &mut Self {
+        self.sub_app_mut(RenderApp).add_system_to_stage(stage, system);
+
+        self
+    }
+}
+
 #[derive(Default)]
 pub struct RenderWorld(World);
 
@@ -44,24 +72,139 @@ impl DerefMut for RenderWorld {
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, AppLabel)]
 pub struct RenderApp;
 
-#[derive(Default)]
-pub struct RenderPlugin;
+pub struct RenderPlugin {
+    pub application_info: ApplicationInfo,
+    pub enable_validation: bool,
+    pub debug_message_severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+    pub watch_for_changes: bool,
+    pub color_preference: ColorPreference,
+    //2 keeps latency low, 3 lets the CPU run further ahead at the cost of a frame of input latency with FIFO
+    pub frames_in_flight: u32,
+    pub min_vulkan_version: (u32, u32),
+    pub requested_features: RequestedFeatures,
+    //Index into Instance::physical_devices, KAMEL_GPU takes precedence
+    pub adapter_index: Option<usize>,
+    //Added to COLOR_ATTACHMENT, e.g. TRANSFER_SRC for screenshots. Unsupported bits are dropped with a warning
+    pub swapchain_image_usage: vk::ImageUsageFlags
+}
+
+impl Default for RenderPlugin {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            application_info: ApplicationInfo::default(),
+            enable_validation: cfg!(debug_assertions),
+            debug_message_severity: if cfg!(debug_assertions) {
+                vk::DebugUtilsMessageSeverityFlagsEXT::VERBOSE
+            } else {
+                vk::DebugUtilsMessageSeverityFlagsEXT::WARNING
+            },
+            watch_for_changes: cfg!(debug_assertions),
+            color_preference: ColorPreference::default(),
+            frames_in_flight: FRAMES_IN_FLIGHT,
+            min_vulkan_version: (1, 1),
+            requested_features: RequestedFeatures::default(),
+            adapter_index: None,
+            swapchain_image_usage: vk::ImageUsageFlags::empty()
+        }
+    }
+}
 
 impl Plugin for RenderPlugin {
     fn build(&self, app: &mut App) {
         app.add_asset::<Shader>()
             .add_debug_asset::<Shader>()
             .init_asset_loader::<ShaderLoader>()
-            .init_debug_asset_loader::<ShaderLoader>();
+            .init_debug_asset_loader::<ShaderLoader>()
+            .add_event::<ShaderReloaded>()
+            .add_system(shader_reload_system)
+            .init_resource::<FrameTimings>()
+            .add_system_to_stage(CoreStage::First, frame_timings_system);
+
+        if self.watch_for_changes {
+            if let Err(e) = app.world.resource::<AssetServer>().watch_for_changes() {
+                warn!("Failed to watch assets for changes: {:?}", e);
+            }
+        }
+
+        let windows = app.world.resource::<Windows>();
+
+        let (instance, device, surfaces) = renderer::initialize(windows, self);
+        let gpu_capabilities = GpuCapabilities::new(&device);
+
+        let mut render_app = App::new();
+        render_app
+            .insert_resource(device.clone())
+            .insert_resource(gpu_capabilities)
+            .init_resource::<ResourceExtractors>()
+            .init_resource::<FrameCommandBuffers>()
+            .add_stage(RenderStage::Prepare, SystemStage::parallel())
+            .add_stage_after(RenderStage::Prepare, RenderStage::Render, SystemStage::parallel())
+            .add_stage_after(RenderStage::Render, RenderStage::Cleanup, SystemStage::parallel())
+            .add_system_to_stage(RenderStage::Prepare, swapchain_resize_system)
+            .add_system_to_stage(RenderStage::Render, clear_frame_system);
+
+        app.insert_resource(instance)
+            .insert_resource(device)
+            .insert_resource(gpu_capabilities)
+            .insert_resource(surfaces)
+            .add_system(window_surface_system);
+
+        app.add_sub_app(RenderApp, render_app, |app_world, render_app| {
+            ResourceExtractors::run(app_world, &mut render_app.world);
+
+            //Surfaces live in the main world for window events, the render world borrows them for the frame.
+            //Windows is borrowed too so lost surfaces can be recreated from the window handles
+            if let Some(surfaces) = app_world.remove_resource::<Surfaces>() {
+                render_app.world.insert_resource(surfaces);
+            }
+            if let Some(windows) = app_world.remove_resource::<Windows>() {
+                render_app.world.insert_resource(windows);
+            }
+
+            render_app.update();
+
+            if let Some(surfaces) = render_app.world.remove_resource::<Surfaces>() {
+                app_world.insert_resource(surfaces);
+            }
+            if let Some(windows) = render_app.world.remove_resource::<Windows>() {
+                app_world.insert_resource(windows);
+            }
+        });
+
+        app.init_resource::<ClearColor>()
+            .add_extract_resource::<FrameTimings>()
+            .add_extract_resource::<ClearColor>();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kamel_bevy::ecs::system::ResMut;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct RenderedFrames(u32);
+
+    fn count_frames(mut rendered_frames: ResMut<RenderedFrames>) {
+        rendered_frames.0 += 1;
+    }
 
-        let render_app = App::new();
+    #[test]
+    fn render_systems_run_in_the_render_app() {
+        let mut render_app = App::new();
+        render_app.init_resource::<RenderedFrames>().add_stage(RenderStage::Render, SystemStage::parallel());
 
-        let windows = app.world.resource_mut::<Windows>();
-        let raw_handle = unsafe { windows.get_primary().unwrap().raw_window_handle().get_handle() };
+        let mut app = App::new();
+        app.add_sub_app(RenderApp, render_app, |_, render_app| render_app.update());
 
-        let (instance, surface, device, swapchain) = renderer::initialize(&raw_handle);
-        app.insert_resource(instance).insert_resource(surface).insert_resource(device).insert_resource(swapchain);
+        app.add_render_system_to_stage(RenderStage::Render, || {})
+            .add_render_system_to_stage(RenderStage::Render, count_frames);
+        app.update();
+        app.update();
 
-        app.add_sub_app(RenderApp, render_app, |_app_world, _render_app| {});
+        assert_eq!(app.sub_app(RenderApp).world.resource::<RenderedFrames>().0, 2);
+        assert!(!app.world.contains_resource::<RenderedFrames>());
     }
 }
@@ -0,0 +1,56 @@
+use ash::vk;
+
+use crate::backend::Device;
+
+//What the device was created with, for picking rendering paths and graphics settings without touching Vulkan structs
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct GpuCapabilities {
+    pub mesh_shader: bool,
+    pub ray_tracing: bool,
+    pub bindless: bool,
+    pub dynamic_rendering: bool,
+    pub timeline_semaphores: bool,
+    pub synchronization2: bool,
+    pub buffer_device_address: bool,
+    pub timestamps: bool,
+    pub hdr_metadata: bool,
+    pub sampler_anisotropy: bool,
+
+    pub max_texture_size: u32,
+    pub max_texture_size_3d: u32,
+    pub max_sampler_anisotropy: f32,
+    //Highest sample count supported by color and depth attachments alike
+    pub max_msaa_samples: u32,
+    pub max_compute_work_group_count: [u32; 3],
+    pub device_local_memory: vk::DeviceSize
+}
+
+impl GpuCapabilities {
+    pub fn new(device: &Device) -> Self {
+        let limits = &device.properties().properties.limits;
+        let enabled_features = device.enabled_features();
+
+        let sample_counts = limits.framebuffer_color_sample_counts & limits.framebuffer_depth_sample_counts;
+        let max_msaa_samples = 1 << (31 - sample_counts.as_raw().max(1).leading_zeros());
+
+        Self {
+            mesh_shader: device.extensions().nv_mesh_shader() && enabled_features.mesh_shader_features.mesh_shader == vk::TRUE,
+            ray_tracing: device.supports_ray_tracing(),
+            bindless: device.supports_bindless(),
+            dynamic_rendering: device.supports_dynamic_rendering(),
+            timeline_semaphores: device.supports_timeline_semaphores(),
+            synchronization2: device.supports_synchronization2(),
+            buffer_device_address: device.supports_buffer_device_address(),
+            timestamps: device.supports_timestamps(),
+            hdr_metadata: device.extensions().ext_hdr_metadata(),
+            sampler_anisotropy: enabled_features.features.sampler_anisotropy == vk::TRUE,
+
+            max_texture_size: limits.max_image_dimension2_d,
+            max_texture_size_3d: limits.max_image_dimension3_d,
+            max_sampler_anisotropy: if enabled_features.features.sampler_anisotropy == vk::TRUE { limits.max_sampler_anisotropy } else { 1.0 },
+            max_msaa_samples,
+            max_compute_work_group_count: limits.max_compute_work_group_count,
+            device_local_memory: device.adapter_info().device_local_memory
+        }
+    }
+}
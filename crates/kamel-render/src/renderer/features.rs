@@ -0,0 +1,98 @@
+use ash::vk;
+
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+pub struct RequestedFeatures {
+    pub sampler_anisotropy: bool,
+    pub fill_mode_non_solid: bool,
+    pub multi_draw_indirect: bool,
+    pub draw_indirect_first_instance: bool,
+    pub wide_lines: bool,
+    pub depth_clamp: bool,
+    pub geometry_shader: bool,
+    pub tessellation_shader: bool,
+    pub shader_int64: bool,
+    pub buffer_device_address: bool,
+    pub ray_tracing: bool
+}
+
+impl Default for RequestedFeatures {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            sampler_anisotropy: true,
+            fill_mode_non_solid: false,
+            multi_draw_indirect: false,
+            draw_indirect_first_instance: false,
+            wide_lines: false,
+            depth_clamp: false,
+            geometry_shader: false,
+            tessellation_shader: false,
+            shader_int64: false,
+            buffer_device_address: false,
+            ray_tracing: false
+        }
+    }
+}
+
+impl RequestedFeatures {
+    //Returns the names of requested features that are not supported
+    pub fn enable_supported(&self, supported: &vk::PhysicalDeviceFeatures, enabled: &mut vk::PhysicalDeviceFeatures) -> Vec<&'static str> {
+        let mut unsupported = Vec::new();
+
+        let mut enable = |requested: bool, name: &'static str, supported: vk::Bool32, enabled: &mut vk::Bool32| {
+            if !requested {
+                return
+            }
+
+            if supported == vk::TRUE {
+                *enabled = vk::TRUE;
+            } else {
+                unsupported.push(name);
+            }
+        };
+
+        enable(self.sampler_anisotropy, "samplerAnisotropy", supported.sampler_anisotropy, &mut enabled.sampler_anisotropy);
+        enable(self.fill_mode_non_solid, "fillModeNonSolid", supported.fill_mode_non_solid, &mut enabled.fill_mode_non_solid);
+        enable(self.multi_draw_indirect, "multiDrawIndirect", supported.multi_draw_indirect, &mut enabled.multi_draw_indirect);
+        enable(
+            self.draw_indirect_first_instance,
+            "drawIndirectFirstInstance",
+            supported.draw_indirect_first_instance,
+            &mut enabled.draw_indirect_first_instance
+        );
+        enable(self.wide_lines, "wideLines", supported.wide_lines, &mut enabled.wide_lines);
+        enable(self.depth_clamp, "depthClamp", supported.depth_clamp, &mut enabled.depth_clamp);
+        enable(self.geometry_shader, "geometryShader", supported.geometry_shader, &mut enabled.geometry_shader);
+        enable(self.tessellation_shader, "tessellationShader", supported.tessellation_shader, &mut enabled.tessellation_shader);
+        enable(self.shader_int64, "shaderInt64", supported.shader_int64, &mut enabled.shader_int64);
+
+        unsupported
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_supported_requested_features_are_enabled() {
+        let requested = RequestedFeatures {
+            sampler_anisotropy: true,
+            wide_lines: true,
+            ..Default::default()
+        };
+        let supported = vk::PhysicalDeviceFeatures {
+            sampler_anisotropy: vk::TRUE,
+            depth_clamp: vk::TRUE,
+            ..Default::default()
+        };
+        let mut enabled = vk::PhysicalDeviceFeatures::default();
+
+        let unsupported = requested.enable_supported(&supported, &mut enabled);
+
+        assert_eq!(unsupported, ["wideLines"]);
+        assert_eq!(enabled.sampler_anisotropy, vk::TRUE);
+        assert_eq!(enabled.wide_lines, vk::FALSE);
+        assert_eq!(enabled.depth_clamp, vk::FALSE);
+    }
+}
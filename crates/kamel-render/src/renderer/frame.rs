@@ -0,0 +1,392 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    slice,
+    sync::Arc
+};
+
+use anyhow::Result;
+use ash::{prelude::VkResult, vk};
+use kamel_bevy::{
+    ecs::system::{Res, ResMut},
+    window::{WindowId, Windows}
+};
+use log::error;
+
+use crate::{
+    backend::{
+        resource::{aspect_mask_from_format, Image},
+        CommandBuffer, CommandPool, Device, Swapchain, SurfaceError
+    },
+    extract::ClearColor,
+    graph::ImageUsage,
+    renderer::Surfaces
+};
+
+#[derive(Default)]
+pub struct FrameCommandBuffers(HashMap<WindowId, Vec<CommandBuffer>>);
+
+pub fn create_frame_command_buffers(device: &Arc<Device>, frames_in_flight: usize) -> VkResult<Vec<CommandBuffer>> {
+    let command_pool = CommandPool::new(
+        device.clone(),
+        device.direct_queue().family_index(),
+        vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER
+    )?;
+
+    (0..frames_in_flight)
+        .map(|_| CommandBuffer::new(command_pool.clone(), vk::CommandBufferLevel::PRIMARY))
+        .collect()
+}
+
+//command_buffers holds one command buffer per frame in flight, see create_frame_command_buffers
+//Returns the image index to present, None if the swapchain is out of date
+pub fn clear_frame(swapchain: &mut Swapchain, command_buffers: &[CommandBuffer], clear_color: [f32; 4]) -> Result<Option<u32>> {
+    let image_index = match swapchain.acquire_next_image() {
+        Ok((image_index, suboptimal)) => {
+            if suboptimal {
+                swapchain.request_recreate();
+            }
+            image_index
+        }
+        Err(SurfaceError::OutOfDate) => {
+            swapchain.request_recreate();
+            return Ok(None)
+        }
+        Err(e) => return Err(e.into())
+    };
+
+    let device = swapchain.device().clone();
+    let device_loader = device.loader();
+
+    let command_buffer = &command_buffers[swapchain.current_frame()];
+    command_buffer.begin_one_time_submit()?;
+
+    let clear_values = [
+        vk::ClearValue {
+            color: vk::ClearColorValue { float32: clear_color }
+        },
+        vk::ClearValue {
+            depth_stencil: vk::ClearDepthStencilValue { depth: 1.0, stencil: 0 }
+        }
+    ];
+
+    let render_area = vk::Rect2D::default().extent(swapchain.extent());
+
+    if swapchain.uses_dynamic_rendering() {
+        let image_index = image_index as usize;
+        record_clear(
+            &device,
+            command_buffer,
+            (swapchain.images()[image_index], *swapchain.image_view_at(image_index)),
+            swapchain.depth_image_at(image_index),
+            render_area,
+            &clear_values,
+            ImageUsage::Present
+        );
+    } else {
+        let render_pass_begin_info = vk::RenderPassBeginInfo::default()
+            .render_pass(*swapchain.render_pass())
+            .framebuffer(*swapchain.framebuffer_at(image_index as usize))
+            .render_area(render_area)
+            .clear_values(&clear_values);
+
+        unsafe {
+            device_loader.cmd_begin_render_pass(*command_buffer.command_buffer(), &render_pass_begin_info, vk::SubpassContents::INLINE);
+            device_loader.cmd_end_render_pass(*command_buffer.command_buffer());
+        }
+    }
+
+    swapchain.cmd_release_to_present_queue(command_buffer, image_index);
+
+    command_buffer.end()?;
+
+    device.direct_queue().submit(
+        &device,
+        slice::from_ref(command_buffer.command_buffer()),
+        &[(*swapchain.image_available_semaphore(), vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)],
+        slice::from_ref(swapchain.render_finished_semaphore()),
+        Some(swapchain.in_flight_fence())
+    )?;
+
+    Ok(Some(image_index))
+}
+
+//Clears with dynamic rendering and leaves the color image in final_usage's layout
+fn record_clear(
+    device: &Device,
+    command_buffer: &CommandBuffer,
+    (image, image_view): (vk::Image, vk::ImageView),
+    depth_image: &Image,
+    render_area: vk::Rect2D,
+    clear_values: &[vk::ClearValue; 2],
+    final_usage: ImageUsage
+) {
+    let device_loader = device.loader();
+
+    let color_subresource_range = vk::ImageSubresourceRange::default().aspect_mask(vk::ImageAspectFlags::COLOR).level_count(1).layer_count(1);
+    let depth_subresource_range = vk::ImageSubresourceRange::default()
+        .aspect_mask(aspect_mask_from_format(depth_image.format()))
+        .level_count(1)
+        .layer_count(1);
+
+    let begin_barriers = [
+        vk::ImageMemoryBarrier::default()
+            .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+            .old_layout(vk::ImageLayout::UNDEFINED)
+            .new_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(image)
+            .subresource_range(color_subresource_range),
+        vk::ImageMemoryBarrier::default()
+            .dst_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+            .old_layout(vk::ImageLayout::UNDEFINED)
+            .new_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(*depth_image.image())
+            .subresource_range(depth_subresource_range)
+    ];
+
+    let end_barrier = vk::ImageMemoryBarrier::default()
+        .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+        .dst_access_mask(final_usage.access_mask())
+        .old_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+        .new_layout(final_usage.layout())
+        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .image(image)
+        .subresource_range(color_subresource_range);
+
+    let color_attachment = vk::RenderingAttachmentInfo::default()
+        .image_view(image_view)
+        .image_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+        .load_op(vk::AttachmentLoadOp::CLEAR)
+        .store_op(vk::AttachmentStoreOp::STORE)
+        .clear_value(clear_values[0]);
+
+    let depth_attachment = vk::RenderingAttachmentInfo::default()
+        .image_view(*depth_image.view())
+        .image_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+        .load_op(vk::AttachmentLoadOp::CLEAR)
+        .store_op(vk::AttachmentStoreOp::DONT_CARE)
+        .clear_value(clear_values[1]);
+
+    unsafe {
+        device_loader.cmd_pipeline_barrier(
+            *command_buffer.command_buffer(),
+            vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS,
+            vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &begin_barriers
+        );
+    }
+
+    command_buffer.begin_rendering(render_area, slice::from_ref(&color_attachment), Some(&depth_attachment));
+    command_buffer.end_rendering();
+
+    unsafe {
+        device_loader.cmd_pipeline_barrier(
+            *command_buffer.command_buffer(),
+            vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            final_usage.stage_mask(),
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            slice::from_ref(&end_barrier)
+        );
+    }
+}
+
+#[inline]
+fn is_surface_lost(e: &anyhow::Error) -> bool {
+    matches!(e.downcast_ref::<SurfaceError>(), Some(SurfaceError::Lost))
+}
+
+//A lost surface is recreated from the window's handle, which also rebuilds the swapchain on top of it
+fn recreate_lost_surfaces(surfaces: &mut Surfaces, windows: &Windows, lost_window_ids: &[WindowId]) {
+    for window_id in lost_window_ids {
+        //The window was closed in the meantime, window_surface_system removes its surface
+        let window = match windows.get(*window_id) {
+            Some(window) => window,
+            None => continue
+        };
+
+        if let Err(e) = surfaces.recreate_surface(window) {
+            error!("Failed to recreate lost surface for window {:?}: {:?}", window_id, e);
+        }
+    }
+}
+
+pub fn clear_frame_system(
+    mut surfaces: ResMut<Surfaces>,
+    mut frame_command_buffers: ResMut<FrameCommandBuffers>,
+    device: Res<Arc<Device>>,
+    windows: Res<Windows>,
+    clear_color: Res<ClearColor>
+) {
+    let mut window_ids = Vec::new();
+    let mut presents = Vec::new();
+    let mut lost_window_ids = Vec::new();
+
+    for (window_id, swapchain) in surfaces.iter_mut() {
+        if swapchain.is_minimized() {
+            continue
+        }
+
+        let command_buffers = match frame_command_buffers.0.entry(*window_id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => match create_frame_command_buffers(&device, swapchain.frames_in_flight()) {
+                Ok(command_buffers) => entry.insert(command_buffers),
+                Err(e) => {
+                    error!("Failed to create command buffers for window {:?}: {:?}", window_id, e);
+                    continue
+                }
+            }
+        };
+
+        match clear_frame(swapchain, command_buffers, clear_color.0) {
+            Ok(Some(image_index)) => {
+                window_ids.push(*window_id);
+                presents.push((image_index, &*swapchain));
+            }
+            Ok(None) => {}
+            Err(e) if is_surface_lost(&e) => lost_window_ids.push(*window_id),
+            Err(e) => error!("Failed to clear frame for window {:?}: {:?}", window_id, e)
+        }
+    }
+
+    //Suboptimal and out of date swapchains already requested their recreation
+    if let Some((_, swapchain)) = presents.first() {
+        match swapchain.present_all(&presents) {
+            Ok(_) => lost_window_ids.extend(
+                window_ids
+                    .iter()
+                    .zip(presents.iter())
+                    .filter(|(_, (_, swapchain))| swapchain.is_surface_lost())
+                    .map(|(window_id, _)| *window_id)
+            ),
+            Err(e) => error!("Failed to present windows {:?}: {:?}", window_ids, e)
+        }
+    }
+
+    recreate_lost_surfaces(&mut surfaces, &windows, &lost_window_ids);
+
+    frame_command_buffers.0.retain(|window_id, _| surfaces.contains(*window_id));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backend::{
+            resource::{Buffer, BufferDesc, ImageDesc},
+            Fence, SwapchainDesc
+        },
+        renderer::test_device
+    };
+    use kamel_bevy::ecs::{
+        schedule::{Stage, SystemStage},
+        world::World
+    };
+
+    #[test]
+    fn only_lost_surfaces_are_recreated() {
+        assert!(is_surface_lost(&SurfaceError::Lost.into()));
+        assert!(!is_surface_lost(&SurfaceError::OutOfDate.into()));
+        assert!(!is_surface_lost(&SurfaceError::Other(vk::Result::ERROR_DEVICE_LOST).into()));
+        assert!(!is_surface_lost(&anyhow::anyhow!("Surface was lost")));
+    }
+
+    #[test]
+    fn lost_surfaces_of_closed_windows_are_skipped() {
+        let device = match test_device() {
+            Some(device) => device,
+            None => return
+        };
+
+        let mut world = World::new();
+        world.insert_resource(Surfaces::new(SwapchainDesc::default()));
+        world.insert_resource(FrameCommandBuffers::default());
+        world.insert_resource(device);
+        world.insert_resource(Windows::default());
+        world.insert_resource(ClearColor::default());
+
+        let mut stage = SystemStage::single_threaded().with_system(clear_frame_system);
+        stage.run(&mut world);
+
+        //A surface lost by a window that was closed since is skipped, window_surface_system removes it
+        let mut surfaces = world.resource_mut::<Surfaces>();
+        recreate_lost_surfaces(&mut surfaces, &Windows::default(), &[WindowId::primary()]);
+        assert!(!surfaces.contains(WindowId::primary()));
+    }
+
+    #[test]
+    fn clear_is_visible_in_read_back() {
+        let device = match test_device() {
+            Some(device) if device.supports_dynamic_rendering() => device,
+            _ => return
+        };
+
+        let extent = vk::Extent2D { width: 4, height: 4 };
+        let image = Image::new(
+            device.clone(),
+            &ImageDesc::new_2d(extent, vk::Format::R8G8B8A8_UNORM, vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC)
+        )
+        .unwrap();
+        let depth_image = Image::new(device.clone(), &ImageDesc::new_2d(extent, vk::Format::D32_SFLOAT, vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT)).unwrap();
+        let read_back_buffer = Buffer::new(device.clone(), &BufferDesc::new_gpu_to_cpu(4 * 4 * 4, vk::BufferUsageFlags::TRANSFER_DST)).unwrap();
+
+        let command_buffers = create_frame_command_buffers(&device, 1).unwrap();
+        let command_buffer = &command_buffers[0];
+        let fence = Fence::new(device.clone(), false).unwrap();
+
+        //A few frames through the same command buffer and fence, like the frame loop
+        for (clear_color, expected) in [([1.0, 0.0, 0.0, 1.0], [255u8, 0, 0, 255]), ([0.0, 0.0, 1.0, 1.0], [0, 0, 255, 255]), ([0.0, 1.0, 0.0, 0.0], [0, 255, 0, 0])] {
+            let clear_values = [
+                vk::ClearValue {
+                    color: vk::ClearColorValue { float32: clear_color }
+                },
+                vk::ClearValue {
+                    depth_stencil: vk::ClearDepthStencilValue { depth: 1.0, stencil: 0 }
+                }
+            ];
+
+            command_buffer.begin_one_time_submit().unwrap();
+            record_clear(
+                &device,
+                command_buffer,
+                (*image.image(), *image.view()),
+                &depth_image,
+                vk::Rect2D::default().extent(extent),
+                &clear_values,
+                ImageUsage::TransferSrc
+            );
+
+            let buffer_image_copy = vk::BufferImageCopy::default()
+                .image_subresource(vk::ImageSubresourceLayers::default().aspect_mask(vk::ImageAspectFlags::COLOR).layer_count(1))
+                .image_extent(vk::Extent3D {
+                    width: extent.width,
+                    height: extent.height,
+                    depth: 1
+                });
+            unsafe {
+                device.loader().cmd_copy_image_to_buffer(
+                    *command_buffer.command_buffer(),
+                    *image.image(),
+                    vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    *read_back_buffer.buffer(),
+                    slice::from_ref(&buffer_image_copy)
+                );
+            }
+            command_buffer.end().unwrap();
+
+            device.direct_queue().submit(&device, slice::from_ref(command_buffer.command_buffer()), &[], &[], Some(&fence)).unwrap();
+            fence.wait(u64::MAX).unwrap();
+            fence.reset().unwrap();
+
+            assert!(read_back_buffer.read_bytes().unwrap().chunks_exact(4).all(|texel| texel == expected));
+        }
+    }
+}
@@ -1,69 +1,278 @@
-use std::sync::Arc;
+mod capabilities;
+mod features;
+mod frame;
+mod surfaces;
 
-use anyhow::bail;
+pub use capabilities::*;
+pub use features::*;
+pub use frame::*;
+pub use surfaces::*;
+
+use std::{env, sync::Arc};
+
+use anyhow::{bail, Result};
 use ash::{
-    extensions::{khr, khr::GetSurfaceCapabilities2, nv::MeshShader},
-    vk
+    extensions::{ext::DebugUtils, khr, khr::GetSurfaceCapabilities2, nv::MeshShader},
+    vk, Entry
 };
+use kamel_bevy::window::Windows;
+use log::{error, warn};
 use raw_window_handle::HasRawWindowHandle;
 
-use crate::backend::{Device, Instance, Surface, Swapchain};
+use crate::{
+    backend::{
+        instance::Extensions as InstanceExtensions, is_vulkan12, Device, Instance, Layers, Surface, SwapchainDesc, EXT_DESCRIPTOR_INDEXING_NAME,
+        KHR_MAINTENANCE3_NAME, KHR_PORTABILITY_ENUMERATION_NAME
+    },
+    RenderPlugin
+};
+
+fn check_version(version: u32, min_version: (u32, u32)) -> Result<()> {
+    let major = vk::api_version_major(version);
+    let minor = vk::api_version_minor(version);
+
+    if (major, minor) < min_version {
+        bail!(
+            "Only Vulkan {}.{}.{} is supported, but minimum supported version is {}.{}",
+            major,
+            minor,
+            vk::api_version_patch(version),
+            min_version.0,
+            min_version.1
+        );
+    }
 
-pub fn initialize(window: &impl HasRawWindowHandle) -> (Arc<Instance>, Arc<Surface>, Arc<Device>, Arc<Swapchain>) {
-    let instance = Instance::new(window, |entry_loader, layers, extensions| unsafe {
+    Ok(())
+}
+
+fn create_instance(window: Option<&dyn HasRawWindowHandle>, plugin: &RenderPlugin) -> Result<Arc<Instance>> {
+    let callback = |entry_loader: &Entry, layers: &mut Layers, extensions: &mut InstanceExtensions| unsafe {
         let version = entry_loader.try_enumerate_instance_version()?.unwrap_or(vk::API_VERSION_1_0);
-        let major = vk::api_version_major(version);
-        let minor = vk::api_version_minor(version);
-
-        if major < 1 || minor < 1 {
-            bail!(
-                "Only Vulkan {}.{}.{} is supported, but minimum supported version is 1.1",
-                major,
-                minor,
-                vk::api_version_patch(version)
-            );
+        check_version(version, plugin.min_vulkan_version)?;
+
+        if plugin.enable_validation {
+            if !layers.try_push_str("VK_LAYER_KHRONOS_validation") {
+                warn!("Validation was requested, but VK_LAYER_KHRONOS_validation is not available");
+            }
+
+            extensions.try_push(DebugUtils::name().as_ptr());
         }
 
-        layers.push("VK_LAYER_KHRONOS_validation\0".as_ptr().cast());
+        if window.is_some() {
+            extensions.push(GetSurfaceCapabilities2::name().as_ptr());
+        }
 
-        extensions.push(GetSurfaceCapabilities2::name().as_ptr());
+        //Only exposed by portability implementations such as MoltenVK
+        extensions.try_push(KHR_PORTABILITY_ENUMERATION_NAME.as_ptr().cast());
 
         Ok(version)
-    })
-    .unwrap();
-
-    let surface = Surface::new(instance.clone(), window).unwrap();
-
-    let device = unsafe {
-        Device::new(
-            instance.clone(),
-            surface.clone(),
-            instance.find_optimal_physical_device(),
-            |properties, _memory_properties, _queue_family_properties, extensions, _supported_features, _enabled_features| {
-                let version = properties.properties.api_version;
-                let major = vk::api_version_minor(version);
-                let minor = vk::api_version_minor(version);
-
-                if major < 1 || minor < 1 {
-                    bail!(
-                        "Only Vulkan {}.{}.{} is supported, but minimum supported version is 1.1",
-                        major,
-                        minor,
-                        vk::api_version_patch(version)
-                    );
-                }
+    };
+
+    match window {
+        Some(window) => Instance::new(window, &plugin.application_info, plugin.debug_message_severity, callback),
+        None => Instance::new_headless(&plugin.application_info, plugin.debug_message_severity, callback)
+    }
+}
+
+fn select_physical_device(instance: &Instance, adapter_index: Option<usize>) -> vk::PhysicalDevice {
+    //KAMEL_GPU selects an adapter by index or by a case-insensitive name substring
+    if let Ok(gpu) = env::var("KAMEL_GPU") {
+        let physical_device = match gpu.parse::<usize>() {
+            Ok(index) => instance.physical_devices().get(index).copied(),
+            Err(_) => instance.find_physical_device_by_name(&gpu)
+        };
+
+        match physical_device {
+            Some(physical_device) => return physical_device,
+            None => warn!("KAMEL_GPU={} does not match any physical device", gpu)
+        }
+    }
 
-                extensions.try_push(b"VK_KHR_portability_subset\0".as_ptr().cast());
+    if let Some(index) = adapter_index {
+        match instance.physical_devices().get(index) {
+            Some(physical_device) => return *physical_device,
+            None => warn!("Adapter index {} is out of range, {} adapters are available", index, instance.physical_devices().len())
+        }
+    }
+
+    //KAMEL_FORCE_SOFTWARE=1 prefers a CPU device, e.g. lavapipe on CI runners without a GPU
+    let prefer_software = env::var("KAMEL_FORCE_SOFTWARE").map_or(false, |value| value == "1");
+
+    let physical_device = instance.find_optimal_physical_device(prefer_software);
+    if prefer_software
+        && physical_device != vk::PhysicalDevice::null()
+        && unsafe { instance.loader().get_physical_device_properties(physical_device).device_type } != vk::PhysicalDeviceType::CPU
+    {
+        warn!("KAMEL_FORCE_SOFTWARE is set but no CPU device is present");
+    }
+
+    physical_device
+}
+
+unsafe fn create_device(instance: &Arc<Instance>, surface: Option<Arc<Surface>>, plugin: &RenderPlugin) -> Result<Arc<Device>> {
+    let headless = surface.is_none();
+    let min_vulkan_version = plugin.min_vulkan_version;
+    let requested_features = plugin.requested_features;
+
+    Device::new(
+        instance.clone(),
+        surface,
+        select_physical_device(instance, plugin.adapter_index),
+        |properties, _memory_properties, _queue_family_properties, extensions, supported_features, enabled_features| {
+            check_version(properties.properties.api_version, min_vulkan_version)?;
+
+            extensions.try_push_str("VK_KHR_portability_subset");
+            if !headless {
                 extensions.push(khr::Swapchain::name().as_ptr());
-                extensions.try_push(MeshShader::name().as_ptr());
+                extensions.try_push(vk::ExtHdrMetadataFn::name().as_ptr());
+            }
+            for feature in requested_features.enable_supported(&supported_features.features, &mut enabled_features.features) {
+                warn!("Requested device feature {} is not supported", feature);
+            }
+
+            if extensions.try_push(MeshShader::name().as_ptr()) {
+                enabled_features.mesh_shader_features.mesh_shader = supported_features.mesh_shader_features.mesh_shader;
+                enabled_features.mesh_shader_features.task_shader = supported_features.mesh_shader_features.task_shader;
+            }
+
+            //Core since Vulkan 1.2, where it is enabled through PhysicalDeviceVulkan12Features instead of the extension
+            if is_vulkan12(properties.properties.api_version) {
+                enabled_features.vulkan12_features.timeline_semaphore = supported_features.vulkan12_features.timeline_semaphore;
+            } else if supported_features.timeline_semaphore_features.timeline_semaphore == vk::TRUE && extensions.try_push(khr::TimelineSemaphore::name().as_ptr()) {
+                enabled_features.timeline_semaphore_features.timeline_semaphore = vk::TRUE;
+            }
+
+            if supported_features.dynamic_rendering_features.dynamic_rendering == vk::TRUE {
+                let core = vk::api_version_major(properties.properties.api_version) > 1 || vk::api_version_minor(properties.properties.api_version) >= 3;
+                if extensions.try_push(khr::DynamicRendering::name().as_ptr()) || core {
+                    enabled_features.dynamic_rendering_features.dynamic_rendering = vk::TRUE;
+                }
+            }
 
-                Ok(())
+            if supported_features.synchronization2_features.synchronization2 == vk::TRUE {
+                let core = vk::api_version_major(properties.properties.api_version) > 1 || vk::api_version_minor(properties.properties.api_version) >= 3;
+                if extensions.try_push(khr::Synchronization2::name().as_ptr()) || core {
+                    enabled_features.synchronization2_features.synchronization2 = vk::TRUE;
+                }
+            }
+
+            //Bindless textures, core since Vulkan 1.2
+            let supported_descriptor_indexing = &supported_features.descriptor_indexing_features;
+            if supported_descriptor_indexing.descriptor_binding_partially_bound == vk::TRUE
+                && supported_descriptor_indexing.runtime_descriptor_array == vk::TRUE
+                && supported_descriptor_indexing.shader_sampled_image_array_non_uniform_indexing == vk::TRUE
+                && supported_descriptor_indexing.descriptor_binding_sampled_image_update_after_bind == vk::TRUE
+            {
+                let core = vk::api_version_major(properties.properties.api_version) > 1 || vk::api_version_minor(properties.properties.api_version) >= 2;
+                if core || (extensions.try_push(KHR_MAINTENANCE3_NAME.as_ptr().cast()) && extensions.try_push(EXT_DESCRIPTOR_INDEXING_NAME.as_ptr().cast())) {
+                    let enabled_descriptor_indexing = &mut enabled_features.descriptor_indexing_features;
+                    enabled_descriptor_indexing.descriptor_binding_partially_bound = vk::TRUE;
+                    enabled_descriptor_indexing.runtime_descriptor_array = vk::TRUE;
+                    enabled_descriptor_indexing.shader_sampled_image_array_non_uniform_indexing = vk::TRUE;
+                    enabled_descriptor_indexing.descriptor_binding_sampled_image_update_after_bind = vk::TRUE;
+                }
+            }
+
+            if requested_features.buffer_device_address {
+                if supported_features.buffer_device_address_features.buffer_device_address == vk::TRUE
+                    && extensions.try_push(khr::BufferDeviceAddress::name().as_ptr())
+                {
+                    enabled_features.buffer_device_address_features.buffer_device_address = vk::TRUE;
+                } else {
+                    warn!("Requested device feature bufferDeviceAddress is not supported");
+                }
+            }
+
+            if requested_features.ray_tracing {
+                let supported = supported_features.acceleration_structure_features.acceleration_structure == vk::TRUE
+                    && supported_features.ray_tracing_pipeline_features.ray_tracing_pipeline == vk::TRUE
+                    && supported_features.buffer_device_address_features.buffer_device_address == vk::TRUE
+                    && extensions.is_supported(khr::DeferredHostOperations::name().as_ptr())
+                    && extensions.is_supported(khr::AccelerationStructure::name().as_ptr())
+                    && extensions.is_supported(khr::RayTracingPipeline::name().as_ptr())
+                    && extensions.is_supported(khr::BufferDeviceAddress::name().as_ptr());
+
+                if supported {
+                    extensions.try_push(khr::DeferredHostOperations::name().as_ptr());
+                    extensions.try_push(khr::AccelerationStructure::name().as_ptr());
+                    extensions.try_push(khr::RayTracingPipeline::name().as_ptr());
+                    extensions.try_push(khr::BufferDeviceAddress::name().as_ptr());
+
+                    enabled_features.acceleration_structure_features.acceleration_structure = vk::TRUE;
+                    enabled_features.ray_tracing_pipeline_features.ray_tracing_pipeline = vk::TRUE;
+                    enabled_features.buffer_device_address_features.buffer_device_address = vk::TRUE;
+                } else {
+                    warn!("Requested ray tracing is not supported");
+                }
             }
-        )
-        .unwrap()
+
+            Ok(())
+        }
+    )
+}
+
+pub fn initialize(windows: &Windows, plugin: &RenderPlugin) -> (Arc<Instance>, Arc<Device>, Surfaces) {
+    let primary_window = windows.get_primary().unwrap();
+    let raw_handle = unsafe { primary_window.raw_window_handle().get_handle() };
+
+    let instance = create_instance(Some(&raw_handle), plugin).unwrap();
+
+    let surface = Surface::new(instance.clone(), &raw_handle).unwrap();
+
+    let device = unsafe { create_device(&instance, Some(surface.clone()), plugin).unwrap() };
+
+    let swapchain_desc = SwapchainDesc {
+        color_preference: plugin.color_preference,
+        frames_in_flight: plugin.frames_in_flight,
+        image_usage: vk::ImageUsageFlags::COLOR_ATTACHMENT | plugin.swapchain_image_usage,
+        ..Default::default()
     };
 
-    let swapchain = Swapchain::new(instance.clone(), surface.clone(), device.clone(), true).unwrap();
+    let mut surfaces = Surfaces::new(swapchain_desc);
+    surfaces.insert(&instance, &device, primary_window.id(), surface, window_extent(primary_window)).unwrap();
+
+    for window in windows.iter().filter(|window| window.id() != primary_window.id()) {
+        if let Err(e) = surfaces.create(&instance, &device, window) {
+            error!("Failed to create surface for window {:?}: {:?}", window.id(), e);
+        }
+    }
+
+    (instance, device, surfaces)
+}
+
+pub fn initialize_headless(plugin: &RenderPlugin) -> (Arc<Instance>, Arc<Device>) {
+    let instance = create_instance(None, plugin).unwrap();
+
+    let device = unsafe { create_device(&instance, None, plugin).unwrap() };
+
+    (instance, device)
+}
+
+//Validated headless device for tests, None without a Vulkan implementation so GPU tests skip instead of failing
+#[cfg(test)]
+pub(crate) fn test_device() -> Option<Arc<Device>> {
+    let plugin = RenderPlugin {
+        enable_validation: true,
+        ..Default::default()
+    };
+
+    let instance = create_instance(None, &plugin).ok()?;
+
+    unsafe { create_device(&instance, None, &plugin).ok() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headless_device_has_no_surface_extensions() {
+        let device = match test_device() {
+            Some(device) => device,
+            None => return
+        };
 
-    (instance, surface, device, swapchain)
+        assert!(!device.instance().extensions().khr_surface());
+        assert!(!device.extensions().khr_swapchain());
+    }
 }
@@ -0,0 +1,163 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{ensure, Result};
+use ash::vk;
+use kamel_bevy::{
+    ecs::{
+        event::EventReader,
+        system::{Res, ResMut}
+    },
+    window::{Window, WindowClosed, WindowCreated, WindowId, WindowResized, Windows}
+};
+use log::error;
+
+use crate::backend::{Device, Instance, Surface, Swapchain, SwapchainDesc};
+
+#[inline]
+pub fn window_extent(window: &Window) -> vk::Extent2D {
+    vk::Extent2D {
+        width: window.physical_width(),
+        height: window.physical_height()
+    }
+}
+
+pub struct Surfaces {
+    surfaces: HashMap<WindowId, Swapchain>,
+    swapchain_desc: SwapchainDesc
+}
+
+impl Surfaces {
+    #[inline]
+    pub fn new(swapchain_desc: SwapchainDesc) -> Self {
+        Self {
+            surfaces: HashMap::new(),
+            swapchain_desc
+        }
+    }
+
+    pub fn create(&mut self, instance: &Arc<Instance>, device: &Arc<Device>, window: &Window) -> Result<()> {
+        let raw_handle = unsafe { window.raw_window_handle().get_handle() };
+        let surface = Surface::new(instance.clone(), &raw_handle)?;
+
+        self.insert(instance, device, window.id(), surface, window_extent(window))
+    }
+
+    pub fn insert(&mut self, instance: &Arc<Instance>, device: &Arc<Device>, window_id: WindowId, surface: Arc<Surface>, window_extent: vk::Extent2D) -> Result<()> {
+        //The present queue is fixed at device creation, a window on an output it can't reach can't get a swapchain
+        ensure!(
+            surface.supports_present(device)?,
+            "Present queue family {} can't present to the surface of window {:?}",
+            device.present_queue().family_index(),
+            window_id
+        );
+
+        let swapchain = Swapchain::new(instance.clone(), surface, device.clone(), &self.swapchain_desc, window_extent)?;
+        self.surfaces.insert(window_id, swapchain);
+
+        Ok(())
+    }
+
+    pub fn recreate_surface(&mut self, window: &Window) -> Result<()> {
+        if let Some(swapchain) = self.surfaces.get_mut(&window.id()) {
+            let raw_handle = unsafe { window.raw_window_handle().get_handle() };
+            swapchain.set_window_extent(window_extent(window));
+            swapchain.recreate_surface(&raw_handle)?;
+        }
+
+        Ok(())
+    }
+
+    #[inline]
+    pub fn remove(&mut self, window_id: WindowId) -> Option<Swapchain> {
+        self.surfaces.remove(&window_id)
+    }
+
+    #[inline]
+    pub fn contains(&self, window_id: WindowId) -> bool {
+        self.surfaces.contains_key(&window_id)
+    }
+
+    #[inline]
+    pub fn get(&self, window_id: WindowId) -> Option<&Swapchain> {
+        self.surfaces.get(&window_id)
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self, window_id: WindowId) -> Option<&mut Swapchain> {
+        self.surfaces.get_mut(&window_id)
+    }
+
+    #[inline]
+    pub fn iter(&self) -> impl Iterator<Item = (&WindowId, &Swapchain)> {
+        self.surfaces.iter()
+    }
+
+    #[inline]
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&WindowId, &mut Swapchain)> {
+        self.surfaces.iter_mut()
+    }
+
+    #[inline]
+    pub fn swapchain_desc(&self) -> &SwapchainDesc {
+        &self.swapchain_desc
+    }
+}
+
+pub fn window_surface_system(
+    mut surfaces: ResMut<Surfaces>,
+    windows: Res<Windows>,
+    instance: Res<Arc<Instance>>,
+    device: Res<Arc<Device>>,
+    mut window_created_events: EventReader<WindowCreated>,
+    mut window_closed_events: EventReader<WindowClosed>,
+    mut window_resized_events: EventReader<WindowResized>
+) {
+    for event in window_created_events.iter() {
+        if surfaces.contains(event.id) {
+            continue
+        }
+
+        if let Some(window) = windows.get(event.id) {
+            if let Err(e) = surfaces.create(&instance, &device, window) {
+                error!("Failed to create surface for window {:?}: {:?}", event.id, e);
+            }
+        }
+    }
+
+    for event in window_closed_events.iter() {
+        if surfaces.contains(event.id) {
+            if let Err(e) = device.wait_idle() {
+                error!("Failed to wait for device idle: {:?}", e);
+            }
+
+            surfaces.remove(event.id);
+        }
+    }
+
+    for event in window_resized_events.iter() {
+        if let Some(swapchain) = surfaces.get_mut(event.id) {
+            match windows.get(event.id) {
+                Some(window) => swapchain.set_window_extent(window_extent(window)),
+                None => swapchain.request_recreate()
+            }
+        }
+    }
+}
+
+pub fn swapchain_resize_system(mut surfaces: ResMut<Surfaces>, device: Res<Arc<Device>>) {
+    if !surfaces.iter().any(|(_, swapchain)| swapchain.needs_recreate()) {
+        return
+    }
+
+    //Frames in flight may still reference the old swapchain images
+    if let Err(e) = device.wait_idle() {
+        error!("Failed to wait for device idle: {:?}", e);
+        return
+    }
+
+    for (window_id, swapchain) in surfaces.iter_mut().filter(|(_, swapchain)| swapchain.needs_recreate()) {
+        if let Err(e) = swapchain.recreate() {
+            error!("Failed to recreate swapchain for window {:?}: {:?}", window_id, e);
+        }
+    }
+}
@@ -1,3 +1,5 @@
+mod reflection;
 mod shader;
 
+pub use reflection::*;
 pub use shader::*;
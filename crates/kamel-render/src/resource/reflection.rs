@@ -0,0 +1,196 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, bail, Result};
+use ash::vk;
+use rspirv_reflect::{
+    rspirv::dr::{self, Operand},
+    spirv::ExecutionModel,
+    BindingCount, Reflection
+};
+
+use crate::resource::ShaderStage;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DescriptorBinding {
+    pub set: u32,
+    pub binding: u32,
+    pub descriptor_type: vk::DescriptorType,
+    //0 for runtime sized arrays
+    pub count: u32,
+    pub name: String
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ShaderReflection {
+    entry_points: Vec<(ShaderStage, String)>,
+    descriptor_bindings: Vec<DescriptorBinding>,
+    push_constant_ranges: Vec<vk::PushConstantRange>
+}
+
+impl ShaderReflection {
+    pub fn new(spirv: &[u32]) -> Result<Self> {
+        let module = dr::load_words(spirv).map_err(|e| anyhow!("Failed to parse SPIR-V: {}", e))?;
+
+        let entry_points: Vec<_> = module
+            .entry_points
+            .iter()
+            .filter_map(|instruction| match (&instruction.operands[0], &instruction.operands[2]) {
+                (Operand::ExecutionModel(execution_model), Operand::LiteralString(name)) => {
+                    let stage = match execution_model {
+                        ExecutionModel::Vertex => ShaderStage::Vertex,
+                        ExecutionModel::Fragment => ShaderStage::Fragment,
+                        ExecutionModel::GLCompute => ShaderStage::Compute,
+                        ExecutionModel::MeshNV => ShaderStage::Mesh,
+                        ExecutionModel::TaskNV => ShaderStage::Task,
+                        _ => return None
+                    };
+
+                    Some((stage, name.clone()))
+                }
+                _ => None
+            })
+            .collect();
+
+        let stage_flags = entry_points.iter().fold(vk::ShaderStageFlags::empty(), |flags, (stage, _)| flags | stage.to_vk());
+
+        let reflection = Reflection::new(module);
+
+        let mut descriptor_bindings = Vec::new();
+        for (set, bindings) in reflection.get_descriptor_sets().map_err(|e| anyhow!("Failed to reflect descriptor sets: {:?}", e))? {
+            for (binding, info) in bindings {
+                descriptor_bindings.push(DescriptorBinding {
+                    set,
+                    binding,
+                    descriptor_type: vk::DescriptorType::from_raw(info.ty.0 as i32),
+                    count: match info.binding_count {
+                        BindingCount::One => 1,
+                        BindingCount::StaticSized(count) => count as u32,
+                        BindingCount::Unbounded => 0
+                    },
+                    name: info.name
+                });
+            }
+        }
+
+        let push_constant_ranges = reflection
+            .get_push_constant_range()
+            .map_err(|e| anyhow!("Failed to reflect push constants: {:?}", e))?
+            .map(|info| vk::PushConstantRange::default().stage_flags(stage_flags).offset(info.offset).size(info.size))
+            .into_iter()
+            .collect();
+
+        Ok(Self {
+            entry_points,
+            descriptor_bindings,
+            push_constant_ranges
+        })
+    }
+
+    #[inline]
+    pub fn entry_points(&self) -> &[(ShaderStage, String)] {
+        &self.entry_points
+    }
+
+    #[inline]
+    pub fn descriptor_bindings(&self) -> &[DescriptorBinding] {
+        &self.descriptor_bindings
+    }
+
+    #[inline]
+    pub fn push_constant_ranges(&self) -> &[vk::PushConstantRange] {
+        &self.push_constant_ranges
+    }
+
+    #[inline]
+    pub fn stage_flags(&self) -> vk::ShaderStageFlags {
+        self.entry_points.iter().fold(vk::ShaderStageFlags::empty(), |flags, (stage, _)| flags | stage.to_vk())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PipelineLayoutDesc {
+    pub sets: BTreeMap<u32, Vec<vk::DescriptorSetLayoutBinding<'static>>>,
+    pub push_constant_ranges: Vec<vk::PushConstantRange>
+}
+
+impl PipelineLayoutDesc {
+    pub fn from_reflections(reflections: &[&ShaderReflection]) -> Result<Self> {
+        let mut desc = Self::default();
+
+        for reflection in reflections.iter() {
+            let stage_flags = reflection.stage_flags();
+
+            for descriptor_binding in reflection.descriptor_bindings.iter() {
+                let bindings = desc.sets.entry(descriptor_binding.set).or_default();
+
+                match bindings.iter_mut().find(|binding| binding.binding == descriptor_binding.binding) {
+                    Some(binding) => {
+                        if binding.descriptor_type != descriptor_binding.descriptor_type || binding.descriptor_count != descriptor_binding.count {
+                            bail!(
+                                "Conflicting declarations of set {} binding {} ({})",
+                                descriptor_binding.set,
+                                descriptor_binding.binding,
+                                descriptor_binding.name
+                            );
+                        }
+
+                        binding.stage_flags |= stage_flags;
+                    }
+                    None => bindings.push(
+                        vk::DescriptorSetLayoutBinding::default()
+                            .binding(descriptor_binding.binding)
+                            .descriptor_type(descriptor_binding.descriptor_type)
+                            .descriptor_count(descriptor_binding.count)
+                            .stage_flags(stage_flags)
+                    )
+                }
+            }
+
+            for push_constant_range in reflection.push_constant_ranges.iter() {
+                match desc
+                    .push_constant_ranges
+                    .iter_mut()
+                    .find(|range| range.offset == push_constant_range.offset && range.size == push_constant_range.size)
+                {
+                    Some(range) => range.stage_flags |= push_constant_range.stage_flags,
+                    None => desc.push_constant_ranges.push(*push_constant_range)
+                }
+            }
+        }
+
+        Ok(desc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resource::Shader;
+
+    const FRAGMENT_GLSL: &str = "#version 450
+layout(set = 0, binding = 0) uniform Material { vec4 tint; } material;
+layout(set = 0, binding = 1) uniform sampler linear_sampler;
+layout(set = 0, binding = 2) uniform texture2D albedo;
+layout(location = 0) in vec2 uv;
+layout(location = 0) out vec4 color;
+void main() { color = material.tint * texture(sampler2D(albedo, linear_sampler), uv); }
+";
+
+    #[test]
+    fn reflects_uniform_buffer_and_sampler() {
+        let spirv = Shader::from_glsl(FRAGMENT_GLSL).compile(ShaderStage::Fragment, "main").unwrap();
+        let reflection = ShaderReflection::new(&spirv).unwrap();
+
+        let binding = |binding: u32| reflection.descriptor_bindings().iter().find(|descriptor_binding| descriptor_binding.set == 0 && descriptor_binding.binding == binding).unwrap();
+
+        assert_eq!(binding(0).descriptor_type, vk::DescriptorType::UNIFORM_BUFFER);
+        assert_eq!(binding(1).descriptor_type, vk::DescriptorType::SAMPLER);
+        assert_eq!(binding(1).count, 1);
+        assert_eq!(reflection.stage_flags(), vk::ShaderStageFlags::FRAGMENT);
+
+        let desc = PipelineLayoutDesc::from_reflections(&[&reflection]).unwrap();
+        let set = &desc.sets[&0];
+        assert_eq!(set.len(), 3);
+        assert!(set.iter().all(|binding| binding.stage_flags == vk::ShaderStageFlags::FRAGMENT));
+    }
+}
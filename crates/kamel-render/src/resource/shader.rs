@@ -1,52 +1,538 @@
-use std::borrow::Cow;
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    error,
+    fmt::{self, Write},
+    io::Cursor,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex}
+};
 
-use anyhow::{Error, Result};
+use anyhow::{anyhow, bail, ensure, Context, Error, Result};
+use ash::vk;
 use kamel_bevy::{
-    asset::{AssetLoader, BoxedFuture, LoadContext, LoadedAsset},
+    asset::{AssetEvent, AssetLoader, AssetPath, AssetServer, Assets, BoxedFuture, Handle, LoadContext, LoadedAsset},
+    ecs::{
+        event::{EventReader, EventWriter},
+        system::Res
+    },
     reflect::{self as bevy_reflect, TypeUuid}
 };
+use shaderc::{CompileOptions, Compiler, ShaderKind};
+
+use crate::resource::ShaderReflection;
+
+//Preprocessor macros for shader variants, ordered so equal sets hash equally
+#[derive(Debug, Clone, Default, Hash, PartialEq, Eq)]
+pub struct ShaderDefines(BTreeMap<String, String>);
+
+impl ShaderDefines {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[inline]
+    pub fn define(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.insert(name, value);
+        self
+    }
+
+    #[inline]
+    pub fn insert(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.0.insert(name.into(), value.into());
+    }
+
+    #[inline]
+    pub fn contains(&self, name: &str) -> bool {
+        self.0.contains_key(name)
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    #[inline]
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+}
 
 #[derive(Debug, Clone, TypeUuid)]
 #[uuid = "d09ec4a9-f995-429d-8924-d3cf6ddbc1bc"]
 pub struct Shader {
-    source: Source
+    source: Source,
+    //Files expanded into source by #include, shader_reload_system reloads the shader when one of them changes
+    includes: Vec<PathBuf>,
+    compiled: Arc<Mutex<HashMap<(ShaderStage, String, ShaderDefines), Vec<u32>>>>
 }
 
 impl Shader {
     pub fn from_hlsl(source: impl Into<Cow<'static, str>>) -> Self {
         Self {
-            source: Source::Hlsl(source.into())
+            source: Source::Hlsl(source.into()),
+            includes: Vec::new(),
+            compiled: Default::default()
+        }
+    }
+
+    pub fn from_glsl(source: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            source: Source::Glsl(source.into()),
+            includes: Vec::new(),
+            compiled: Default::default()
+        }
+    }
+
+    pub fn from_wgsl(source: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            source: Source::Wgsl(source.into()),
+            includes: Vec::new(),
+            compiled: Default::default()
         }
     }
 
     pub fn from_spirv(source: impl Into<Cow<'static, [u8]>>) -> Self {
         Self {
-            source: Source::SpirV(source.into())
+            source: Source::SpirV(source.into()),
+            includes: Vec::new(),
+            compiled: Default::default()
+        }
+    }
+
+    #[inline]
+    pub fn compile(&self, stage: ShaderStage, entry_point: &str) -> Result<Vec<u32>> {
+        self.compile_with_defines(stage, entry_point, &ShaderDefines::default())
+    }
+
+    //Each set of defines is compiled once and cached as its own variant
+    pub fn compile_with_defines(&self, stage: ShaderStage, entry_point: &str, defines: &ShaderDefines) -> Result<Vec<u32>> {
+        let key = (stage, entry_point.to_owned(), defines.clone());
+
+        if let Some(words) = self.compiled.lock().unwrap().get(&key) {
+            return Ok(words.clone())
+        }
+
+        let words = match &self.source {
+            Source::Hlsl(source) => {
+                let hlsl_defines: Vec<_> = defines.iter().map(|(name, value)| (name, Some(value))).collect();
+                let spirv = hassle_rs::compile_hlsl("shader.hlsl", source, entry_point, stage.target_profile(), &["-spirv"], &hlsl_defines)
+                    .with_context(|| format!("Failed to compile HLSL {:?} shader with entry point {}", stage, entry_point))?;
+
+                ash::util::read_spv(&mut Cursor::new(spirv))?
+            }
+            Source::Glsl(source) => ash::util::read_spv(&mut Cursor::new(compile_glsl(source, stage, "shader.glsl", entry_point, defines)?))?,
+            Source::Wgsl(source) => compile_wgsl(&preprocess_wgsl(source, defines)?, stage, entry_point)?,
+            Source::SpirV(source) => {
+                ensure!(defines.is_empty(), "Precompiled SPIR-V shaders can't be compiled with defines");
+                read_spirv(source)?
+            }
+        };
+
+        self.compiled.lock().unwrap().insert(key, words.clone());
+
+        Ok(words)
+    }
+
+    pub fn reflect(&self) -> Result<ShaderReflection> {
+        match &self.source {
+            Source::SpirV(source) => ShaderReflection::new(&read_spirv(source)?),
+            _ => bail!("Only SPIR-V shaders can be reflected directly, reflect the output of Shader::compile instead")
         }
     }
+
+    pub fn entry_points(&self) -> Result<Vec<(ShaderStage, String)>> {
+        match &self.source {
+            Source::Wgsl(source) => {
+                let (module, _) = parse_wgsl(&preprocess_wgsl(source, &ShaderDefines::default())?)?;
+
+                Ok(module
+                    .entry_points
+                    .iter()
+                    .map(|entry_point| (ShaderStage::from_naga(entry_point.stage), entry_point.name.clone()))
+                    .collect())
+            }
+            _ => Ok(self.reflect()?.entry_points().to_vec())
+        }
+    }
+
+    #[inline]
+    pub fn source(&self) -> &Source {
+        &self.source
+    }
+
+    #[inline]
+    pub fn includes(&self) -> &[PathBuf] {
+        &self.includes
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum Source {
     Hlsl(Cow<'static, str>),
+    Glsl(Cow<'static, str>),
+    Wgsl(Cow<'static, str>),
     SpirV(Cow<'static, [u8]>)
 }
 
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum ShaderStage {
+    Vertex,
+    Fragment,
+    Compute,
+    Mesh,
+    Task
+}
+
+impl ShaderStage {
+    #[inline]
+    pub fn from_extension(ext: &str) -> Option<Self> {
+        match ext {
+            "vert" => Some(Self::Vertex),
+            "frag" => Some(Self::Fragment),
+            "comp" => Some(Self::Compute),
+            "mesh" => Some(Self::Mesh),
+            "task" => Some(Self::Task),
+            _ => None
+        }
+    }
+
+    #[inline]
+    pub fn from_naga(stage: naga::ShaderStage) -> Self {
+        match stage {
+            naga::ShaderStage::Vertex => Self::Vertex,
+            naga::ShaderStage::Fragment => Self::Fragment,
+            naga::ShaderStage::Compute => Self::Compute
+        }
+    }
+
+    #[inline]
+    fn to_naga(self) -> Option<naga::ShaderStage> {
+        match self {
+            Self::Vertex => Some(naga::ShaderStage::Vertex),
+            Self::Fragment => Some(naga::ShaderStage::Fragment),
+            Self::Compute => Some(naga::ShaderStage::Compute),
+            Self::Mesh | Self::Task => None
+        }
+    }
+
+    #[inline]
+    pub fn to_vk(self) -> vk::ShaderStageFlags {
+        match self {
+            Self::Vertex => vk::ShaderStageFlags::VERTEX,
+            Self::Fragment => vk::ShaderStageFlags::FRAGMENT,
+            Self::Compute => vk::ShaderStageFlags::COMPUTE,
+            Self::Mesh => vk::ShaderStageFlags::MESH_NV,
+            Self::Task => vk::ShaderStageFlags::TASK_NV
+        }
+    }
+
+    #[inline]
+    pub fn target_profile(self) -> &'static str {
+        match self {
+            Self::Vertex => "vs_6_0",
+            Self::Fragment => "ps_6_0",
+            Self::Compute => "cs_6_0",
+            Self::Mesh => "ms_6_5",
+            Self::Task => "as_6_5"
+        }
+    }
+
+    #[inline]
+    fn shader_kind(self) -> ShaderKind {
+        match self {
+            Self::Vertex => ShaderKind::Vertex,
+            Self::Fragment => ShaderKind::Fragment,
+            Self::Compute => ShaderKind::Compute,
+            Self::Mesh => ShaderKind::Mesh,
+            Self::Task => ShaderKind::Task
+        }
+    }
+}
+
+fn compile_glsl(source: &str, stage: ShaderStage, file_name: &str, entry_point: &str, defines: &ShaderDefines) -> Result<Vec<u8>> {
+    let compiler = Compiler::new().ok_or_else(|| anyhow!("Failed to create shaderc compiler"))?;
+
+    let mut options = CompileOptions::new().ok_or_else(|| anyhow!("Failed to create shaderc compile options"))?;
+    for (name, value) in defines.iter() {
+        options.add_macro_definition(name, Some(value));
+    }
+
+    let artifact = compiler.compile_into_spirv(source, stage.shader_kind(), file_name, entry_point, Some(&options))?;
+
+    Ok(artifact.as_binary_u8().to_vec())
+}
+
+fn parse_wgsl(source: &str) -> Result<(naga::Module, naga::valid::ModuleInfo)> {
+    let module = naga::front::wgsl::parse_str(source).map_err(|e| anyhow!("Failed to parse WGSL:\n{}", e.emit_to_string(source)))?;
+
+    let module_info = naga::valid::Validator::new(naga::valid::ValidationFlags::all(), naga::valid::Capabilities::all())
+        .validate(&module)
+        .map_err(|e| anyhow!("Failed to validate WGSL: {:?}", e))?;
+
+    Ok((module, module_info))
+}
+
+//WGSL has no preprocessor, so only #ifdef, #ifndef, #else and #endif lines are handled here, values are not substituted
+fn preprocess_wgsl(source: &str, defines: &ShaderDefines) -> Result<String> {
+    let mut output = String::with_capacity(source.len());
+    //Whether each enclosing block is active
+    let mut scopes = vec![true];
+
+    for (i, line) in source.lines().enumerate() {
+        let trimmed = line.trim();
+
+        if let Some(name) = trimmed.strip_prefix("#ifdef ") {
+            scopes.push(*scopes.last().unwrap() && defines.contains(name.trim()));
+        } else if let Some(name) = trimmed.strip_prefix("#ifndef ") {
+            scopes.push(*scopes.last().unwrap() && !defines.contains(name.trim()));
+        } else if trimmed == "#else" {
+            ensure!(scopes.len() > 1, "#else without #ifdef on line {}", i + 1);
+            let active = !scopes.pop().unwrap();
+            scopes.push(*scopes.last().unwrap() && active);
+        } else if trimmed == "#endif" {
+            ensure!(scopes.len() > 1, "#endif without #ifdef on line {}", i + 1);
+            scopes.pop();
+        } else if *scopes.last().unwrap() {
+            output.push_str(line);
+        }
+
+        //Keeps line numbers in naga errors intact
+        output.push('\n');
+    }
+
+    ensure!(scopes.len() == 1, "Unterminated #ifdef in WGSL shader");
+
+    Ok(output)
+}
+
+fn compile_wgsl(source: &str, stage: ShaderStage, entry_point: &str) -> Result<Vec<u32>> {
+    let naga_stage = stage.to_naga().ok_or_else(|| anyhow!("WGSL does not support {:?} shaders", stage))?;
+
+    let (module, module_info) = parse_wgsl(source)?;
+    if !module.entry_points.iter().any(|e| e.stage == naga_stage && e.name == entry_point) {
+        bail!("WGSL module has no {:?} entry point named {}", stage, entry_point);
+    }
+
+    let pipeline_options = naga::back::spv::PipelineOptions {
+        shader_stage: naga_stage,
+        entry_point: entry_point.to_owned()
+    };
+
+    naga::back::spv::write_vec(&module, &module_info, &naga::back::spv::Options::default(), Some(&pipeline_options))
+        .with_context(|| format!("Failed to compile WGSL {:?} shader with entry point {}", stage, entry_point))
+}
+
+const SPIRV_MAGIC: u32 = 0x0723_0203;
+
+//Checks the header before handing the bytes to ash, which would otherwise fail with a bare io error
+fn read_spirv(bytes: &[u8]) -> Result<Vec<u32>> {
+    ensure!(bytes.len() % 4 == 0, "SPIR-V size of {} bytes is not a multiple of 4", bytes.len());
+    ensure!(bytes.len() >= 20, "SPIR-V size of {} bytes is smaller than the 20 byte header", bytes.len());
+
+    let magic = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    ensure!(
+        magic == SPIRV_MAGIC || magic.swap_bytes() == SPIRV_MAGIC,
+        "Invalid SPIR-V magic number {:#010x}, expected {:#010x}",
+        magic,
+        SPIRV_MAGIC
+    );
+
+    Ok(ash::util::read_spv(&mut Cursor::new(bytes))?)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpirvValidationError {
+    pub instruction: Option<usize>,
+    pub message: String
+}
+
+impl fmt::Display for SpirvValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.instruction {
+            Some(instruction) => write!(f, "SPIR-V validation failed at instruction {}: {}", instruction, self.message),
+            None => write!(f, "SPIR-V validation failed: {}", self.message)
+        }
+    }
+}
+
+impl error::Error for SpirvValidationError {}
+
+#[cfg(feature = "spirv-validation")]
+pub fn validate_spirv(words: &[u32]) -> Result<(), SpirvValidationError> {
+    use spirv_tools::val::Validator;
+
+    spirv_tools::val::create(Some(spirv_tools::TargetEnv::Vulkan_1_1))
+        .validate(words, None)
+        .map_err(|e| match e.diagnostic {
+            Some(diagnostic) => SpirvValidationError {
+                instruction: Some(diagnostic.index),
+                message: diagnostic.message
+            },
+            None => SpirvValidationError {
+                instruction: None,
+                message: e.to_string()
+            }
+        })
+}
+
+#[cfg(not(feature = "spirv-validation"))]
+#[inline]
+pub fn validate_spirv(_words: &[u32]) -> Result<(), SpirvValidationError> {
+    Ok(())
+}
+
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component)
+        }
+    }
+
+    normalized
+}
+
+fn parse_include(line: &str) -> Option<&str> {
+    let include = line.trim_start().strip_prefix("#include")?.trim();
+
+    include
+        .strip_prefix('"')
+        .and_then(|include| include.strip_suffix('"'))
+        .or_else(|| include.strip_prefix('<').and_then(|include| include.strip_suffix('>')))
+}
+
+trait IncludeReader: Sync {
+    fn read<'a>(&'a self, path: &'a Path) -> BoxedFuture<'a, Result<Vec<u8>>>;
+}
+
+impl IncludeReader for LoadContext<'_> {
+    #[inline]
+    fn read<'a>(&'a self, path: &'a Path) -> BoxedFuture<'a, Result<Vec<u8>>> {
+        Box::pin(async move { Ok(self.read_asset_bytes(path).await?) })
+    }
+}
+
+//Expands #include directives relative to the including file, every file is included at most once.
+//Each expansion is wrapped in #line directives so compiler errors point into the right file and line
+fn resolve_includes<'a, R: IncludeReader>(reader: &'a R, path: &'a Path, source: &'a str, included: &'a mut Vec<PathBuf>) -> BoxedFuture<'a, Result<String>> {
+    Box::pin(async move {
+        let mut resolved = String::with_capacity(source.len());
+
+        for (i, line) in source.lines().enumerate() {
+            match parse_include(line) {
+                Some(include) => {
+                    let include_path = normalize_path(&path.parent().unwrap_or_else(|| Path::new("")).join(include));
+                    if included.contains(&include_path) {
+                        //Keeps the following line numbers intact
+                        resolved.push('\n');
+                        continue
+                    }
+                    included.push(include_path.clone());
+
+                    let bytes = reader
+                        .read(&include_path)
+                        .await
+                        .with_context(|| format!("Failed to resolve include {} in {}", include_path.display(), path.display()))?;
+                    let include_source = String::from_utf8(bytes)?;
+
+                    writeln!(resolved, "#line 1 \"{}\"", include_path.display())?;
+                    resolved.push_str(&resolve_includes(reader, &include_path, &include_source, included).await?);
+                    writeln!(resolved, "#line {} \"{}\"", i + 2, path.display())?;
+                }
+                None => {
+                    resolved.push_str(line);
+                    resolved.push('\n');
+                }
+            }
+        }
+
+        Ok(resolved)
+    })
+}
+
+//glslang only accepts file names in #line with GL_GOOGLE_cpp_style_line_directive, which has to follow #version
+fn enable_glsl_line_directives(path: &Path, source: &str) -> String {
+    let mut lines = source.lines();
+    let mut enabled = String::with_capacity(source.len() + 128);
+
+    let version_line = source.lines().position(|line| line.trim_start().starts_with("#version"));
+    if let Some(version_line) = version_line {
+        for line in lines.by_ref().take(version_line + 1) {
+            enabled.push_str(line);
+            enabled.push('\n');
+        }
+    }
+
+    let next_line = version_line.map_or(1, |version_line| version_line + 2);
+    enabled.push_str("#extension GL_GOOGLE_cpp_style_line_directive : require\n");
+    enabled.push_str(&format!("#line {} \"{}\"\n", next_line, path.display()));
+
+    for line in lines {
+        enabled.push_str(line);
+        enabled.push('\n');
+    }
+
+    enabled
+}
+
+//Sources are compiled lazily by Shader::compile, only the pipeline knows the stage and entry point to compile
+fn shader_from_source(path: &Path, ext: &str, source: String) -> Result<Shader> {
+    match ext {
+        "hlsl" => Ok(Shader::from_hlsl(source)),
+        "vert" | "frag" | "comp" | "glsl" => Ok(Shader::from_glsl(source)),
+        "wgsl" => {
+            //Parsing needs no entry point, so broken WGSL still fails the load
+            parse_wgsl(&preprocess_wgsl(&source, &ShaderDefines::default())?).with_context(|| format!("Failed to load {}", path.display()))?;
+
+            Ok(Shader::from_wgsl(source))
+        }
+        _ => bail!("Unhandled extension: {}", ext)
+    }
+}
+
 #[derive(Default)]
 pub struct ShaderLoader;
 
 impl AssetLoader for ShaderLoader {
     fn load<'a>(&'a self, bytes: &'a [u8], load_context: &'a mut LoadContext) -> BoxedFuture<'a, Result<(), Error>> {
         Box::pin(async move {
-            let ext = load_context.path().extension().unwrap().to_str().unwrap();
+            let path = load_context.path().to_path_buf();
+            let ext = path.extension().and_then(|ext| ext.to_str()).unwrap_or_default();
+
+            let mut included = Vec::new();
+
+            let mut shader = match ext {
+                "hlsl" | "vert" | "frag" | "comp" | "glsl" => {
+                    let mut source = resolve_includes(&*load_context, &path, std::str::from_utf8(bytes)?, &mut included).await?;
+                    if ext != "hlsl" && !included.is_empty() {
+                        source = enable_glsl_line_directives(&path, &source);
+                    }
 
-            let shader = match ext {
-                "hlsl" => Shader::from_hlsl(String::from_utf8(Vec::from(bytes))?),
-                "spv" => Shader::from_spirv(Vec::from(bytes)),
-                _ => panic!("Unhandled extension: {}", ext)
+                    shader_from_source(&path, ext, source)?
+                }
+                "wgsl" => shader_from_source(&path, ext, std::str::from_utf8(bytes)?.to_owned())?,
+                "spv" => {
+                    let words = read_spirv(bytes).with_context(|| format!("Failed to load {}", path.display()))?;
+                    validate_spirv(&words).with_context(|| format!("Failed to load {}", path.display()))?;
+
+                    Shader::from_spirv(Vec::from(bytes))
+                }
+                _ => bail!("Unhandled extension: {}", ext)
             };
 
-            let asset = LoadedAsset::new(shader);
+            shader.includes = included.clone();
+
+            //Loading the includes as dependencies makes the asset server watch them, but it won't reload this shader when they change
+            let asset = included
+                .into_iter()
+                .fold(LoadedAsset::new(shader), |asset, include_path| asset.with_dependency(AssetPath::new(include_path, None)));
             load_context.set_default_asset(asset);
 
             Ok(())
@@ -54,6 +540,246 @@ impl AssetLoader for ShaderLoader {
     }
 
     fn extensions(&self) -> &[&str] {
-        &["hlsl", "spv"]
+        &["hlsl", "vert", "frag", "comp", "glsl", "wgsl", "spv"]
+    }
+}
+
+//Sent after a watched shader reloaded, pipelines using handle compile it with their entry points and keep their old modules if that fails
+pub struct ShaderReloaded {
+    pub handle: Handle<Shader>
+}
+
+pub fn shader_reload_system(
+    mut asset_events: EventReader<AssetEvent<Shader>>,
+    shaders: Res<Assets<Shader>>,
+    asset_server: Res<AssetServer>,
+    mut shader_reloaded_events: EventWriter<ShaderReloaded>
+) {
+    for event in asset_events.iter() {
+        if let AssetEvent::Modified { handle } = event {
+            if shaders.contains(handle) {
+                shader_reloaded_events.send(ShaderReloaded { handle: handle.clone_weak() });
+            }
+
+            //Shaders including the modified file are reloaded to expand it again, which sends their own Modified events
+            let path = match asset_server.get_handle_path(handle) {
+                Some(path) => path,
+                None => continue
+            };
+
+            for (id, shader) in shaders.iter() {
+                if !shader.includes().iter().any(|include| include == path.path()) {
+                    continue
+                }
+
+                if let Some(parent_path) = asset_server.get_handle_path(id) {
+                    asset_server.reload_asset(parent_path);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::Future,
+        task::{Context as TaskContext, Poll, RawWaker, RawWakerVTable, Waker}
+    };
+
+    use super::*;
+
+    impl IncludeReader for HashMap<PathBuf, String> {
+        fn read<'a>(&'a self, path: &'a Path) -> BoxedFuture<'a, Result<Vec<u8>>> {
+            let bytes = self.get(path).map(|source| source.as_bytes().to_vec()).ok_or_else(|| anyhow!("{} not found", path.display()));
+
+            Box::pin(async move { bytes })
+        }
+    }
+
+    //The in memory reader never suspends, so a single poll completes the future
+    fn block_on<T>(future: BoxedFuture<T>) -> T {
+        fn raw_waker() -> RawWaker {
+            static VTABLE: RawWakerVTable = RawWakerVTable::new(|_| raw_waker(), |_| {}, |_| {}, |_| {});
+            RawWaker::new(std::ptr::null(), &VTABLE)
+        }
+
+        let waker = unsafe { Waker::from_raw(raw_waker()) };
+        let mut future = future;
+
+        match future.as_mut().poll(&mut TaskContext::from_waker(&waker)) {
+            Poll::Ready(value) => value,
+            Poll::Pending => panic!("Include resolution suspended")
+        }
+    }
+
+    #[test]
+    fn resolves_relative_includes() {
+        let files: HashMap<PathBuf, String> = [
+            (PathBuf::from("shaders/common/lighting.glsl"), "#include \"../shared.glsl\"\nfloat lighting() { return shared(); }".to_owned()),
+            (PathBuf::from("shaders/shared.glsl"), "float shared() { return 1.0; }".to_owned())
+        ]
+        .into_iter()
+        .collect();
+
+        let source = "#version 450\n#include \"common/lighting.glsl\"\n#include \"shared.glsl\"\nvoid main() {}\n";
+        let mut included = Vec::new();
+        let resolved = block_on(resolve_includes(&files, Path::new("shaders/main.frag"), source, &mut included)).unwrap();
+
+        assert_eq!(resolved.matches("float shared()").count(), 1);
+        assert!(resolved.contains("float lighting()"));
+        assert_eq!(included, [PathBuf::from("shaders/common/lighting.glsl"), PathBuf::from("shaders/shared.glsl")]);
+
+        let source = enable_glsl_line_directives(Path::new("shaders/main.frag"), &resolved);
+        let shader = shader_from_source(Path::new("shaders/main.frag"), "frag", source).unwrap();
+        assert_eq!(shader.compile(ShaderStage::Fragment, "main").unwrap()[0], SPIRV_MAGIC);
+    }
+
+    #[test]
+    fn errors_point_into_the_included_file() {
+        let files: HashMap<PathBuf, String> = [(PathBuf::from("shaders/broken.glsl"), "float ok() { return 1.0; }\nfloat broken() { return missing; }".to_owned())]
+            .into_iter()
+            .collect();
+
+        let source = "#version 450\n#include \"broken.glsl\"\n#include \"broken.glsl\"\nvoid main() {\n    undeclared();\n}\n";
+        let mut included = Vec::new();
+        let resolved = block_on(resolve_includes(&files, Path::new("shaders/main.frag"), source, &mut included)).unwrap();
+        let source = enable_glsl_line_directives(Path::new("shaders/main.frag"), &resolved);
+
+        let error = Shader::from_glsl(source).compile(ShaderStage::Fragment, "main").unwrap_err().to_string();
+        assert!(error.contains("shaders/broken.glsl:2"), "{}", error);
+        assert!(error.contains("shaders/main.frag:5"), "{}", error);
+    }
+
+    #[test]
+    fn missing_include_names_the_file() {
+        let files = HashMap::new();
+        let mut included = Vec::new();
+
+        let error = block_on(resolve_includes(&files, Path::new("shaders/main.frag"), "#include \"missing.glsl\"\n", &mut included)).unwrap_err();
+
+        assert!(format!("{:#}", error).contains("shaders/missing.glsl"));
+    }
+
+    #[test]
+    fn loads_glsl_vertex_shader() {
+        let source = "#version 450\nvoid main() {\n    gl_Position = vec4(0.0, 0.0, 0.0, 1.0);\n}\n";
+        let shader = shader_from_source(Path::new("shaders/triangle.vert"), "vert", source.to_owned()).unwrap();
+
+        let words = shader.compile(ShaderStage::Vertex, "main").unwrap();
+        assert_eq!(words[0], SPIRV_MAGIC);
+    }
+
+    #[test]
+    fn loading_leaves_the_entry_point_to_the_pipeline() {
+        //Without an entry point named main, compiling at load time used to fail
+        let source = "float4 ps_main() : SV_Target { return float4(1.0, 0.0, 0.0, 1.0); }\n";
+        let shader = shader_from_source(Path::new("shaders/lit.frag.hlsl"), "hlsl", source.to_owned()).unwrap();
+
+        assert!(matches!(shader.source(), Source::Hlsl(_)));
+        assert!(shader.compiled.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn unknown_extension_is_an_error() {
+        let error = shader_from_source(Path::new("shaders/triangle.txt"), "txt", String::new()).unwrap_err();
+
+        assert!(error.to_string().contains("txt"));
+    }
+
+    #[test]
+    fn defines_change_glsl_spirv() {
+        let shader = Shader::from_glsl(
+            "#version 450
+layout(location = 0) out vec4 color;
+void main() {
+#ifdef RED
+    color = vec4(1.0, 0.0, 0.0, 1.0);
+#else
+    color = vec4(0.0);
+#endif
+}
+"
+        );
+
+        let plain = shader.compile(ShaderStage::Fragment, "main").unwrap();
+        let red = shader.compile_with_defines(ShaderStage::Fragment, "main", &ShaderDefines::new().define("RED", "1")).unwrap();
+
+        assert_eq!(plain[0], SPIRV_MAGIC);
+        assert_eq!(red[0], SPIRV_MAGIC);
+        assert_ne!(plain, red);
+        assert_eq!(shader.compile(ShaderStage::Fragment, "main").unwrap(), plain);
+    }
+
+    #[test]
+    fn preprocess_wgsl_selects_branches() {
+        let source = "a\n#ifdef FOO\nb\n#ifndef BAR\nc\n#endif\n#else\nd\n#endif\ne\n";
+
+        let without = preprocess_wgsl(source, &ShaderDefines::new()).unwrap();
+        let with_foo = preprocess_wgsl(source, &ShaderDefines::new().define("FOO", "1")).unwrap();
+        let with_both = preprocess_wgsl(source, &ShaderDefines::new().define("FOO", "1").define("BAR", "1")).unwrap();
+
+        let lines = |output: &str| output.lines().filter(|line| !line.is_empty()).map(str::to_owned).collect::<Vec<_>>();
+        assert_eq!(lines(&without), ["a", "d", "e"]);
+        assert_eq!(lines(&with_foo), ["a", "b", "c", "e"]);
+        assert_eq!(lines(&with_both), ["a", "b", "e"]);
+
+        //Line numbers are preserved for naga diagnostics
+        assert_eq!(without.lines().count(), source.lines().count());
+    }
+
+    #[test]
+    fn preprocess_wgsl_rejects_unbalanced_blocks() {
+        assert!(preprocess_wgsl("#ifdef FOO\na\n", &ShaderDefines::new()).is_err());
+        assert!(preprocess_wgsl("a\n#endif\n", &ShaderDefines::new()).is_err());
+        assert!(preprocess_wgsl("#else\n", &ShaderDefines::new()).is_err());
+    }
+
+    const COMBINED_WGSL: &str = "
+[[stage(vertex)]]
+fn vs_main([[builtin(vertex_index)]] index: u32) -> [[builtin(position)]] vec4<f32> {
+    return vec4<f32>(f32(index), 0.0, 0.0, 1.0);
+}
+
+[[stage(fragment)]]
+fn fs_main() -> [[location(0)]] vec4<f32> {
+    return vec4<f32>(1.0);
+}
+";
+
+    #[test]
+    fn combined_module_exposes_both_entry_points() {
+        let expected = [(ShaderStage::Vertex, "vs_main".to_owned()), (ShaderStage::Fragment, "fs_main".to_owned())];
+
+        assert_eq!(Shader::from_wgsl(COMBINED_WGSL).entry_points().unwrap(), expected);
+
+        //Without pipeline options naga writes every entry point into one SPIR-V module
+        let (module, module_info) = parse_wgsl(COMBINED_WGSL).unwrap();
+        let words = naga::back::spv::write_vec(&module, &module_info, &naga::back::spv::Options::default(), None).unwrap();
+        let shader = Shader::from_spirv(bytemuck::cast_slice::<u32, u8>(&words).to_vec());
+
+        let mut entry_points = shader.entry_points().unwrap();
+        entry_points.sort_by_key(|(stage, _)| *stage != ShaderStage::Vertex);
+        assert_eq!(entry_points, expected);
+    }
+
+    #[test]
+    fn wgsl_compiles_to_spirv() {
+        let shader = shader_from_source(Path::new("shaders/triangle.wgsl"), "wgsl", COMBINED_WGSL.to_owned()).unwrap();
+
+        assert_eq!(shader.compile(ShaderStage::Vertex, "vs_main").unwrap()[0], SPIRV_MAGIC);
+        assert_eq!(shader.compile(ShaderStage::Fragment, "fs_main").unwrap()[0], SPIRV_MAGIC);
+        assert!(shader.compile(ShaderStage::Fragment, "vs_main").is_err());
+    }
+
+    #[test]
+    fn truncated_spirv_is_rejected() {
+        let mut bytes = SPIRV_MAGIC.to_le_bytes().to_vec();
+        bytes.resize(22, 0);
+
+        let error = read_spirv(&bytes).unwrap_err();
+        assert_eq!(error.to_string(), "SPIR-V size of 22 bytes is not a multiple of 4");
+
+        assert!(Shader::from_spirv(bytes).compile(ShaderStage::Vertex, "main").is_err());
     }
 }
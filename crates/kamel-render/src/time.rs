@@ -0,0 +1,69 @@
+use std::time::{Duration, Instant};
+
+use kamel_bevy::ecs::system::ResMut;
+
+#[derive(Clone, Debug)]
+pub struct FrameTimings {
+    delta: Duration,
+    elapsed: Duration,
+    fps: f64,
+
+    start: Instant,
+    last_frame: Option<Instant>
+}
+
+impl Default for FrameTimings {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            delta: Duration::ZERO,
+            elapsed: Duration::ZERO,
+            fps: 0.0,
+
+            start: Instant::now(),
+            last_frame: None
+        }
+    }
+}
+
+impl FrameTimings {
+    const FPS_SMOOTHING: f64 = 0.9;
+
+    pub fn update(&mut self, now: Instant) {
+        if let Some(last_frame) = self.last_frame {
+            self.delta = now - last_frame;
+
+            let delta_seconds = self.delta.as_secs_f64();
+            if delta_seconds > 0.0 {
+                let fps = 1.0 / delta_seconds;
+                self.fps = if self.fps == 0.0 {
+                    fps
+                } else {
+                    self.fps * Self::FPS_SMOOTHING + fps * (1.0 - Self::FPS_SMOOTHING)
+                };
+            }
+        }
+
+        self.elapsed = now - self.start;
+        self.last_frame = Some(now);
+    }
+
+    #[inline]
+    pub fn delta(&self) -> Duration {
+        self.delta
+    }
+
+    #[inline]
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    #[inline]
+    pub fn fps(&self) -> f64 {
+        self.fps
+    }
+}
+
+pub fn frame_timings_system(mut frame_timings: ResMut<FrameTimings>) {
+    frame_timings.update(Instant::now());
+}